@@ -2,8 +2,12 @@ cargo bootimage --target x86_64-sos.json && echo "=== FINISHED COMPILING, RUNNIN
 qemu-system-x86_64 \
     -drive file=target/x86_64-sos/debug/bootimage-sos.bin,format=raw,if=ide,index=0 \
     -drive file=disk.img,format=raw,if=ide,index=1 \
+    -smp 4 \
     -m 2G\
     -boot order=c \
     -serial stdio \
     -device virtio-gpu-pci \
+    -netdev user,id=net0 \
+    -device virtio-net-pci,netdev=net0 \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
     -display sdl
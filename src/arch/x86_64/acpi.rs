@@ -0,0 +1,233 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use x86_64::{PhysAddr, VirtAddr};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+const EBDA_POINTER: u64 = 0x40E;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_ENTRIES_OFFSET: usize = size_of::<SdtHeader>() + 8;
+const LAPIC_ENABLED: u32 = 1 << 0;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+ only.
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+fn phys_to_virt(physical_memory_offset: VirtAddr, phys: u64) -> *const u8 {
+    (physical_memory_offset + phys).as_ptr()
+}
+
+fn checksum_ok(ptr: *const u8, len: usize) -> bool {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn scan_for_rsdp(physical_memory_offset: VirtAddr, start: u64, end: u64) -> Option<Rsdp> {
+    (start..end).step_by(16).find_map(|phys| {
+        let ptr = phys_to_virt(physical_memory_offset, phys);
+        let rsdp = unsafe { read_unaligned(ptr as *const Rsdp) };
+        // The v1 checksum only covers the first 20 bytes.
+        (&rsdp.signature == RSDP_SIGNATURE && checksum_ok(ptr, 20)).then_some(rsdp)
+    })
+}
+
+/// Looks for the RSDP in the first KiB of the EBDA, then in the BIOS
+/// read-only area at 0xE0000-0xFFFFF.
+fn find_rsdp(physical_memory_offset: VirtAddr) -> Option<Rsdp> {
+    let ebda_segment =
+        unsafe { read_unaligned(phys_to_virt(physical_memory_offset, EBDA_POINTER) as *const u16) };
+    let ebda = (ebda_segment as u64) << 4;
+
+    if ebda != 0 {
+        if let Some(rsdp) = scan_for_rsdp(physical_memory_offset, ebda, ebda + 1024) {
+            return Some(rsdp);
+        }
+    }
+    scan_for_rsdp(physical_memory_offset, BIOS_AREA_START, BIOS_AREA_END)
+}
+
+/// Walks the XSDT (or the RSDT on ACPI 1.0) for a table with `signature`
+/// and returns its physical address.
+fn find_table(physical_memory_offset: VirtAddr, rsdp: &Rsdp, signature: &[u8; 4]) -> Option<u64> {
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (rsdp.rsdt_address as u64, 4)
+    };
+
+    let root_ptr = phys_to_virt(physical_memory_offset, root);
+    let header = unsafe { read_unaligned(root_ptr as *const SdtHeader) };
+    let entries = (header.length as usize).saturating_sub(size_of::<SdtHeader>()) / entry_size;
+
+    (0..entries).find_map(|i| {
+        let entry = unsafe { root_ptr.add(size_of::<SdtHeader>() + i * entry_size) };
+        let table = unsafe {
+            if entry_size == 8 {
+                read_unaligned(entry as *const u64)
+            } else {
+                read_unaligned(entry as *const u32) as u64
+            }
+        };
+        let table_header = unsafe {
+            read_unaligned(phys_to_virt(physical_memory_offset, table) as *const SdtHeader)
+        };
+        (&table_header.signature == signature).then_some(table)
+    })
+}
+
+/// Calls `f` with the type and start of every entry in the MADT.
+fn for_each_madt_entry(
+    physical_memory_offset: VirtAddr,
+    mut f: impl FnMut(u8, *const u8),
+) -> Result<(), &'static str> {
+    let rsdp = find_rsdp(physical_memory_offset).ok_or("RSDP not found")?;
+    let madt = find_table(physical_memory_offset, &rsdp, MADT_SIGNATURE).ok_or("MADT not found")?;
+
+    let madt_ptr = phys_to_virt(physical_memory_offset, madt);
+    let header = unsafe { read_unaligned(madt_ptr as *const SdtHeader) };
+    if !checksum_ok(madt_ptr, header.length as usize) {
+        return Err("MADT checksum mismatch");
+    }
+
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= header.length as usize {
+        let entry = unsafe { madt_ptr.add(offset) };
+        let (entry_type, entry_len) = unsafe { (*entry, *entry.add(1) as usize) };
+        if entry_len < 2 {
+            return Err("malformed MADT entry");
+        }
+        f(entry_type, entry);
+        offset += entry_len;
+    }
+    Ok(())
+}
+
+/// Returns the APIC IDs of every enabled processor listed in the MADT,
+/// including the BSP.
+pub fn local_apic_ids(physical_memory_offset: VirtAddr) -> Result<Vec<u32>, &'static str> {
+    let mut ids = Vec::new();
+    for_each_madt_entry(
+        physical_memory_offset,
+        |entry_type, entry| match entry_type {
+            MADT_LOCAL_APIC => {
+                let apic_id = unsafe { *entry.add(3) } as u32;
+                let flags = unsafe { read_unaligned(entry.add(4) as *const u32) };
+                if flags & LAPIC_ENABLED != 0 {
+                    ids.push(apic_id);
+                }
+            }
+            MADT_LOCAL_X2APIC => {
+                let apic_id = unsafe { read_unaligned(entry.add(4) as *const u32) };
+                let flags = unsafe { read_unaligned(entry.add(8) as *const u32) };
+                if flags & LAPIC_ENABLED != 0 && !ids.contains(&apic_id) {
+                    ids.push(apic_id);
+                }
+            }
+            _ => {}
+        },
+    )?;
+    Ok(ids)
+}
+
+/// An IO APIC from the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: u64,
+    /// First global system interrupt its inputs are numbered from.
+    pub gsi_base: u32,
+}
+
+/// A legacy IRQ the firmware wired to a different IO APIC input, or with
+/// a polarity or trigger mode other than ISA's active-high edge.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: polarity in bits 0-1, trigger mode in bits 2-3.
+    pub flags: u16,
+}
+
+/// Returns the IO APICs and the legacy IRQ overrides listed in the MADT.
+pub fn io_apics(
+    physical_memory_offset: VirtAddr,
+) -> Result<(Vec<IoApicEntry>, Vec<InterruptOverride>), &'static str> {
+    let mut io_apics = Vec::new();
+    let mut overrides = Vec::new();
+    for_each_madt_entry(
+        physical_memory_offset,
+        |entry_type, entry| match entry_type {
+            MADT_IO_APIC => io_apics.push(IoApicEntry {
+                id: unsafe { *entry.add(2) },
+                address: unsafe { read_unaligned(entry.add(4) as *const u32) } as u64,
+                gsi_base: unsafe { read_unaligned(entry.add(8) as *const u32) },
+            }),
+            MADT_INTERRUPT_OVERRIDE => overrides.push(InterruptOverride {
+                irq: unsafe { *entry.add(3) },
+                gsi: unsafe { read_unaligned(entry.add(4) as *const u32) },
+                flags: unsafe { read_unaligned(entry.add(8) as *const u16) },
+            }),
+            _ => {}
+        },
+    )?;
+    Ok((io_apics, overrides))
+}
+
+/// Number of CPUs boot starts: the enabled processors in the MADT, capped
+/// at `smp::MAX_CPUS`. Needs paging to be initialized.
+pub fn cpu_count() -> Result<usize, &'static str> {
+    let offset =
+        crate::paging::phys_to_virt(PhysAddr::new(0)).ok_or("Paging is not initialized")?;
+    Ok(local_apic_ids(offset)?.len().min(crate::smp::MAX_CPUS))
+}
+
+/// Checks the MADT lists the BSP, and no APIC ID more than once.
+pub fn test_madt() -> Result<(), &'static str> {
+    crate::serial_println!("=== MADT Test ===");
+
+    let offset =
+        crate::paging::phys_to_virt(PhysAddr::new(0)).ok_or("Paging is not initialized")?;
+    let ids = local_apic_ids(offset)?;
+    if !ids.contains(&crate::smp::current_apic_id()) {
+        return Err("MADT does not list the BSP");
+    }
+    if ids.iter().enumerate().any(|(i, id)| ids[..i].contains(id)) {
+        return Err("MADT lists an APIC ID twice");
+    }
+
+    crate::serial_println!("✓ MADT lists {} processor(s): {:?}", ids.len(), ids);
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use core::arch::x86_64::{__cpuid, CpuidResult};
+use core::fmt;
+use lazy_static::lazy_static;
+
+const LEAF_VENDOR: u32 = 0;
+const LEAF_FEATURES: u32 = 1;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+const LEAF_BRAND_FIRST: u32 = 0x8000_0002;
+const LEAF_BRAND_LAST: u32 = 0x8000_0004;
+
+/// CPU features the kernel cares about, as reported by CPUID leaf 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Tsc,
+    Apic,
+    X2Apic,
+    Pat,
+    Fxsr,
+    Sse,
+    Sse2,
+    Rdrand,
+}
+
+const ALL_FEATURES: [Feature; 8] = [
+    Feature::Tsc,
+    Feature::Apic,
+    Feature::X2Apic,
+    Feature::Pat,
+    Feature::Fxsr,
+    Feature::Sse,
+    Feature::Sse2,
+    Feature::Rdrand,
+];
+
+impl Feature {
+    /// Whether the bit is in EDX (`false`) or ECX (`true`), and which.
+    fn bit(self) -> (bool, u32) {
+        match self {
+            Feature::Tsc => (false, 4),
+            Feature::Apic => (false, 9),
+            Feature::Pat => (false, 16),
+            Feature::Fxsr => (false, 24),
+            Feature::Sse => (false, 25),
+            Feature::Sse2 => (false, 26),
+            Feature::X2Apic => (true, 21),
+            Feature::Rdrand => (true, 30),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Tsc => "tsc",
+            Feature::Apic => "apic",
+            Feature::X2Apic => "x2apic",
+            Feature::Pat => "pat",
+            Feature::Fxsr => "fxsr",
+            Feature::Sse => "sse",
+            Feature::Sse2 => "sse2",
+            Feature::Rdrand => "rdrand",
+        }
+    }
+}
+
+/// What CPUID said at boot; the answers don't change while running.
+struct CpuIdentity {
+    vendor: [u8; 12],
+    brand: Option<[u8; 48]>,
+    features_ecx: u32,
+    features_edx: u32,
+}
+
+lazy_static! {
+    static ref IDENTITY: CpuIdentity = read_identity();
+}
+
+fn read_identity() -> CpuIdentity {
+    let CpuidResult {
+        eax: max_leaf,
+        ebx,
+        ecx,
+        edx,
+    } = __cpuid(LEAF_VENDOR);
+    let mut vendor = [0u8; 12];
+    vendor[0..4].copy_from_slice(&ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&ecx.to_le_bytes());
+
+    let (features_ecx, features_edx) = if max_leaf >= LEAF_FEATURES {
+        let leaf = __cpuid(LEAF_FEATURES);
+        (leaf.ecx, leaf.edx)
+    } else {
+        (0, 0)
+    };
+
+    // Leaves past the reported maximum return garbage, not zeros.
+    let brand = (__cpuid(LEAF_EXTENDED_MAX).eax >= LEAF_BRAND_LAST).then(|| {
+        let mut brand = [0u8; 48];
+        for (i, leaf) in (LEAF_BRAND_FIRST..=LEAF_BRAND_LAST).enumerate() {
+            let result = __cpuid(leaf);
+            for (j, reg) in [result.eax, result.ebx, result.ecx, result.edx]
+                .iter()
+                .enumerate()
+            {
+                let at = i * 16 + j * 4;
+                brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+        brand
+    });
+
+    CpuIdentity {
+        vendor,
+        brand,
+        features_ecx,
+        features_edx,
+    }
+}
+
+pub fn has_feature(feature: Feature) -> bool {
+    let (in_ecx, bit) = feature.bit();
+    let register = if in_ecx {
+        IDENTITY.features_ecx
+    } else {
+        IDENTITY.features_edx
+    };
+    register & (1 << bit) != 0
+}
+
+/// The vendor ID, e.g. `GenuineIntel` or `AuthenticAMD`.
+pub fn vendor() -> &'static str {
+    core::str::from_utf8(&IDENTITY.vendor).unwrap_or("unknown")
+}
+
+/// The marketing name, trimmed, or `None` on CPUs without the extended
+/// brand leaves.
+pub fn brand() -> Option<&'static str> {
+    let brand = IDENTITY.brand.as_ref()?;
+    let end = brand.iter().position(|&b| b == 0).unwrap_or(brand.len());
+    core::str::from_utf8(&brand[..end]).ok().map(str::trim)
+}
+
+/// Prints the vendor, brand and which of the features in `Feature` the
+/// CPU has.
+pub fn print_summary() {
+    crate::serial_println!(
+        "CPU: {} {}",
+        vendor(),
+        brand().unwrap_or("(no brand string)")
+    );
+    crate::serial_println!("CPU features:{}", FeatureList);
+}
+
+/// Formats as ` +tsc +apic -x2apic ...`.
+struct FeatureList;
+
+impl fmt::Display for FeatureList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for feature in ALL_FEATURES {
+            let mark = if has_feature(feature) { '+' } else { '-' };
+            write!(f, " {}{}", mark, feature.name())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use core::arch::asm;
+use core::fmt;
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::registers::rflags;
+use x86_64::VirtAddr;
+
+/// Most stack words `dump_stack` prints.
+const STACK_DUMP_WORDS: u64 = 16;
+/// Most frames `print_backtrace` follows, in case the chain loops.
+const MAX_BACKTRACE_FRAMES: usize = 32;
+
+/// Registers at the point of a crash. Taken in the panic handler, the
+/// callee-saved ones still hold what the panicking code left in them.
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterDump {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rflags: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+}
+
+impl RegisterDump {
+    /// Reads the registers where this is inlined, without touching memory
+    /// beyond the stack.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let (rip, rsp, rbp, rbx, r12, r13, r14, r15): (u64, u64, u64, u64, u64, u64, u64, u64);
+        unsafe {
+            asm!(
+                "lea {rip}, [rip]",
+                "mov {rsp}, rsp",
+                "mov {rbp}, rbp",
+                "mov {rbx}, rbx",
+                rip = out(reg) rip,
+                rsp = out(reg) rsp,
+                rbp = out(reg) rbp,
+                rbx = out(reg) rbx,
+                out("r12") r12,
+                out("r13") r13,
+                out("r14") r14,
+                out("r15") r15,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        RegisterDump {
+            rip,
+            rsp,
+            rbp,
+            rbx,
+            r12,
+            r13,
+            r14,
+            r15,
+            rflags: rflags::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+        }
+    }
+}
+
+impl fmt::Display for RegisterDump {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP {:016x}  RSP {:016x}  RBP {:016x}",
+            self.rip, self.rsp, self.rbp
+        )?;
+        writeln!(
+            f,
+            "RBX {:016x}  R12 {:016x}  R13 {:016x}",
+            self.rbx, self.r12, self.r13
+        )?;
+        writeln!(
+            f,
+            "R14 {:016x}  R15 {:016x}  RFL {:016x}",
+            self.r14, self.r15, self.rflags
+        )?;
+        write!(f, "CR2 {:016x}  CR3 {:016x}", self.cr2, self.cr3)
+    }
+}
+
+/// The frame pointer where this is inlined. In an interrupt handler it
+/// points at the interrupted code's frame pointer, which the prologue saved.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Prints the return addresses up the frame-pointer chain from `rbp`,
+/// innermost first. A thread's entry frame has a saved `rbp` of 0, which
+/// ends the walk; so does a frame pointer that is misaligned,
+/// non-canonical, in a stack guard page or not above the last one.
+///
+/// # Safety
+///
+/// `rbp` must be a frame pointer from a stack built with frame pointers.
+pub unsafe fn print_backtrace(mut rbp: u64) {
+    crate::serial_println!("Backtrace:");
+    for depth in 0..MAX_BACKTRACE_FRAMES {
+        if rbp == 0 {
+            return;
+        }
+        if !rbp.is_multiple_of(8)
+            || VirtAddr::try_new(rbp).is_err()
+            || crate::context::stack_guard_owner(rbp).is_some()
+        {
+            crate::serial_println!("  <bad frame pointer {:#x}>", rbp);
+            return;
+        }
+        let next = unsafe { (rbp as *const u64).read_volatile() };
+        let return_address = unsafe { ((rbp + 8) as *const u64).read_volatile() };
+        if return_address == 0 {
+            return;
+        }
+        crate::serial_println!("  #{:<2} {:#018x}", depth, return_address);
+        if next != 0 && next <= rbp {
+            crate::serial_println!("  <frame chain goes backwards at {:#x}>", next);
+            return;
+        }
+        rbp = next;
+    }
+    crate::serial_println!("  <stopped after {} frames>", MAX_BACKTRACE_FRAMES);
+}
+
+/// Prints up to 16 words upwards from `rsp`, stopping at the end of its
+/// page: the page `rsp` is in is mapped, the next one may be a guard page.
+///
+/// # Safety
+///
+/// `rsp` must point into a mapped stack.
+pub unsafe fn dump_stack(rsp: u64) {
+    let rsp = rsp & !7;
+    let page_end = (rsp | 0xFFF) + 1;
+    let words = ((page_end - rsp) / 8).min(STACK_DUMP_WORDS);
+    crate::serial_println!("Stack from {:#x}:", rsp);
+    for i in 0..words {
+        let addr = rsp + i * 8;
+        let value = unsafe { (addr as *const u64).read_volatile() };
+        crate::serial_println!("  {:016x}: {:016x}", addr, value);
+    }
+}
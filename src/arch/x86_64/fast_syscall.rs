@@ -0,0 +1,85 @@
+use crate::gdt;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// Kernel stack `syscall_fast_entry` switches to: the TSS ring 0 stack,
+/// which interrupts from ring 3 also use. That is safe because entry
+/// masks interrupts, and there is only ever one user program.
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+/// The caller's stack pointer while the kernel handles its syscall.
+static USER_RSP: AtomicU64 = AtomicU64::new(0);
+
+unsafe extern "C" {
+    fn syscall_fast_entry();
+}
+
+// `syscall` leaves the user stack in place, the return address in rcx and
+// RFLAGS in r11, with interrupts masked through SFMASK. The registers
+// follow `syscall::syscall3` so both paths dispatch the same way, and as
+// with `int 0x80` everything but rax comes back unchanged, apart from rcx
+// and r11, which the instruction itself clobbers. With the ring 0 stack
+// aligned first, the tenth slot keeps it 16-byte aligned for the call.
+global_asm!(
+    r#"
+    .text
+    .global syscall_fast_entry
+    .type syscall_fast_entry, @function
+syscall_fast_entry:
+    mov [rip + {user_rsp}], rsp
+    mov rsp, [rip + {kernel_rsp}]
+    and rsp, -16
+    push qword ptr [rip + {user_rsp}]
+    push rcx
+    push r11
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    sub rsp, 8
+
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    call {dispatch}
+
+    add rsp, 8
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+"#,
+    user_rsp = sym USER_RSP,
+    kernel_rsp = sym KERNEL_RSP,
+    dispatch = sym syscall_fast_dispatch,
+);
+
+extern "C" fn syscall_fast_dispatch(num: u64, a0: u64, a1: u64, a2: u64) -> u64 {
+    crate::syscall::syscall_identifier(num, a0, a1, a2)
+}
+
+/// Turns on the `syscall` instruction for user programs, alongside
+/// `int 0x80`. Must run after `gdt::init`.
+pub fn init_fast_syscalls() -> Result<(), &'static str> {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    Star::write(user_code, user_data, kernel_code, kernel_data)?;
+    KERNEL_RSP.store(gdt::privilege_stack_top().as_u64(), Ordering::SeqCst);
+    LStar::write(VirtAddr::new(
+        syscall_fast_entry as unsafe extern "C" fn() as usize as u64,
+    ));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use super::smp::MAX_CPUS;
 use lazy_static::lazy_static;
 use x86_64::VirtAddr;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
@@ -5,6 +6,8 @@ use x86_64::structures::tss::TaskStateSegment;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const AP_STACK_SIZE: usize = 4096 * 5;
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
@@ -16,6 +19,14 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
+        // Loaded on interrupts and syscalls from ring 3.
+        tss.privilege_stack_table[0] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+            stack_start + STACK_SIZE
+        };
         tss
     };
 }
@@ -23,13 +34,22 @@ lazy_static! {
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
+        // `syscall` and `sysret` derive their selectors from STAR, which
+        // needs kernel data right after kernel code and user data right
+        // before user code.
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         (
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
                 tss_selector,
+                user_code_selector,
+                user_data_selector,
             },
         )
     };
@@ -37,16 +57,90 @@ lazy_static! {
 
 struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+}
+
+/// The ring 3 code and data selectors, for entering user mode.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// The ring 0 code and data selectors.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+#[repr(align(16))]
+struct ApStack([u8; AP_STACK_SIZE]);
+
+// Loading a TSS marks it busy, so each AP needs its own, and a GDT of its
+// own to point at it. Index 0 is the BSP's slot and stays unused: it runs
+// on `GDT` and `TSS` above.
+static mut AP_TSS: [TaskStateSegment; MAX_CPUS] = [const { TaskStateSegment::new() }; MAX_CPUS];
+static mut AP_GDT: [GlobalDescriptorTable; MAX_CPUS] =
+    [const { GlobalDescriptorTable::new() }; MAX_CPUS];
+static mut AP_DOUBLE_FAULT_STACKS: [ApStack; MAX_CPUS] =
+    [const { ApStack([0; AP_STACK_SIZE]) }; MAX_CPUS];
+static mut AP_PRIVILEGE_STACKS: [ApStack; MAX_CPUS] =
+    [const { ApStack([0; AP_STACK_SIZE]) }; MAX_CPUS];
+
+/// Top of the stack the CPU switches to on entering ring 0 from ring 3.
+pub fn privilege_stack_top() -> VirtAddr {
+    TSS.privilege_stack_table[0]
 }
 
 pub fn init() {
-    use x86_64::instructions::segmentation::{CS, Segment};
+    use x86_64::instructions::segmentation::{CS, SS, Segment};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Builds and loads CPU `cpu_id`'s own GDT and TSS, replacing the
+/// trampoline's GDT. Run once per AP, before it loads the IDT.
+///
+/// The descriptors are added in the same order as in `GDT`, so the
+/// selectors match the BSP's and `kernel_selectors`/`user_selectors` hold
+/// on every CPU.
+pub fn init_ap(cpu_id: usize) {
+    use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
+    use x86_64::instructions::tables::load_tss;
+
+    assert!(
+        cpu_id != 0 && cpu_id < MAX_CPUS,
+        "gdt::init_ap: not an AP index"
+    );
+
+    unsafe {
+        let tss = &mut (*(&raw mut AP_TSS))[cpu_id];
+        let double_fault = &raw const (*(&raw const AP_DOUBLE_FAULT_STACKS))[cpu_id].0;
+        let privilege = &raw const (*(&raw const AP_PRIVILEGE_STACKS))[cpu_id].0;
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            VirtAddr::from_ptr(double_fault) + AP_STACK_SIZE;
+        tss.privilege_stack_table[0] = VirtAddr::from_ptr(privilege) + AP_STACK_SIZE;
+        let tss: &'static TaskStateSegment = tss;
+
+        let gdt = &mut (*(&raw mut AP_GDT))[cpu_id];
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+        gdt.add_entry(Descriptor::user_data_segment());
+        gdt.add_entry(Descriptor::user_code_segment());
+        let gdt: &'static GlobalDescriptorTable = gdt;
+
+        gdt.load();
+        CS::set_reg(code_selector);
+        SS::set_reg(data_selector);
+        DS::set_reg(data_selector);
+        ES::set_reg(data_selector);
+        load_tss(tss_selector);
+    }
+}
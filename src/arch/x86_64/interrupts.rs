@@ -1,8 +1,11 @@
 use crate::{gdt, hlt_loop, println};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -11,9 +14,7 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard,
-    AtaPrimary = PIC_1_OFFSET + 14,
-    AtaSecondary = PIC_1_OFFSET + 15,
+    ApicTimer = PIC_2_OFFSET + 8,
 }
 
 impl InterruptIndex {
@@ -26,26 +27,127 @@ impl InterruptIndex {
     }
 }
 
+/// Number of legacy IRQ lines behind the two 8259s.
+pub const IRQ_COUNT: usize = 16;
+
+/// How many handlers one line can have. PCI INTx lines are shared; QEMU
+/// puts every PCI slot on one of four links.
+const HANDLERS_PER_IRQ: usize = 4;
+
+/// Handlers for IRQs 1-15 added with `register_irq`, 0 for an empty slot.
+/// IRQ 0, the timer, is bound directly in the IDT.
+static IRQ_HANDLERS: [[AtomicUsize; HANDLERS_PER_IRQ]; IRQ_COUNT] =
+    [const { [const { AtomicUsize::new(0) }; HANDLERS_PER_IRQ] }; IRQ_COUNT];
+/// Interrupts taken on each legacy IRQ line since boot.
+static IRQ_COUNTS: [AtomicUsize; IRQ_COUNT] = [const { AtomicUsize::new(0) }; IRQ_COUNT];
+
+/// Vectors `IPI_VECTOR_BASE..IPI_VECTOR_BASE + IPI_VECTOR_COUNT` are set
+/// aside for inter-processor interrupts sent with `smp::send_ipi`.
+pub const IPI_VECTOR_BASE: u8 = 0xF0;
+pub const IPI_VECTOR_COUNT: usize = 4;
+
+static IPI_HANDLERS: [AtomicUsize; IPI_VECTOR_COUNT] =
+    [const { AtomicUsize::new(0) }; IPI_VECTOR_COUNT];
+
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Which controller delivers the legacy IRQs, and so takes their EOI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    /// The two 8259s, as set up at boot. Fine for a single core.
+    Pic,
+    /// The local APIC, with the 8259s masked; the IO APIC has to route
+    /// device IRQs for them to arrive at all.
+    Apic,
+}
+
+static APIC_CONTROLLER: AtomicBool = AtomicBool::new(false);
+
+pub fn interrupt_controller() -> InterruptController {
+    if APIC_CONTROLLER.load(Ordering::SeqCst) {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
+    }
+}
+
+/// Routes every registered IRQ through the IO APIC to the same vector,
+/// masks every 8259 line and sends legacy IRQ EOIs to the local APIC from
+/// now on. That masks the PIT too, so start the APIC timer first. There is
+/// no way back to the PIC.
+pub fn switch_to_apic() -> Result<(), &'static str> {
+    if !crate::ioapic::is_initialized() {
+        return Err("IO APIC not initialized");
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        crate::smp::enable_local_apic();
+        for irq in 1..IRQ_COUNT as u8 {
+            if IRQ_HANDLERS[usize::from(irq)][0].load(Ordering::SeqCst) != 0 {
+                crate::ioapic::route_irq(irq, PIC_1_OFFSET + irq)?;
+            }
+        }
+        unsafe { PICS.lock().disable() };
+        APIC_CONTROLLER.store(true, Ordering::SeqCst);
+        Ok(())
+    })
+}
+
+/// Interrupts taken on legacy IRQ line `irq` since boot.
+pub fn irq_count(irq: u8) -> usize {
+    IRQ_COUNTS
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// Acknowledges interrupt `vector` at whichever controller raised it. The
+/// APIC timer and IPIs always come from the local APIC; legacy IRQs follow
+/// `interrupt_controller`.
+pub fn end_of_interrupt(vector: u8) {
+    let legacy = (PIC_1_OFFSET..PIC_1_OFFSET + IRQ_COUNT as u8).contains(&vector);
+    if legacy && interrupt_controller() == InterruptController::Pic {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    } else {
+        crate::timer::apic_eoi();
+    }
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present
+            .set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault
+            .set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::ApicTimer.as_usize()].set_handler_fn(apic_timer_interrupt_handler);
+        for (irq, &stub) in IRQ_STUBS.iter().enumerate().skip(1) {
+            idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+        }
+        unsafe {
+            idt[SYSCALL_VECTOR as usize].set_handler_addr(x86_64::VirtAddr::new(
+                syscall_entry as unsafe extern "C" fn() as usize as u64,
+            ))
+            // User programs make system calls too.
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        }
 
-        idt[InterruptIndex::AtaPrimary.as_usize()].set_handler_fn(ata_primary_interrupt_handler);
-        idt[InterruptIndex::AtaSecondary.as_usize()]
-            .set_handler_fn(ata_secondary_interrupt_handler);
-        idt[0x80].set_handler_fn(syscall_handler);
+        idt[IPI_VECTOR_BASE as usize].set_handler_fn(ipi_handler_0);
+        idt[IPI_VECTOR_BASE as usize + 1].set_handler_fn(ipi_handler_1);
+        idt[IPI_VECTOR_BASE as usize + 2].set_handler_fn(ipi_handler_2);
+        idt[IPI_VECTOR_BASE as usize + 3].set_handler_fn(ipi_handler_3);
 
         idt
     };
@@ -55,24 +157,196 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn syscall_handler(_stack_frame: InterruptStackFrame) {
-    let num: u64;
-    let a0: u64;
-    let a1: u64;
-    let a2: u64;
+/// Runs `handler` on whichever CPU receives an IPI with `vector`. The APIC
+/// EOI is sent after `handler` returns.
+pub fn register_ipi_handler(vector: u8, handler: fn()) -> Result<(), &'static str> {
+    let slot = vector
+        .checked_sub(IPI_VECTOR_BASE)
+        .map(usize::from)
+        .filter(|&slot| slot < IPI_VECTOR_COUNT)
+        .ok_or("not an IPI vector")?;
+    IPI_HANDLERS[slot].store(handler as usize, Ordering::SeqCst);
+    Ok(())
+}
 
-    unsafe {
-        core::arch::asm!("mov {}, rax", out(reg) num);
-        core::arch::asm!("mov {}, rdi", out(reg) a0);
-        core::arch::asm!("mov {}, rsi", out(reg) a1);
-        core::arch::asm!("mov {}, rdx", out(reg) a2);
+/// Runs `handler` for legacy IRQ line `irq` (1-15) and unmasks the line
+/// at the PIC. A line can have several handlers, which all run on every
+/// interrupt, so each must check its device raised it. Registering the
+/// same handler twice is a no-op. The common stub sends the EOI after the
+/// handlers return.
+pub fn register_irq(irq: u8, handler: fn()) -> Result<(), &'static str> {
+    match usize::from(irq) {
+        0 => return Err("IRQ 0 is reserved for the timer"),
+        n if n >= IRQ_COUNT => return Err("no such IRQ line"),
+        _ => {}
     }
+    let handler = handler as usize;
+    let added = IRQ_HANDLERS[usize::from(irq)].iter().any(|slot| {
+        match slot.compare_exchange(0, handler, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => true,
+            Err(current) => current == handler,
+        }
+    });
+    if !added {
+        return Err("too many handlers on one IRQ line");
+    }
+    if interrupt_controller() == InterruptController::Apic {
+        return crate::ioapic::route_irq(irq, PIC_1_OFFSET + irq);
+    }
+    unmask_irq(irq);
+    Ok(())
+}
 
-    let result = crate::syscall::syscall_identifier(num, a0, a1, a2);
+/// Clears `irq`'s bit in its PIC's mask, plus the IRQ 2 cascade for lines
+/// on the slave.
+fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
 
-    unsafe {
-        core::arch::asm!("mov rax, {}", in(reg) result);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut pic1_data: Port<u8> = Port::new(0x21);
+        let mut pic2_data: Port<u8> = Port::new(0xA1);
+        if irq < 8 {
+            let mask = pic1_data.read() & !(1 << irq);
+            pic1_data.write(mask);
+        } else {
+            let mask = pic1_data.read() & !(1 << 2);
+            pic1_data.write(mask);
+            let mask = pic2_data.read() & !(1 << (irq - 8));
+            pic2_data.write(mask);
+        }
+    });
+}
+
+fn dispatch_irq(irq: u8) {
+    IRQ_COUNTS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    for slot in &IRQ_HANDLERS[usize::from(irq)] {
+        let handler = slot.load(Ordering::SeqCst);
+        if handler == 0 {
+            break;
+        }
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
     }
+    end_of_interrupt(PIC_1_OFFSET + irq);
+}
+
+macro_rules! irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_irq($irq);
+        }
+    };
+}
+
+irq_handler!(irq_handler_0, 0);
+irq_handler!(irq_handler_1, 1);
+irq_handler!(irq_handler_2, 2);
+irq_handler!(irq_handler_3, 3);
+irq_handler!(irq_handler_4, 4);
+irq_handler!(irq_handler_5, 5);
+irq_handler!(irq_handler_6, 6);
+irq_handler!(irq_handler_7, 7);
+irq_handler!(irq_handler_8, 8);
+irq_handler!(irq_handler_9, 9);
+irq_handler!(irq_handler_10, 10);
+irq_handler!(irq_handler_11, 11);
+irq_handler!(irq_handler_12, 12);
+irq_handler!(irq_handler_13, 13);
+irq_handler!(irq_handler_14, 14);
+irq_handler!(irq_handler_15, 15);
+
+static IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] = [
+    irq_handler_0,
+    irq_handler_1,
+    irq_handler_2,
+    irq_handler_3,
+    irq_handler_4,
+    irq_handler_5,
+    irq_handler_6,
+    irq_handler_7,
+    irq_handler_8,
+    irq_handler_9,
+    irq_handler_10,
+    irq_handler_11,
+    irq_handler_12,
+    irq_handler_13,
+    irq_handler_14,
+    irq_handler_15,
+];
+
+fn dispatch_ipi(slot: usize) {
+    let handler = IPI_HANDLERS[slot].load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    end_of_interrupt(IPI_VECTOR_BASE + slot as u8);
+}
+
+macro_rules! ipi_handler {
+    ($name:ident, $slot:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+            dispatch_ipi($slot);
+        }
+    };
+}
+
+ipi_handler!(ipi_handler_0, 0);
+ipi_handler!(ipi_handler_1, 1);
+ipi_handler!(ipi_handler_2, 2);
+ipi_handler!(ipi_handler_3, 3);
+
+/// Software interrupt used for system calls. See `syscall::syscall3` for
+/// the calling convention.
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+unsafe extern "C" {
+    fn syscall_entry();
+}
+
+// Runs before any Rust code so the argument registers are read as the caller
+// left them. Everything the C ABI lets `syscall_dispatch` clobber is saved
+// apart from rax, which carries the result back. The CPU pushed a 5-word
+// frame, so after nine more pushes the stack is 16-byte aligned again.
+global_asm!(
+    r#"
+    .text
+    .global syscall_entry
+    .type syscall_entry, @function
+syscall_entry:
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    push rbx
+
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    cld
+    call {dispatch}
+
+    pop rbx
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    iretq
+"#,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" fn syscall_dispatch(num: u64, a0: u64, a1: u64, a2: u64) -> u64 {
+    crate::syscall::syscall_identifier(num, a0, a1, a2)
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
@@ -86,64 +360,106 @@ extern "x86-interrupt" fn page_fault_handler(
     use crate::serial_println;
     use x86_64::registers::control::Cr2;
 
+    report_stack_overflow(Cr2::read().as_u64());
     serial_println!("EXCEPTION: PAGE FAULT");
     serial_println!("Accessed Address: {:?}", Cr2::read());
     serial_println!("Error Code: {:?}", error_code);
     serial_println!("{:#?}", stack_frame);
+    // The prologue saved the faulting code's frame pointer at our own.
+    unsafe {
+        let rbp = (crate::crash::frame_pointer() as *const u64).read();
+        crate::crash::print_backtrace(rbp);
+    }
     hlt_loop();
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
+/// Reports an unrecoverable CPU exception on serial and halts this CPU.
+fn fatal_exception(
+    name: &str,
+    vector: u8,
+    error_code: Option<u64>,
+    stack_frame: &InterruptStackFrame,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
+    use crate::serial_println;
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    serial_println!("EXCEPTION: {} (vector {})", name, vector);
+    if let Some(code) = error_code {
+        serial_println!("Error Code: {:#x}", code);
     }
+    serial_println!(
+        "At {:?} on CPU {}",
+        stack_frame.instruction_pointer,
+        crate::smp::current_cpu_id()
+    );
+    serial_println!("{:#?}", stack_frame);
+    hlt_loop();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    fatal_exception("DIVIDE ERROR", 0, None, &stack_frame);
+}
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    fatal_exception("INVALID OPCODE", 6, None, &stack_frame);
+}
 
-    crate::task::keyboard::add_scancode(scancode);
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    fatal_exception("INVALID TSS", 10, Some(error_code), &stack_frame);
+}
 
-    unsafe {
-        crate::interrupts::PICS
-            .lock()
-            .notify_end_of_interrupt(crate::interrupts::InterruptIndex::Keyboard.as_u8());
-    }
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_exception("SEGMENT NOT PRESENT", 11, Some(error_code), &stack_frame);
 }
 
-extern "x86-interrupt" fn ata_primary_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use crate::drivers::ata::PRIMARY_ATA;
-    unsafe {
-        let status = PRIMARY_ATA.lock().status_port.read();
-        crate::serial_println!("ATA Primary interrupt: status 0x{:02X}", status);
-    }
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_exception("STACK SEGMENT FAULT", 12, Some(error_code), &stack_frame);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::AtaPrimary.as_u8());
-    }
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    fatal_exception(
+        "GENERAL PROTECTION FAULT",
+        13,
+        Some(error_code),
+        &stack_frame,
+    );
 }
 
-extern "x86-interrupt" fn ata_secondary_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use crate::drivers::ata::SECONDARY_ATA;
-    unsafe {
-        let status = SECONDARY_ATA.lock().status_port.read();
-        crate::serial_println!("ATA Secondary interrupt: status 0x{:02X}", status);
+/// Prints which thread ran off its stack if `addr` is in a guard page.
+fn report_stack_overflow(addr: u64) {
+    match crate::context::stack_guard_owner(addr) {
+        Some(Some(tid)) => crate::serial_println!("stack overflow in thread {}", tid),
+        Some(None) => crate::serial_println!("stack overflow in a CPU loop context"),
+        None => {}
     }
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::AtaSecondary.as_u8());
-    }
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    // An overflow usually ends up here: the page fault can't push its frame
+    // onto the guard page and escalates.
+    report_stack_overflow(x86_64::registers::control::Cr2::read().as_u64());
+    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // EOI before ticking: the tick may switch to another thread and not
+    // return here until that thread is preempted in turn.
+    end_of_interrupt(InterruptIndex::Timer.as_u8());
+    crate::timer::on_timer_tick();
+}
+
+extern "x86-interrupt" fn apic_timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    end_of_interrupt(InterruptIndex::ApicTimer.as_u8());
+    crate::timer::on_timer_tick();
 }
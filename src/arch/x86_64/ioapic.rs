@@ -0,0 +1,156 @@
+use crate::acpi::InterruptOverride;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, Size4KiB};
+
+/// Where QEMU and most chipsets put the IO APIC when the MADT says nothing.
+const IOAPIC_DEFAULT_BASE: u64 = 0xFEC0_0000;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_POLARITY_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+/// Virtual address of the IO APIC registers, 0 until `init`.
+static IOAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// Number of redirection entries, from the version register.
+static ENTRY_COUNT: AtomicU8 = AtomicU8::new(0);
+/// Local APIC ID of the BSP, which every routed IRQ is delivered to.
+static BSP_APIC_ID: AtomicU8 = AtomicU8::new(0);
+/// MADT overrides for legacy IRQs, e.g. QEMU puts IRQ 0 on input 2.
+static OVERRIDES: Mutex<[Option<InterruptOverride>; 16]> = Mutex::new([None; 16]);
+
+fn write_register(reg: u32, value: u32) {
+    let base = IOAPIC_BASE.load(Ordering::SeqCst) as *mut u32;
+    unsafe {
+        base.byte_add(IOREGSEL).write_volatile(reg);
+        base.byte_add(IOWIN).write_volatile(value);
+    }
+}
+
+fn read_register(reg: u32) -> u32 {
+    let base = IOAPIC_BASE.load(Ordering::SeqCst) as *mut u32;
+    unsafe {
+        base.byte_add(IOREGSEL).write_volatile(reg);
+        base.byte_add(IOWIN).read_volatile()
+    }
+}
+
+/// Maps the IO APIC the MADT lists for GSI 0, or the one at 0xFEC00000
+/// without ACPI, and masks all its inputs. Must run on the BSP.
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let (address, overrides) = match crate::acpi::io_apics(mapper.phys_offset()) {
+        Ok((io_apics, overrides)) => {
+            let first = io_apics
+                .iter()
+                .find(|io_apic| io_apic.gsi_base == 0)
+                .ok_or("no IO APIC for the legacy IRQs")?;
+            (first.address, overrides)
+        }
+        Err(e) => {
+            crate::serial_println!("IO APIC: {}, assuming {:#x}", e, IOAPIC_DEFAULT_BASE);
+            (IOAPIC_DEFAULT_BASE, alloc::vec::Vec::new())
+        }
+    };
+
+    let base = crate::drivers::pci::map_mmio(address, 0x20, mapper, frame_allocator)?;
+    IOAPIC_BASE.store(base as u64, Ordering::SeqCst);
+    BSP_APIC_ID.store(crate::smp::current_apic_id() as u8, Ordering::SeqCst);
+
+    {
+        let mut table = OVERRIDES.lock();
+        for entry in overrides {
+            if let Some(slot) = table.get_mut(usize::from(entry.irq)) {
+                *slot = Some(entry);
+            }
+        }
+    }
+
+    let version = read_register(IOAPICVER);
+    let entries = ((version >> 16) & 0xFF) as u8 + 1;
+    ENTRY_COUNT.store(entries, Ordering::SeqCst);
+    for gsi in 0..u32::from(entries) {
+        write_register(IOREDTBL + gsi * 2, REDIRECT_MASKED);
+    }
+
+    crate::serial_println!(
+        "IO APIC {} at {:#x}: version {:#x}, {} inputs",
+        (read_register(IOAPICID) >> 24) & 0x0F,
+        address,
+        version & 0xFF,
+        entries
+    );
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    IOAPIC_BASE.load(Ordering::SeqCst) != 0
+}
+
+/// Number of redirection entries, or 0 before `init`.
+pub fn entry_count() -> u8 {
+    ENTRY_COUNT.load(Ordering::SeqCst)
+}
+
+/// The input and redirection flags legacy `irq` arrives with. ISA IRQs are
+/// active-high and edge-triggered unless the MADT overrides them.
+fn gsi_for_irq(irq: u8) -> (u32, u32) {
+    let Some(entry) = OVERRIDES.lock()[usize::from(irq)] else {
+        return (u32::from(irq), 0);
+    };
+    let mut flags = 0;
+    if entry.flags & INTI_POLARITY_MASK == INTI_POLARITY_LOW {
+        flags |= REDIRECT_ACTIVE_LOW;
+    }
+    if entry.flags & INTI_TRIGGER_MASK == INTI_TRIGGER_LEVEL {
+        flags |= REDIRECT_LEVEL;
+    }
+    (entry.gsi, flags)
+}
+
+/// Delivers legacy IRQ `irq` (0-15) to the BSP as fixed interrupt `vector`.
+pub fn route_irq(irq: u8, vector: u8) -> Result<(), &'static str> {
+    if !is_initialized() {
+        return Err("IO APIC not initialized");
+    }
+    if irq >= 16 {
+        return Err("no such IRQ line");
+    }
+    let (gsi, flags) = gsi_for_irq(irq);
+    if gsi >= u32::from(entry_count()) {
+        return Err("IRQ routed past the IO APIC's inputs");
+    }
+    let destination = u32::from(BSP_APIC_ID.load(Ordering::SeqCst)) << 24;
+    // High half first, so the entry is never live with a stale destination.
+    write_register(IOREDTBL + gsi * 2 + 1, destination);
+    write_register(IOREDTBL + gsi * 2, u32::from(vector) | flags);
+    Ok(())
+}
+
+/// Stops legacy IRQ `irq` at the IO APIC.
+pub fn mask_irq(irq: u8) -> Result<(), &'static str> {
+    if !is_initialized() {
+        return Err("IO APIC not initialized");
+    }
+    let (gsi, _) = gsi_for_irq(irq);
+    if gsi >= u32::from(entry_count()) {
+        return Err("IRQ routed past the IO APIC's inputs");
+    }
+    let low = read_register(IOREDTBL + gsi * 2);
+    write_register(IOREDTBL + gsi * 2, low | REDIRECT_MASKED);
+    Ok(())
+}
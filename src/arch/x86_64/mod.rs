@@ -1,9 +1,21 @@
+pub mod acpi;
+pub mod cpuid;
+pub mod crash;
+pub mod fast_syscall;
 pub mod gdt;
 pub mod interrupts;
+pub mod ioapic;
+pub mod power;
 pub mod smp;
 pub mod timer;
+pub mod usermode;
 
+pub use acpi::*;
+pub use crash::*;
+pub use fast_syscall::*;
 pub use gdt::*;
 pub use interrupts::*;
+pub use power::*;
 pub use smp::*;
 pub use timer::*;
+pub use usermode::*;
@@ -0,0 +1,63 @@
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+/// ACPI PM1a control ports emulators wire to power-off: QEMU's i440fx and
+/// q35 machines, then older QEMU and Bochs.
+const EMULATOR_POWER_OFF_PORTS: [u16; 2] = [0x604, 0xB004];
+/// SLP_EN with the S5 sleep type those emulators expect.
+const SLP_EN_S5: u16 = 0x2000;
+/// Where `build.sh` and `test.sh` put QEMU's isa-debug-exit device.
+const ISA_DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// Status for `exit_qemu`. QEMU exits with `(code << 1) | 1`, so 33 for
+/// success and 35 for failure; 0 and 1 stay free for QEMU's own errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Resets the machine by pulsing the CPU reset line through the keyboard
+/// controller. If that doesn't take, a triple fault does.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    unsafe {
+        let mut status: Port<u8> = Port::new(0x64);
+        while status.read() & 0x02 != 0 {}
+        status.write(0xFE);
+
+        let empty = DescriptorTablePointer {
+            limit: 0,
+            base: VirtAddr::new(0),
+        };
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    crate::hlt_loop();
+}
+
+/// Powers off an emulated machine through its fixed ACPI ports, and halts
+/// if none of them worked. Real hardware needs the S5 values from the DSDT,
+/// which means an AML interpreter; that is out of scope for now, so there
+/// this only halts.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    for port in EMULATOR_POWER_OFF_PORTS {
+        unsafe { Port::<u16>::new(port).write(SLP_EN_S5) };
+    }
+    crate::serial_println!("Shutdown failed, halting");
+    crate::halt();
+}
+
+/// Ends the QEMU run with `code` through the isa-debug-exit device. Without
+/// that device, e.g. on real hardware, the write does nothing and this
+/// halts instead.
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    interrupts::disable();
+    unsafe { Port::<u32>::new(ISA_DEBUG_EXIT_PORT).write(code as u32) };
+    crate::serial_println!("isa-debug-exit not present, halting");
+    crate::halt();
+}
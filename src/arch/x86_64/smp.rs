@@ -4,14 +4,31 @@ use alloc::boxed::Box;
 use alloc::sync::Arc;
 use core::cell::UnsafeCell;
 use core::mem::{self, MaybeUninit};
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
 
+use crate::interrupt::no_interrupt;
 use crate::processor::Processor;
 use crate::thread_pool::{self, ThreadPool};
 
 const APIC_BASE: usize = 0xFEE0_0000;
+const APIC_ID: usize = 0x20;
 const APIC_ICR_LOW: usize = 0x300;
 const APIC_ICR_HIGH: usize = 0x310;
+const APIC_SPURIOUS: usize = 0xF0;
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const SPURIOUS_VECTOR: u32 = 0xFF;
+const DELIVERY_MODE_FIXED: u32 = 0x0 << 8;
+const DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+const DESTINATION_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
 const DELIVERY_MODE_INIT: u32 = 0x5 << 8;
 const DELIVERY_MODE_STARTUP: u32 = 0x6 << 8;
 const LEVEL_ASSERT: u32 = 1 << 14;
@@ -20,6 +37,14 @@ const TRIGGER_MODE_LEVEL: u32 = 1 << 15;
 const TRAMPOLINE_PADDR: usize = 0x7000;
 const TRAMPOLINE_VECTOR: u8 = (TRAMPOLINE_PADDR >> 12) as u8;
 
+const AP_ONLINE_TIMEOUT_SPINS: usize = 100_000_000;
+
+pub const TLB_SHOOTDOWN_VECTOR: u8 = crate::interrupts::IPI_VECTOR_BASE;
+/// Above this many pages a shootdown reloads CR3 instead of `invlpg`-ing
+/// each page.
+const TLB_FULL_FLUSH_THRESHOLD: u64 = 32;
+const TLB_SHOOTDOWN_TIMEOUT_SPINS: usize = 100_000_000;
+
 pub const MAX_CPUS: usize = 8;
 
 #[repr(C, align(64))]
@@ -32,6 +57,7 @@ pub struct CpuInfo {
 
 pub struct CpuStorage {
     inner: UnsafeCell<[MaybeUninit<CpuInfo>; MAX_CPUS]>,
+    initialized: AtomicBool,
 }
 
 unsafe impl Sync for CpuStorage {}
@@ -42,6 +68,7 @@ impl CpuStorage {
             inner: UnsafeCell::new(unsafe {
                 MaybeUninit::<[MaybeUninit<CpuInfo>; MAX_CPUS]>::uninit().assume_init()
             }),
+            initialized: AtomicBool::new(false),
         }
     }
 
@@ -58,6 +85,17 @@ impl CpuStorage {
                 ptr.add(i).write(MaybeUninit::new(entry));
             }
         }
+        self.initialized.store(true, Ordering::SeqCst);
+    }
+
+    /// Number of CPUs marked online, or zero before `init`.
+    pub fn online_count(&self) -> usize {
+        if !self.initialized.load(Ordering::SeqCst) {
+            return 0;
+        }
+        (0..MAX_CPUS)
+            .filter(|&i| self.get(i).online.load(Ordering::SeqCst) == 1)
+            .count()
     }
 
     pub fn get(&self, idx: usize) -> &CpuInfo {
@@ -95,67 +133,372 @@ pub struct AlignedStack(pub [u8; 16 * 1024]);
 
 pub static mut AP_STACKS: [AlignedStack; MAX_CPUS] = [AlignedStack([0u8; 16 * 1024]); MAX_CPUS];
 
+// Real-mode entry for the APs. The blob is assembled as part of the kernel
+// image but copied to `TRAMPOLINE_PADDR` (identity-mapped) before the SIPI,
+// so every absolute address is computed as `TRAMPOLINE_PADDR + offset`.
+// It enables A20, loads its own GDT, goes through protected mode into long
+// mode on the BSP's PML4 and calls `ap_trampoline_entry` on the AP's stack.
+// The three quads at the end are patched per AP by `start_one_ap`.
 core::arch::global_asm!(
-    "
-    .section .boot, \"ax\"
-    .org 0x7000
-    .global ap_trampoline_phys
-ap_trampoline_phys:
-    nop
-    jmp ap_trampoline_entry
-"
+    r#"
+    .section .rodata.ap_trampoline, "a"
+    .code16
+    .global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+
+    in al, 0x92
+    or al, 2
+    out 0x92, al
+
+    lgdt [AP_GDTR_OFFSET]
+
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+
+    .byte 0x66, 0xEA
+    .long 0x7000 + (ap_trampoline_protected - ap_trampoline_start)
+    .word 0x08
+
+    .code32
+ap_trampoline_protected:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    mov eax, [0x7000 + AP_PML4_OFFSET]
+    mov cr3, eax
+
+    mov ecx, 0xC0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    mov eax, cr0
+    or eax, 1 << 31
+    mov cr0, eax
+
+    .byte 0xEA
+    .long 0x7000 + (ap_trampoline_long - ap_trampoline_start)
+    .word 0x18
+
+    .code64
+ap_trampoline_long:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor ax, ax
+    mov fs, ax
+    mov gs, ax
+
+    mov rsp, [0x7000 + AP_STACK_OFFSET]
+    mov rax, [0x7000 + AP_ENTRY_OFFSET]
+    call rax
+    ud2
+
+    .align 8
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00AF9A000000FFFF
+ap_trampoline_gdtr:
+    .word ap_trampoline_gdtr - ap_trampoline_gdt - 1
+    .long 0x7000 + (ap_trampoline_gdt - ap_trampoline_start)
+
+    .align 8
+    .global ap_trampoline_pml4
+ap_trampoline_pml4:
+    .quad 0
+    .global ap_trampoline_stack
+ap_trampoline_stack:
+    .quad 0
+    .global ap_trampoline_entry_addr
+ap_trampoline_entry_addr:
+    .quad 0
+    .global ap_trampoline_end
+ap_trampoline_end:
+
+    .set AP_GDTR_OFFSET, ap_trampoline_gdtr - ap_trampoline_start
+    .set AP_PML4_OFFSET, ap_trampoline_pml4 - ap_trampoline_start
+    .set AP_STACK_OFFSET, ap_trampoline_stack - ap_trampoline_start
+    .set AP_ENTRY_OFFSET, ap_trampoline_entry_addr - ap_trampoline_start
+"#
 );
 
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_pml4: u64;
+    static ap_trampoline_stack: u64;
+    static ap_trampoline_entry_addr: u64;
+}
+
+/// Where the trampoline was copied to, as seen through the physical memory
+/// mapping. Zero until `install_trampoline` has run.
+static mut TRAMPOLINE_VIRT: u64 = 0;
+
+/// Identity-maps `TRAMPOLINE_PADDR` and copies the AP trampoline there.
+/// Must run before the first `start_one_ap`.
+pub fn install_trampoline(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), &'static str> {
+    let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(TRAMPOLINE_PADDR as u64));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(_)) => {}
+        Err(_) => return Err("failed to identity-map AP trampoline"),
+    }
+
+    unsafe {
+        let start = &raw const ap_trampoline_start;
+        let len = (&raw const ap_trampoline_end) as usize - start as usize;
+        assert!(len <= 4096, "AP trampoline does not fit in one page");
+
+        let dst = mapper.phys_offset() + TRAMPOLINE_PADDR as u64;
+        core::ptr::copy_nonoverlapping(start, dst.as_mut_ptr::<u8>(), len);
+        TRAMPOLINE_VIRT = dst.as_u64();
+    }
+    Ok(())
+}
+
+/// Writes `value` into the copy of the trampoline field at `field`.
+unsafe fn patch_trampoline(field: *const u64, value: u64) {
+    unsafe {
+        let offset = field as usize - (&raw const ap_trampoline_start) as usize;
+        core::ptr::write_volatile((TRAMPOLINE_VIRT as usize + offset) as *mut u64, value);
+    }
+}
+
 #[unsafe(no_mangle)]
 pub static mut GLOBAL_THREAD_POOL_PTR: *const () = core::ptr::null();
 
 #[unsafe(no_mangle)]
 pub static mut PROCESSORS_PTR: *mut Processor = core::ptr::null_mut();
 
+/// The executing CPU's `CpuInfo`. Every CPU points GS base at its own
+/// entry: APs during startup and the BSP in `init_bsp`.
+///
+/// Panics if called on a CPU whose GS base hasn't been set yet.
+pub fn current_cpu() -> &'static CpuInfo {
+    try_current_cpu().expect("current_cpu(): GS base is not set on this CPU")
+}
+
+fn try_current_cpu() -> Option<&'static CpuInfo> {
+    use x86_64::registers::model_specific::GsBase;
+
+    let base = GsBase::read().as_u64();
+    if base == 0 {
+        None
+    } else {
+        Some(unsafe { &*(base as *const CpuInfo) })
+    }
+}
+
+/// Index of the executing CPU. Before `init_bsp` only the BSP is running,
+/// so a CPU without GS base set is CPU 0.
+pub fn current_cpu_id() -> usize {
+    try_current_cpu().map_or(0, |cpu| cpu.id)
+}
+
+/// Local APIC ID of the executing CPU.
+pub fn current_apic_id() -> u32 {
+    apic_read(APIC_ID) >> 24
+}
+
+/// Sets up the BSP's `Processor` so threads can be spawned and run on CPU 0.
+pub fn init_bsp(pool: Arc<ThreadPool>, procs_ptr: *mut Processor) {
+    let cpu = CPUS.get_mut(0);
+    cpu.apic_id = current_apic_id();
+    cpu.online.store(1, Ordering::SeqCst);
+    x86_64::registers::model_specific::GsBase::write(x86_64::VirtAddr::from_ptr(cpu));
+
+    unsafe {
+        PROCESSORS_PTR = procs_ptr;
+
+        let loop_ctx_raw = crate::context::create_loop_context_for_thread_pool();
+        let loop_ctx_box: Box<dyn thread_pool::Context> = Box::from_raw(loop_ctx_raw);
+        (*procs_ptr).init(0, loop_ctx_box, pool);
+    }
+}
+
 unsafe fn apic_base() -> *mut u32 {
     APIC_BASE as *mut u32
 }
 
-fn apic_write(offset: usize, value: u32) {
+pub(crate) fn apic_write(offset: usize, value: u32) {
     unsafe {
         core::ptr::write_volatile(apic_base().add(offset / 4), value);
     }
 }
 
-pub fn nop(max: usize) {
-    unsafe {
-        for _ in 0..max {
-            core::arch::asm!("nop", options(nomem, nostack, preserves_flags));
+pub(crate) fn apic_read(offset: usize) -> u32 {
+    unsafe { core::ptr::read_volatile(apic_base().add(offset / 4)) }
+}
+
+/// Sets the software-enable bit in the spurious interrupt vector register
+/// so this CPU's local APIC accepts interrupts.
+pub(crate) fn enable_local_apic() {
+    apic_write(
+        APIC_SPURIOUS,
+        apic_read(APIC_SPURIOUS) | APIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR,
+    );
+}
+
+fn wait_for_ipi_delivery() {
+    while apic_read(APIC_ICR_LOW) & DELIVERY_STATUS_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Sends a fixed-mode IPI with `vector` to the CPU with `target_apic_id`.
+/// The handler for `vector` is set with `interrupts::register_ipi_handler`.
+pub fn send_ipi(target_apic_id: u8, vector: u8) {
+    no_interrupt(|| {
+        wait_for_ipi_delivery();
+        apic_write(APIC_ICR_HIGH, (target_apic_id as u32) << 24);
+        apic_write(
+            APIC_ICR_LOW,
+            DELIVERY_MODE_FIXED | LEVEL_ASSERT | vector as u32,
+        );
+        wait_for_ipi_delivery();
+    });
+}
+
+/// Sends a fixed-mode IPI with `vector` to every CPU except this one.
+pub fn broadcast_ipi(vector: u8) {
+    no_interrupt(|| {
+        wait_for_ipi_delivery();
+        apic_write(APIC_ICR_HIGH, 0);
+        apic_write(
+            APIC_ICR_LOW,
+            DELIVERY_MODE_FIXED | LEVEL_ASSERT | DESTINATION_ALL_EXCLUDING_SELF | vector as u32,
+        );
+        wait_for_ipi_delivery();
+    });
+}
+
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+/// One bit per CPU id that has flushed for the current shootdown, so a CPU
+/// that answered while waiting for `SHOOTDOWN_LOCK` doesn't count twice
+/// when the IPI arrives.
+static SHOOTDOWN_ACKED: AtomicU64 = AtomicU64::new(0);
+
+fn flush_tlb_local(start: u64, pages: u64) {
+    if pages > TLB_FULL_FLUSH_THRESHOLD {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(VirtAddr::new(start + i * Page::<Size4KiB>::SIZE));
+        }
+    }
+}
+
+fn tlb_shootdown_handler() {
+    flush_tlb_local(
+        SHOOTDOWN_START.load(Ordering::SeqCst),
+        SHOOTDOWN_PAGES.load(Ordering::SeqCst),
+    );
+    let bit = 1 << current_cpu_id();
+    if SHOOTDOWN_ACKED.fetch_or(bit, Ordering::SeqCst) & bit == 0 {
+        SHOOTDOWN_PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Hooks up the TLB shootdown IPI. Must run before the APs are started.
+pub fn init_tlb_shootdown() -> Result<(), &'static str> {
+    crate::interrupts::register_ipi_handler(TLB_SHOOTDOWN_VECTOR, tlb_shootdown_handler)
+}
+
+/// Flushes `page` from the TLB of every online CPU.
+pub fn tlb_shootdown(page: Page) {
+    tlb_shootdown_range(Page::range(page, page + 1));
+}
+
+/// Flushes `range` from the TLB of every online CPU and waits until all of
+/// them have acknowledged. Works with interrupts disabled, as in syscalls:
+/// a CPU waiting for another CPU's shootdown to finish answers it itself.
+pub fn tlb_shootdown_range(range: PageRange) {
+    let start = range.start.start_address().as_u64();
+    let pages = range.end - range.start;
+    flush_tlb_local(start, pages);
+
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
         }
+        if SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+            tlb_shootdown_handler();
+        }
+        core::hint::spin_loop();
+    };
+    let others = CPUS.online_count().saturating_sub(1);
+    if others == 0 {
+        return;
     }
+
+    SHOOTDOWN_START.store(start, Ordering::SeqCst);
+    SHOOTDOWN_PAGES.store(pages, Ordering::SeqCst);
+    SHOOTDOWN_PENDING.store(others, Ordering::SeqCst);
+    // Late IPIs from the last round find their bit still set until here.
+    SHOOTDOWN_ACKED.store(1 << current_cpu_id(), Ordering::SeqCst);
+
+    broadcast_ipi(TLB_SHOOTDOWN_VECTOR);
+
+    for _ in 0..TLB_SHOOTDOWN_TIMEOUT_SPINS {
+        if SHOOTDOWN_PENDING.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    crate::serial_println!(
+        "TLB shootdown: {} CPU(s) did not acknowledge",
+        SHOOTDOWN_PENDING.load(Ordering::SeqCst)
+    );
 }
+
+/// The INIT-SIPI-SIPI sequence with the MP spec's delays: 10 ms after
+/// INIT, 200 us after each SIPI.
 fn send_init_sipi(apic_id: u8, vector: u8) {
     apic_write(APIC_ICR_HIGH, (apic_id as u32) << 24);
     apic_write(
         APIC_ICR_LOW,
         DELIVERY_MODE_INIT | LEVEL_ASSERT | TRIGGER_MODE_LEVEL,
     );
-    nop(10_000);
+    crate::timer::delay_us(200);
     apic_write(APIC_ICR_LOW, DELIVERY_MODE_INIT);
-    nop(20_000);
+    crate::timer::delay_us(10_000);
 
     apic_write(APIC_ICR_HIGH, (apic_id as u32) << 24);
     apic_write(APIC_ICR_LOW, DELIVERY_MODE_STARTUP | (vector as u32));
-    nop(20_000);
+    crate::timer::delay_us(200);
     apic_write(APIC_ICR_HIGH, (apic_id as u32) << 24);
     apic_write(APIC_ICR_LOW, DELIVERY_MODE_STARTUP | (vector as u32));
+    crate::timer::delay_us(200);
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn ap_trampoline_entry() -> ! {
     unsafe {
         let data = &raw const AP_STARTUP as *const ApStartupData;
-        let stack_top = (*data).stack_top as usize;
         let cpu_id = (*data).cpu_id as usize;
         let apic_id = (*data).apic_id;
 
-        core::arch::asm!("mov rsp, {}", in(reg) stack_top, options(nostack, preserves_flags));
-
         let cpu = CPUS.get_mut(cpu_id);
         cpu.apic_id = apic_id;
         cpu.online.store(1, Ordering::SeqCst);
@@ -170,6 +513,14 @@ pub extern "C" fn ap_trampoline_entry() -> ! {
             in("edx") high,
         );
 
+        // Still on the trampoline's GDT, whose 0x08 is a 32-bit code
+        // segment and which has no TSS: any interrupt before this would
+        // triple fault.
+        crate::gdt::init_ap(cpu_id);
+        // The IDT's double-fault entry needs the IST stack in this CPU's
+        // TSS, so it can only be loaded now.
+        crate::interrupts::init_idt();
+
         if GLOBAL_THREAD_POOL_PTR.is_null() {
             loop {
                 core::arch::asm!("hlt");
@@ -194,6 +545,13 @@ pub extern "C" fn ap_trampoline_entry() -> ! {
         let loop_ctx_box: Box<dyn thread_pool::Context> = Box::from_raw(loop_ctx_raw);
 
         procs.init(cpu_id, loop_ctx_box, pool_arc.clone());
+        crate::context::init_fpu();
+
+        // Take IPIs and timer ticks from here on; `hlt` below wakes up on
+        // them, and the ticks preempt threads running on this CPU.
+        enable_local_apic();
+        crate::timer::init_ap_apic_timer();
+        x86_64::instructions::interrupts::enable();
 
         loop {
             procs.run_next(cpu_id);
@@ -202,22 +560,129 @@ pub extern "C" fn ap_trampoline_entry() -> ! {
     }
 }
 
+/// Boots the AP with `apic_id` through the trampoline and waits for it to
+/// mark itself online. Returns `false` if it didn't within the timeout.
 pub fn start_one_ap(
     ap_index: usize,
     apic_id: u32,
     pool: Arc<ThreadPool>,
     procs_ptr: *mut Processor,
-) {
+) -> bool {
     unsafe {
+        assert!(
+            TRAMPOLINE_VIRT != 0,
+            "install_trampoline() must run before starting APs"
+        );
+
         GLOBAL_THREAD_POOL_PTR = Arc::into_raw(pool.clone()) as *const ();
         PROCESSORS_PTR = procs_ptr;
 
         let stack_top = (&AP_STACKS[ap_index].0 as *const _ as usize) + AP_STACKS[ap_index].0.len();
+        let (pml4, _) = Cr3::read();
         AP_STARTUP.stack_top = stack_top as u64;
-        AP_STARTUP.pml4_phys = 0;
+        AP_STARTUP.pml4_phys = pml4.start_address().as_u64();
         AP_STARTUP.cpu_id = ap_index as u32;
         AP_STARTUP.apic_id = apic_id;
 
+        patch_trampoline(&raw const ap_trampoline_pml4, AP_STARTUP.pml4_phys);
+        patch_trampoline(&raw const ap_trampoline_stack, AP_STARTUP.stack_top);
+        patch_trampoline(
+            &raw const ap_trampoline_entry_addr,
+            ap_trampoline_entry as extern "C" fn() -> ! as usize as u64,
+        );
+
         send_init_sipi(apic_id as u8, TRAMPOLINE_VECTOR);
     }
+
+    // The trampoline fields and AP_STARTUP are shared, so don't let the
+    // next AP start until this one has picked them up.
+    for _ in 0..AP_ONLINE_TIMEOUT_SPINS {
+        if CPUS.get(ap_index).online.load(Ordering::SeqCst) == 1 {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Checks CPUs 0 to `expected - 1` are all online, each with its own APIC
+/// ID, after boot has started the APs.
+pub fn test_aps_online(expected: usize) -> Result<(), &'static str> {
+    crate::serial_println!("=== AP Startup Test ===");
+
+    if expected > MAX_CPUS {
+        return Err("more CPUs expected than MAX_CPUS");
+    }
+    for i in 0..expected {
+        let cpu = CPUS.get(i);
+        if cpu.online.load(Ordering::SeqCst) != 1 {
+            return Err("an AP did not come online");
+        }
+        if (0..i).any(|j| CPUS.get(j).apic_id == cpu.apic_id) {
+            return Err("two CPUs report the same APIC ID");
+        }
+    }
+    if CPUS.online_count() != expected {
+        return Err("more CPUs online than expected");
+    }
+
+    crate::serial_println!("✓ All {} CPUs online", expected);
+    Ok(())
+}
+
+/// Pins a thread that spins without yielding to CPU 1, and once it runs, a
+/// second one behind it that ends the spin. The second only gets a turn if
+/// the AP's own timer preempts the first; the spin gives up after two
+/// seconds so a missing tick fails the test instead of hanging it.
+pub fn test_ap_preemption() -> Result<(), &'static str> {
+    use crate::std_thread;
+    use crate::timer::uptime_ms;
+
+    const TIMEOUT_MS: u64 = 2000;
+    crate::serial_println!("=== AP Preemption Test ===");
+
+    if CPUS.online_count() < 2 {
+        return Err("no AP online");
+    }
+
+    let started = Arc::new(AtomicBool::new(false));
+    let released = Arc::new(AtomicBool::new(false));
+    let spinner = {
+        let (started, released) = (started.clone(), released.clone());
+        std_thread::spawn_on(1, move || {
+            started.store(true, Ordering::Release);
+            let deadline = uptime_ms() + TIMEOUT_MS;
+            while !released.load(Ordering::Acquire) {
+                if uptime_ms() > deadline {
+                    return None;
+                }
+                core::hint::spin_loop();
+            }
+            Some(current_cpu_id())
+        })
+    };
+
+    let deadline = uptime_ms() + TIMEOUT_MS;
+    while !started.load(Ordering::Acquire) {
+        if uptime_ms() > deadline {
+            return Err("AP never ran the thread pinned to it");
+        }
+        core::hint::spin_loop();
+    }
+    let releaser = std_thread::spawn_on(1, move || {
+        released.store(true, Ordering::Release);
+        current_cpu_id()
+    });
+
+    match spinner.join() {
+        Ok(Some(1)) => {}
+        Ok(Some(_)) => return Err("pinned thread ran on another CPU"),
+        _ => return Err("spinning thread on the AP was never preempted"),
+    }
+    if releaser.join() != Ok(1) {
+        return Err("pinned thread ran on another CPU");
+    }
+
+    crate::serial_println!("✓ AP timer preempted a thread pinned to CPU 1");
+    Ok(())
 }
@@ -1,4 +1,61 @@
 use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::interrupt::no_interrupt;
+use crate::interrupts::InterruptIndex;
+use crate::smp::{apic_read, apic_write, enable_local_apic};
+use crate::thread_pool::Tid;
+
+const APIC_EOI: usize = 0xB0;
+const APIC_LVT_TIMER: usize = 0x320;
+const APIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const APIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const APIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const PIT_FREQUENCY: u32 = 1_193_182;
+const CALIBRATION_MS: u32 = 10;
+/// Tick rate the PIT is programmed to at boot.
+pub const DEFAULT_FREQUENCY_HZ: u32 = 100;
+
+static APIC_MODE: AtomicBool = AtomicBool::new(false);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY_HZ: AtomicU32 = AtomicU32::new(DEFAULT_FREQUENCY_HZ);
+/// APIC timer count per tick from `init_apic_timer`, reused by the APs.
+static APIC_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+/// TSC ticks per second from `calibrate_tsc`, 0 before.
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+
+/// Pending wake-ups, advanced once per BSP timer tick.
+static SLEEP_TIMER: Mutex<Option<Timer<Sleeper>>> = Mutex::new(None);
+
+/// Something waiting in `SLEEP_TIMER`.
+pub enum Sleeper {
+    /// A scheduler thread parked by `std_thread::sleep`.
+    Thread(Tid),
+    /// A `Sleep` future; the flag is set when it fires.
+    Task(Arc<AtomicBool>, Waker),
+}
+
+impl PartialEq for Sleeper {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Sleeper::Thread(a), Sleeper::Thread(b)) => a == b,
+            (Sleeper::Task(a, _), Sleeper::Task(b, _)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
 
 type Time = usize;
 
@@ -25,7 +82,7 @@ impl<T: PartialEq> Timer<T> {
     pub fn pop(&mut self) -> Option<T> {
         match self.timers.front() {
             None => return None,
-            Some(timer) if timer.time != self.tick => return None,
+            Some(timer) if timer.time > self.tick => return None,
             _ => {}
         };
         self.timers.pop_front().map(|t| t.data)
@@ -52,3 +109,325 @@ impl<T: PartialEq> Timer<T> {
         }
     }
 }
+
+/// Whether timer interrupts come from the local APIC instead of the 8259.
+pub fn apic_mode() -> bool {
+    APIC_MODE.load(Ordering::Relaxed)
+}
+
+/// BSP timer ticks since interrupts were enabled. Only the BSP counts, so
+/// this stays monotonic and independent of how many CPUs are online.
+pub fn uptime_ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since boot. The resolution is one tick, 10 ms at the
+/// default 100 Hz.
+pub fn uptime_ms() -> u64 {
+    uptime_ticks() * 1000 / frequency_hz() as u64
+}
+
+pub fn apic_eoi() {
+    apic_write(APIC_EOI, 0);
+}
+
+/// Timer interrupts per second on each CPU.
+pub fn frequency_hz() -> u32 {
+    FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+/// Converts `dur` to timer ticks, rounding up so a non-zero sleep always
+/// lasts at least one tick.
+pub fn duration_to_ticks(dur: Duration) -> usize {
+    let hz = frequency_hz() as u128;
+    dur.as_nanos().saturating_mul(hz).div_ceil(1_000_000_000) as usize
+}
+
+/// Wakes `sleeper` after `ticks` more BSP ticks.
+pub fn wake_after(ticks: usize, sleeper: Sleeper) {
+    no_interrupt(|| {
+        SLEEP_TIMER
+            .lock()
+            .get_or_insert_with(Timer::new)
+            .start(ticks, sleeper)
+    });
+}
+
+/// Drops a pending wake-up for `sleeper`, if there is one.
+pub fn cancel(sleeper: Sleeper) {
+    no_interrupt(|| {
+        if let Some(timer) = SLEEP_TIMER.lock().as_mut() {
+            timer.stop(sleeper);
+        }
+    });
+}
+
+/// Advances the sleep timer and wakes everything that has expired.
+fn wake_expired() {
+    let mut expired = VecDeque::new();
+    if let Some(timer) = SLEEP_TIMER.lock().as_mut() {
+        timer.tick();
+        while let Some(sleeper) = timer.pop() {
+            expired.push_back(sleeper);
+        }
+    }
+
+    for sleeper in expired {
+        match sleeper {
+            Sleeper::Thread(tid) => {
+                if let Some(processor) = crate::std_thread::try_processor() {
+                    let manager = processor.manager();
+                    // The thread may not have switched away yet.
+                    manager.cancel_sleeping(tid);
+                    manager.wakeup(tid);
+                }
+            }
+            Sleeper::Task(fired, waker) => {
+                fired.store(true, Ordering::Release);
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Common path for both the PIC and APIC timer interrupts: counts the tick,
+/// fires expired sleeps and lets the scheduler preempt the running thread.
+pub fn on_timer_tick() {
+    // Every CPU's APIC timer ends up here; only the BSP drives the clock.
+    if crate::smp::current_cpu_id() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        wake_expired();
+    }
+    if let Some(processor) = crate::std_thread::try_processor() {
+        processor.tick();
+    }
+}
+
+/// Future returned by `sleep_ms`.
+pub struct Sleep {
+    ticks: usize,
+    fired: Option<Arc<AtomicBool>>,
+}
+
+/// Resolves after roughly `ms` milliseconds, rounded up to whole ticks.
+pub fn sleep_ms(ms: u64) -> Sleep {
+    Sleep {
+        ticks: duration_to_ticks(Duration::from_millis(ms)),
+        fired: None,
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.ticks == 0 {
+            return Poll::Ready(());
+        }
+        match &self.fired {
+            Some(fired) if fired.load(Ordering::Acquire) => Poll::Ready(()),
+            Some(_) => Poll::Pending,
+            None => {
+                let fired = Arc::new(AtomicBool::new(false));
+                wake_after(self.ticks, Sleeper::Task(fired.clone(), cx.waker().clone()));
+                self.fired = Some(fired);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A `Sleep` dropped before it fires (say, the losing side of a timeout)
+/// takes its wake-up out of the timer instead of leaving a stale waker.
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(fired) = self.fired.take() {
+            if !fired.load(Ordering::Acquire) {
+                cancel(Sleeper::Task(fired, futures_util::task::noop_waker()));
+            }
+        }
+    }
+}
+
+/// Slowest rate channel 0 can divide down to, with the largest divisor.
+pub const PIT_MIN_FREQUENCY_HZ: u32 = PIT_FREQUENCY.div_ceil(u16::MAX as u32);
+/// Fastest rate `init_pit` allows; beyond this the tick handler would eat
+/// the CPU.
+pub const PIT_MAX_FREQUENCY_HZ: u32 = 10_000;
+
+/// The channel 0 divisor for the fastest rate no higher than
+/// `frequency_hz`, once that is clamped to
+/// `PIT_MIN_FREQUENCY_HZ..=PIT_MAX_FREQUENCY_HZ`.
+fn pit_divisor(frequency_hz: u32) -> u16 {
+    let hz = frequency_hz.clamp(PIT_MIN_FREQUENCY_HZ, PIT_MAX_FREQUENCY_HZ);
+    PIT_FREQUENCY.div_ceil(hz) as u16
+}
+
+/// Programs PIT channel 0 to fire about `frequency_hz` times a second on
+/// IRQ 0, in rate generator mode, and returns the rate it really runs at.
+/// Rates out of range are clamped. This is the scheduler tick until
+/// `init_apic_timer` takes over, and stays it on CPUs without an APIC.
+pub fn init_pit(frequency_hz: u32) -> u32 {
+    let divisor = pit_divisor(frequency_hz);
+
+    no_interrupt(|| unsafe {
+        let mut command: Port<u8> = Port::new(0x43);
+        let mut channel0: Port<u8> = Port::new(0x40);
+        // Channel 0, lobyte/hibyte, mode 2 (rate generator).
+        command.write(0b0011_0100);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    });
+    let actual = PIT_FREQUENCY / u32::from(divisor);
+    FREQUENCY_HZ.store(actual, Ordering::Relaxed);
+    actual
+}
+
+/// Checks the divisor `init_pit` picks at, inside and beyond its limits.
+pub fn test_pit_divisor() -> Result<(), &'static str> {
+    if pit_divisor(100) != 11932 {
+        return Err("wrong divisor for 100 Hz");
+    }
+    if pit_divisor(0) != pit_divisor(PIT_MIN_FREQUENCY_HZ) || pit_divisor(1) != pit_divisor(0) {
+        return Err("rates below the minimum not clamped");
+    }
+    if pit_divisor(u32::MAX) != pit_divisor(PIT_MAX_FREQUENCY_HZ) {
+        return Err("rates above the maximum not clamped");
+    }
+    if PIT_FREQUENCY / u32::from(pit_divisor(PIT_MAX_FREQUENCY_HZ)) > PIT_MAX_FREQUENCY_HZ {
+        return Err("maximum rate overshoots");
+    }
+    crate::serial_println!(
+        "✓ PIT divisors clamp to {}-{} Hz",
+        PIT_MIN_FREQUENCY_HZ,
+        PIT_MAX_FREQUENCY_HZ
+    );
+    Ok(())
+}
+
+/// Busy-waits `CALIBRATION_MS` using PIT channel 2 in one-shot mode.
+fn pit_calibration_wait() {
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel2: Port<u8> = Port::new(0x42);
+    let count = PIT_FREQUENCY / (1000 / CALIBRATION_MS);
+
+    unsafe {
+        // Gate on, speaker off.
+        let value = (gate.read() & !0x02) | 0x01;
+        gate.write(value);
+
+        // Channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count).
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        // Restart the count by pulsing the gate.
+        let value = gate.read() & !0x01;
+        gate.write(value);
+        gate.write(value | 0x01);
+
+        while gate.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Measures the TSC against the PIT and stores its rate for `delay_us`.
+/// Assumes an invariant TSC, which every CPU QEMU emulates has.
+pub fn calibrate_tsc() -> u64 {
+    let hz = no_interrupt(|| {
+        let start = unsafe { core::arch::x86_64::_rdtsc() };
+        pit_calibration_wait();
+        let elapsed = unsafe { core::arch::x86_64::_rdtsc() } - start;
+        elapsed * (1000 / CALIBRATION_MS) as u64
+    });
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    hz
+}
+
+/// TSC ticks per second, calibrating on first use.
+pub fn tsc_frequency_hz() -> u64 {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => calibrate_tsc(),
+        hz => hz,
+    }
+}
+
+/// Spins for at least `microseconds`, timed with the TSC, so it works with
+/// interrupts off and before the tick is running.
+pub fn delay_us(microseconds: u64) {
+    let cycles = (microseconds as u128 * tsc_frequency_hz() as u128).div_ceil(1_000_000) as u64;
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    while unsafe { core::arch::x86_64::_rdtsc() } - start < cycles {
+        core::hint::spin_loop();
+    }
+}
+
+/// Switches the scheduler tick from the 8259 PIT to the local APIC timer,
+/// firing `frequency_hz` times per second in periodic mode. The APIC timer
+/// is calibrated against the PIT, and IRQ 0 is masked at the PIC afterwards.
+/// Fails, leaving the PIT in charge, on a CPU without a local APIC.
+pub fn init_apic_timer(frequency_hz: u32) -> Result<(), &'static str> {
+    assert!(frequency_hz > 0, "APIC timer frequency must be non-zero");
+    if !crate::arch::cpuid::has_feature(crate::arch::cpuid::Feature::Apic) {
+        return Err("no local APIC");
+    }
+
+    no_interrupt(|| {
+        enable_local_apic();
+        apic_write(APIC_TIMER_DIVIDE, DIVIDE_BY_16);
+
+        apic_write(APIC_LVT_TIMER, LVT_MASKED);
+        apic_write(APIC_TIMER_INITIAL_COUNT, u32::MAX);
+        pit_calibration_wait();
+        let elapsed = u32::MAX - apic_read(APIC_TIMER_CURRENT_COUNT);
+        apic_write(APIC_TIMER_INITIAL_COUNT, 0);
+
+        let ticks_per_sec = elapsed as u64 * (1000 / CALIBRATION_MS) as u64;
+        let initial_count = (ticks_per_sec / frequency_hz as u64).clamp(1, u32::MAX as u64);
+        crate::serial_println!(
+            "APIC timer: {} ticks/s, initial count {} for {} Hz",
+            ticks_per_sec,
+            initial_count,
+            frequency_hz
+        );
+
+        apic_write(
+            APIC_LVT_TIMER,
+            InterruptIndex::ApicTimer as u32 | LVT_TIMER_PERIODIC,
+        );
+        apic_write(APIC_TIMER_INITIAL_COUNT, initial_count as u32);
+
+        unsafe {
+            let mut pic1_data: Port<u8> = Port::new(0x21);
+            let mask = pic1_data.read() | 0x01;
+            pic1_data.write(mask);
+        }
+
+        FREQUENCY_HZ.store(frequency_hz, Ordering::Relaxed);
+        APIC_INITIAL_COUNT.store(initial_count as u32, Ordering::Relaxed);
+        APIC_MODE.store(true, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+/// Starts the executing AP's local APIC timer at the BSP's rate, with the
+/// count `init_apic_timer` calibrated: every local APIC runs off the same
+/// bus clock. Does nothing while the BSP is still on the PIT.
+pub fn init_ap_apic_timer() {
+    if !apic_mode() {
+        return;
+    }
+    no_interrupt(|| {
+        apic_write(APIC_TIMER_DIVIDE, DIVIDE_BY_16);
+        apic_write(
+            APIC_LVT_TIMER,
+            InterruptIndex::ApicTimer as u32 | LVT_TIMER_PERIODIC,
+        );
+        apic_write(
+            APIC_TIMER_INITIAL_COUNT,
+            APIC_INITIAL_COUNT.load(Ordering::Relaxed),
+        );
+    });
+}
@@ -0,0 +1,121 @@
+use crate::gdt;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+/// Set while a user program runs; there is only one user stack region.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Kernel stack pointer saved by `user_mode_enter`, which `exit_user_mode`
+/// returns to. 0 when no user program is running.
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+
+unsafe extern "C" {
+    fn user_mode_enter(
+        entry: u64,
+        user_stack: u64,
+        user_cs: u64,
+        user_ss: u64,
+        saved_rsp: *mut u64,
+    ) -> u64;
+    fn user_mode_return(kernel_rsp: u64, code: u64) -> !;
+}
+
+// `user_mode_enter` saves the callee-saved registers and its stack pointer,
+// then builds an interrupt frame for ring 3 and drops into it with `iretq`.
+// `user_mode_return` switches back to that stack from wherever the kernel
+// is (the TSS ring 0 stack, inside a syscall), so `user_mode_enter`
+// appears to return the exit code.
+global_asm!(
+    r#"
+    .text
+    .global user_mode_enter
+    .type user_mode_enter, @function
+user_mode_enter:
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [r8], rsp
+
+    push rcx
+    push rsi
+    push 0x202
+    push rdx
+    push rdi
+
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+
+    .global user_mode_return
+    .type user_mode_return, @function
+user_mode_return:
+    mov rsp, rdi
+    mov rax, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+"#
+);
+
+/// Runs user code from `entry` in ring 3 on `user_stack`, with interrupts
+/// enabled, until it calls `sys_exit`, and returns the exit code. Fails if
+/// another user program is already running.
+///
+/// # Safety
+///
+/// `entry` must be user-accessible code and `user_stack` the top of a
+/// writable, user-accessible stack. A user program that faults instead of
+/// exiting takes the kernel down with it.
+pub unsafe fn run_user(entry: VirtAddr, user_stack: VirtAddr) -> Result<u64, &'static str> {
+    if RUNNING.swap(true, Ordering::SeqCst) {
+        return Err("a user program is already running");
+    }
+    let were_enabled = interrupts::are_enabled();
+    let (code_selector, data_selector) = gdt::user_selectors();
+    let code = unsafe {
+        user_mode_enter(
+            entry.as_u64(),
+            user_stack.as_u64(),
+            u64::from(code_selector.0),
+            u64::from(data_selector.0),
+            KERNEL_RSP.as_ptr(),
+        )
+    };
+    KERNEL_RSP.store(0, Ordering::SeqCst);
+    RUNNING.store(false, Ordering::SeqCst);
+    // Both syscall paths turn interrupts off on the way back.
+    if were_enabled {
+        interrupts::enable();
+    }
+    Ok(code)
+}
+
+/// Ends the running user program with `code`, resuming the kernel in
+/// `run_user`. Returns only when no user program is running.
+pub fn exit_user_mode(code: u64) {
+    let rsp = KERNEL_RSP.load(Ordering::SeqCst);
+    if rsp != 0 {
+        unsafe { user_mode_return(rsp, code) }
+    }
+}
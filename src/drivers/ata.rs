@@ -1,5 +1,10 @@
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
@@ -15,6 +20,78 @@ const ATA_STATUS_DRQ: u8 = 0x08;
 const ATA_STATUS_ERR: u8 = 0x01;
 const ATA_STATUS_DF: u8 = 0x20;
 
+const ATA_PRIMARY_BASE: u16 = 0x1F0;
+const ATA_SECONDARY_BASE: u16 = 0x170;
+
+/// Completion signal raised by a channel's IRQ handler.
+struct AtaIrq {
+    pending: AtomicBool,
+    waker: AtomicWaker,
+    /// Set while `read_sectors_async` has the channel, so other callers
+    /// wait instead of issuing a command over its transfer.
+    busy: AtomicBool,
+}
+
+impl AtaIrq {
+    const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+            busy: AtomicBool::new(false),
+        }
+    }
+}
+
+static PRIMARY_IRQ: AtaIrq = AtaIrq::new();
+static SECONDARY_IRQ: AtaIrq = AtaIrq::new();
+
+const ATA_PRIMARY_IRQ: u8 = 14;
+const ATA_SECONDARY_IRQ: u8 = 15;
+
+/// Hooks both channels up to IRQs 14 and 15.
+pub fn init_irqs() -> Result<(), &'static str> {
+    crate::interrupts::register_irq(ATA_PRIMARY_IRQ, || handle_irq(true))?;
+    crate::interrupts::register_irq(ATA_SECONDARY_IRQ, || handle_irq(false))
+}
+
+/// Reads the status register directly (which acknowledges the interrupt on
+/// the drive) instead of locking the controller, which the interrupted code
+/// may be holding.
+fn handle_irq(primary: bool) {
+    let (base, irq) = if primary {
+        (ATA_PRIMARY_BASE, &PRIMARY_IRQ)
+    } else {
+        (ATA_SECONDARY_BASE, &SECONDARY_IRQ)
+    };
+    unsafe {
+        PortReadOnly::<u8>::new(base + 7).read();
+    }
+    irq.pending.store(true, Ordering::Release);
+    irq.waker.wake();
+}
+
+struct IrqWait {
+    irq: &'static AtaIrq,
+}
+
+impl Future for IrqWait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.irq.pending.swap(false, Ordering::AcqRel) {
+            return Poll::Ready(());
+        }
+
+        self.irq.waker.register(cx.waker());
+        if self.irq.pending.swap(false, Ordering::AcqRel) {
+            self.irq.waker.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AtaDevice {
     Master = 0,
@@ -52,7 +129,187 @@ impl core::fmt::Display for AtaError {
     }
 }
 
+/// First sector LBA28 can't address.
+const LBA28_LIMIT: u64 = 1 << 28;
+/// Most sectors one LBA28 command can move; the count register's 0 means 256.
+const LBA28_MAX_SECTORS: usize = 256;
+
+/// One READ/WRITE command of a transfer `plan_commands` split up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Command {
+    lba: u64,
+    count: usize,
+    lba48: bool,
+}
+
+impl Command {
+    /// Where this command's sectors sit in the buffer of a transfer that
+    /// starts at `start_lba`.
+    fn byte_range(&self, start_lba: u64) -> core::ops::Range<usize> {
+        let start = (self.lba - start_lba) as usize * 512;
+        start..start + self.count * 512
+    }
+}
+
+/// Iterator returned by `plan_commands`.
+struct CommandPlan {
+    next: u64,
+    end: u64,
+    max: u64,
+}
+
+/// Splits `count` sectors at `lba` into commands of at most `max` sectors,
+/// none straddling `LBA28_LIMIT`. A command goes out as LBA28 when it can,
+/// that is when it lies below the limit and is at most 256 sectors, and as
+/// LBA48 otherwise.
+fn plan_commands(lba: u64, count: usize, max: usize) -> CommandPlan {
+    CommandPlan {
+        next: lba,
+        end: lba + count as u64,
+        max: max as u64,
+    }
+}
+
+impl Iterator for CommandPlan {
+    type Item = Command;
+
+    fn next(&mut self) -> Option<Command> {
+        if self.next >= self.end {
+            return None;
+        }
+        let limit = if self.next < LBA28_LIMIT {
+            self.end.min(LBA28_LIMIT)
+        } else {
+            self.end
+        };
+        let count = (limit - self.next).min(self.max) as usize;
+        let command = Command {
+            lba: self.next,
+            count,
+            lba48: self.next >= LBA28_LIMIT || count > LBA28_MAX_SECTORS,
+        };
+        self.next += count as u64;
+        Some(command)
+    }
+}
+
+/// Sectors each controller's read cache holds.
+const CACHE_SECTORS: usize = 64;
+
+struct CachedSector {
+    device: AtaDevice,
+    lba: u64,
+    data: [u8; 512],
+    last_used: u64,
+}
+
+/// Write-through LRU cache of recently read sectors. Writes update cached
+/// copies on success and evict them on failure, so a hit always matches
+/// what is on disk.
+struct SectorCache {
+    entries: Vec<CachedSector>,
+    /// Bumped on every access; the entry with the oldest stamp goes first.
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Sector cache counters for one controller.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl SectorCache {
+    const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn position(&self, device: AtaDevice, lba: u64) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|e| e.device == device && e.lba == lba)
+    }
+
+    /// Fills `buffer` from the cache if every sector in it is cached.
+    fn read(&mut self, device: AtaDevice, lba: u64, buffer: &mut [u8]) -> bool {
+        let count = buffer.len() / 512;
+        let mut found = Vec::with_capacity(count);
+        for i in 0..count as u64 {
+            match self.position(device, lba + i) {
+                Some(index) => found.push(index),
+                None => return false,
+            }
+        }
+        for (sector, index) in buffer.chunks_exact_mut(512).zip(found) {
+            self.clock += 1;
+            let entry = &mut self.entries[index];
+            entry.last_used = self.clock;
+            sector.copy_from_slice(&entry.data);
+        }
+        true
+    }
+
+    /// Caches the sectors in `buffer`, evicting the least recently used
+    /// entries to make room.
+    fn insert(&mut self, device: AtaDevice, lba: u64, buffer: &[u8]) {
+        for (i, sector) in buffer.chunks_exact(512).enumerate() {
+            let lba = lba + i as u64;
+            self.clock += 1;
+            let index = match self.position(device, lba) {
+                Some(index) => index,
+                None if self.entries.len() < CACHE_SECTORS => {
+                    self.entries.push(CachedSector {
+                        device,
+                        lba,
+                        data: [0; 512],
+                        last_used: 0,
+                    });
+                    self.entries.len() - 1
+                }
+                None => {
+                    let (index, _) = self
+                        .entries
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, e)| e.last_used)
+                        .expect("cache is full, so not empty");
+                    self.entries[index].lba = lba;
+                    self.entries[index].device = device;
+                    index
+                }
+            };
+            let entry = &mut self.entries[index];
+            entry.data.copy_from_slice(sector);
+            entry.last_used = self.clock;
+        }
+    }
+
+    /// Brings cached copies of a successful write up to date.
+    fn update(&mut self, device: AtaDevice, lba: u64, buffer: &[u8]) {
+        for (i, sector) in buffer.chunks_exact(512).enumerate() {
+            if let Some(index) = self.position(device, lba + i as u64) {
+                self.entries[index].data.copy_from_slice(sector);
+            }
+        }
+    }
+
+    /// Drops cached copies of `count` sectors whose contents are unknown,
+    /// e.g. after a failed write.
+    fn evict(&mut self, device: AtaDevice, lba: u64, count: u64) {
+        self.entries
+            .retain(|e| e.device != device || !(lba..lba + count).contains(&e.lba));
+    }
+}
+
 pub struct AtaController {
+    base: u16,
     pub data_port: Port<u16>,
     pub error_port: PortReadOnly<u8>,
     pub features_port: PortWriteOnly<u8>,
@@ -68,11 +325,15 @@ pub struct AtaController {
 
     pub supports_lba48: [bool; 2],
     pub max_sectors: [u64; 2],
+    cache: SectorCache,
+    /// IDENTIFY results per device, so it is only issued once.
+    drive_info: [Option<DriveInfo>; 2],
 }
 
 impl AtaController {
     pub const fn new(base: u16) -> Self {
         Self {
+            base,
             data_port: Port::new(base),
             error_port: PortReadOnly::new(base + 1),
             features_port: PortWriteOnly::new(base + 1),
@@ -87,9 +348,13 @@ impl AtaController {
             alt_status_port: PortReadOnly::new(base + 0x206),
             supports_lba48: [false; 2],
             max_sectors: [0; 2],
+            cache: SectorCache::new(),
+            drive_info: [None, None],
         }
     }
 
+    /// Reads `count` sectors into `buffer`, from the sector cache when all
+    /// of them are there.
     pub fn read_sectors(
         &mut self,
         device: AtaDevice,
@@ -101,14 +366,68 @@ impl AtaController {
             return Err(AtaError::BufferTooSmall);
         }
 
-        let device_idx = device as usize;
+        let buffer = &mut buffer[..count as usize * 512];
+        if self.cache.read(device, lba, buffer) {
+            self.cache.hits += count as u64;
+            return Ok(());
+        }
+        self.cache.misses += count as u64;
+        self.read_sectors_uncached(device, lba, count, buffer)?;
+        self.cache.insert(device, lba, buffer);
+        Ok(())
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.cache.hits,
+            misses: self.cache.misses,
+        }
+    }
+
+    fn read_sectors_uncached(
+        &mut self,
+        device: AtaDevice,
+        lba: u64,
+        count: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), AtaError> {
         crate::serial_println!("ATA: Reading {} sectors from LBA {}", count, lba);
 
-        if lba > 0xFFFFFFF || count > 256 || self.supports_lba48[device_idx] {
-            self.read_sectors_lba48(device, lba, count, buffer)
+        let buffer = &mut buffer[..count as usize * 512];
+        for command in self.commands(device, lba, count as usize)? {
+            let chunk = &mut buffer[command.byte_range(lba)];
+            if command.lba48 {
+                self.read_sectors_lba48(device, command.lba, command.count as u16, chunk)?;
+            } else {
+                // A count of 256 goes out as 0, which the drive reads as 256.
+                self.read_sectors_lba28(device, command.lba as u32, command.count as u8, chunk)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Most sectors one READ/WRITE command can move: 256 with LBA28, and
+    /// 65535 with LBA48, where the largest count (65536) would not fit the
+    /// u16 the command paths take.
+    fn max_sectors_per_command(&self, device: AtaDevice) -> usize {
+        if self.supports_lba48[device as usize] {
+            u16::MAX as usize
         } else {
-            self.read_sectors_lba28(device, lba as u32, count as u8, buffer)
+            LBA28_MAX_SECTORS
+        }
+    }
+
+    /// The commands a transfer of `count` sectors at `lba` takes, or
+    /// `InvalidLba` if it reaches past LBA28 on a drive without LBA48.
+    fn commands(&self, device: AtaDevice, lba: u64, count: usize) -> Result<CommandPlan, AtaError> {
+        if !self.supports_lba48[device as usize] && lba + count as u64 > LBA28_LIMIT {
+            return Err(AtaError::InvalidLba);
         }
+        Ok(plan_commands(
+            lba,
+            count,
+            self.max_sectors_per_command(device),
+        ))
     }
 
     fn read_sectors_lba48(
@@ -117,6 +436,27 @@ impl AtaController {
         lba: u64,
         count: u16,
         buffer: &mut [u8],
+    ) -> Result<(), AtaError> {
+        self.submit_read_lba48(device, lba, count)?;
+        self.read_data_sectors(count, buffer)
+    }
+
+    fn read_sectors_lba28(
+        &mut self,
+        device: AtaDevice,
+        lba: u32,
+        count: u8,
+        buffer: &mut [u8],
+    ) -> Result<(), AtaError> {
+        self.submit_read_lba28(device, lba, count)?;
+        self.read_data_sectors(count as u16, buffer)
+    }
+
+    fn submit_read_lba48(
+        &mut self,
+        device: AtaDevice,
+        lba: u64,
+        count: u16,
     ) -> Result<(), AtaError> {
         self.select_device(device)?;
         self.wait_ready()?;
@@ -135,16 +475,14 @@ impl AtaController {
             self.device_port.write(0x40 | ((device as u8) << 4));
             self.command_port.write(ATA_CMD_READ_SECTORS_EXT);
         }
-
-        self.read_data_sectors(count, buffer)
+        Ok(())
     }
 
-    fn read_sectors_lba28(
+    fn submit_read_lba28(
         &mut self,
         device: AtaDevice,
         lba: u32,
         count: u8,
-        buffer: &mut [u8],
     ) -> Result<(), AtaError> {
         self.select_device(device)?;
         self.wait_ready()?;
@@ -158,20 +496,46 @@ impl AtaController {
                 .write(0xE0 | ((device as u8) << 4) | ((lba >> 24) as u8 & 0x0F));
             self.command_port.write(ATA_CMD_READ_SECTORS);
         }
+        Ok(())
+    }
 
-        self.read_data_sectors(count as u16, buffer)
+    /// Issues `command` as a read with the drive's interrupt enabled. For
+    /// `read_sectors_async`.
+    fn submit_read_irq(&mut self, device: AtaDevice, command: Command) -> Result<(), AtaError> {
+        self.irq().pending.store(false, Ordering::Release);
+        self.enable_interrupts();
+        let submitted = if command.lba48 {
+            self.submit_read_lba48(device, command.lba, command.count as u16)
+        } else {
+            self.submit_read_lba28(device, command.lba as u32, command.count as u8)
+        };
+        if submitted.is_err() {
+            self.disable_interrupts();
+        }
+        submitted
+    }
+
+    fn irq(&self) -> &'static AtaIrq {
+        if self.base == ATA_PRIMARY_BASE {
+            &PRIMARY_IRQ
+        } else {
+            &SECONDARY_IRQ
+        }
     }
 
     fn read_data_sectors(&mut self, count: u16, buffer: &mut [u8]) -> Result<(), AtaError> {
-        for sector in 0..count {
-            self.wait_data_ready()?;
+        for sector in buffer[..count as usize * 512].chunks_exact_mut(512) {
+            self.read_data_sector(sector)?;
+        }
+        Ok(())
+    }
 
-            let sector_start = sector as usize * 512;
-            for i in (0..512).step_by(2) {
-                let word = unsafe { self.data_port.read() };
-                buffer[sector_start + i] = word as u8;
-                buffer[sector_start + i + 1] = (word >> 8) as u8;
-            }
+    /// Takes the next 512-byte sector of a read off the data port.
+    fn read_data_sector(&mut self, sector: &mut [u8]) -> Result<(), AtaError> {
+        self.wait_data_ready()?;
+        for pair in sector.chunks_exact_mut(2) {
+            let word = unsafe { self.data_port.read() };
+            pair.copy_from_slice(&word.to_le_bytes());
         }
         Ok(())
     }
@@ -187,15 +551,43 @@ impl AtaController {
         }
 
         let count = buffer.len() / 512;
-        let device_idx = device as usize;
-
         crate::serial_println!("ATA: Writing {} sectors at LBA {}", count, lba);
 
-        if lba > 0xFFFFFFF || count > 256 || self.supports_lba48[device_idx] {
-            self.write_sectors_lba48(device, lba, count as u16, buffer)
-        } else {
-            self.write_sectors_lba28(device, lba as u32, count as u8, buffer)
+        let result = self.commands(device, lba, count).and_then(|mut plan| {
+            plan.try_for_each(|command| {
+                let chunk = &buffer[command.byte_range(lba)];
+                if command.lba48 {
+                    self.write_sectors_lba48(device, command.lba, command.count as u16, chunk)
+                } else {
+                    self.write_sectors_lba28(device, command.lba as u32, command.count as u8, chunk)
+                }
+            })
+        });
+        match result {
+            Ok(()) => self.cache.update(device, lba, buffer),
+            Err(_) => self.cache.evict(device, lba, count as u64),
+        }
+        result
+    }
+
+    /// `write_sectors`, then reads the sectors back and fails with
+    /// `CommandFailed` if they differ from `buffer`.
+    pub fn write_sectors_verified(
+        &mut self,
+        device: AtaDevice,
+        lba: u64,
+        buffer: &[u8],
+    ) -> Result<(), AtaError> {
+        self.write_sectors(device, lba, buffer)?;
+
+        // Straight from the disk: the cache already holds what was written.
+        let mut readback = vec![0u8; buffer.len()];
+        self.read_sectors_uncached(device, lba, (buffer.len() / 512) as u16, &mut readback)?;
+        if readback != buffer {
+            crate::serial_println!("ATA: Verify failed at LBA {}", lba);
+            return Err(AtaError::CommandFailed);
         }
+        Ok(())
     }
 
     fn write_sectors_lba48(
@@ -299,7 +691,18 @@ impl AtaController {
         Err(AtaError::Timeout)
     }
 
+    /// Identifies `device`. Only the first call issues IDENTIFY; later ones
+    /// return the cached result.
     pub fn identify(&mut self, device: AtaDevice) -> Result<DriveInfo, AtaError> {
+        if let Some(info) = &self.drive_info[device as usize] {
+            return Ok(info.clone());
+        }
+        let info = self.identify_uncached(device)?;
+        self.drive_info[device as usize] = Some(info.clone());
+        Ok(info)
+    }
+
+    fn identify_uncached(&mut self, device: AtaDevice) -> Result<DriveInfo, AtaError> {
         crate::serial_println!("ATA: Starting IDENTIFY for device {:?}", device);
 
         self.disable_interrupts();
@@ -367,6 +770,12 @@ impl AtaController {
         }
     }
 
+    fn enable_interrupts(&mut self) {
+        unsafe {
+            self.control_port.write(0x00);
+        }
+    }
+
     fn delay_400ns(&mut self) {
         for _ in 0..4 {
             unsafe {
@@ -436,6 +845,7 @@ impl AtaController {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct DriveInfo {
     pub model: String,
     pub serial: String,
@@ -536,18 +946,31 @@ fn extract_string(data: &[u16; 256], start_word: usize, word_count: usize) -> St
     result.trim().to_string()
 }
 
-pub static PRIMARY_ATA: Mutex<AtaController> = Mutex::new(AtaController::new(0x1F0));
-pub static SECONDARY_ATA: Mutex<AtaController> = Mutex::new(AtaController::new(0x170));
+pub static PRIMARY_ATA: Mutex<AtaController> = Mutex::new(AtaController::new(ATA_PRIMARY_BASE));
+pub static SECONDARY_ATA: Mutex<AtaController> = Mutex::new(AtaController::new(ATA_SECONDARY_BASE));
 
-fn with_controller<F, R>(primary: bool, f: F) -> R
-where
-    F: FnOnce(&mut AtaController) -> R,
-{
+fn channel(primary: bool) -> (&'static Mutex<AtaController>, &'static AtaIrq) {
     if primary {
-        f(&mut PRIMARY_ATA.lock())
+        (&PRIMARY_ATA, &PRIMARY_IRQ)
     } else {
-        f(&mut SECONDARY_ATA.lock())
+        (&SECONDARY_ATA, &SECONDARY_IRQ)
+    }
+}
+
+/// Runs `f` on a channel's controller, or fails with `NotReady` while a
+/// `read_sectors_async` holds the channel. Waiting for it instead could
+/// spin forever on a single CPU, where the read only moves on once the
+/// caller gives the CPU back.
+fn with_controller<F, R>(primary: bool, f: F) -> Result<R, AtaError>
+where
+    F: FnOnce(&mut AtaController) -> Result<R, AtaError>,
+{
+    let (controller, irq) = channel(primary);
+    let mut controller = controller.lock();
+    if irq.busy.load(Ordering::Acquire) {
+        return Err(AtaError::NotReady);
     }
+    f(&mut controller)
 }
 
 pub fn read_sectors(
@@ -562,6 +985,82 @@ pub fn read_sectors(
     })
 }
 
+/// A channel claimed for `read_sectors_async`. Dropping it, also when the
+/// read is abandoned, masks the drive's interrupt and frees the channel.
+struct ChannelClaim {
+    controller: &'static Mutex<AtaController>,
+    irq: &'static AtaIrq,
+}
+
+impl ChannelClaim {
+    /// Waits, letting other tasks run, until no other read holds the
+    /// channel.
+    async fn acquire(primary: bool) -> Self {
+        let (controller, irq) = channel(primary);
+        core::future::poll_fn(|cx| {
+            match irq
+                .busy
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => Poll::Ready(()),
+                Err(_) => {
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
+        ChannelClaim { controller, irq }
+    }
+}
+
+impl Drop for ChannelClaim {
+    fn drop(&mut self) {
+        self.controller.lock().disable_interrupts();
+        self.irq.busy.store(false, Ordering::Release);
+    }
+}
+
+/// Interrupt-driven `read_sectors`, which always goes to the drive. The
+/// controller is only locked to issue each command and to take each sector
+/// off the drive; in between, the task sleeps until the channel's IRQ
+/// fires. The channel stays claimed for the whole read, so other async
+/// reads wait and polled calls fail with `NotReady` rather than interleave
+/// commands. Falls back to `read_sectors` when interrupts are disabled,
+/// since the IRQ would never be delivered.
+pub async fn read_sectors_async(
+    primary: bool,
+    device: AtaDevice,
+    lba: u64,
+    count: u16,
+    buffer: &mut [u8],
+) -> Result<(), AtaError> {
+    if !x86_64::instructions::interrupts::are_enabled() {
+        return read_sectors(primary, device, lba, count, buffer);
+    }
+    if buffer.len() < (count as usize * 512) {
+        return Err(AtaError::BufferTooSmall);
+    }
+
+    let claim = ChannelClaim::acquire(primary).await;
+
+    let plan = claim
+        .controller
+        .lock()
+        .commands(device, lba, count as usize)?;
+    let buffer = &mut buffer[..count as usize * 512];
+    for command in plan {
+        claim.controller.lock().submit_read_irq(device, command)?;
+        for sector in buffer[command.byte_range(lba)].chunks_exact_mut(512) {
+            IrqWait { irq: claim.irq }.await;
+            let mut controller = claim.controller.lock();
+            controller.wait_ready()?;
+            controller.read_data_sector(sector)?;
+        }
+    }
+    Ok(())
+}
+
 pub fn write_sectors(
     primary: bool,
     device: AtaDevice,
@@ -573,11 +1072,126 @@ pub fn write_sectors(
     })
 }
 
+pub fn write_sectors_verified(
+    primary: bool,
+    device: AtaDevice,
+    lba: u64,
+    buffer: &[u8],
+) -> Result<(), AtaError> {
+    with_controller(primary, |controller| {
+        controller.write_sectors_verified(device, lba, buffer)
+    })
+}
+
+pub fn cache_stats(primary: bool) -> CacheStats {
+    channel(primary).0.lock().cache_stats()
+}
+
 pub fn identify_drive(primary: bool, device: AtaDevice) -> Result<DriveInfo, AtaError> {
     with_controller(primary, |controller| controller.identify(device))
 }
 
-use crate::alloc::{collections::BTreeMap, vec};
+const MBR_PARTITION_TABLE_OFFSET: usize = 0x1BE;
+const MBR_PARTITION_ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionEntry {
+    pub bootable: bool,
+    pub partition_type: u8,
+    pub start_lba: u64,
+    pub sector_count: u64,
+}
+
+impl PartitionEntry {
+    /// Parses one 16-byte MBR entry. Returns `None` for unused slots.
+    fn parse(raw: &[u8]) -> Option<Self> {
+        let partition_type = raw[4];
+        let start_lba = u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]) as u64;
+        let sector_count = u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]) as u64;
+
+        if partition_type == 0 || sector_count == 0 {
+            return None;
+        }
+
+        Some(Self {
+            bootable: raw[0] == 0x80,
+            partition_type,
+            start_lba,
+            sector_count,
+        })
+    }
+}
+
+/// Reads the four primary MBR partition entries from sector 0. Unused slots
+/// are `None`; a disk without an MBR signature yields no partitions at all.
+pub fn read_partition_table(
+    primary: bool,
+    device: AtaDevice,
+) -> Result<[Option<PartitionEntry>; 4], AtaError> {
+    let mut sector = [0u8; 512];
+    read_sectors(primary, device, 0, 1, &mut sector)?;
+
+    let mut partitions = [None; 4];
+    if sector[510] != 0x55 || sector[511] != 0xAA {
+        return Ok(partitions);
+    }
+
+    for (i, partition) in partitions.iter_mut().enumerate() {
+        let start = MBR_PARTITION_TABLE_OFFSET + i * MBR_PARTITION_ENTRY_SIZE;
+        *partition = PartitionEntry::parse(&sector[start..start + MBR_PARTITION_ENTRY_SIZE]);
+    }
+
+    Ok(partitions)
+}
+
+/// Looks up a single partition by its index in the MBR table.
+pub fn find_partition(
+    primary: bool,
+    device: AtaDevice,
+    index: usize,
+) -> Result<PartitionEntry, AtaError> {
+    if index >= 4 {
+        return Err(AtaError::InvalidLba);
+    }
+    read_partition_table(primary, device)?[index].ok_or(AtaError::DeviceNotFound)
+}
+
+use crate::alloc::{
+    collections::{BTreeMap, BTreeSet},
+    format, vec,
+};
+use crate::fs::vfs::{DirEntry as VfsDirEntry, FileSystem, FsError, VolumeStats};
+
+// On-disk layout, in sectors relative to the filesystem start:
+//   0        superblock
+//   1..16    directory records
+//   16..32   FAT records
+//   32..     data clusters
+const DIRECTORY_START_SECTOR: u64 = 1;
+const DIRECTORY_SECTORS: usize = 15;
+const FAT_START_SECTOR: u64 = 16;
+const FAT_SECTORS: usize = 16;
+const FIRST_DATA_CLUSTER: u64 = 4;
+
+// A directory record is the entry's flags, the length of its last path
+// component, the record id of its parent directory (`DIR_PARENT_ROOT` at the
+// top level), that component, then its first cluster and size. Record ids
+// are the record's index plus one.
+const DIR_RECORD_SIZE: usize = 64;
+const DIR_NAME_MAX: usize = 44;
+const DIR_PARENT_ROOT: u16 = 0;
+const DIR_FLAG_USED: u8 = 0x01;
+const DIR_FLAG_DIRECTORY: u8 = 0x02;
+
+// Superblock byte 6. Filesystems written before records carried a parent id
+// have 0 there and a flat name in bytes 2..48 of each record.
+const DIRECTORY_FORMAT_FLAT: u8 = 0;
+const DIRECTORY_FORMAT_TREE: u8 = 1;
+const DIR_FLAT_NAME_MAX: usize = 46;
+
+const FAT_RECORD_SIZE: usize = 16;
+const FAT_END_OF_CHAIN: u64 = u64::MAX;
+const FAT_RECORD_UNUSED: u64 = 0;
 
 #[allow(dead_code)]
 struct SuperBlock {
@@ -624,16 +1238,38 @@ pub struct AtaFileSystem {
     superblock: SuperBlock,
     directory: BTreeMap<String, DirEntry>,
     fat: BTreeMap<u64, Option<u64>>,
+    /// Clusters below `next_free_cluster` that no file uses.
+    free_clusters: BTreeSet<u64>,
     next_free_cluster: u64,
 }
 
 impl AtaFileSystem {
+    /// Creates or loads a filesystem. With `partition` set, `start_lba` is
+    /// relative to that MBR partition and the range must fit inside it;
+    /// otherwise it is an absolute LBA on the disk.
     pub fn new(
         controller: bool,
         device: AtaDevice,
+        partition: Option<usize>,
         start_lba: u64,
         size_sectors: u64,
     ) -> Result<Self, AtaError> {
+        let start_lba = match partition {
+            Some(index) => {
+                let entry = find_partition(controller, device, index)?;
+                if start_lba + size_sectors > entry.sector_count {
+                    crate::serial_println!(
+                        "ATA FS: Error - filesystem range exceeds partition {} ({} sectors)",
+                        index,
+                        entry.sector_count
+                    );
+                    return Err(AtaError::InvalidLba);
+                }
+                entry.start_lba + start_lba
+            }
+            None => start_lba,
+        };
+
         crate::serial_println!(
             "ATA FS: Initializing filesystem at LBA {} with {} sectors",
             start_lba,
@@ -646,6 +1282,11 @@ impl AtaFileSystem {
             return Err(AtaError::InvalidLba);
         }
 
+        if size_sectors <= FIRST_DATA_CLUSTER * 8 {
+            crate::serial_println!("ATA FS: Error - filesystem too small for metadata");
+            return Err(AtaError::InvalidLba);
+        }
+
         let superblock = SuperBlock::new(start_lba, size_sectors);
 
         let mut fs = Self {
@@ -654,15 +1295,21 @@ impl AtaFileSystem {
             superblock,
             directory: BTreeMap::new(),
             fat: BTreeMap::new(),
-            next_free_cluster: 1,
+            free_clusters: BTreeSet::new(),
+            next_free_cluster: FIRST_DATA_CLUSTER,
         };
 
         crate::serial_println!("ATA FS: Checking for existing filesystem...");
         match fs.load_superblock() {
-            Ok(_) => {
+            Ok(format) => {
                 crate::serial_println!("ATA FS: Found existing filesystem, loading...");
-                fs.load_directory()?;
+                fs.load_directory(format)?;
                 fs.load_fat()?;
+                if format != DIRECTORY_FORMAT_TREE {
+                    crate::serial_println!("ATA FS: Rewriting directory with parent ids");
+                    fs.write_superblock()?;
+                    fs.write_directory()?;
+                }
             }
             Err(_) => {
                 crate::serial_println!("ATA FS: Creating new filesystem...");
@@ -678,7 +1325,8 @@ impl AtaFileSystem {
 
         self.directory.clear();
         self.fat.clear();
-        self.next_free_cluster = 1;
+        self.free_clusters.clear();
+        self.next_free_cluster = FIRST_DATA_CLUSTER;
 
         self.write_superblock()?;
         self.write_directory()?;
@@ -692,32 +1340,59 @@ impl AtaFileSystem {
         self.superblock.start_lba + cluster * self.superblock.sectors_per_cluster as u64
     }
 
-    fn allocate_cluster(&mut self) -> u64 {
-        let cluster = self.next_free_cluster;
-        self.next_free_cluster += 1;
+    /// Takes the lowest freed cluster, or a fresh one past the end of the
+    /// ones in use.
+    fn allocate_cluster(&mut self) -> Result<u64, AtaError> {
+        let cluster = match self.free_clusters.pop_first() {
+            Some(cluster) => cluster,
+            None => {
+                let cluster = self.next_free_cluster;
+                let end = self.superblock.start_lba + self.superblock.total_sectors;
+                if self.cluster_to_lba(cluster + 1) > end {
+                    crate::serial_println!("ATA FS: No free clusters left");
+                    return Err(AtaError::InvalidLba);
+                }
+                self.next_free_cluster += 1;
+                cluster
+            }
+        };
         self.fat.insert(cluster, None);
-        cluster
+        Ok(cluster)
     }
 
-    pub fn create_file(&mut self, name: &str, data: &[u8]) -> Result<(), AtaError> {
-        if self.directory.contains_key(name) {
-            return Err(AtaError::CommandFailed);
+    /// Releases every cluster in the chain starting at `start`.
+    fn free_chain(&mut self, start: u64) {
+        let mut current_cluster = self.fat.contains_key(&start).then_some(start);
+        while let Some(cluster) = current_cluster {
+            current_cluster = self.fat.remove(&cluster).flatten();
+            self.free_clusters.insert(cluster);
         }
+    }
 
-        crate::serial_println!("ATA FS: Creating file '{}' ({} bytes)", name, data.len());
-
+    /// Writes `data` into newly allocated clusters and links them, returning
+    /// the first one (0 for empty data).
+    fn write_chain(&mut self, data: &[u8]) -> Result<u64, AtaError> {
         let cluster_size = self.superblock.cluster_size();
         let mut clusters = Vec::new();
 
         for (i, chunk) in data.chunks(cluster_size).enumerate() {
-            let cluster = self.allocate_cluster();
+            let cluster = match self.allocate_cluster() {
+                Ok(cluster) => cluster,
+                Err(e) => {
+                    self.release(&clusters);
+                    return Err(e);
+                }
+            };
             clusters.push(cluster);
 
             let mut buffer = vec![0u8; cluster_size];
             buffer[..chunk.len()].copy_from_slice(chunk);
 
             let lba = self.cluster_to_lba(cluster);
-            write_sectors(self.controller, self.device, lba, &buffer)?;
+            if let Err(e) = write_sectors(self.controller, self.device, lba, &buffer) {
+                self.release(&clusters);
+                return Err(e);
+            }
 
             crate::serial_println!(
                 "ATA FS: Wrote chunk {} to cluster {} (LBA {})",
@@ -736,8 +1411,80 @@ impl AtaFileSystem {
             self.fat.insert(clusters[i], next_cluster);
         }
 
-        let first_cluster = clusters.first().copied().unwrap_or(0);
+        Ok(clusters.first().copied().unwrap_or(0))
+    }
+
+    fn release(&mut self, clusters: &[u64]) {
+        for cluster in clusters {
+            self.fat.remove(cluster);
+            self.free_clusters.insert(*cluster);
+        }
+    }
+
+    /// Whether `path`'s parent is the root or an existing directory.
+    fn parent_is_directory(&self, path: &str) -> bool {
+        match path.rsplit_once('/') {
+            Some((parent, _)) => self.directory.get(parent).is_some_and(|e| e.is_directory),
+            None => true,
+        }
+    }
+
+    /// The entries directly inside directory `path`, `""` being the root.
+    fn children<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a DirEntry> + 'a {
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", path)
+        };
+        let skip = prefix.len();
+        self.directory
+            .range(prefix.clone()..)
+            .take_while(move |(key, _)| key.starts_with(&prefix))
+            .filter(move |(key, _)| !key[skip..].contains('/'))
+            .map(|(_, entry)| entry)
+    }
+
+    /// Creates directory `path`. Its parent must already exist.
+    pub fn create_dir(&mut self, path: &str) -> Result<(), AtaError> {
+        let path = entry_path(path)?;
+        if self.directory.contains_key(&path) {
+            return Err(AtaError::CommandFailed);
+        }
+        if !self.parent_is_directory(&path) {
+            return Err(AtaError::DeviceNotFound);
+        }
+
+        crate::serial_println!("ATA FS: Creating directory '{}'", path);
+
         self.directory.insert(
+            path.clone(),
+            DirEntry {
+                name: path,
+                start_cluster: 0,
+                size: 0,
+                is_directory: true,
+            },
+        );
+        self.write_directory()
+    }
+
+    /// Creates `name`, replacing any existing file of that name. The old
+    /// contents are only freed once the new ones are written. `name` may be
+    /// a path whose parent directories already exist.
+    pub fn create_file(&mut self, name: &str, data: &[u8]) -> Result<(), AtaError> {
+        let name = entry_path(name)?;
+        let name = name.as_str();
+        if self.directory.get(name).is_some_and(|e| e.is_directory) {
+            return Err(AtaError::CommandFailed);
+        }
+        if !self.parent_is_directory(name) {
+            return Err(AtaError::DeviceNotFound);
+        }
+
+        crate::serial_println!("ATA FS: Creating file '{}' ({} bytes)", name, data.len());
+
+        let first_cluster = self.write_chain(data)?;
+        let old = self.directory.insert(
             name.to_string(),
             DirEntry {
                 name: name.to_string(),
@@ -746,6 +1493,12 @@ impl AtaFileSystem {
                 is_directory: false,
             },
         );
+        if let Some(old) = old {
+            crate::serial_println!("ATA FS: Replacing previous '{}'", name);
+            if old.size > 0 {
+                self.free_chain(old.start_cluster);
+            }
+        }
 
         self.write_directory()?;
         self.write_fat()?;
@@ -755,7 +1508,11 @@ impl AtaFileSystem {
     }
 
     pub fn read_file(&self, name: &str) -> Result<Vec<u8>, AtaError> {
-        let entry = self.directory.get(name).ok_or(AtaError::DeviceNotFound)?;
+        let name = entry_path(name)?;
+        let entry = self.directory.get(&name).ok_or(AtaError::DeviceNotFound)?;
+        if entry.is_directory {
+            return Err(AtaError::CommandFailed);
+        }
 
         crate::serial_println!("ATA FS: Reading file '{}' ({} bytes)", name, entry.size);
 
@@ -791,6 +1548,7 @@ impl AtaFileSystem {
         Ok(data)
     }
 
+    /// Every file and directory, by full path.
     pub fn list_files(&self) -> Vec<(String, usize, bool)> {
         self.directory
             .iter()
@@ -798,7 +1556,34 @@ impl AtaFileSystem {
             .collect()
     }
 
+    /// The names, sizes and types of the entries directly inside `path`.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<(String, usize, bool)>, AtaError> {
+        let path: String = split_path(path).join("/");
+        if !path.is_empty() && !self.directory.get(&path).is_some_and(|e| e.is_directory) {
+            return Err(AtaError::DeviceNotFound);
+        }
+        Ok(self
+            .children(&path)
+            .map(|entry| {
+                let name = entry.name.rsplit('/').next().unwrap_or_default();
+                (name.to_string(), entry.size, entry.is_directory)
+            })
+            .collect())
+    }
+
+    /// Deletes a file, or a directory once it is empty.
     pub fn delete_file(&mut self, name: &str) -> Result<(), AtaError> {
+        let name = entry_path(name)?;
+        let name = name.as_str();
+        let is_directory = self
+            .directory
+            .get(name)
+            .ok_or(AtaError::DeviceNotFound)?
+            .is_directory;
+        if is_directory && self.children(name).next().is_some() {
+            crate::serial_println!("ATA FS: Directory '{}' is not empty", name);
+            return Err(AtaError::CommandFailed);
+        }
         let entry = self
             .directory
             .remove(name)
@@ -806,10 +1591,8 @@ impl AtaFileSystem {
 
         crate::serial_println!("ATA FS: Deleting file '{}'", name);
 
-        let mut current_cluster = Some(entry.start_cluster);
-        while let Some(cluster) = current_cluster {
-            let next = self.fat.remove(&cluster).flatten();
-            current_cluster = next;
+        if entry.size > 0 {
+            self.free_chain(entry.start_cluster);
         }
 
         self.write_directory()?;
@@ -819,7 +1602,107 @@ impl AtaFileSystem {
         Ok(())
     }
 
-    fn load_superblock(&mut self) -> Result<(), AtaError> {
+    /// Every cluster of the chain starting at `start`, in order. Stops early
+    /// on a loop rather than walking it forever.
+    fn chain(&self, start: u64) -> Vec<u64> {
+        let mut clusters = Vec::new();
+        let mut current_cluster = self.fat.contains_key(&start).then_some(start);
+        while let Some(cluster) = current_cluster {
+            if clusters.len() > self.fat.len() {
+                crate::serial_println!("ATA FS: Cluster chain from {} loops", start);
+                break;
+            }
+            clusters.push(cluster);
+            current_cluster = self.fat.get(&cluster).copied().flatten();
+        }
+        clusters
+    }
+
+    /// Moves every file into one contiguous run of clusters from
+    /// `FIRST_DATA_CLUSTER`, in directory order, and rewrites the FAT and
+    /// directory to match. Afterwards nothing is freed below
+    /// `next_free_cluster`. Returns how many clusters were moved.
+    ///
+    /// Taking `&mut self` means no `read_file` can be walking a chain while
+    /// clusters move; `fs_compact` refuses rather than waits if the global
+    /// filesystem is busy. Clusters that move are all read before any is
+    /// written, so overlapping moves are safe, but a failed write part way
+    /// leaves the on-disk tables pointing at the old layout.
+    pub fn compact(&mut self) -> Result<usize, AtaError> {
+        let chains: Vec<(String, Vec<u64>)> = self
+            .directory
+            .values()
+            .filter(|entry| entry.size > 0)
+            .map(|entry| (entry.name.clone(), self.chain(entry.start_cluster)))
+            .collect();
+
+        let mut relocated = BTreeMap::new();
+        let mut next = FIRST_DATA_CLUSTER;
+        for (_, clusters) in &chains {
+            for &cluster in clusters {
+                if cluster != next {
+                    relocated.insert(cluster, next);
+                }
+                next += 1;
+            }
+        }
+
+        crate::serial_println!(
+            "ATA FS: Compacting {} files, moving {} of {} clusters",
+            chains.len(),
+            relocated.len(),
+            next - FIRST_DATA_CLUSTER
+        );
+
+        let cluster_size = self.superblock.cluster_size();
+        let mut moved = Vec::with_capacity(relocated.len());
+        for (&from, &to) in &relocated {
+            let mut buffer = vec![0u8; cluster_size];
+            read_sectors(
+                self.controller,
+                self.device,
+                self.cluster_to_lba(from),
+                self.superblock.sectors_per_cluster(),
+                &mut buffer,
+            )?;
+            moved.push((to, buffer));
+        }
+        for (to, buffer) in &moved {
+            write_sectors(
+                self.controller,
+                self.device,
+                self.cluster_to_lba(*to),
+                buffer,
+            )?;
+        }
+
+        let new_cluster = |cluster: u64| relocated.get(&cluster).copied().unwrap_or(cluster);
+        let mut fat = BTreeMap::new();
+        for (name, clusters) in &chains {
+            for (i, &cluster) in clusters.iter().enumerate() {
+                let following = clusters.get(i + 1).map(|&c| new_cluster(c));
+                fat.insert(new_cluster(cluster), following);
+            }
+            if let (Some(entry), Some(&first)) = (self.directory.get_mut(name), clusters.first()) {
+                entry.start_cluster = new_cluster(first);
+            }
+        }
+        self.fat = fat;
+        self.free_clusters.clear();
+        self.next_free_cluster = next;
+
+        self.write_fat()?;
+        self.write_directory()?;
+
+        crate::serial_println!(
+            "ATA FS: Compaction complete, next free cluster {}",
+            self.next_free_cluster
+        );
+        Ok(relocated.len())
+    }
+
+    /// Checks the signature and returns the directory format byte.
+    fn load_superblock(&mut self) -> Result<u8, AtaError> {
         crate::serial_println!(
             "ATA FS: Reading superblock from LBA {}",
             self.superblock.start_lba
@@ -837,7 +1720,7 @@ impl AtaFileSystem {
         let signature = &buffer[0..6];
         if signature == b"ATA_FS" {
             crate::serial_println!("ATA FS: Found valid filesystem signature");
-            Ok(())
+            Ok(buffer[6])
         } else {
             crate::serial_println!("ATA FS: No valid filesystem signature found");
             Err(AtaError::DeviceNotFound)
@@ -847,6 +1730,7 @@ impl AtaFileSystem {
     fn write_superblock(&self) -> Result<(), AtaError> {
         let mut buffer = [0u8; 512];
         buffer[0..6].copy_from_slice(b"ATA_FS");
+        buffer[6] = DIRECTORY_FORMAT_TREE;
 
         write_sectors(
             self.controller,
@@ -856,20 +1740,306 @@ impl AtaFileSystem {
         )
     }
 
-    fn load_directory(&mut self) -> Result<(), AtaError> {
+    /// Rebuilds the path-keyed directory from the records. `write_directory`
+    /// puts every directory before its children, so a record whose parent
+    /// hasn't been seen yet is orphaned and dropped.
+    fn load_directory(&mut self, format: u8) -> Result<(), AtaError> {
+        let mut buffer = vec![0u8; DIRECTORY_SECTORS * 512];
+        read_sectors(
+            self.controller,
+            self.device,
+            self.superblock.start_lba + DIRECTORY_START_SECTOR,
+            DIRECTORY_SECTORS as u16,
+            &mut buffer,
+        )?;
+
+        self.directory.clear();
+        let mut paths: BTreeMap<u16, String> = BTreeMap::new();
+        for (index, record) in buffer.chunks_exact(DIR_RECORD_SIZE).enumerate() {
+            let flags = record[0];
+            if (flags & DIR_FLAG_USED) == 0 {
+                continue;
+            }
+
+            let name = if format == DIRECTORY_FORMAT_FLAT {
+                let name_len = (record[1] as usize).min(DIR_FLAT_NAME_MAX);
+                String::from_utf8_lossy(&record[2..2 + name_len]).into_owned()
+            } else {
+                let name_len = (record[1] as usize).min(DIR_NAME_MAX);
+                let component = String::from_utf8_lossy(&record[4..4 + name_len]);
+                let parent = u16::from_le_bytes([record[2], record[3]]);
+                let name = if parent == DIR_PARENT_ROOT {
+                    component.into_owned()
+                } else if let Some(parent_path) = paths.get(&parent) {
+                    format!("{}/{}", parent_path, component)
+                } else {
+                    crate::serial_println!(
+                        "ATA FS: Dropping '{}', parent record {} not found",
+                        component,
+                        parent
+                    );
+                    continue;
+                };
+                if (flags & DIR_FLAG_DIRECTORY) != 0 {
+                    paths.insert(index as u16 + 1, name.clone());
+                }
+                name
+            };
+            let start_cluster = u64::from_le_bytes(record[48..56].try_into().unwrap());
+            let size = u64::from_le_bytes(record[56..64].try_into().unwrap()) as usize;
+
+            self.directory.insert(
+                name.clone(),
+                DirEntry {
+                    name,
+                    start_cluster,
+                    size,
+                    is_directory: (flags & DIR_FLAG_DIRECTORY) != 0,
+                },
+            );
+        }
+
+        crate::serial_println!("ATA FS: Loaded {} directory entries", self.directory.len());
         Ok(())
     }
 
     fn write_directory(&self) -> Result<(), AtaError> {
-        Ok(())
+        let mut buffer = vec![0u8; DIRECTORY_SECTORS * 512];
+        if self.directory.len() > buffer.len() / DIR_RECORD_SIZE {
+            crate::serial_println!("ATA FS: Directory table full");
+            return Err(AtaError::BufferTooSmall);
+        }
+
+        // Keys sort each directory before everything under it, so a parent's
+        // id is always known by the time its children are written.
+        let mut ids: BTreeMap<&str, u16> = BTreeMap::new();
+        for (index, (entry, record)) in self
+            .directory
+            .values()
+            .zip(buffer.chunks_exact_mut(DIR_RECORD_SIZE))
+            .enumerate()
+        {
+            let (parent, name) = match entry.name.rsplit_once('/') {
+                Some((parent, name)) => (
+                    *ids.get(parent).ok_or(AtaError::CommandFailed)?,
+                    name.as_bytes(),
+                ),
+                None => (DIR_PARENT_ROOT, entry.name.as_bytes()),
+            };
+            if name.len() > DIR_NAME_MAX {
+                return Err(AtaError::BufferTooSmall);
+            }
+            if entry.is_directory {
+                ids.insert(&entry.name, index as u16 + 1);
+            }
+
+            record[0] = DIR_FLAG_USED
+                | if entry.is_directory {
+                    DIR_FLAG_DIRECTORY
+                } else {
+                    0
+                };
+            record[1] = name.len() as u8;
+            record[2..4].copy_from_slice(&parent.to_le_bytes());
+            record[4..4 + name.len()].copy_from_slice(name);
+            record[48..56].copy_from_slice(&entry.start_cluster.to_le_bytes());
+            record[56..64].copy_from_slice(&(entry.size as u64).to_le_bytes());
+        }
+
+        write_sectors(
+            self.controller,
+            self.device,
+            self.superblock.start_lba + DIRECTORY_START_SECTOR,
+            &buffer,
+        )
     }
 
     fn load_fat(&mut self) -> Result<(), AtaError> {
+        let mut buffer = vec![0u8; FAT_SECTORS * 512];
+        read_sectors(
+            self.controller,
+            self.device,
+            self.superblock.start_lba + FAT_START_SECTOR,
+            FAT_SECTORS as u16,
+            &mut buffer,
+        )?;
+
+        self.fat.clear();
+        for record in buffer.chunks_exact(FAT_RECORD_SIZE) {
+            let cluster = u64::from_le_bytes(record[0..8].try_into().unwrap());
+            if cluster == FAT_RECORD_UNUSED {
+                continue;
+            }
+            let next = match u64::from_le_bytes(record[8..16].try_into().unwrap()) {
+                FAT_END_OF_CHAIN => None,
+                next => Some(next),
+            };
+            self.fat.insert(cluster, next);
+        }
+
+        self.next_free_cluster = self
+            .fat
+            .keys()
+            .next_back()
+            .map_or(FIRST_DATA_CLUSTER, |&last| last + 1)
+            .max(FIRST_DATA_CLUSTER);
+        self.free_clusters = (FIRST_DATA_CLUSTER..self.next_free_cluster)
+            .filter(|cluster| !self.fat.contains_key(cluster))
+            .collect();
+
+        crate::serial_println!(
+            "ATA FS: Loaded {} FAT entries, next free cluster {}, {} freed",
+            self.fat.len(),
+            self.next_free_cluster,
+            self.free_clusters.len()
+        );
         Ok(())
     }
 
     fn write_fat(&self) -> Result<(), AtaError> {
-        Ok(())
+        let mut buffer = vec![0u8; FAT_SECTORS * 512];
+        if self.fat.len() > buffer.len() / FAT_RECORD_SIZE {
+            crate::serial_println!("ATA FS: FAT table full");
+            return Err(AtaError::BufferTooSmall);
+        }
+
+        for ((&cluster, &next), record) in self
+            .fat
+            .iter()
+            .zip(buffer.chunks_exact_mut(FAT_RECORD_SIZE))
+        {
+            record[0..8].copy_from_slice(&cluster.to_le_bytes());
+            record[8..16].copy_from_slice(&next.unwrap_or(FAT_END_OF_CHAIN).to_le_bytes());
+        }
+
+        write_sectors(
+            self.controller,
+            self.device,
+            self.superblock.start_lba + FAT_START_SECTOR,
+            &buffer,
+        )
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|p| !p.is_empty()).collect()
+}
+
+/// The directory key for `path`: its components joined by `/`, with no
+/// leading slash. Records store one component each, so only the components
+/// are limited in length, not the path.
+fn entry_path(path: &str) -> Result<String, AtaError> {
+    let components = split_path(path);
+    if components.is_empty() {
+        return Err(AtaError::CommandFailed);
+    }
+    if let Some(component) = components.iter().find(|c| c.len() > DIR_NAME_MAX) {
+        crate::serial_println!("ATA FS: Path component '{}' is too long", component);
+        return Err(AtaError::BufferTooSmall);
+    }
+    Ok(components.join("/"))
+}
+
+impl AtaFileSystem {
+    /// The entry at `path` for the `FileSystem` methods, which report a
+    /// missing or non-directory parent precisely where the ATA errors can't.
+    fn lookup(&self, path: &str) -> Result<Option<&DirEntry>, FsError> {
+        let path = split_path(path).join("/");
+        if path.is_empty() {
+            return Err(FsError::IsADirectory);
+        }
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            match self.directory.get(parent) {
+                Some(entry) if entry.is_directory => {}
+                Some(_) => return Err(FsError::NotADirectory),
+                None => return Err(FsError::NotFound),
+            }
+        }
+        Ok(self.directory.get(&path))
+    }
+}
+
+impl FileSystem for AtaFileSystem {
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        if self.lookup(path)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(AtaFileSystem::create_file(self, path, &[])?)
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        match self.lookup(path)? {
+            Some(entry) if entry.is_directory => Err(FsError::IsADirectory),
+            Some(_) => Ok(AtaFileSystem::read_file(self, path)?),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        if self.lookup(path)?.is_some_and(|entry| entry.is_directory) {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(AtaFileSystem::create_file(self, path, data)?)
+    }
+
+    fn delete_file(&mut self, path: &str) -> Result<(), FsError> {
+        match self.lookup(path)? {
+            Some(entry) if entry.is_directory => Err(FsError::IsADirectory),
+            Some(_) => Ok(AtaFileSystem::delete_file(self, path)?),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        if self.lookup(path)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(AtaFileSystem::create_dir(self, path)?)
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let entry = self.lookup(path)?.ok_or(FsError::NotFound)?;
+        if !entry.is_directory {
+            return Err(FsError::NotADirectory);
+        }
+        if self.children(&entry.name).next().is_some() {
+            return Err(FsError::NotEmpty);
+        }
+        Ok(AtaFileSystem::delete_file(self, path)?)
+    }
+
+    /// Clusters are handed out upwards from `next_free_cluster`, so what is
+    /// free is the space past it plus the freed clusters below it.
+    fn volume_stats(&mut self) -> Result<VolumeStats, FsError> {
+        let cluster_size = self.superblock.cluster_size() as u64;
+        let per_cluster = self.superblock.sectors_per_cluster as u64;
+        let data_clusters =
+            (self.superblock.total_sectors / per_cluster).saturating_sub(FIRST_DATA_CLUSTER);
+        let used = self.next_free_cluster - FIRST_DATA_CLUSTER;
+        let free = data_clusters.saturating_sub(used) + self.free_clusters.len() as u64;
+        Ok(VolumeStats {
+            total_bytes: data_clusters * cluster_size,
+            free_bytes: free * cluster_size,
+            cluster_size: cluster_size as u32,
+        })
+    }
+
+    fn list_dir(&mut self, path: &str) -> Result<Vec<VfsDirEntry>, FsError> {
+        if !split_path(path).is_empty() {
+            match self.lookup(path)? {
+                Some(entry) if entry.is_directory => {}
+                Some(_) => return Err(FsError::NotADirectory),
+                None => return Err(FsError::NotFound),
+            }
+        }
+        Ok(AtaFileSystem::list_dir(self, path)?
+            .into_iter()
+            .map(|(name, size, is_directory)| VfsDirEntry {
+                name,
+                size: size as u64,
+                is_directory,
+            })
+            .collect())
     }
 }
 
@@ -897,7 +2067,7 @@ pub fn init_global_filesystem() -> Result<(), AtaError> {
         filesystem_size
     );
 
-    let fs = AtaFileSystem::new(true, AtaDevice::Slave, start_lba, filesystem_size)?;
+    let fs = AtaFileSystem::new(true, AtaDevice::Slave, None, start_lba, filesystem_size)?;
     *GLOBAL_FS.lock() = Some(fs);
     crate::serial_println!("Global ATA filesystem initialized successfully");
     Ok(())
@@ -927,6 +2097,340 @@ pub fn fs_list_files() -> Result<Vec<(String, usize, bool)>, AtaError> {
     Ok(fs.list_files())
 }
 
+/// Compacts the global filesystem, failing with `NotReady` instead of
+/// waiting if a read or write holds it.
+pub fn fs_compact() -> Result<usize, AtaError> {
+    let mut fs_guard = GLOBAL_FS.try_lock().ok_or(AtaError::NotReady)?;
+    let fs = fs_guard.as_mut().ok_or(AtaError::DeviceNotFound)?;
+    fs.compact()
+}
+
+/// Creates, deletes and recreates files on the global filesystem and checks
+/// the freed clusters are handed out again, and that overwriting a file
+/// frees its old chain.
+pub fn test_cluster_reuse() -> Result<(), AtaError> {
+    crate::serial_println!("=== ATA FS Cluster Reuse Test ===");
+
+    let mut fs_guard = GLOBAL_FS.lock();
+    let fs = fs_guard.as_mut().ok_or(AtaError::DeviceNotFound)?;
+    let cluster_size = fs.superblock.cluster_size();
+    let first = vec![0xA5u8; cluster_size * 2];
+    let second = vec![0x5Au8; cluster_size * 2];
+
+    fs.create_file("reuse_a", &first)?;
+    let start = fs.directory["reuse_a"].start_cluster;
+    let high_water = fs.next_free_cluster;
+    fs.delete_file("reuse_a")?;
+
+    fs.create_file("reuse_b", &second)?;
+    if fs.directory["reuse_b"].start_cluster != start || fs.next_free_cluster != high_water {
+        crate::serial_println!("✗ Deleted clusters were not reused");
+        return Err(AtaError::CommandFailed);
+    }
+
+    // Overwriting takes fresh clusters first, then frees the old chain.
+    fs.create_file("reuse_b", &first[..cluster_size])?;
+    if AtaFileSystem::read_file(fs, "reuse_b")? != first[..cluster_size]
+        || fs.free_clusters.len() < 2
+    {
+        crate::serial_println!("✗ Overwrite did not replace the file and free its chain");
+        return Err(AtaError::CommandFailed);
+    }
+    fs.delete_file("reuse_b")?;
+
+    crate::serial_println!("✓ Freed clusters are reused");
+    Ok(())
+}
+
+/// Overwrites a file with longer and then shorter contents, checking each
+/// read returns the latest data and the chains it replaced are all freed.
+pub fn test_overwrite() -> Result<(), AtaError> {
+    crate::serial_println!("=== ATA FS Overwrite Test ===");
+
+    let mut fs_guard = GLOBAL_FS.lock();
+    let fs = fs_guard.as_mut().ok_or(AtaError::DeviceNotFound)?;
+    let cluster_size = fs.superblock.cluster_size();
+    let in_use = fs.fat.len();
+    let versions = [
+        vec![0x11u8; 100],
+        vec![0x22u8; cluster_size * 3 + 7],
+        vec![0x33u8; cluster_size / 2],
+        Vec::new(),
+    ];
+
+    for (i, data) in versions.iter().enumerate() {
+        fs.create_file("overwrite", data)?;
+        if AtaFileSystem::read_file(fs, "overwrite")? != *data {
+            crate::serial_println!("✗ Read after overwrite {} returned stale data", i);
+            return Err(AtaError::CommandFailed);
+        }
+        if fs.fat.len() != in_use + data.len().div_ceil(cluster_size) {
+            crate::serial_println!("✗ Overwrite {} left {} clusters in use", i, fs.fat.len());
+            return Err(AtaError::CommandFailed);
+        }
+    }
+    fs.delete_file("overwrite")?;
+
+    crate::serial_println!("✓ Overwrites replace contents and free old chains");
+    Ok(())
+}
+
+/// Builds a small tree on the global filesystem and checks nested files
+/// read back, listings only show direct children, and a directory with
+/// anything in it, or a missing parent, is refused.
+pub fn test_directories() -> Result<(), AtaError> {
+    crate::serial_println!("=== ATA FS Directory Test ===");
+
+    let mut fs_guard = GLOBAL_FS.lock();
+    let fs = fs_guard.as_mut().ok_or(AtaError::DeviceNotFound)?;
+
+    fs.create_dir("tdir")?;
+    fs.create_dir("/tdir/sub")?;
+    fs.create_file("tdir/top", b"top level")?;
+    fs.create_file("tdir/sub/deep", b"two levels down")?;
+
+    let listing = AtaFileSystem::list_dir(fs, "/tdir")?;
+    let expected = [("sub".to_string(), 0, true), ("top".to_string(), 9, false)];
+    if listing != expected {
+        crate::serial_println!("✗ Listing of tdir was {:?}", listing);
+        return Err(AtaError::CommandFailed);
+    }
+    if AtaFileSystem::list_dir(fs, "tdir/sub")? != [("deep".to_string(), 15, false)] {
+        crate::serial_println!("✗ Listing of tdir/sub is wrong");
+        return Err(AtaError::CommandFailed);
+    }
+    if AtaFileSystem::read_file(fs, "/tdir/sub/deep")? != b"two levels down" {
+        crate::serial_println!("✗ Nested file read back wrong");
+        return Err(AtaError::CommandFailed);
+    }
+    if fs.create_file("missing/file", b"x").is_ok() || fs.delete_file("tdir/sub").is_ok() {
+        crate::serial_println!("✗ Missing parent or non-empty directory accepted");
+        return Err(AtaError::CommandFailed);
+    }
+
+    fs.delete_file("tdir/sub/deep")?;
+    fs.delete_file("tdir/sub")?;
+    fs.delete_file("tdir/top")?;
+    fs.delete_file("tdir")?;
+    if AtaFileSystem::list_dir(fs, "")
+        .is_ok_and(|root| root.iter().any(|(name, _, _)| name == "tdir"))
+    {
+        crate::serial_println!("✗ tdir still listed after deleting it");
+        return Err(AtaError::CommandFailed);
+    }
+
+    crate::serial_println!("✓ Nested directories work");
+    Ok(())
+}
+
+/// Nests a file deeper than one record's name could hold as a single path
+/// and checks it reads back both before and after the directory is reloaded
+/// from disk, and that an over-long component is still refused.
+pub fn test_long_paths() -> Result<(), AtaError> {
+    crate::serial_println!("=== ATA FS Long Path Test ===");
+
+    let mut fs_guard = GLOBAL_FS.lock();
+    let fs = fs_guard.as_mut().ok_or(AtaError::DeviceNotFound)?;
+
+    let outer = "long_directory_name_number_one";
+    let inner = "long_directory_name_number_one/long_directory_name_number_two";
+    let file = "long_directory_name_number_one/long_directory_name_number_two/file.txt";
+    fs.create_dir(outer)?;
+    fs.create_dir(inner)?;
+    fs.create_file(file, b"deep and long")?;
+
+    if AtaFileSystem::read_file(fs, file)? != b"deep and long" {
+        crate::serial_println!("✗ {}-byte path read back wrong", file.len());
+        return Err(AtaError::CommandFailed);
+    }
+
+    fs.load_directory(DIRECTORY_FORMAT_TREE)?;
+    if AtaFileSystem::read_file(fs, file)? != b"deep and long" {
+        crate::serial_println!("✗ {}-byte path lost on reload", file.len());
+        return Err(AtaError::CommandFailed);
+    }
+    if AtaFileSystem::list_dir(fs, inner)? != [("file.txt".to_string(), 13, false)] {
+        crate::serial_println!("✗ Listing of the inner directory is wrong after reload");
+        return Err(AtaError::CommandFailed);
+    }
+
+    let component = "c".repeat(DIR_NAME_MAX + 1);
+    if fs
+        .create_file(&format!("{}/{}", outer, component), b"x")
+        .is_ok()
+    {
+        crate::serial_println!("✗ {}-byte component accepted", component.len());
+        return Err(AtaError::CommandFailed);
+    }
+
+    fs.delete_file(file)?;
+    fs.delete_file(inner)?;
+    fs.delete_file(outer)?;
+
+    crate::serial_println!("✓ {}-byte nested path survives a reload", file.len());
+    Ok(())
+}
+
+/// Churns files of mixed sizes on the global filesystem and checks
+/// `next_free_cluster` never passes what one round needs, then leaves holes
+/// and checks `compact` closes them without changing any file.
+pub fn test_compaction() -> Result<(), AtaError> {
+    const FILES: usize = 6;
+    crate::serial_println!("=== ATA FS Compaction Test ===");
+
+    let mut fs_guard = GLOBAL_FS.lock();
+    let fs = fs_guard.as_mut().ok_or(AtaError::DeviceNotFound)?;
+    let cluster_size = fs.superblock.cluster_size();
+    let contents = |i: usize| vec![i as u8; cluster_size * (i % 3 + 1) - i];
+    let round_clusters: u64 = (0..FILES).map(|i| (i % 3 + 1) as u64).sum();
+    let bound = fs.next_free_cluster + round_clusters;
+
+    for round in 0..8 {
+        for i in 0..FILES {
+            fs.create_file(&format!("compact_{}", i), &contents(i))?;
+        }
+        // Delete in a different order each round so the holes move about.
+        for i in (0..FILES).map(|i| (i + round) % FILES) {
+            fs.delete_file(&format!("compact_{}", i))?;
+        }
+        if fs.next_free_cluster > bound {
+            crate::serial_println!(
+                "✗ next_free_cluster reached {} (bound {}) in round {}",
+                fs.next_free_cluster,
+                bound,
+                round
+            );
+            return Err(AtaError::CommandFailed);
+        }
+    }
+
+    for i in 0..FILES {
+        fs.create_file(&format!("compact_{}", i), &contents(i))?;
+    }
+    for i in (0..FILES).step_by(2) {
+        fs.delete_file(&format!("compact_{}", i))?;
+    }
+    fs.compact()?;
+
+    let expected_end = FIRST_DATA_CLUSTER + fs.fat.len() as u64;
+    if !fs.free_clusters.is_empty() || fs.next_free_cluster != expected_end {
+        crate::serial_println!(
+            "✗ Compaction left {} holes, next free cluster {} (expected {})",
+            fs.free_clusters.len(),
+            fs.next_free_cluster,
+            expected_end
+        );
+        return Err(AtaError::CommandFailed);
+    }
+    for i in (1..FILES).step_by(2) {
+        let name = format!("compact_{}", i);
+        if AtaFileSystem::read_file(fs, &name)? != contents(i) {
+            crate::serial_println!("✗ {} changed during compaction", name);
+            return Err(AtaError::CommandFailed);
+        }
+        fs.delete_file(&name)?;
+    }
+
+    crate::serial_println!("✓ Cluster use stays bounded and compacts");
+    Ok(())
+}
+
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register("disks", "identify the primary ATA drives", cmd_disks);
+    shell.register(
+        "patch",
+        "overwrite bytes of a primary master sector: patch <lba> <offset> <hexbytes>",
+        cmd_patch,
+    );
+}
+
+fn cmd_disks(_shell: &crate::sshell::Shell, _args: &[&str]) {
+    for device in [AtaDevice::Master, AtaDevice::Slave] {
+        match identify_drive(true, device) {
+            Ok(info) => crate::println!("{:?}: {} ({} MB)", device, info.model, info.capacity_mb()),
+            Err(e) => crate::println!("{:?}: {:?}", device, e),
+        }
+    }
+    let stats = cache_stats(true);
+    crate::println!("sector cache: {} hits, {} misses", stats.hits, stats.misses);
+}
+
+/// Parses a decimal number, or hexadecimal with a `0x` prefix.
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Parses pairs of hex digits, e.g. `55AA`, into bytes.
+fn parse_hex_bytes(text: &str) -> Result<Vec<u8>, &'static str> {
+    if text.is_empty() {
+        return Err("no bytes given");
+    }
+    if !text.len().is_multiple_of(2) {
+        return Err("odd number of hex digits");
+    }
+    text.as_bytes()
+        .chunks_exact(2)
+        .map(|pair| {
+            core::str::from_utf8(pair)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or("invalid hex digit")
+        })
+        .collect()
+}
+
+/// Reads a sector of the primary master, overwrites the bytes from
+/// `offset` and writes it back once confirmed. Spaces between the hex
+/// bytes are allowed.
+fn cmd_patch(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let [lba, offset, hex @ ..] = args else {
+        crate::println!("usage: patch <lba> <offset> <hexbytes>");
+        return;
+    };
+    let (Some(lba), Some(offset)) = (parse_number(lba), parse_number(offset)) else {
+        crate::println!("patch: lba and offset must be numbers");
+        return;
+    };
+    let bytes = match parse_hex_bytes(&hex.concat()) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            crate::println!("patch: {}", e);
+            return;
+        }
+    };
+    let offset = offset as usize;
+    if offset >= 512 || bytes.len() > 512 - offset {
+        crate::println!(
+            "patch: {} bytes at offset {} run past the 512-byte sector",
+            bytes.len(),
+            offset
+        );
+        return;
+    }
+
+    let mut sector = [0u8; 512];
+    if let Err(e) = read_sectors(true, AtaDevice::Master, lba, 1, &mut sector) {
+        crate::println!("patch: reading LBA {} failed: {:?}", lba, e);
+        return;
+    }
+    let end = offset + bytes.len();
+    crate::println!("LBA {} bytes {}..{}:", lba, offset, end);
+    crate::println!("  old: {:02X?}", &sector[offset..end]);
+    crate::println!("  new: {:02X?}", bytes);
+    if !crate::sshell::confirm("Write the sector?") {
+        crate::println!("patch: aborted");
+        return;
+    }
+    sector[offset..end].copy_from_slice(&bytes);
+    match write_sectors_verified(true, AtaDevice::Master, lba, &sector) {
+        Ok(()) => crate::println!("patch: LBA {} written", lba),
+        Err(e) => crate::println!("patch: writing LBA {} failed: {:?}", lba, e),
+    }
+}
+
 pub fn test_ata_driver_comprehensive() {
     crate::serial_println!("=== COMPREHENSIVE ATA DRIVER TEST START ===");
 
@@ -960,6 +2464,11 @@ pub fn test_ata_driver_comprehensive() {
 
                 if info.sectors > 0 {
                     test_read_sectors(name, *use_primary, *device);
+                    match test_write_round_trip(*use_primary, *device, info.sectors) {
+                        Ok(true) => crate::serial_println!("✓ {} write round-trip verified", name),
+                        Ok(false) => {}
+                        Err(e) => crate::serial_println!("✗ {} write round-trip: {}", name, e),
+                    }
                 }
             }
             Err(e) => {
@@ -972,6 +2481,210 @@ pub fn test_ata_driver_comprehensive() {
     crate::serial_println!("=== COMPREHENSIVE ATA DRIVER TEST COMPLETE ===");
 }
 
+/// Identifies the boot disk, the primary master, which is always there,
+/// and reads its first sector.
+pub fn test_identify_boot_disk() -> Result<(), AtaError> {
+    let info = identify_drive(true, AtaDevice::Master)?;
+    if info.sectors == 0 {
+        return Err(AtaError::DeviceNotFound);
+    }
+    if info.sector_size != 512 {
+        return Err(AtaError::InvalidSectorSize);
+    }
+    let mut sector = [0u8; 512];
+    read_sectors(true, AtaDevice::Master, 0, 1, &mut sector)
+}
+
+/// Reads the start of the boot disk through the IRQ path, checks it
+/// against a polled read and that the channel is free for polled reads
+/// again afterwards.
+pub fn test_read_sectors_async() -> Result<(), AtaError> {
+    const COUNT: u16 = 8;
+    crate::serial_println!("=== ATA IRQ Read Test ===");
+
+    if !x86_64::instructions::interrupts::are_enabled() {
+        crate::serial_println!("✗ Interrupts are off, so the IRQ path can't be tested");
+        return Err(AtaError::UnsupportedOperation);
+    }
+    let mut polled = [0u8; COUNT as usize * 512];
+    let mut interrupted = [0u8; COUNT as usize * 512];
+    read_sectors(true, AtaDevice::Master, 0, COUNT, &mut polled)?;
+    crate::task::simple_executor::block_on(read_sectors_async(
+        true,
+        AtaDevice::Master,
+        0,
+        COUNT,
+        &mut interrupted,
+    ))?;
+    if polled != interrupted {
+        crate::serial_println!("✗ IRQ read differs from the polled read");
+        return Err(AtaError::CommandFailed);
+    }
+    if PRIMARY_IRQ.busy.load(Ordering::Acquire) {
+        crate::serial_println!("✗ IRQ read left the channel claimed");
+        return Err(AtaError::NotReady);
+    }
+    read_sectors(true, AtaDevice::Master, COUNT as u64, 1, &mut polled)?;
+
+    crate::serial_println!("✓ Read {} sectors through the IRQ path", COUNT);
+    Ok(())
+}
+
+/// Checks how `plan_commands` splits 1000 sectors: below the LBA28 limit
+/// into 256-sector LBA28 commands, and across it into LBA28 commands for
+/// the part below and LBA48 commands for the rest, with and without LBA48's
+/// larger count. No drive is involved.
+pub fn test_command_plan() -> Result<(), &'static str> {
+    const COUNT: usize = 1000;
+    crate::serial_println!("=== ATA Command Plan Test ===");
+
+    let command = |lba, count, lba48| Command { lba, count, lba48 };
+    let low = LBA28_LIMIT - 100;
+    let cases: [(u64, usize, Vec<Command>); 3] = [
+        (
+            0,
+            LBA28_MAX_SECTORS,
+            vec![
+                command(0, 256, false),
+                command(256, 256, false),
+                command(512, 256, false),
+                command(768, 232, false),
+            ],
+        ),
+        (
+            low,
+            LBA28_MAX_SECTORS,
+            vec![
+                command(low, 100, false),
+                command(LBA28_LIMIT, 256, true),
+                command(LBA28_LIMIT + 256, 256, true),
+                command(LBA28_LIMIT + 512, 256, true),
+                command(LBA28_LIMIT + 768, 132, true),
+            ],
+        ),
+        (
+            low,
+            u16::MAX as usize,
+            vec![command(low, 100, false), command(LBA28_LIMIT, 900, true)],
+        ),
+    ];
+    for (lba, max, expected) in cases {
+        let plan: Vec<Command> = plan_commands(lba, COUNT, max).collect();
+        if plan != expected {
+            crate::serial_println!("✗ {} sectors at {:#x}: {:?}", COUNT, lba, plan);
+            return Err("transfer split into the wrong commands");
+        }
+        if plan.last().map(|c| c.byte_range(lba).end) != Some(COUNT * 512) {
+            return Err("commands do not cover the whole buffer");
+        }
+    }
+
+    crate::serial_println!("✓ LBA28 below the limit, LBA48 from it on");
+    Ok(())
+}
+
+/// Reads 1000 sectors from the primary slave in one call through the LBA28
+/// path, which has to split it into 256-sector commands, and checks the
+/// result against a single LBA48 command for the same range. Returns
+/// `Ok(false)` without reading if the drive is too small or lacks LBA48,
+/// so a skip can't pass for a success.
+pub fn test_large_read() -> Result<bool, AtaError> {
+    const COUNT: u16 = 1000;
+    crate::serial_println!("=== ATA Large Read Test ===");
+
+    let info = identify_drive(true, AtaDevice::Slave)?;
+    if info.sectors < COUNT as u64 || !info.supports_lba48 {
+        crate::serial_println!("- Primary slave too small or without LBA48; skipped");
+        return Ok(false);
+    }
+
+    let len = COUNT as usize * 512;
+    let mut chunked = vec![0u8; len];
+    let mut single = vec![0u8; len];
+    {
+        let mut controller = PRIMARY_ATA.lock();
+        let device = AtaDevice::Slave as usize;
+        // Pretend the drive lacks LBA48 so the read is split up.
+        controller.supports_lba48[device] = false;
+        let result = controller.read_sectors_uncached(AtaDevice::Slave, 0, COUNT, &mut chunked);
+        controller.supports_lba48[device] = true;
+        result?;
+        controller.read_sectors_lba48(AtaDevice::Slave, 0, COUNT, &mut single)?;
+    }
+
+    if chunked != single {
+        crate::serial_println!("✗ Chunked LBA28 read differs from the LBA48 read");
+        return Err(AtaError::CommandFailed);
+    }
+    crate::serial_println!("✓ Read {} sectors in 256-sector chunks", COUNT);
+    Ok(true)
+}
+
+/// Size of the region `test_write_round_trip` borrows.
+const SCRATCH_SECTORS: u64 = 8;
+
+/// Picks a scratch region near the end of the disk for the write test, or
+/// `None` if there is no place that is provably unused. Only disks with an
+/// MBR qualify, so the boot image and unpartitioned disks are never touched;
+/// the region must also miss LBA 0, every partition and the ATA filesystem.
+fn scratch_region(primary: bool, device: AtaDevice, total_sectors: u64) -> Option<u64> {
+    let mut sector = [0u8; 512];
+    read_sectors(primary, device, 0, 1, &mut sector).ok()?;
+    if sector[510] != 0x55 || sector[511] != 0xAA {
+        return None;
+    }
+
+    // Keep clear of the very last sector, which some images round off.
+    let start = total_sectors.checked_sub(SCRATCH_SECTORS + 1)?;
+    let end = start + SCRATCH_SECTORS;
+    let overlaps = |first: u64, count: u64| start < first + count && first < end;
+
+    if start == 0 {
+        return None;
+    }
+    let partitions = read_partition_table(primary, device).ok()?;
+    if partitions
+        .iter()
+        .flatten()
+        .any(|p| overlaps(p.start_lba, p.sector_count))
+    {
+        return None;
+    }
+    if let Some(fs) = GLOBAL_FS.lock().as_ref() {
+        let same_disk = fs.controller == primary && fs.device == device;
+        if same_disk && overlaps(fs.superblock.start_lba, fs.superblock.total_sectors) {
+            return None;
+        }
+    }
+    Some(start)
+}
+
+/// Writes a pattern to the scratch region with verification, then puts the
+/// original contents back (also verified). Returns `Ok(false)` when the disk
+/// has no safe scratch region.
+fn test_write_round_trip(
+    primary: bool,
+    device: AtaDevice,
+    total_sectors: u64,
+) -> Result<bool, AtaError> {
+    let Some(lba) = scratch_region(primary, device, total_sectors) else {
+        crate::serial_println!("No safe scratch region; skipping write round-trip");
+        return Ok(false);
+    };
+    let len = SCRATCH_SECTORS as usize * 512;
+
+    let mut original = vec![0u8; len];
+    read_sectors(primary, device, lba, SCRATCH_SECTORS as u16, &mut original)?;
+
+    let pattern: Vec<u8> = (0..len).map(|i| (i * 7 + (i >> 9)) as u8 ^ 0x5A).collect();
+    let written = write_sectors_verified(primary, device, lba, &pattern);
+    // Restore even if the verify failed, so the test never leaves a trace.
+    let restored = write_sectors_verified(primary, device, lba, &original);
+    written?;
+    restored?;
+    Ok(true)
+}
+
 fn test_read_sectors(name: &str, primary: bool, device: AtaDevice) {
     let mut buffer = [0u8; 512];
 
@@ -981,30 +2694,26 @@ fn test_read_sectors(name: &str, primary: bool, device: AtaDevice) {
 
             if buffer[510] == 0x55 && buffer[511] == 0xAA {
                 crate::serial_println!("Valid MBR signature found");
+                if let Ok(partitions) = read_partition_table(primary, device) {
+                    for (i, entry) in partitions.iter().enumerate() {
+                        if let Some(entry) = entry {
+                            crate::serial_println!(
+                                "  Partition {}: type 0x{:02X}, start LBA {}, {} sectors{}",
+                                i,
+                                entry.partition_type,
+                                entry.start_lba,
+                                entry.sector_count,
+                                if entry.bootable { " (bootable)" } else { "" }
+                            );
+                        }
+                    }
+                }
             } else {
                 crate::serial_println!("MBR: Invalid or missing signature");
             }
 
-            crate::serial_print!("First 32 bytes of sector 0: ");
-            for i in 0..32 {
-                if i % 16 == 0 {
-                    crate::serial_println!();
-                    crate::serial_print!("{:04X}: ", i);
-                }
-                crate::serial_print!("{:02X} ", buffer[i]);
-            }
-            crate::serial_println!();
-
-            crate::serial_print!("As ASCII: ");
-            for i in 0..32 {
-                let c = buffer[i];
-                if c >= 0x20 && c <= 0x7E {
-                    crate::serial_print!("{}", c as char);
-                } else {
-                    crate::serial_print!(".");
-                }
-            }
-            crate::serial_println!();
+            crate::serial_println!("First 32 bytes of sector 0:");
+            crate::util::serial_hexdump(&buffer[..32], 0);
         }
         Err(e) => {
             crate::serial_println!("Error reading sector from {}: {:?}", name, e);
@@ -1019,22 +2728,7 @@ pub fn test_disk_identification() -> Result<(), AtaError> {
     read_sectors(true, AtaDevice::Slave, 0, 1, &mut sector_0)?;
 
     crate::serial_println!("First 64 bytes of sector 0 (Primary Slave):");
-    for i in (0..64).step_by(16) {
-        crate::serial_print!("{:04X}: ", i);
-        for j in 0..16 {
-            crate::serial_print!("{:02X} ", sector_0[i + j]);
-        }
-        crate::serial_print!(" ");
-        for j in 0..16 {
-            let c = sector_0[i + j];
-            if c >= 0x20 && c <= 0x7E {
-                crate::serial_print!("{}", c as char);
-            } else {
-                crate::serial_print!(".");
-            }
-        }
-        crate::serial_println!();
-    }
+    crate::util::serial_hexdump(&sector_0[..64], 0);
 
     if sector_0[510] == 0x55 && sector_0[511] == 0xAA {
         crate::serial_println!("Found MBR signature - this looks like a boot disk");
@@ -0,0 +1,332 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+use crate::drivers::pci::VirtioGpu;
+use crate::drivers::vga_buffer::{AnsiAction, AnsiParser};
+
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 16;
+
+const DEFAULT_FG: u32 = 0xFFAA_AAAA;
+const DEFAULT_BG: u32 = 0xFF00_0000;
+
+/// Printable ASCII (0x20..=0x7E) from the public-domain font8x8 set, one
+/// byte per row with bit 0 as the leftmost pixel. Each row is drawn twice
+/// to fill an 8x16 cell.
+const FONT_8X8: [[u8; 8]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00], // '#'
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00], // '%'
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // "'"
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00], // '('
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // '0'
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // '1'
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // '2'
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // '3'
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // '4'
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // '5'
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // '6'
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // '7'
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // '8'
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ';'
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00], // '='
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00], // '>'
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00], // '?'
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00], // '@'
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00], // 'A'
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00], // 'B'
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00], // 'C'
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00], // 'D'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00], // 'E'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00], // 'F'
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00], // 'L'
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00], // 'O'
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00], // 'P'
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00], // 'Q'
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00], // 'S'
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00], // 'Y'
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00], // 'Z'
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00], // '['
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00], // '\\'
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00], // ']'
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF], // '_'
+    [0x0C, 0x0C, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x1E, 0x30, 0x3E, 0x33, 0x6E, 0x00], // 'a'
+    [0x07, 0x06, 0x06, 0x3E, 0x66, 0x66, 0x3B, 0x00], // 'b'
+    [0x00, 0x00, 0x1E, 0x33, 0x03, 0x33, 0x1E, 0x00], // 'c'
+    [0x38, 0x30, 0x30, 0x3E, 0x33, 0x33, 0x6E, 0x00], // 'd'
+    [0x00, 0x00, 0x1E, 0x33, 0x3F, 0x03, 0x1E, 0x00], // 'e'
+    [0x1C, 0x36, 0x06, 0x0F, 0x06, 0x06, 0x0F, 0x00], // 'f'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'g'
+    [0x07, 0x06, 0x36, 0x6E, 0x66, 0x66, 0x67, 0x00], // 'h'
+    [0x0C, 0x00, 0x0E, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'i'
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E], // 'j'
+    [0x07, 0x06, 0x66, 0x36, 0x1E, 0x36, 0x67, 0x00], // 'k'
+    [0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'l'
+    [0x00, 0x00, 0x33, 0x7F, 0x7F, 0x6B, 0x63, 0x00], // 'm'
+    [0x00, 0x00, 0x1F, 0x33, 0x33, 0x33, 0x33, 0x00], // 'n'
+    [0x00, 0x00, 0x1E, 0x33, 0x33, 0x33, 0x1E, 0x00], // 'o'
+    [0x00, 0x00, 0x3B, 0x66, 0x66, 0x3E, 0x06, 0x0F], // 'p'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x78], // 'q'
+    [0x00, 0x00, 0x3B, 0x6E, 0x66, 0x06, 0x0F, 0x00], // 'r'
+    [0x00, 0x00, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x00], // 's'
+    [0x08, 0x0C, 0x3E, 0x0C, 0x0C, 0x2C, 0x18, 0x00], // 't'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6E, 0x00], // 'u'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'v'
+    [0x00, 0x00, 0x63, 0x6B, 0x7F, 0x7F, 0x36, 0x00], // 'w'
+    [0x00, 0x00, 0x63, 0x36, 0x1C, 0x36, 0x63, 0x00], // 'x'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'y'
+    [0x00, 0x00, 0x3F, 0x19, 0x0C, 0x26, 0x3F, 0x00], // 'z'
+    [0x38, 0x0C, 0x0C, 0x07, 0x0C, 0x0C, 0x38, 0x00], // '{'
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // '|'
+    [0x07, 0x0C, 0x0C, 0x38, 0x0C, 0x0C, 0x07, 0x00], // '}'
+    [0x6E, 0x3B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConsoleTarget {
+    Vga = 0,
+    Framebuffer = 1,
+}
+
+static CONSOLE_TARGET: AtomicU8 = AtomicU8::new(ConsoleTarget::Vga as u8);
+
+pub static FB_CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+pub fn console_target() -> ConsoleTarget {
+    match CONSOLE_TARGET.load(Ordering::Relaxed) {
+        1 => ConsoleTarget::Framebuffer,
+        _ => ConsoleTarget::Vga,
+    }
+}
+
+/// Chooses where `print!`/`println!` output goes. Switching to the
+/// framebuffer fails until `install_framebuffer_console` has run.
+pub fn set_console_target(target: ConsoleTarget) -> Result<(), &'static str> {
+    if target == ConsoleTarget::Framebuffer && FB_CONSOLE.lock().is_none() {
+        return Err("No framebuffer console installed");
+    }
+    CONSOLE_TARGET.store(target as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Hands an initialized GPU over to the framebuffer console.
+pub fn install_framebuffer_console(gpu: VirtioGpu) -> Result<(), &'static str> {
+    let console = FramebufferConsole::new(gpu)?;
+    *FB_CONSOLE.lock() = Some(console);
+    Ok(())
+}
+
+pub struct FramebufferConsole {
+    gpu: VirtioGpu,
+    framebuffer: *mut u32,
+    width: usize,
+    height: usize,
+    cols: usize,
+    rows: usize,
+    col: usize,
+    row: usize,
+    fg: u32,
+    bg: u32,
+    /// Pixel rectangle touched since the last flush, as
+    /// `(x0, y0, x1, y1)` with exclusive upper bounds.
+    dirty: Option<(usize, usize, usize, usize)>,
+    ansi: AnsiParser,
+}
+
+unsafe impl Send for FramebufferConsole {}
+
+impl FramebufferConsole {
+    pub fn new(gpu: VirtioGpu) -> Result<Self, &'static str> {
+        let (framebuffer, width, height) = gpu.get_framebuffer();
+        if framebuffer.is_null() {
+            return Err("GPU framebuffer not set up");
+        }
+        let (width, height) = (width as usize, height as usize);
+
+        let mut console = FramebufferConsole {
+            gpu,
+            framebuffer,
+            width,
+            height,
+            cols: width / GLYPH_WIDTH,
+            rows: height / GLYPH_HEIGHT,
+            col: 0,
+            row: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            dirty: None,
+            ansi: AnsiParser::new(),
+        };
+        console.clear();
+        Ok(console)
+    }
+
+    /// The GPU behind the console, for things like the hardware cursor.
+    pub fn gpu(&mut self) -> &mut VirtioGpu {
+        &mut self.gpu
+    }
+
+    /// The text colors as 0xAARRGGBB pixels, foreground first.
+    pub fn colors(&self) -> (u32, u32) {
+        (self.fg, self.bg)
+    }
+
+    /// Sets the text colors as 0xAARRGGBB pixels.
+    pub fn set_colors(&mut self, fg: u32, bg: u32) {
+        self.fg = fg;
+        self.bg = bg;
+    }
+
+    pub fn clear(&mut self) {
+        let pixels = self.width * self.height;
+        for i in 0..pixels {
+            unsafe { self.framebuffer.add(i).write_volatile(self.bg) };
+        }
+        self.col = 0;
+        self.row = 0;
+        self.mark_dirty(0, 0, self.width, self.height);
+        self.flush();
+    }
+
+    fn mark_dirty(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((a0, b0, a1, b1)) => (a0.min(x0), b0.min(y0), a1.max(x1), b1.max(y1)),
+            None => (x0, y0, x1, y1),
+        });
+    }
+
+    fn draw_glyph(&mut self, col: usize, row: usize, byte: u8) {
+        let glyph = match byte {
+            0x20..=0x7E => &FONT_8X8[(byte - 0x20) as usize],
+            _ => &FONT_8X8[(b'?' - 0x20) as usize],
+        };
+        let x0 = col * GLYPH_WIDTH;
+        let y0 = row * GLYPH_HEIGHT;
+
+        for y in 0..GLYPH_HEIGHT {
+            let bits = glyph[y / 2];
+            let line = unsafe { self.framebuffer.add((y0 + y) * self.width + x0) };
+            for x in 0..GLYPH_WIDTH {
+                let color = if bits & (1 << x) != 0 {
+                    self.fg
+                } else {
+                    self.bg
+                };
+                unsafe { line.add(x).write_volatile(color) };
+            }
+        }
+        self.mark_dirty(x0, y0, x0 + GLYPH_WIDTH, y0 + GLYPH_HEIGHT);
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        let byte = match self.ansi.feed(byte) {
+            AnsiAction::Byte(byte) => byte,
+            AnsiAction::None => return,
+            AnsiAction::ClearScreen => return self.clear(),
+            AnsiAction::Home => {
+                self.col = 0;
+                self.row = 0;
+                return;
+            }
+        };
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.col = 0,
+            0x08 => {
+                if self.col > 0 {
+                    self.col -= 1;
+                    self.draw_glyph(self.col, self.row, b' ');
+                }
+            }
+            byte => {
+                if self.col >= self.cols {
+                    self.new_line();
+                }
+                self.draw_glyph(self.col, self.row, byte);
+                self.col += 1;
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+            return;
+        }
+
+        let row_pixels = GLYPH_HEIGHT * self.width;
+        let text_pixels = self.rows * row_pixels;
+        unsafe {
+            core::ptr::copy(
+                self.framebuffer.add(row_pixels),
+                self.framebuffer,
+                text_pixels - row_pixels,
+            );
+            for i in text_pixels - row_pixels..text_pixels {
+                self.framebuffer.add(i).write_volatile(self.bg);
+            }
+        }
+        self.mark_dirty(0, 0, self.width, text_pixels / self.width);
+    }
+
+    /// Width, height and the 0xAARRGGBB pixels currently on screen.
+    pub fn pixels(&self) -> (usize, usize, &[u32]) {
+        let pixels =
+            unsafe { core::slice::from_raw_parts(self.framebuffer, self.width * self.height) };
+        (self.width, self.height, pixels)
+    }
+
+    /// Pushes the dirty rectangle to the display.
+    pub fn flush(&mut self) {
+        let Some((x0, y0, x1, y1)) = self.dirty.take() else {
+            return;
+        };
+        let (x, y) = (x0 as u32, y0 as u32);
+        let (w, h) = ((x1 - x0) as u32, (y1 - y0) as u32);
+        if let Err(e) = self.gpu.flush_rect(x, y, w, h) {
+            crate::serial_println!("fb console: flush failed: {}", e);
+        }
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte);
+        }
+        self.flush();
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use core::str::FromStr;
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Level the logger starts at. The scheduler traces every switch, so
+/// `trace` is too chatty to leave on.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Sends `log` records to COM1, one line each, tagged with the level and
+/// the module they came from.
+struct SerialLogger;
+
+static LOGGER: SerialLogger = SerialLogger;
+
+impl Log for SerialLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        crate::serial_println!(
+            "[{:<5} {}] {}",
+            record.level(),
+            record.module_path().unwrap_or("?"),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the serial logger at `DEFAULT_LEVEL`. Fails if a logger is
+/// already installed.
+pub fn init() -> Result<(), &'static str> {
+    log::set_logger(&LOGGER).map_err(|_| "a logger is already installed")?;
+    log::set_max_level(DEFAULT_LEVEL);
+    Ok(())
+}
+
+/// Changes which records get through from now on; `Off` silences them all.
+pub fn set_log_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+pub fn log_level() -> LevelFilter {
+    log::max_level()
+}
+
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register(
+        "loglevel",
+        "show or set the log level (off to trace)",
+        cmd_loglevel,
+    );
+}
+
+fn cmd_loglevel(_shell: &crate::sshell::Shell, args: &[&str]) {
+    match args.first() {
+        None => crate::println!("log level: {}", log_level()),
+        Some(arg) => match LevelFilter::from_str(arg) {
+            Ok(level) => {
+                set_log_level(level);
+                crate::println!("log level set to {}", level);
+            }
+            Err(_) => crate::println!("loglevel: unknown level '{}'", arg),
+        },
+    }
+}
@@ -1,5 +1,9 @@
 pub mod ata;
+pub mod fb_console;
+pub mod logger;
+pub mod mouse;
 pub mod pci;
+pub mod rtc;
 pub mod serial;
 pub mod sshell;
 pub mod vga_buffer;
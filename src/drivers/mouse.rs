@@ -0,0 +1,235 @@
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use x86_64::instructions::port::Port;
+
+use crate::drivers::fb_console::FB_CONSOLE;
+use crate::println;
+
+const PS2_DATA: u16 = 0x60;
+const PS2_STATUS: u16 = 0x64;
+const PS2_COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const CMD_ENABLE_AUX: u8 = 0xA8;
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_WRITE_AUX: u8 = 0xD4;
+
+const CONFIG_AUX_IRQ: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xF6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xF4;
+const MOUSE_ACK: u8 = 0xFA;
+
+const CONTROLLER_TIMEOUT: usize = 100_000;
+const MOUSE_IRQ: u8 = 12;
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// One decoded movement packet. `dy` is positive upwards, as the mouse
+/// reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: MouseButtons,
+}
+
+fn wait_write() -> Result<(), &'static str> {
+    let mut status: Port<u8> = Port::new(PS2_STATUS);
+    for _ in 0..CONTROLLER_TIMEOUT {
+        if unsafe { status.read() } & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("PS/2 controller input buffer stuck full")
+}
+
+fn wait_read() -> Result<(), &'static str> {
+    let mut status: Port<u8> = Port::new(PS2_STATUS);
+    for _ in 0..CONTROLLER_TIMEOUT {
+        if unsafe { status.read() } & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("PS/2 controller did not respond")
+}
+
+fn controller_command(cmd: u8) -> Result<(), &'static str> {
+    wait_write()?;
+    unsafe { Port::<u8>::new(PS2_COMMAND).write(cmd) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), &'static str> {
+    wait_write()?;
+    unsafe { Port::<u8>::new(PS2_DATA).write(byte) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, &'static str> {
+    wait_read()?;
+    Ok(unsafe { Port::<u8>::new(PS2_DATA).read() })
+}
+
+fn mouse_command(cmd: u8) -> Result<(), &'static str> {
+    controller_command(CMD_WRITE_AUX)?;
+    write_data(cmd)?;
+    match read_data()? {
+        MOUSE_ACK => Ok(()),
+        _ => Err("Mouse did not acknowledge command"),
+    }
+}
+
+/// Enables the PS/2 aux port with IRQ 12, resets the mouse to its defaults
+/// and turns on data reporting.
+pub fn init() -> Result<(), &'static str> {
+    crate::interrupt::no_interrupt(|| {
+        controller_command(CMD_ENABLE_AUX)?;
+
+        controller_command(CMD_READ_CONFIG)?;
+        let config = read_data()?;
+        let config = (config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED;
+        controller_command(CMD_WRITE_CONFIG)?;
+        write_data(config)?;
+
+        mouse_command(MOUSE_SET_DEFAULTS)?;
+        mouse_command(MOUSE_ENABLE_REPORTING)
+    })?;
+    crate::interrupts::register_irq(MOUSE_IRQ, handle_irq)?;
+
+    crate::serial_println!("PS/2 mouse enabled");
+    Ok(())
+}
+
+/// IRQ 12 handler: queues the byte waiting in port 0x60.
+fn handle_irq() {
+    let byte = unsafe { Port::<u8>::new(PS2_DATA).read() };
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// Stream of decoded mouse packets. Bytes arriving before the stream is
+/// created are dropped.
+pub struct MouseStream {
+    packet: [u8; 3],
+    len: usize,
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(256))
+            .expect("MouseStream::new should only be called once");
+        MouseStream {
+            packet: [0; 3],
+            len: 0,
+        }
+    }
+
+    /// Adds a byte to the current packet and returns the event once all
+    /// three bytes are in.
+    fn push(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Bit 3 is always set in the first byte; anything else means we
+        // joined mid-packet, so wait for the next one.
+        if self.len == 0 && byte & 0x08 == 0 {
+            return None;
+        }
+        self.packet[self.len] = byte;
+        self.len += 1;
+        if self.len < 3 {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y] = self.packet;
+        // Overflowed packets carry garbage deltas.
+        if flags & 0xC0 != 0 {
+            return None;
+        }
+        let dx = x as i16 - (((flags as i16) << 4) & 0x100);
+        let dy = y as i16 - (((flags as i16) << 3) & 0x100);
+        Some(MouseEvent {
+            dx,
+            dy,
+            buttons: MouseButtons {
+                left: flags & 0x01 != 0,
+                right: flags & 0x02 != 0,
+                middle: flags & 0x04 != 0,
+            },
+        })
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>> {
+        let queue = BYTE_QUEUE.try_get().expect("mouse queue not initialized");
+
+        loop {
+            while let Some(byte) = queue.pop() {
+                if let Some(event) = self.push(byte) {
+                    return Poll::Ready(Some(event));
+                }
+            }
+
+            WAKER.register(&cx.waker());
+            if queue.is_empty() {
+                return Poll::Pending;
+            }
+            WAKER.take();
+        }
+    }
+}
+
+/// Moves the VirtIO-GPU hardware cursor with the mouse, if the framebuffer
+/// console is up.
+pub async fn track_cursor() {
+    let mut events = MouseStream::new();
+    let (mut x, mut y) = (0i32, 0i32);
+
+    while let Some(event) = events.next().await {
+        let mut console = FB_CONSOLE.lock();
+        let Some(gpu) = console.as_mut().map(|c| c.gpu()) else {
+            continue;
+        };
+        let (_, width, height) = gpu.get_framebuffer();
+        x = (x + event.dx as i32).clamp(0, width as i32 - 1);
+        y = (y - event.dy as i32).clamp(0, height as i32 - 1);
+        if let Err(e) = gpu.move_cursor(x as u32, y as u32) {
+            crate::serial_println!("mouse: move_cursor failed: {}", e);
+        }
+    }
+}
+
+pub async fn print_mouse_events() {
+    let mut events = MouseStream::new();
+    while let Some(event) = events.next().await {
+        println!("mouse: dx={} dy={} {:?}", event.dx, event.dy, event.buttons);
+    }
+}
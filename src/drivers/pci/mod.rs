@@ -1,10 +1,39 @@
 use crate::serial_println;
 use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
 use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{
+    FrameAllocator, OffsetPageTable, PageSize, PageTableFlags, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
 
 pub mod virtio_gpu;
+pub mod virtio_net;
 pub use virtio_gpu::*;
 
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_VENDOR: u8 = 0x09;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_COMMAND_INTX_DISABLE: u32 = 1 << 10;
+
+const MSIX_CONTROL_ENABLE: u32 = 1 << 15;
+const MSIX_CONTROL_FUNCTION_MASK: u32 = 1 << 14;
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_CTRL_MASKED: u32 = 1;
+
+/// Base of the local APIC's MSI message address window.
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/// Result of the first bus scan. Devices don't come and go at runtime, so
+/// lookups share it instead of probing every bus again.
+static PCI_DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+/// Virtual window device BARs are mapped into, at this base plus their
+/// physical address.
+const MMIO_BASE: u64 = 0xFFFF_8000_0000_0000;
+
 #[derive(Debug, Clone, Copy)]
 pub struct PciDevice {
     pub bus: u8,
@@ -93,26 +122,18 @@ impl PciDevice {
         })
     }
 
-    pub fn enable(&self) {
-        let addr = (1 << 31)                // enable bit
-            | ((self.bus as u32) << 16)
-            | ((self.slot as u32) << 11)
-            | ((self.func as u32) << 8)
-            | 0x04; // command register offset
-
-        // Read current command register
-        let mut port = Port::<u32>::new(0xCF8);
-        unsafe { port.write(addr) };
-        let mut data_port = Port::<u32>::new(0xCFC);
-        let mut command: u32 = unsafe { data_port.read() };
+    pub fn read_config(&self, offset: u8) -> u32 {
+        pci_read_config(self.bus, self.slot, self.func, offset)
+    }
 
-        // Enable I/O space, memory space, bus mastering
-        command |= 1 | (1 << 1) | (1 << 2);
+    pub fn write_config(&self, offset: u8, value: u32) {
+        pci_write_config(self.bus, self.slot, self.func, offset, value)
+    }
 
-        unsafe {
-            port.write(addr);
-            data_port.write(command);
-        }
+    pub fn enable(&self) {
+        // Enable I/O space, memory space, bus mastering
+        let command = self.read_config(0x04) | 1 | (1 << 1) | (1 << 2);
+        self.write_config(0x04, command);
 
         serial_println!(
             "PCI device {}:{}:{} enabled with command 0x{:04X}",
@@ -123,6 +144,102 @@ impl PciDevice {
         );
     }
 
+    /// Programs the interrupt line register (0x3C) that tells drivers which
+    /// legacy IRQ the device is wired to.
+    pub fn set_interrupt_line(&self, irq: u8) {
+        let value = self.read_config(0x3C);
+        self.write_config(0x3C, (value & !0xFF) | irq as u32);
+    }
+
+    /// Walks the capability list starting at 0x34 and returns the config
+    /// offset of the first capability with `cap_id`.
+    pub fn find_capability(&self, cap_id: u8) -> Option<u8> {
+        if self.status & PCI_STATUS_CAP_LIST == 0 {
+            return None;
+        }
+
+        let mut current = (self.read_config(0x34) & 0xFC) as u8;
+        // The list lives in the 192 bytes after the header, so a well-formed
+        // one can't be longer than 48 entries; this also stops on loops.
+        for _ in 0..48 {
+            if current == 0 {
+                return None;
+            }
+            let header = self.read_config(current);
+            if (header & 0xFF) as u8 == cap_id {
+                return Some(current);
+            }
+            current = ((header >> 8) & 0xFC) as u8;
+        }
+        None
+    }
+
+    /// Routes every MSI-X table entry to `vector` on the current CPU and
+    /// turns MSI-X on, which also disables the legacy INTx pin. The table
+    /// BAR is mapped through `mapper` if it isn't already.
+    pub fn enable_msix(
+        &self,
+        vector: u8,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), &'static str> {
+        let cap = self
+            .find_capability(PCI_CAP_ID_MSIX)
+            .ok_or("No MSI-X capability")?;
+
+        let header = self.read_config(cap);
+        let control = header >> 16;
+        let table_size = (control & 0x7FF) as usize + 1;
+
+        let table_info = self.read_config(cap + 4);
+        let bir = (table_info & 0x7) as usize;
+        let table_offset = (table_info & !0x7) as u64;
+        let bar = self.get_bar(bir).ok_or("MSI-X table BAR not present")?;
+        if bar.bar_type == PciBarType::Io {
+            return Err("MSI-X table BAR is not memory");
+        }
+
+        let table = map_mmio(
+            bar.address + table_offset,
+            (table_size * MSIX_ENTRY_SIZE) as u64,
+            mapper,
+            frame_allocator,
+        )?;
+
+        // Mask the whole function while the table is being rewritten.
+        let masked = header | ((MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK) << 16);
+        self.write_config(cap, masked);
+
+        let address = MSI_ADDRESS_BASE | ((crate::smp::current_apic_id() as u64 & 0xFF) << 12);
+        for i in 0..table_size {
+            unsafe {
+                let entry = table.add(i * MSIX_ENTRY_SIZE) as *mut u32;
+                entry.add(3).write_volatile(MSIX_ENTRY_CTRL_MASKED);
+                entry.write_volatile(address as u32);
+                entry.add(1).write_volatile((address >> 32) as u32);
+                // Fixed delivery, edge triggered.
+                entry.add(2).write_volatile(vector as u32);
+                entry.add(3).write_volatile(0);
+            }
+        }
+
+        let enabled = (header & !(MSIX_CONTROL_FUNCTION_MASK << 16)) | (MSIX_CONTROL_ENABLE << 16);
+        self.write_config(cap, enabled);
+
+        let command = self.read_config(0x04);
+        self.write_config(0x04, command | PCI_COMMAND_INTX_DISABLE);
+
+        serial_println!(
+            "PCI device {}:{}:{} MSI-X enabled: {} entries -> vector 0x{:02X}",
+            self.bus,
+            self.slot,
+            self.func,
+            table_size,
+            vector
+        );
+        Ok(())
+    }
+
     pub fn get_bar(&self, index: usize) -> Option<&PciBar> {
         if index < 6 && self.bars[index].bar_type != PciBarType::None {
             Some(&self.bars[index])
@@ -286,6 +403,35 @@ fn calculate_bar_size_64(size_mask: u64) -> u64 {
     (!size_mask).wrapping_add(1)
 }
 
+/// Maps `size` bytes of device memory at `phys_addr` uncached and returns
+/// its virtual address. Large aligned BARs get 2 MiB pages. Pages that are
+/// already mapped to the same frame, e.g. when a BAR is shared between a
+/// driver and its MSI-X table, are left alone.
+pub fn map_mmio(
+    phys_addr: u64,
+    size: u64,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<*mut u8, &'static str> {
+    let start = PhysAddr::new(phys_addr).align_down(Size4KiB::SIZE);
+    let end = PhysAddr::new(phys_addr + size).align_up(Size4KiB::SIZE);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+
+    unsafe {
+        crate::memory::paging::map_region(
+            mapper,
+            VirtAddr::new(MMIO_BASE + start.as_u64()),
+            start,
+            end - start,
+            flags,
+            frame_allocator,
+        )
+        .map_err(|_| "MMIO mapping failed")?;
+    }
+
+    Ok(VirtAddr::new(MMIO_BASE + phys_addr).as_mut_ptr())
+}
+
 pub fn scan_pci() -> Vec<PciDevice> {
     let mut devices = Vec::new();
 
@@ -309,8 +455,28 @@ pub fn scan_pci() -> Vec<PciDevice> {
     devices
 }
 
+/// All PCI functions in the system, scanned on first use.
+pub fn devices() -> &'static [PciDevice] {
+    PCI_DEVICES.get_or_init(scan_pci)
+}
+
+pub fn find_device_by_class(class_code: u8, subclass: u8) -> Option<PciDevice> {
+    devices()
+        .iter()
+        .find(|dev| dev.class_code == class_code && dev.subclass == subclass)
+        .copied()
+}
+
+pub fn find_all_by_vendor(vendor_id: u16) -> Vec<PciDevice> {
+    devices()
+        .iter()
+        .filter(|dev| dev.vendor_id == vendor_id)
+        .copied()
+        .collect()
+}
+
 pub fn find_virtio_gpu() -> Option<PciDevice> {
-    for dev in scan_pci() {
+    for &dev in devices() {
         // VirtIO vendor ID is 0x1AF4
         // VirtIO GPU device ID is 0x1050 (modern) or 0x1010 (legacy)
         if dev.vendor_id == 0x1AF4 && (dev.device_id == 0x1050 || dev.device_id == 0x1010) {
@@ -322,6 +488,17 @@ pub fn find_virtio_gpu() -> Option<PciDevice> {
     None
 }
 
+pub fn find_virtio_net() -> Option<PciDevice> {
+    // VirtIO network device ID is 0x1041 (modern) or 0x1000 (transitional)
+    let dev = devices()
+        .iter()
+        .find(|dev| dev.vendor_id == 0x1AF4 && (dev.device_id == 0x1041 || dev.device_id == 0x1000))
+        .copied()?;
+    serial_println!("Found VirtIO-net device:");
+    dev.print_info();
+    Some(dev)
+}
+
 unsafe fn outl(port: u16, val: u32) {
     let mut p = Port::<u32>::new(port);
     p.write(val);
@@ -360,9 +537,7 @@ fn pci_write_config(bus: u8, slot: u8, func: u8, offset: u8, value: u32) {
 
 pub fn test_pci() {
     serial_println!("=== PCI Device Scan ===");
-    let devices = scan_pci();
-
-    for dev in &devices {
+    for dev in devices() {
         dev.print_info();
         serial_println!(""); // Empty line for readability
     }
@@ -1,88 +1,220 @@
 use crate::drivers::pci::PciDevice;
+use crate::memory::ContiguousFrameAllocator;
 use crate::serial_println;
+use crate::task::timeout::with_timeout;
 use alloc::vec::Vec;
 use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+    FrameDeallocator, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
-const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
-const VIRTIO_STATUS_DRIVER: u8 = 2;
-const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
-const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
-
-const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
-const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
-const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
-const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
-
-const VIRTIO_PCI_COMMON_STATUS: usize = 0x14;
-const VIRTIO_PCI_COMMON_DFSELECT: usize = 0x00;
-const VIRTIO_PCI_COMMON_DF: usize = 0x04;
-const VIRTIO_PCI_COMMON_GFSELECT: usize = 0x08;
-const VIRTIO_PCI_COMMON_GF: usize = 0x0C;
-const VIRTIO_PCI_COMMON_Q_SELECT: usize = 0x16;
-const VIRTIO_PCI_COMMON_Q_SIZE: usize = 0x18;
-const VIRTIO_PCI_COMMON_Q_ENABLE: usize = 0x1C;
-const VIRTIO_PCI_COMMON_Q_DESCLO: usize = 0x20;
-const VIRTIO_PCI_COMMON_Q_DESCHI: usize = 0x24;
-const VIRTIO_PCI_COMMON_Q_AVAILLO: usize = 0x28;
-const VIRTIO_PCI_COMMON_Q_AVAILHI: usize = 0x2C;
-const VIRTIO_PCI_COMMON_Q_USEDLO: usize = 0x30;
-const VIRTIO_PCI_COMMON_Q_USEDHI: usize = 0x34;
-
+pub(super) const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1;
+pub(super) const VIRTIO_STATUS_DRIVER: u8 = 2;
+pub(super) const VIRTIO_STATUS_DRIVER_OK: u8 = 4;
+pub(super) const VIRTIO_STATUS_FEATURES_OK: u8 = 8;
+const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u8 = 64;
+
+pub(super) const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+pub(super) const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+pub(super) const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+pub(super) const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+pub(super) const VIRTIO_PCI_COMMON_STATUS: usize = 0x14;
+pub(super) const VIRTIO_PCI_COMMON_DFSELECT: usize = 0x00;
+pub(super) const VIRTIO_PCI_COMMON_DF: usize = 0x04;
+pub(super) const VIRTIO_PCI_COMMON_GFSELECT: usize = 0x08;
+pub(super) const VIRTIO_PCI_COMMON_GF: usize = 0x0C;
+pub(super) const VIRTIO_PCI_COMMON_Q_SELECT: usize = 0x16;
+pub(super) const VIRTIO_PCI_COMMON_Q_SIZE: usize = 0x18;
+pub(super) const VIRTIO_PCI_COMMON_Q_ENABLE: usize = 0x1C;
+pub(super) const VIRTIO_PCI_COMMON_Q_NOFF: usize = 0x1E;
+pub(super) const VIRTIO_PCI_COMMON_Q_DESCLO: usize = 0x20;
+pub(super) const VIRTIO_PCI_COMMON_Q_DESCHI: usize = 0x24;
+pub(super) const VIRTIO_PCI_COMMON_Q_AVAILLO: usize = 0x28;
+pub(super) const VIRTIO_PCI_COMMON_Q_AVAILHI: usize = 0x2C;
+pub(super) const VIRTIO_PCI_COMMON_Q_USEDLO: usize = 0x30;
+pub(super) const VIRTIO_PCI_COMMON_Q_USEDHI: usize = 0x34;
+
+const VIRTIO_GPU_CMD_GET_DISPLAY_INFO: u32 = 0x0100;
 const VIRTIO_GPU_CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const VIRTIO_GPU_CMD_RESOURCE_UNREF: u32 = 0x0102;
 const VIRTIO_GPU_CMD_SET_SCANOUT: u32 = 0x0103;
 const VIRTIO_GPU_CMD_RESOURCE_FLUSH: u32 = 0x0104;
 const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
 const VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
 
+const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32 = 0x0300;
+const VIRTIO_GPU_CMD_MOVE_CURSOR: u32 = 0x0301;
+
 const VIRTIO_GPU_RESP_OK_NODATA: u32 = 0x1100;
+const VIRTIO_GPU_RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
 
 const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
 
-const QUEUE_SIZE: u16 = 32;
+pub(super) const QUEUE_SIZE: u16 = 32;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// How many times `init` resets the device and starts over after a
+/// command times out.
+const INIT_ATTEMPTS: usize = 3;
+
+/// How long `send_command_async` waits for the device to answer.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The device's ISR status byte, read by the IRQ handler without going
+/// through the `VirtioGpu`, which its owner may have locked.
+static ISR: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+/// Woken when the device reports used control queue buffers.
+static CONTROLQ_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Start of the virtual window DMA buffers are mapped into.
+const DMA_BASE: u64 = 0xFFFF_A000_0000_0000;
+
+const CONTROL_QUEUE: u16 = 0;
+const CURSOR_QUEUE: u16 = 1;
+
+const FRAMEBUFFER_RESOURCE: u32 = 1;
+const CURSOR_RESOURCE: u32 = 2;
+pub const CURSOR_SIZE: u32 = 64;
 
 #[repr(C)]
-struct VirtqDesc {
-    addr: u64,
-    len: u32,
-    flags: u16,
-    next: u16,
+pub(super) struct VirtqDesc {
+    pub(super) addr: u64,
+    pub(super) len: u32,
+    pub(super) flags: u16,
+    pub(super) next: u16,
 }
 
 #[repr(C)]
-struct VirtqAvail {
-    flags: u16,
-    idx: u16,
-    ring: [u16; QUEUE_SIZE as usize],
+pub(super) struct VirtqAvail {
+    pub(super) flags: u16,
+    pub(super) idx: u16,
+    pub(super) ring: [u16; QUEUE_SIZE as usize],
     used_event: u16,
 }
 
 #[repr(C)]
-struct VirtqUsedElem {
-    id: u32,
-    len: u32,
+pub(super) struct VirtqUsedElem {
+    pub(super) id: u32,
+    pub(super) len: u32,
 }
 
 #[repr(C)]
-struct VirtqUsed {
-    flags: u16,
-    idx: u16,
-    ring: [VirtqUsedElem; QUEUE_SIZE as usize],
+pub(super) struct VirtqUsed {
+    pub(super) flags: u16,
+    pub(super) idx: u16,
+    pub(super) ring: [VirtqUsedElem; QUEUE_SIZE as usize],
     avail_event: u16,
 }
 
-struct Virtq {
-    desc: *mut VirtqDesc,
-    avail: *mut VirtqAvail,
-    used: *mut VirtqUsed,
-    desc_phys: u64,
-    avail_phys: u64,
-    used_phys: u64,
-    free_head: u16,
-    used_idx: u16,
+pub(super) struct Virtq {
+    pub(super) desc: *mut VirtqDesc,
+    pub(super) avail: *mut VirtqAvail,
+    pub(super) used: *mut VirtqUsed,
+    pub(super) desc_phys: u64,
+    pub(super) avail_phys: u64,
+    pub(super) used_phys: u64,
+    pub(super) free_head: u16,
+    pub(super) used_idx: u16,
+    pub(super) notify: *mut u16,
+    pub(super) index: u16,
+}
+
+impl Virtq {
+    pub(super) const fn empty() -> Self {
+        Virtq {
+            desc: core::ptr::null_mut(),
+            avail: core::ptr::null_mut(),
+            used: core::ptr::null_mut(),
+            desc_phys: 0,
+            avail_phys: 0,
+            used_phys: 0,
+            free_head: 0,
+            used_idx: 0,
+            notify: core::ptr::null_mut(),
+            index: 0,
+        }
+    }
+
+    /// Links every descriptor into one free chain starting at 0. The last
+    /// one points at `QUEUE_SIZE`, which marks the end of the chain.
+    ///
+    /// # Safety
+    ///
+    /// `desc` must point at `QUEUE_SIZE` descriptors the device isn't
+    /// using.
+    pub(super) unsafe fn reset_free_chain(&mut self) {
+        unsafe {
+            for i in 0..QUEUE_SIZE {
+                (*self.desc.add(i as usize)).next = i + 1;
+            }
+        }
+        self.free_head = 0;
+    }
+
+    /// Takes a descriptor off the free chain.
+    ///
+    /// # Safety
+    ///
+    /// `desc` must point at the queue's descriptors.
+    unsafe fn alloc_desc(&mut self) -> Option<u16> {
+        let idx = self.free_head;
+        if idx >= QUEUE_SIZE {
+            return None;
+        }
+        self.free_head = unsafe { (*self.desc.add(idx as usize)).next };
+        Some(idx)
+    }
+
+    /// Puts the descriptor chain starting at `head`, as handed back in a
+    /// used element, onto the front of the free chain.
+    ///
+    /// # Safety
+    ///
+    /// The device must be done with the chain.
+    unsafe fn free_chain(&mut self, head: u16) {
+        if head >= QUEUE_SIZE {
+            return;
+        }
+        let mut idx = head;
+        // A chain can't be longer than the queue; stop there if the device
+        // handed back something bogus.
+        for _ in 0..QUEUE_SIZE {
+            let desc = unsafe { &mut *self.desc.add(idx as usize) };
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 || desc.next >= QUEUE_SIZE {
+                desc.flags = 0;
+                desc.next = self.free_head;
+                break;
+            }
+            desc.flags = 0;
+            idx = desc.next;
+        }
+        self.free_head = head;
+    }
+
+    /// Number of descriptors on the free chain.
+    ///
+    /// # Safety
+    ///
+    /// `desc` must point at the queue's descriptors.
+    unsafe fn free_count(&self) -> usize {
+        let mut count = 0;
+        let mut idx = self.free_head;
+        while idx < QUEUE_SIZE && count <= QUEUE_SIZE as usize {
+            count += 1;
+            idx = unsafe { (*self.desc.add(idx as usize)).next };
+        }
+        count
+    }
 }
 
 #[repr(C)]
@@ -102,6 +234,44 @@ struct VirtioGpuRect {
     height: u32,
 }
 
+#[repr(C)]
+struct VirtioGpuDisplayOne {
+    r: VirtioGpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct VirtioGpuRespDisplayInfo {
+    hdr: VirtioGpuCtrlHdr,
+    pmodes: [VirtioGpuDisplayOne; VIRTIO_GPU_MAX_SCANOUTS],
+}
+
+#[repr(C)]
+struct VirtioGpuCursorPos {
+    scanout_id: u32,
+    x: u32,
+    y: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct VirtioGpuUpdateCursor {
+    hdr: VirtioGpuCtrlHdr,
+    pos: VirtioGpuCursorPos,
+    resource_id: u32,
+    hot_x: u32,
+    hot_y: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+struct VirtioGpuResourceUnref {
+    hdr: VirtioGpuCtrlHdr,
+    resource_id: u32,
+    padding: u32,
+}
+
 #[repr(C)]
 struct VirtioGpuResourceCreate2d {
     hdr: VirtioGpuCtrlHdr,
@@ -150,10 +320,129 @@ struct VirtioGpuMemEntry {
     padding: u32,
 }
 
-struct DmaBuffer {
-    virt: *mut u8,
-    phys: u64,
+/// An enabled scanout as reported by `VIRTIO_GPU_CMD_GET_DISPLAY_INFO`.
+#[derive(Debug, Clone, Copy)]
+pub struct ScanoutInfo {
+    pub scanout_id: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub(super) struct DmaBuffer {
+    pub(super) virt: *mut u8,
+    pub(super) phys: u64,
+    pub(super) size: usize,
+}
+
+/// Allocates a zeroed, physically contiguous DMA buffer of at least `size`
+/// bytes. Buffers are mapped at `DMA_BASE` plus their physical address, so
+/// the virtual window never runs out across re-inits and a buffer's frames
+/// can be recovered from `phys` and `size` alone. Buffers of 2 MiB or more,
+/// like the framebuffer, are placed on a 2 MiB boundary when possible so
+/// they map with huge pages.
+pub(super) fn alloc_dma(
+    size: usize,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl ContiguousFrameAllocator,
+) -> Result<DmaBuffer, &'static str> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    map_dma(size, flags, mapper, frame_allocator)
+}
+
+/// Like `alloc_dma`, but mapped write-back. DMA on x86 snoops the caches,
+/// so this is safe for memory the device only reads after being told to,
+/// like the framebuffer, and far faster for the CPU to fill. The buffer is
+/// ordinary RAM, so this also matches the physical memory mapping.
+pub(super) fn alloc_dma_write_back(
     size: usize,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl ContiguousFrameAllocator,
+) -> Result<DmaBuffer, &'static str> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    map_dma(size, flags, mapper, frame_allocator)
+}
+
+fn map_dma(
+    size: usize,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl ContiguousFrameAllocator,
+) -> Result<DmaBuffer, &'static str> {
+    const HUGE_PAGE_FRAMES: usize = 512;
+
+    let pages = size.max(1).div_ceil(4096);
+    let first = if pages >= HUGE_PAGE_FRAMES {
+        frame_allocator
+            .allocate_contiguous_aligned(pages, HUGE_PAGE_FRAMES)
+            .or_else(|| frame_allocator.allocate_contiguous(pages))
+    } else {
+        frame_allocator.allocate_contiguous(pages)
+    }
+    .ok_or("No contiguous frames available")?;
+    let phys = first.start_address().as_u64();
+    let virt = VirtAddr::new(DMA_BASE + phys);
+
+    let huge_pages = unsafe {
+        crate::memory::paging::map_region(
+            mapper,
+            virt,
+            PhysAddr::new(phys),
+            pages as u64 * 4096,
+            flags,
+            frame_allocator,
+        )
+        .map_err(|_| "DMA buffer mapping failed")?
+    };
+    if huge_pages > 0 {
+        serial_println!(
+            "DMA buffer of {} KiB mapped with {} 2 MiB pages",
+            pages * 4,
+            huge_pages
+        );
+    }
+
+    let buffer = DmaBuffer {
+        virt: virt.as_mut_ptr(),
+        phys,
+        size: pages * 4096,
+    };
+    unsafe { core::ptr::write_bytes(buffer.virt, 0, buffer.size) };
+    Ok(buffer)
+}
+
+/// Stands in for the deallocator while `free_dma` unmaps, so no frame is
+/// freed before other CPUs have dropped their TLB entries for it.
+struct KeepFrames;
+
+impl FrameDeallocator<Size4KiB> for KeepFrames {
+    unsafe fn deallocate_frame(&mut self, _frame: PhysFrame) {}
+}
+
+/// Unmaps a buffer from `alloc_dma`, flushes it from every CPU's TLB and
+/// returns its frames.
+///
+/// # Safety
+///
+/// The device must no longer be using the buffer.
+pub(super) unsafe fn free_dma(
+    buffer: DmaBuffer,
+    mapper: &mut OffsetPageTable,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let virt = VirtAddr::from_ptr(buffer.virt);
+    unsafe {
+        crate::memory::paging::unmap_region(mapper, virt, buffer.size as u64, &mut KeepFrames);
+    }
+    crate::smp::tlb_shootdown_range(Page::range(
+        Page::containing_address(virt),
+        Page::containing_address(virt + buffer.size as u64),
+    ));
+    let first = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(buffer.phys));
+    for frame in PhysFrame::range(first, first + (buffer.size / 4096) as u64) {
+        unsafe { frame_deallocator.deallocate_frame(frame) };
+    }
 }
 
 pub struct VirtioGpu {
@@ -162,12 +451,59 @@ pub struct VirtioGpu {
     notify_base: *mut u8,
     device_cfg: *mut u8,
     isr: *mut u8,
+    notify_off_multiplier: u32,
     controlq: Virtq,
+    cursorq: Virtq,
     framebuffer: *mut u32,
     fb_phys: u64,
     width: u32,
     height: u32,
     dma_buffers: Vec<DmaBuffer>,
+    /// Command and response buffers reused by `flush_rect`.
+    flush_bufs: Option<(usize, usize)>,
+    /// 64x64 ARGB image backing the cursor resource.
+    cursor_buf: Option<usize>,
+    /// Command buffer for the cursor queue.
+    cursor_cmd_buf: Option<usize>,
+    /// Set by `enable_interrupts`; until then commands spin on the used
+    /// ring.
+    irq_enabled: bool,
+}
+
+/// Name of a control or cursor command, for log messages.
+fn command_name(cmd_type: u32) -> &'static str {
+    match cmd_type {
+        VIRTIO_GPU_CMD_GET_DISPLAY_INFO => "GET_DISPLAY_INFO",
+        VIRTIO_GPU_CMD_RESOURCE_CREATE_2D => "RESOURCE_CREATE_2D",
+        VIRTIO_GPU_CMD_RESOURCE_UNREF => "RESOURCE_UNREF",
+        VIRTIO_GPU_CMD_SET_SCANOUT => "SET_SCANOUT",
+        VIRTIO_GPU_CMD_RESOURCE_FLUSH => "RESOURCE_FLUSH",
+        VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D => "TRANSFER_TO_HOST_2D",
+        VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING => "RESOURCE_ATTACH_BACKING",
+        VIRTIO_GPU_CMD_UPDATE_CURSOR => "UPDATE_CURSOR",
+        VIRTIO_GPU_CMD_MOVE_CURSOR => "MOVE_CURSOR",
+        _ => "unknown",
+    }
+}
+
+/// Reading the ISR status acknowledges the interrupt. The line may be
+/// shared, so only wake when this device raised it for a queue rather than
+/// a config change.
+fn handle_irq() {
+    let isr = ISR.load(Ordering::SeqCst);
+    if !isr.is_null() && unsafe { read_volatile(isr) } & 1 != 0 {
+        CONTROLQ_WAKER.wake();
+    }
+}
+
+fn ctrl_hdr(cmd_type: u32) -> VirtioGpuCtrlHdr {
+    VirtioGpuCtrlHdr {
+        cmd_type,
+        flags: 0,
+        fence_id: 0,
+        ctx_id: 0,
+        padding: 0,
+    }
 }
 
 impl VirtioGpu {
@@ -178,62 +514,271 @@ impl VirtioGpu {
             notify_base: core::ptr::null_mut(),
             device_cfg: core::ptr::null_mut(),
             isr: core::ptr::null_mut(),
-            controlq: Virtq {
-                desc: core::ptr::null_mut(),
-                avail: core::ptr::null_mut(),
-                used: core::ptr::null_mut(),
-                desc_phys: 0,
-                avail_phys: 0,
-                used_phys: 0,
-                free_head: 0,
-                used_idx: 0,
-            },
+            notify_off_multiplier: 0,
+            controlq: Virtq::empty(),
+            cursorq: Virtq::empty(),
             framebuffer: core::ptr::null_mut(),
             fb_phys: 0,
             width: 1024,
             height: 768,
             dma_buffers: Vec::new(),
+            flush_bufs: None,
+            cursor_buf: None,
+            cursor_cmd_buf: None,
+            irq_enabled: false,
         }
     }
 
+    /// Brings the device up and shows the framebuffer on scanout 0. If a
+    /// command times out partway, the device is reset, everything
+    /// allocated so far is freed and bring-up starts over, up to
+    /// `INIT_ATTEMPTS` times. On failure the device is left reset.
     pub fn init(
         &mut self,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut (impl ContiguousFrameAllocator + FrameDeallocator<Size4KiB>),
     ) -> Result<(), &'static str> {
         self.dev.enable();
         self.parse_capabilities()?;
         self.map_bars(mapper, frame_allocator)?;
+
+        let mut attempt = 1;
+        loop {
+            match self.bring_up(mapper, frame_allocator) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < INIT_ATTEMPTS && self.needs_reset() => {
+                    serial_println!(
+                        "VirtIO-GPU bring-up failed ({}), resetting for attempt {} of {}",
+                        e,
+                        attempt + 1,
+                        INIT_ATTEMPTS
+                    );
+                    self.free(mapper, frame_allocator);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.free(mapper, frame_allocator);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// One pass of `init` after the BARs are mapped.
+    fn bring_up(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<(), &'static str> {
         self.device_init()?;
         self.setup_queues(mapper, frame_allocator)?;
+
+        match self.get_display_info(mapper, frame_allocator) {
+            Ok(scanouts) => match scanouts.iter().find(|s| s.scanout_id == 0) {
+                Some(s) if s.width > 0 && s.height > 0 => {
+                    self.width = s.width;
+                    self.height = s.height;
+                }
+                _ => {
+                    serial_println!(
+                        "No enabled scanout 0, keeping {}x{}",
+                        self.width,
+                        self.height
+                    );
+                }
+            },
+            Err(e) => {
+                serial_println!(
+                    "GET_DISPLAY_INFO failed ({}), keeping {}x{}",
+                    e,
+                    self.width,
+                    self.height
+                );
+            }
+        }
+
         self.setup_framebuffer(mapper, frame_allocator)?;
         self.configure_display(mapper, frame_allocator)?;
+
+        self.alloc_dma_buffer(4096, mapper, frame_allocator)?;
+        self.alloc_dma_buffer(4096, mapper, frame_allocator)?;
+        let len = self.dma_buffers.len();
+        self.flush_bufs = Some((len - 2, len - 1));
+
+        if let Err(e) = self.setup_cursor(mapper, frame_allocator) {
+            serial_println!("Cursor setup failed: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Creates the 64x64 cursor resource and its backing. The image starts
+    /// fully transparent; `set_cursor` fills it in.
+    fn setup_cursor(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<(), &'static str> {
+        let image_size = (CURSOR_SIZE * CURSOR_SIZE * 4) as usize;
+        self.alloc_dma_buffer(image_size, mapper, frame_allocator)?;
+        let image_idx = self.dma_buffers.len() - 1;
+        self.alloc_dma_buffer(4096, mapper, frame_allocator)?;
+        let cmd_idx = self.dma_buffers.len() - 1;
+
+        self.create_2d_resource(
+            CURSOR_RESOURCE,
+            VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+            CURSOR_SIZE,
+            CURSOR_SIZE,
+            mapper,
+            frame_allocator,
+        )?;
+        let image_phys = self.dma_buffers[image_idx].phys;
+        self.attach_backing(
+            CURSOR_RESOURCE,
+            image_phys,
+            image_size as u64,
+            mapper,
+            frame_allocator,
+        )?;
+
+        self.cursor_buf = Some(image_idx);
+        self.cursor_cmd_buf = Some(cmd_idx);
+        Ok(())
+    }
+
+    /// Uploads a 64x64 ARGB cursor image and shows it with its hotspot at
+    /// (`hot_x`, `hot_y`).
+    pub fn set_cursor(
+        &mut self,
+        image: &[u32; (CURSOR_SIZE * CURSOR_SIZE) as usize],
+        hot_x: u32,
+        hot_y: u32,
+    ) -> Result<(), &'static str> {
+        let image_idx = self.cursor_buf.ok_or("Cursor not set up")?;
+        unsafe {
+            let dst = self.dma_buffers[image_idx].virt as *mut u32;
+            core::ptr::copy_nonoverlapping(image.as_ptr(), dst, image.len());
+        }
+
+        self.submit_prealloc(
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+            VirtioGpuTransferToHost2d {
+                hdr: ctrl_hdr(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+                r: VirtioGpuRect {
+                    x: 0,
+                    y: 0,
+                    width: CURSOR_SIZE,
+                    height: CURSOR_SIZE,
+                },
+                offset: 0,
+                resource_id: CURSOR_RESOURCE,
+                padding: 0,
+            },
+        )?;
+
+        self.send_cursor_command(VirtioGpuUpdateCursor {
+            hdr: ctrl_hdr(VIRTIO_GPU_CMD_UPDATE_CURSOR),
+            pos: VirtioGpuCursorPos {
+                scanout_id: 0,
+                x: 0,
+                y: 0,
+                padding: 0,
+            },
+            resource_id: CURSOR_RESOURCE,
+            hot_x,
+            hot_y,
+            padding: 0,
+        })
+    }
+
+    /// Moves the cursor on scanout 0 without re-uploading the image.
+    pub fn move_cursor(&mut self, x: u32, y: u32) -> Result<(), &'static str> {
+        self.send_cursor_command(VirtioGpuUpdateCursor {
+            hdr: ctrl_hdr(VIRTIO_GPU_CMD_MOVE_CURSOR),
+            pos: VirtioGpuCursorPos {
+                scanout_id: 0,
+                x,
+                y,
+                padding: 0,
+            },
+            resource_id: CURSOR_RESOURCE,
+            hot_x: 0,
+            hot_y: 0,
+            padding: 0,
+        })
+    }
+
+    /// Cursor commands take a single device-readable descriptor and get no
+    /// response, so each one reuses the descriptor slot matching its avail
+    /// index. Waits for the device to consume it since the command buffer
+    /// is shared.
+    fn send_cursor_command(&mut self, cmd: VirtioGpuUpdateCursor) -> Result<(), &'static str> {
+        let cmd_idx = self.cursor_cmd_buf.ok_or("Cursor not set up")?;
+        if self.cursorq.desc.is_null() {
+            return Err("Cursor queue not set up");
+        }
+
+        unsafe {
+            let cmd_buf = &self.dma_buffers[cmd_idx];
+            write_volatile(cmd_buf.virt as *mut VirtioGpuUpdateCursor, cmd);
+
+            let avail_idx = (*self.cursorq.avail).idx;
+            let desc_idx = avail_idx % QUEUE_SIZE;
+            let desc = self.cursorq.desc.add(desc_idx as usize);
+            (*desc).addr = cmd_buf.phys;
+            (*desc).len = core::mem::size_of::<VirtioGpuUpdateCursor>() as u32;
+            (*desc).flags = 0;
+            (*desc).next = 0;
+
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            (*self.cursorq.avail).ring[desc_idx as usize] = desc_idx;
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            (*self.cursorq.avail).idx = avail_idx.wrapping_add(1);
+
+            write_volatile(self.cursorq.notify, self.cursorq.index);
+
+            let mut timeout = 1000000;
+            while read_volatile(&(*self.cursorq.used).idx) == self.cursorq.used_idx {
+                timeout -= 1;
+                if timeout == 0 {
+                    return Err("Cursor command timeout");
+                }
+                core::hint::spin_loop();
+            }
+            self.cursorq.used_idx = self.cursorq.used_idx.wrapping_add(1);
+        }
         Ok(())
     }
 
     fn parse_capabilities(&mut self) -> Result<(), &'static str> {
-        let cap_ptr = (self.read_pci_config(0x34) & 0xFF) as u8;
+        let cap_ptr = (self.dev.read_config(0x34) & 0xFF) as u8;
         if cap_ptr == 0 {
             return Err("No capabilities");
         }
 
         let mut current = cap_ptr;
         while current != 0 {
-            let cap_data = self.read_pci_config(current);
+            let cap_data = self.dev.read_config(current);
             let cap_id = (cap_data & 0xFF) as u8;
             let next = ((cap_data >> 8) & 0xFF) as u8;
 
-            if cap_id == 0x09 {
+            if cap_id == super::PCI_CAP_ID_VENDOR {
                 let cfg_type = ((cap_data >> 24) & 0xFF) as u8;
-                let bar = (self.read_pci_config(current + 4) & 0xFF) as u8;
-                let offset = self.read_pci_config(current + 8);
+                let bar = (self.dev.read_config(current + 4) & 0xFF) as u8;
+                let offset = self.dev.read_config(current + 8);
 
                 match cfg_type {
                     VIRTIO_PCI_CAP_COMMON_CFG => {
                         serial_println!("Common cfg: bar={}, offset=0x{:x}", bar, offset);
                     }
                     VIRTIO_PCI_CAP_NOTIFY_CFG => {
-                        serial_println!("Notify cfg: bar={}, offset=0x{:x}", bar, offset);
+                        self.notify_off_multiplier = self.dev.read_config(current + 16);
+                        serial_println!(
+                            "Notify cfg: bar={}, offset=0x{:x}, multiplier={}",
+                            bar,
+                            offset,
+                            self.notify_off_multiplier
+                        );
                     }
                     VIRTIO_PCI_CAP_ISR_CFG => {
                         serial_println!("ISR cfg: bar={}, offset=0x{:x}", bar, offset);
@@ -252,10 +797,10 @@ impl VirtioGpu {
     fn map_bars(
         &mut self,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
         if let Some(bar) = self.dev.get_bar(4) {
-            let base = self.map_mmio(bar.address, bar.size, mapper, frame_allocator)?;
+            let base = super::map_mmio(bar.address, bar.size, mapper, frame_allocator)?;
             self.common_cfg = base;
             self.notify_base = unsafe { base.add(0x3000) };
             self.isr = unsafe { base.add(0x1000) };
@@ -306,10 +851,23 @@ impl VirtioGpu {
     fn setup_queues(
         &mut self,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
+        self.controlq = self.setup_queue(CONTROL_QUEUE, mapper, frame_allocator)?;
+        serial_println!("Control queue setup complete");
+        self.cursorq = self.setup_queue(CURSOR_QUEUE, mapper, frame_allocator)?;
+        serial_println!("Cursor queue setup complete");
+        Ok(())
+    }
+
+    fn setup_queue(
+        &mut self,
+        index: u16,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<Virtq, &'static str> {
         unsafe {
-            self.write_common_u16(VIRTIO_PCI_COMMON_Q_SELECT, 0);
+            self.write_common_u16(VIRTIO_PCI_COMMON_Q_SELECT, index);
             self.write_common_u16(VIRTIO_PCI_COMMON_Q_SIZE, QUEUE_SIZE);
 
             let desc_buf_idx = {
@@ -329,67 +887,228 @@ impl VirtioGpu {
             let avail_buf = &self.dma_buffers[avail_buf_idx];
             let used_buf = &self.dma_buffers[used_buf_idx];
 
-            self.controlq.desc = desc_buf.virt as *mut VirtqDesc;
-            self.controlq.avail = avail_buf.virt as *mut VirtqAvail;
-            self.controlq.used = used_buf.virt as *mut VirtqUsed;
-            self.controlq.desc_phys = desc_buf.phys;
-            self.controlq.avail_phys = avail_buf.phys;
-            self.controlq.used_phys = used_buf.phys;
+            let notify_off = self.read_common_u16(VIRTIO_PCI_COMMON_Q_NOFF) as usize;
+            let mut queue = Virtq {
+                desc: desc_buf.virt as *mut VirtqDesc,
+                avail: avail_buf.virt as *mut VirtqAvail,
+                used: used_buf.virt as *mut VirtqUsed,
+                desc_phys: desc_buf.phys,
+                avail_phys: avail_buf.phys,
+                used_phys: used_buf.phys,
+                free_head: 0,
+                used_idx: 0,
+                notify: self
+                    .notify_base
+                    .add(notify_off * self.notify_off_multiplier as usize)
+                    as *mut u16,
+                index,
+            };
 
-            for i in 0..QUEUE_SIZE - 1 {
-                (*self.controlq.desc.add(i as usize)).next = i + 1;
-            }
-            (*self.controlq.desc.add((QUEUE_SIZE - 1) as usize)).next = 0;
-            self.controlq.free_head = 0;
+            queue.reset_free_chain();
 
             self.write_common_u32(
                 VIRTIO_PCI_COMMON_Q_DESCLO,
-                (self.controlq.desc_phys & 0xffffffff) as u32,
-            );
-            self.write_common_u32(
-                VIRTIO_PCI_COMMON_Q_DESCHI,
-                (self.controlq.desc_phys >> 32) as u32,
+                (queue.desc_phys & 0xffffffff) as u32,
             );
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_DESCHI, (queue.desc_phys >> 32) as u32);
             self.write_common_u32(
                 VIRTIO_PCI_COMMON_Q_AVAILLO,
-                (self.controlq.avail_phys & 0xffffffff) as u32,
-            );
-            self.write_common_u32(
-                VIRTIO_PCI_COMMON_Q_AVAILHI,
-                (self.controlq.avail_phys >> 32) as u32,
+                (queue.avail_phys & 0xffffffff) as u32,
             );
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_AVAILHI, (queue.avail_phys >> 32) as u32);
             self.write_common_u32(
                 VIRTIO_PCI_COMMON_Q_USEDLO,
-                (self.controlq.used_phys & 0xffffffff) as u32,
-            );
-            self.write_common_u32(
-                VIRTIO_PCI_COMMON_Q_USEDHI,
-                (self.controlq.used_phys >> 32) as u32,
+                (queue.used_phys & 0xffffffff) as u32,
             );
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_USEDHI, (queue.used_phys >> 32) as u32);
 
             self.write_common_u16(VIRTIO_PCI_COMMON_Q_ENABLE, 1);
 
-            serial_println!("Control queue setup complete");
-            Ok(())
+            Ok(queue)
         }
     }
 
-    fn setup_framebuffer(
+    /// Asks the device for its scanouts and returns the enabled ones.
+    pub fn get_display_info(
         &mut self,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<Vec<ScanoutInfo>, &'static str> {
+        let cmd_buf_idx = {
+            self.alloc_dma_buffer(
+                core::mem::size_of::<VirtioGpuCtrlHdr>(),
+                mapper,
+                frame_allocator,
+            )?;
+            self.dma_buffers.len() - 1
+        };
+
+        let resp_buf_idx = {
+            self.alloc_dma_buffer(
+                core::mem::size_of::<VirtioGpuRespDisplayInfo>(),
+                mapper,
+                frame_allocator,
+            )?;
+            self.dma_buffers.len() - 1
+        };
+
+        let mut scanouts = Vec::new();
+        unsafe {
+            let cmd_buf = &self.dma_buffers[cmd_buf_idx];
+            let resp_buf = &self.dma_buffers[resp_buf_idx];
+            let (cmd_phys, resp_phys) = (cmd_buf.phys, resp_buf.phys);
+            let resp = resp_buf.virt as *const VirtioGpuRespDisplayInfo;
+
+            (*(cmd_buf.virt as *mut VirtioGpuCtrlHdr)) = VirtioGpuCtrlHdr {
+                cmd_type: VIRTIO_GPU_CMD_GET_DISPLAY_INFO,
+                flags: 0,
+                fence_id: 0,
+                ctx_id: 0,
+                padding: 0,
+            };
+
+            self.send_command_expecting(
+                VIRTIO_GPU_CMD_GET_DISPLAY_INFO,
+                cmd_phys,
+                core::mem::size_of::<VirtioGpuCtrlHdr>() as u32,
+                resp_phys,
+                core::mem::size_of::<VirtioGpuRespDisplayInfo>() as u32,
+                VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+            )?;
+
+            for (i, mode) in (*resp).pmodes.iter().enumerate() {
+                if mode.enabled == 0 {
+                    continue;
+                }
+                serial_println!(
+                    "Scanout {}: {}x{} at ({}, {})",
+                    i,
+                    mode.r.width,
+                    mode.r.height,
+                    mode.r.x,
+                    mode.r.y
+                );
+                scanouts.push(ScanoutInfo {
+                    scanout_id: i as u32,
+                    x: mode.r.x,
+                    y: mode.r.y,
+                    width: mode.r.width,
+                    height: mode.r.height,
+                });
+            }
+        }
+
+        if scanouts.is_empty() {
+            serial_println!("VirtIO-GPU reports no enabled scanouts");
+        }
+        Ok(scanouts)
+    }
+
+    /// Switches scanout 0 to `width`x`height` with a freshly allocated
+    /// framebuffer. The host resource is destroyed and recreated since
+    /// virtio-gpu resources can't be resized in place.
+    pub fn set_resolution(
+        &mut self,
+        width: u32,
+        height: u32,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
-        let fb_size = (self.width * self.height * 4) as usize;
-        let pages = (fb_size + 4095) / 4096;
+        if width == 0 || height == 0 {
+            return Err("Invalid resolution");
+        }
+
+        self.set_scanout(0, 0, 0, 0, 0, 0, mapper, frame_allocator)?;
+        self.resource_unref(1, mapper, frame_allocator)?;
+
+        self.width = width;
+        self.height = height;
+        self.setup_framebuffer(mapper, frame_allocator)?;
+
+        self.create_2d_resource(
+            1,
+            VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM,
+            width,
+            height,
+            mapper,
+            frame_allocator,
+        )?;
+        self.attach_backing(
+            1,
+            self.fb_phys,
+            (width * height * 4) as u64,
+            mapper,
+            frame_allocator,
+        )?;
+        self.set_scanout(0, 1, 0, 0, width, height, mapper, frame_allocator)?;
+        self.refresh_display(mapper, frame_allocator)
+    }
+
+    fn resource_unref(
+        &mut self,
+        resource_id: u32,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<(), &'static str> {
+        let cmd_buf_idx = {
+            self.alloc_dma_buffer(
+                core::mem::size_of::<VirtioGpuResourceUnref>(),
+                mapper,
+                frame_allocator,
+            )?;
+            self.dma_buffers.len() - 1
+        };
 
-        let fb_buf_idx = {
-            self.alloc_dma_buffer(pages * 4096, mapper, frame_allocator)?;
+        let resp_buf_idx = {
+            self.alloc_dma_buffer(
+                core::mem::size_of::<VirtioGpuCtrlHdr>(),
+                mapper,
+                frame_allocator,
+            )?;
             self.dma_buffers.len() - 1
         };
 
-        let fb_buf = &self.dma_buffers[fb_buf_idx];
+        unsafe {
+            let cmd_buf = &self.dma_buffers[cmd_buf_idx];
+            let resp_buf = &self.dma_buffers[resp_buf_idx];
+
+            let cmd = cmd_buf.virt as *mut VirtioGpuResourceUnref;
+            (*cmd) = VirtioGpuResourceUnref {
+                hdr: VirtioGpuCtrlHdr {
+                    cmd_type: VIRTIO_GPU_CMD_RESOURCE_UNREF,
+                    flags: 0,
+                    fence_id: 0,
+                    ctx_id: 0,
+                    padding: 0,
+                },
+                resource_id,
+                padding: 0,
+            };
+
+            self.send_command_raw(
+                VIRTIO_GPU_CMD_RESOURCE_UNREF,
+                cmd_buf.phys,
+                cmd_buf.size as u32,
+                resp_buf.phys,
+                resp_buf.size as u32,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn setup_framebuffer(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<(), &'static str> {
+        let fb_size = (self.width * self.height * 4) as usize;
+
+        // The device only reads the framebuffer on TRANSFER_TO_HOST_2D, so
+        // unlike the queues it can be cached.
+        let fb_buf = alloc_dma_write_back(fb_size, mapper, frame_allocator)?;
         self.framebuffer = fb_buf.virt as *mut u32;
         self.fb_phys = fb_buf.phys;
+        self.dma_buffers.push(fb_buf);
 
         serial_println!(
             "Framebuffer: {}x{} at virt={:p} phys=0x{:x}",
@@ -406,7 +1125,7 @@ impl VirtioGpu {
     fn configure_display(
         &mut self,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
         self.create_2d_resource(
             1,
@@ -441,110 +1160,99 @@ impl VirtioGpu {
         Ok(())
     }
 
+    /// Allocates a DMA buffer with `alloc_dma` and adds it to
+    /// `dma_buffers`.
     fn alloc_dma_buffer(
         &mut self,
         size: usize,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
-        const DMA_BASE: u64 = 0xFFFF_A000_0000_0000;
-        static mut DMA_OFFSET: u64 = 0;
-
-        unsafe {
-            // For small buffers (like VirtIO commands), allocate single page
-            // For large buffers (like framebuffer), we'll handle them specially
-            if size <= 4096 {
-                let virt_addr = VirtAddr::new(DMA_BASE + DMA_OFFSET);
-                let flags =
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
-
-                let page = Page::containing_address(virt_addr);
-                let frame = frame_allocator
-                    .allocate_frame()
-                    .ok_or("No frame available")?;
-                let phys_addr = frame.start_address().as_u64();
-
-                match mapper.map_to(page, frame, flags, frame_allocator) {
-                    Ok(flush) => flush.flush(),
-                    Err(_) => {
-                        DMA_OFFSET += 0x10000;
-                        let new_virt_addr = VirtAddr::new(DMA_BASE + DMA_OFFSET);
-                        let new_page = Page::containing_address(new_virt_addr);
-
-                        mapper
-                            .map_to(new_page, frame, flags, frame_allocator)
-                            .map_err(|_| "Mapping failed after retry")?
-                            .flush();
-
-                        let buffer = DmaBuffer {
-                            virt: new_virt_addr.as_mut_ptr(),
-                            phys: phys_addr,
-                            size: 4096,
-                        };
-
-                        core::ptr::write_bytes(buffer.virt, 0, buffer.size);
-                        self.dma_buffers.push(buffer);
-                        DMA_OFFSET += 4096;
-                        return Ok(());
-                    }
-                }
-
-                DMA_OFFSET += 4096;
+        let buffer = alloc_dma(size, mapper, frame_allocator)?;
+        self.dma_buffers.push(buffer);
+        Ok(())
+    }
 
-                let buffer = DmaBuffer {
-                    virt: virt_addr.as_mut_ptr(),
-                    phys: phys_addr,
-                    size: 4096,
-                };
+    /// Whether the device, or `abandon_controlq`, has flagged that it
+    /// needs a reset before it will take more commands.
+    fn needs_reset(&self) -> bool {
+        if self.common_cfg.is_null() {
+            return false;
+        }
+        let status = unsafe { self.read_common_u8(VIRTIO_PCI_COMMON_STATUS) };
+        status & VIRTIO_STATUS_DEVICE_NEEDS_RESET != 0
+    }
 
-                core::ptr::write_bytes(buffer.virt, 0, buffer.size);
-                self.dma_buffers.push(buffer);
-                Ok(())
-            } else {
-                // For large buffers, allocate multiple pages
-                let pages_needed = (size + 4095) / 4096;
-                let total_size = pages_needed * 4096;
-
-                let virt_addr = VirtAddr::new(DMA_BASE + DMA_OFFSET);
-                let flags =
-                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
-
-                // Allocate and map all pages
-                let mut frames = Vec::new();
-                let mut current_virt_addr = virt_addr;
-
-                for _ in 0..pages_needed {
-                    let frame = frame_allocator
-                        .allocate_frame()
-                        .ok_or("No frame available")?;
-                    frames.push(frame);
-
-                    let page = Page::containing_address(current_virt_addr);
-                    mapper
-                        .map_to(page, frame, flags, frame_allocator)
-                        .map_err(|_| "Large buffer mapping failed")?
-                        .flush();
-
-                    current_virt_addr = VirtAddr::new(current_virt_addr.as_u64() + 4096);
-                }
+    /// Gives up on the control queue after `cmd_type` timed out. The
+    /// device may still hold descriptors, so the free chain and used index
+    /// are rebuilt from scratch, and `VIRTIO_STATUS_DEVICE_NEEDS_RESET` is
+    /// set so later commands fail fast and `init` knows to start over.
+    ///
+    /// # Safety
+    ///
+    /// The control queue must be set up.
+    unsafe fn abandon_controlq(&mut self, cmd_type: u32) {
+        serial_println!(
+            "VirtIO-GPU: {} (0x{:04x}) timed out, control queue abandoned",
+            command_name(cmd_type),
+            cmd_type
+        );
+        unsafe {
+            self.controlq.reset_free_chain();
+            self.controlq.used_idx = read_volatile(&(*self.controlq.used).idx);
+            let status = self.read_common_u8(VIRTIO_PCI_COMMON_STATUS);
+            self.write_common_u8(
+                VIRTIO_PCI_COMMON_STATUS,
+                status | VIRTIO_STATUS_DEVICE_NEEDS_RESET,
+            );
+        }
+    }
 
-                // Use the physical address of the first frame
-                let phys_addr = frames[0].start_address().as_u64();
+    /// Resets the device and releases every DMA buffer, unmapping its pages
+    /// and returning its frames. The `VirtioGpu` is unusable afterwards
+    /// until `init` runs again.
+    pub fn free(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        // The device must stop touching the queues before their memory goes
+        // back to the allocator.
+        if !self.common_cfg.is_null() {
+            unsafe { self.write_common_u8(VIRTIO_PCI_COMMON_STATUS, 0) };
+        }
 
-                let buffer = DmaBuffer {
-                    virt: virt_addr.as_mut_ptr(),
-                    phys: phys_addr,
-                    size: total_size,
-                };
+        for buffer in self.dma_buffers.drain(..) {
+            unsafe { free_dma(buffer, mapper, frame_deallocator) };
+        }
 
-                // Clear the buffer
-                core::ptr::write_bytes(buffer.virt, 0, buffer.size);
-                self.dma_buffers.push(buffer);
+        self.controlq = Virtq::empty();
+        self.cursorq = Virtq::empty();
+        self.framebuffer = core::ptr::null_mut();
+        self.fb_phys = 0;
+        self.flush_bufs = None;
+        self.cursor_buf = None;
+        self.cursor_cmd_buf = None;
+        self.irq_enabled = false;
+    }
 
-                DMA_OFFSET += total_size as u64;
-                Ok(())
-            }
+    /// Hooks the device's legacy INTx line, so `send_command_async` can
+    /// sleep until a command completes instead of spinning. Call after
+    /// `init` once interrupts are up. MSI-X isn't used: the IO APIC path
+    /// already works for virtio-net.
+    pub fn enable_interrupts(&mut self) -> Result<(), &'static str> {
+        if self.isr.is_null() || self.controlq.used.is_null() {
+            return Err("GPU not initialized");
         }
+        let irq = (self.dev.read_config(0x3C) & 0xFF) as u8;
+        if irq == 0 || irq == 0xFF {
+            return Err("No INTx line assigned");
+        }
+        ISR.store(self.isr, Ordering::SeqCst);
+        crate::interrupts::register_irq(irq, handle_irq)?;
+        self.irq_enabled = true;
+        serial_println!("VirtIO-GPU using IRQ {}", irq);
+        Ok(())
     }
 
     fn create_2d_resource(
@@ -554,7 +1262,7 @@ impl VirtioGpu {
         width: u32,
         height: u32,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
         let cmd_buf_idx = {
             self.alloc_dma_buffer(
@@ -594,6 +1302,7 @@ impl VirtioGpu {
             };
 
             self.send_command_raw(
+                VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
                 cmd_buf.phys,
                 cmd_buf.size as u32,
                 resp_buf.phys,
@@ -611,7 +1320,7 @@ impl VirtioGpu {
         addr: u64,
         len: u64,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
         let cmd_size = core::mem::size_of::<VirtioGpuResourceAttachBacking>()
             + core::mem::size_of::<VirtioGpuMemEntry>();
@@ -658,6 +1367,7 @@ impl VirtioGpu {
             };
 
             self.send_command_raw(
+                VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
                 cmd_buf.phys,
                 cmd_size as u32,
                 resp_buf.phys,
@@ -678,7 +1388,7 @@ impl VirtioGpu {
         width: u32,
         height: u32,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
         let cmd_buf_idx = {
             self.alloc_dma_buffer(
@@ -722,6 +1432,7 @@ impl VirtioGpu {
             };
 
             self.send_command_raw(
+                VIRTIO_GPU_CMD_SET_SCANOUT,
                 cmd_buf.phys,
                 cmd_buf.size as u32,
                 resp_buf.phys,
@@ -741,7 +1452,7 @@ impl VirtioGpu {
         width: u32,
         height: u32,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
         let cmd_buf_idx = {
             self.alloc_dma_buffer(
@@ -780,12 +1491,15 @@ impl VirtioGpu {
                     width,
                     height,
                 },
-                offset: 0,
+                // Byte offset of (x, y) in the backing, which has the
+                // framebuffer's stride.
+                offset: ((y * self.width + x) * 4) as u64,
                 resource_id,
                 padding: 0,
             };
 
             self.send_command_raw(
+                VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
                 cmd_buf.phys,
                 cmd_buf.size as u32,
                 resp_buf.phys,
@@ -804,7 +1518,7 @@ impl VirtioGpu {
         width: u32,
         height: u32,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
         let cmd_buf_idx = {
             self.alloc_dma_buffer(
@@ -848,6 +1562,7 @@ impl VirtioGpu {
             };
 
             self.send_command_raw(
+                VIRTIO_GPU_CMD_RESOURCE_FLUSH,
                 cmd_buf.phys,
                 cmd_buf.size as u32,
                 resp_buf.phys,
@@ -860,39 +1575,129 @@ impl VirtioGpu {
 
     fn send_command_raw(
         &mut self,
+        cmd_type: u32,
         cmd_phys: u64,
         cmd_len: u32,
         resp_phys: u64,
         resp_len: u32,
     ) -> Result<(), &'static str> {
-        unsafe {
-            let desc_idx = self.controlq.free_head;
-            if desc_idx >= QUEUE_SIZE {
-                return Err("No free descriptors");
-            }
+        self.send_command_expecting(
+            cmd_type,
+            cmd_phys,
+            cmd_len,
+            resp_phys,
+            resp_len,
+            VIRTIO_GPU_RESP_OK_NODATA,
+        )
+    }
+
+    /// Like `send_command_raw`, for commands whose success response is
+    /// something other than `VIRTIO_GPU_RESP_OK_NODATA`. Spins on the used
+    /// ring, so it works before interrupts are set up; see
+    /// `send_command_async` for the interrupt-driven version.
+    fn send_command_expecting(
+        &mut self,
+        cmd_type: u32,
+        cmd_phys: u64,
+        cmd_len: u32,
+        resp_phys: u64,
+        resp_len: u32,
+        expected_resp: u32,
+    ) -> Result<(), &'static str> {
+        self.submit_command(cmd_phys, cmd_len, resp_phys, resp_len)?;
+
+        let mut timeout = 1000000;
+        while !self.controlq_completed() && timeout > 0 {
+            timeout -= 1;
+            core::hint::spin_loop();
+        }
+        if timeout == 0 {
+            return Err(self.command_timed_out(cmd_type));
+        }
 
-            self.controlq.free_head = (*self.controlq.desc.add(desc_idx as usize)).next;
+        self.finish_command(resp_phys, expected_resp)
+    }
+
+    /// Like `send_command_expecting`, but once `enable_interrupts` has run
+    /// the task sleeps until the device's interrupt says the command is
+    /// done. Before that it falls back to spinning.
+    async fn send_command_async(
+        &mut self,
+        cmd_type: u32,
+        cmd_phys: u64,
+        cmd_len: u32,
+        resp_phys: u64,
+        resp_len: u32,
+        expected_resp: u32,
+    ) -> Result<(), &'static str> {
+        if !self.irq_enabled {
+            return self.send_command_expecting(
+                cmd_type,
+                cmd_phys,
+                cmd_len,
+                resp_phys,
+                resp_len,
+                expected_resp,
+            );
+        }
 
-            (*self.controlq.desc.add(desc_idx as usize)).addr = cmd_phys;
-            (*self.controlq.desc.add(desc_idx as usize)).len = cmd_len;
-            (*self.controlq.desc.add(desc_idx as usize)).flags = 1; // VIRTQ_DESC_F_NEXT
-            (*self.controlq.desc.add(desc_idx as usize)).next = (desc_idx + 1) % QUEUE_SIZE;
+        self.submit_command(cmd_phys, cmd_len, resp_phys, resp_len)?;
 
-            let resp_idx = (desc_idx + 1) % QUEUE_SIZE;
-            if self.controlq.free_head == resp_idx {
-                self.controlq.free_head = (*self.controlq.desc.add(resp_idx as usize)).next;
+        let completion = core::future::poll_fn(|cx| {
+            if self.controlq_completed() {
+                return Poll::Ready(());
+            }
+            CONTROLQ_WAKER.register(cx.waker());
+            if self.controlq_completed() {
+                CONTROLQ_WAKER.take();
+                Poll::Ready(())
+            } else {
+                Poll::Pending
             }
+        });
+        if with_timeout(completion, COMMAND_TIMEOUT).await.is_err() {
+            return Err(self.command_timed_out(cmd_type));
+        }
 
-            (*self.controlq.desc.add(resp_idx as usize)).addr = resp_phys;
-            (*self.controlq.desc.add(resp_idx as usize)).len = resp_len;
-            (*self.controlq.desc.add(resp_idx as usize)).flags = 2; // VIRTQ_DESC_F_WRITE
-            (*self.controlq.desc.add(resp_idx as usize)).next = 0;
+        self.finish_command(resp_phys, expected_resp)
+    }
+
+    /// Puts a command and its response buffer on the control queue and
+    /// notifies the device.
+    fn submit_command(
+        &mut self,
+        cmd_phys: u64,
+        cmd_len: u32,
+        resp_phys: u64,
+        resp_len: u32,
+    ) -> Result<(), &'static str> {
+        if self.needs_reset() {
+            return Err("Device needs reset");
+        }
+        unsafe {
+            let desc_idx = self.controlq.alloc_desc().ok_or("No free descriptors")?;
+            let Some(resp_idx) = self.controlq.alloc_desc() else {
+                self.controlq.free_chain(desc_idx);
+                return Err("No free descriptors");
+            };
+
+            let desc = &mut *self.controlq.desc.add(desc_idx as usize);
+            desc.addr = cmd_phys;
+            desc.len = cmd_len;
+            desc.flags = VIRTQ_DESC_F_NEXT;
+            desc.next = resp_idx;
+
+            let resp = &mut *self.controlq.desc.add(resp_idx as usize);
+            resp.addr = resp_phys;
+            resp.len = resp_len;
+            resp.flags = VIRTQ_DESC_F_WRITE;
+            resp.next = 0;
 
             // Memory barrier before updating available ring
             core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
 
             let avail_idx = (*self.controlq.avail).idx;
-            (*self.controlq.avail).ring[avail_idx as usize] = desc_idx;
+            (*self.controlq.avail).ring[(avail_idx % QUEUE_SIZE) as usize] = desc_idx;
 
             // Memory barrier before notifying device
             core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
@@ -900,21 +1705,51 @@ impl VirtioGpu {
             (*self.controlq.avail).idx = avail_idx.wrapping_add(1);
 
             // Notify the device
-            write_volatile(self.notify_base as *mut u16, 0);
+            write_volatile(self.controlq.notify, self.controlq.index);
+        }
+        Ok(())
+    }
 
-            // Wait for response
-            let start_used = self.controlq.used_idx;
-            let mut timeout = 1000000;
-            while (*self.controlq.used).idx == start_used && timeout > 0 {
-                timeout -= 1;
-                core::hint::spin_loop();
-            }
+    /// Whether the device has used the command `submit_command` put on
+    /// the control queue.
+    fn controlq_completed(&self) -> bool {
+        unsafe { read_volatile(&(*self.controlq.used).idx) != self.controlq.used_idx }
+    }
+
+    /// Logs the state of the queue after `cmd_type` got no answer, gives
+    /// up on the queue and returns the error to report.
+    fn command_timed_out(&mut self, cmd_type: u32) -> &'static str {
+        unsafe {
+            let device_status = self.read_common_u8(VIRTIO_PCI_COMMON_STATUS);
+            let isr_status = read_volatile(self.isr);
+            serial_println!(
+                "Command 0x{:04x} timeout! avail_idx={}, used_idx={} (driver last seen {}), \
+                 device_status=0x{:02x}, isr=0x{:02x}",
+                cmd_type,
+                (*self.controlq.avail).idx,
+                (*self.controlq.used).idx,
+                self.controlq.used_idx,
+                device_status,
+                isr_status
+            );
 
-            if timeout == 0 {
-                serial_println!("Command timeout!");
-                return Err("Timeout");
+            if (device_status & VIRTIO_STATUS_DEVICE_NEEDS_RESET) != 0 {
+                serial_println!("Device set NEEDS_RESET while processing command");
             }
+            self.abandon_controlq(cmd_type);
+        }
+        "Timeout"
+    }
 
+    /// Takes the completed command off the used ring, returns its
+    /// descriptors to the free chain and checks its response type.
+    fn finish_command(&mut self, resp_phys: u64, expected_resp: u32) -> Result<(), &'static str> {
+        unsafe {
+            let slot = (self.controlq.used_idx % QUEUE_SIZE) as usize;
+            let used_elem = &(*self.controlq.used).ring[slot];
+            let (head, used_len) = (read_volatile(&used_elem.id), read_volatile(&used_elem.len));
+            serial_println!("Used element: id={}, len={}", head, used_len);
+            self.controlq.free_chain(head as u16);
             self.controlq.used_idx = self.controlq.used_idx.wrapping_add(1);
 
             // Find the response buffer by searching through DMA buffers
@@ -933,18 +1768,14 @@ impl VirtioGpu {
             }
 
             // Check what the device actually returned
-            let used_elem =
-                &(*self.controlq.used).ring[((*self.controlq.used).idx.wrapping_sub(1)) as usize];
-            serial_println!("Used element: id={}, len={}", used_elem.id, used_elem.len);
-
             let resp_type = (*resp_virt).cmd_type;
             serial_println!(
                 "Response type: 0x{:08x} (expected 0x{:08x})",
                 resp_type,
-                VIRTIO_GPU_RESP_OK_NODATA
+                expected_resp
             );
 
-            if resp_type != VIRTIO_GPU_RESP_OK_NODATA {
+            if resp_type != expected_resp {
                 serial_println!("Command failed with response: 0x{:08x}", resp_type);
                 return Err("Command failed");
             }
@@ -954,90 +1785,72 @@ impl VirtioGpu {
         Ok(())
     }
 
-    fn draw_test_pattern(&mut self) {
+    /// Copies a `width` by `height` block of pixels, stored row after row
+    /// in `src`, into the framebuffer at (`x`, `y`). Whatever falls off the
+    /// edge of the screen is dropped. Like drawing directly, this only
+    /// changes guest memory; `flush_rect` puts it on screen.
+    pub fn blit(
+        &mut self,
+        src: &[u32],
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<(), &'static str> {
         if self.framebuffer.is_null() {
-            return;
+            return Err("GPU framebuffer not set up");
         }
-
-        unsafe {
-            for y in 0..self.height {
-                for x in 0..self.width {
-                    let color = match (x / 128, y / 128) {
-                        (0, 0) => 0xff0000ff,
-                        (1, 0) => 0xff00ff00,
-                        (2, 0) => 0xffff0000,
-                        (3, 0) => 0xffffff00,
-                        (0, 1) => 0xffff00ff,
-                        (1, 1) => 0xff00ffff,
-                        (2, 1) => 0xffffffff,
-                        (3, 1) => 0xff808080,
-                        _ => {
-                            0xff000000
-                                | ((x * 255 / self.width) << 16)
-                                | ((y * 255 / self.height) << 8)
-                        }
-                    };
-                    *self.framebuffer.add((y * self.width + x) as usize) = color;
-                }
-            }
+        if src.len() < width as usize * height as usize {
+            return Err("Blit source smaller than its rectangle");
         }
-        serial_println!("Test pattern drawn to framebuffer");
-    }
-
-    fn map_mmio(
-        &self,
-        phys_addr: u64,
-        size: u64,
-        mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    ) -> Result<*mut u8, &'static str> {
-        const MMIO_BASE: u64 = 0xFFFF_8000_0000_0000;
-        let virt_addr = VirtAddr::new(MMIO_BASE + phys_addr);
-
-        let start_frame: PhysFrame<Size4KiB> =
-            PhysFrame::containing_address(PhysAddr::new(phys_addr));
-        let end_frame: PhysFrame<Size4KiB> =
-            PhysFrame::containing_address(PhysAddr::new(phys_addr + size - 1));
-
-        let mut current_virt = Page::containing_address(virt_addr);
-        let mut current_frame = start_frame;
-
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        let Some((x, y, visible_width, visible_height)) = self.clamp_rect(x, y, width, height)
+        else {
+            return Ok(());
+        };
 
-        loop {
+        for (row, line) in src
+            .chunks_exact(width as usize)
+            .take(visible_height as usize)
+            .enumerate()
+        {
+            let offset = ((y as usize + row) * self.width as usize) + x as usize;
             unsafe {
-                mapper
-                    .map_to(current_virt, current_frame, flags, frame_allocator)
-                    .map_err(|_| "MMIO mapping failed")?
-                    .flush();
-            }
-
-            if current_frame == end_frame {
-                break;
+                core::ptr::copy_nonoverlapping(
+                    line.as_ptr(),
+                    self.framebuffer.add(offset),
+                    visible_width as usize,
+                );
             }
-
-            current_virt = Page::containing_address(current_virt.start_address() + Size4KiB::SIZE);
-            current_frame =
-                PhysFrame::containing_address(current_frame.start_address() + Size4KiB::SIZE);
         }
-
-        Ok(virt_addr.as_mut_ptr())
+        Ok(())
     }
 
-    fn read_pci_config(&self, offset: u8) -> u32 {
-        let address = (1u32 << 31)
-            | ((self.dev.bus as u32) << 16)
-            | ((self.dev.slot as u32) << 11)
-            | ((self.dev.func as u32) << 8)
-            | ((offset & 0xFC) as u32);
+    /// Fills the screen with colour blocks over a gradient, building each
+    /// row in memory and blitting it.
+    fn draw_test_pattern(&mut self) {
+        if self.framebuffer.is_null() {
+            return;
+        }
 
-        use x86_64::instructions::port::Port;
-        unsafe {
-            let mut addr_port = Port::<u32>::new(0xCF8);
-            let mut data_port = Port::<u32>::new(0xCFC);
-            addr_port.write(address);
-            data_port.read()
+        let mut line = Vec::with_capacity(self.width as usize);
+        for y in 0..self.height {
+            line.clear();
+            line.extend((0..self.width).map(|x| match (x / 128, y / 128) {
+                (0, 0) => 0xff0000ff,
+                (1, 0) => 0xff00ff00,
+                (2, 0) => 0xffff0000,
+                (3, 0) => 0xffffff00,
+                (0, 1) => 0xffff00ff,
+                (1, 1) => 0xff00ffff,
+                (2, 1) => 0xffffffff,
+                (3, 1) => 0xff808080,
+                _ => 0xff000000 | ((x * 255 / self.width) << 16) | ((y * 255 / self.height) << 8),
+            }));
+            if self.blit(&line, 0, y, self.width, 1).is_err() {
+                return;
+            }
         }
+        serial_println!("Test pattern drawn to framebuffer");
     }
 
     unsafe fn write_common_u8(&self, offset: usize, value: u8) {
@@ -1067,10 +1880,173 @@ impl VirtioGpu {
     pub fn refresh_display(
         &mut self,
         mapper: &mut OffsetPageTable,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
     ) -> Result<(), &'static str> {
-        self.transfer_to_host_2d(1, 0, 0, self.width, self.height, mapper, frame_allocator)?;
-        self.resource_flush(1, 0, 0, self.width, self.height, mapper, frame_allocator)?;
+        self.refresh_rect(0, 0, self.width, self.height, mapper, frame_allocator)
+    }
+
+    /// Like `refresh_display`, but only transfers and flushes the given
+    /// rectangle, clamped to the framebuffer. Empty rectangles are a no-op.
+    pub fn refresh_rect(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<(), &'static str> {
+        let Some((x, y, width, height)) = self.clamp_rect(x, y, width, height) else {
+            return Ok(());
+        };
+        self.transfer_to_host_2d(1, x, y, width, height, mapper, frame_allocator)?;
+        self.resource_flush(1, x, y, width, height, mapper, frame_allocator)?;
+        Ok(())
+    }
+
+    fn clamp_rect(&self, x: u32, y: u32, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let width = width.min(self.width - x);
+        let height = height.min(self.height - y);
+        (width > 0 && height > 0).then_some((x, y, width, height))
+    }
+
+    /// Transfers the given framebuffer rectangle to the host resource and
+    /// flushes it to the scanout. Unlike `refresh_display` this reuses the
+    /// command buffers set aside in `init`, so it needs no page mapping.
+    pub fn flush_rect(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<(), &'static str> {
+        let Some((transfer, flush)) = self.flush_commands(x, y, width, height) else {
+            return Ok(());
+        };
+        self.submit_prealloc(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, transfer)?;
+        self.submit_prealloc(VIRTIO_GPU_CMD_RESOURCE_FLUSH, flush)
+    }
+
+    /// Like `flush_rect`, but sleeps on the device's interrupt while each
+    /// command runs once `enable_interrupts` has been called.
+    pub async fn flush_rect_async(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<(), &'static str> {
+        let Some((transfer, flush)) = self.flush_commands(x, y, width, height) else {
+            return Ok(());
+        };
+        self.submit_prealloc_async(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, transfer)
+            .await?;
+        self.submit_prealloc_async(VIRTIO_GPU_CMD_RESOURCE_FLUSH, flush)
+            .await
+    }
+
+    /// The two commands that put a framebuffer rectangle on screen, or
+    /// `None` if it is empty after clamping.
+    fn flush_commands(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> Option<(VirtioGpuTransferToHost2d, VirtioGpuResourceFlush)> {
+        let (x, y, width, height) = self.clamp_rect(x, y, width, height)?;
+        let r = || VirtioGpuRect {
+            x,
+            y,
+            width,
+            height,
+        };
+        Some((
+            VirtioGpuTransferToHost2d {
+                hdr: ctrl_hdr(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+                r: r(),
+                offset: ((y * self.width + x) * 4) as u64,
+                resource_id: FRAMEBUFFER_RESOURCE,
+                padding: 0,
+            },
+            VirtioGpuResourceFlush {
+                hdr: ctrl_hdr(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+                r: r(),
+                resource_id: FRAMEBUFFER_RESOURCE,
+                padding: 0,
+            },
+        ))
+    }
+
+    /// Sends `cmd` on the control queue through the command buffers set
+    /// aside in `init`.
+    fn submit_prealloc<T>(&mut self, cmd_type: u32, cmd: T) -> Result<(), &'static str> {
+        let (cmd_phys, resp_phys) = self.write_prealloc(cmd)?;
+        self.send_command_raw(
+            cmd_type,
+            cmd_phys,
+            core::mem::size_of::<T>() as u32,
+            resp_phys,
+            core::mem::size_of::<VirtioGpuCtrlHdr>() as u32,
+        )
+    }
+
+    /// `submit_prealloc` through `send_command_async`.
+    async fn submit_prealloc_async<T>(
+        &mut self,
+        cmd_type: u32,
+        cmd: T,
+    ) -> Result<(), &'static str> {
+        let (cmd_phys, resp_phys) = self.write_prealloc(cmd)?;
+        self.send_command_async(
+            cmd_type,
+            cmd_phys,
+            core::mem::size_of::<T>() as u32,
+            resp_phys,
+            core::mem::size_of::<VirtioGpuCtrlHdr>() as u32,
+            VIRTIO_GPU_RESP_OK_NODATA,
+        )
+        .await
+    }
+
+    /// Copies `cmd` into the preallocated command buffer and returns the
+    /// physical addresses of it and the response buffer.
+    fn write_prealloc<T>(&mut self, cmd: T) -> Result<(u64, u64), &'static str> {
+        let (cmd_idx, resp_idx) = self.flush_bufs.ok_or("GPU not initialized")?;
+        let cmd_buf = &self.dma_buffers[cmd_idx];
+        unsafe { write_volatile(cmd_buf.virt as *mut T, cmd) };
+        Ok((cmd_buf.phys, self.dma_buffers[resp_idx].phys))
+    }
+
+    /// Sends 100 full-screen `RESOURCE_FLUSH` commands, several times more
+    /// than the control queue has descriptors, and checks each succeeds
+    /// and gives both its descriptors back.
+    pub fn test_descriptor_recycling(&mut self) -> Result<(), &'static str> {
+        const COMMANDS: usize = 100;
+
+        if self.controlq.desc.is_null() {
+            return Err("GPU not initialized");
+        }
+        let free_before = unsafe { self.controlq.free_count() };
+        for _ in 0..COMMANDS {
+            let (_, flush) = self
+                .flush_commands(0, 0, self.width, self.height)
+                .ok_or("GPU has an empty framebuffer")?;
+            self.submit_prealloc(VIRTIO_GPU_CMD_RESOURCE_FLUSH, flush)?;
+            if unsafe { self.controlq.free_count() } != free_before {
+                return Err("flush did not return its descriptors");
+            }
+        }
+
+        serial_println!(
+            "✓ {} flushes completed with {} of {} descriptors free",
+            COMMANDS,
+            free_before,
+            QUEUE_SIZE
+        );
         Ok(())
     }
 
@@ -0,0 +1,517 @@
+use super::virtio_gpu::{
+    alloc_dma, DmaBuffer, Virtq, VirtqAvail, VirtqDesc, VirtqUsed, QUEUE_SIZE,
+    VIRTIO_PCI_CAP_COMMON_CFG, VIRTIO_PCI_CAP_DEVICE_CFG, VIRTIO_PCI_CAP_ISR_CFG,
+    VIRTIO_PCI_CAP_NOTIFY_CFG, VIRTIO_PCI_COMMON_DF, VIRTIO_PCI_COMMON_DFSELECT,
+    VIRTIO_PCI_COMMON_GF, VIRTIO_PCI_COMMON_GFSELECT, VIRTIO_PCI_COMMON_Q_AVAILHI,
+    VIRTIO_PCI_COMMON_Q_AVAILLO, VIRTIO_PCI_COMMON_Q_DESCHI, VIRTIO_PCI_COMMON_Q_DESCLO,
+    VIRTIO_PCI_COMMON_Q_ENABLE, VIRTIO_PCI_COMMON_Q_NOFF, VIRTIO_PCI_COMMON_Q_SELECT,
+    VIRTIO_PCI_COMMON_Q_SIZE, VIRTIO_PCI_COMMON_Q_USEDHI, VIRTIO_PCI_COMMON_Q_USEDLO,
+    VIRTIO_PCI_COMMON_STATUS, VIRTIO_STATUS_ACKNOWLEDGE, VIRTIO_STATUS_DRIVER,
+    VIRTIO_STATUS_DRIVER_OK, VIRTIO_STATUS_FEATURES_OK,
+};
+use crate::drivers::pci::PciDevice;
+use crate::memory::ContiguousFrameAllocator;
+use crate::serial_println;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, AtomicPtr, Ordering};
+use core::task::Poll;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::structures::paging::OffsetPageTable;
+
+/// Device has a MAC address in its config space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// Modern (virtio 1.0) device; required for the layout used here.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// Each queue slot gets a fixed buffer this big: header plus a full
+/// Ethernet frame, rounded up.
+const PACKET_BUF_SIZE: usize = 2048;
+/// Largest frame without FCS that `send` accepts.
+pub const MAX_FRAME_SIZE: usize = 1514;
+/// Smallest frame `send` accepts: just the Ethernet header.
+pub const MIN_FRAME_SIZE: usize = 14;
+
+/// Header in front of every packet. With `VIRTIO_F_VERSION_1` it always
+/// includes `num_buffers`, even without mergeable RX buffers.
+#[repr(C)]
+#[derive(Default)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    num_buffers: u16,
+}
+
+const HDR_LEN: usize = core::mem::size_of::<VirtioNetHdr>();
+
+/// The installed device, if any.
+static NET: Mutex<Option<VirtioNet>> = Mutex::new(None);
+/// The device's ISR status byte, read by the IRQ handler without taking
+/// the `NET` lock.
+static ISR: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
+/// Woken when the device reports used buffers.
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// A virtio-net device with one RX and one TX queue. Every descriptor owns
+/// a fixed `PACKET_BUF_SIZE` slot of a DMA buffer, so nothing is allocated
+/// per packet on the DMA side.
+pub struct VirtioNet {
+    dev: PciDevice,
+    common_cfg: *mut u8,
+    notify_base: *mut u8,
+    device_cfg: *mut u8,
+    isr: *mut u8,
+    notify_off_multiplier: u32,
+    rxq: Virtq,
+    txq: Virtq,
+    rx_bufs: Option<DmaBuffer>,
+    tx_bufs: Option<DmaBuffer>,
+    /// TX slots the device hasn't handed back yet.
+    tx_busy: [bool; QUEUE_SIZE as usize],
+    mac: [u8; 6],
+}
+
+unsafe impl Send for VirtioNet {}
+
+impl VirtioNet {
+    pub fn new(dev: PciDevice) -> Self {
+        Self {
+            dev,
+            common_cfg: core::ptr::null_mut(),
+            notify_base: core::ptr::null_mut(),
+            device_cfg: core::ptr::null_mut(),
+            isr: core::ptr::null_mut(),
+            notify_off_multiplier: 0,
+            rxq: Virtq::empty(),
+            txq: Virtq::empty(),
+            rx_bufs: None,
+            tx_bufs: None,
+            tx_busy: [false; QUEUE_SIZE as usize],
+            mac: [0; 6],
+        }
+    }
+
+    pub fn init(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<(), &'static str> {
+        self.dev.enable();
+        self.map_capabilities(mapper, frame_allocator)?;
+        let features = self.negotiate_features()?;
+        if features & VIRTIO_NET_F_MAC != 0 {
+            for (i, byte) in self.mac.iter_mut().enumerate() {
+                *byte = unsafe { read_volatile(self.device_cfg.add(i)) };
+            }
+        }
+
+        self.rxq = self.setup_queue(RX_QUEUE, mapper, frame_allocator)?;
+        self.txq = self.setup_queue(TX_QUEUE, mapper, frame_allocator)?;
+        let rx_bufs = alloc_dma(
+            QUEUE_SIZE as usize * PACKET_BUF_SIZE,
+            mapper,
+            frame_allocator,
+        )?;
+        let tx_bufs = alloc_dma(
+            QUEUE_SIZE as usize * PACKET_BUF_SIZE,
+            mapper,
+            frame_allocator,
+        )?;
+
+        // Hand every RX slot to the device up front.
+        unsafe {
+            for i in 0..QUEUE_SIZE {
+                let desc = &mut *self.rxq.desc.add(i as usize);
+                desc.addr = rx_bufs.phys + i as u64 * PACKET_BUF_SIZE as u64;
+                desc.len = PACKET_BUF_SIZE as u32;
+                desc.flags = VIRTQ_DESC_F_WRITE;
+                desc.next = 0;
+                (*self.rxq.avail).ring[i as usize] = i;
+            }
+            fence(Ordering::SeqCst);
+            write_volatile(&mut (*self.rxq.avail).idx, QUEUE_SIZE);
+        }
+        self.rx_bufs = Some(rx_bufs);
+        self.tx_bufs = Some(tx_bufs);
+
+        unsafe {
+            self.write_common_u8(
+                VIRTIO_PCI_COMMON_STATUS,
+                VIRTIO_STATUS_ACKNOWLEDGE
+                    | VIRTIO_STATUS_DRIVER
+                    | VIRTIO_STATUS_FEATURES_OK
+                    | VIRTIO_STATUS_DRIVER_OK,
+            );
+            write_volatile(self.rxq.notify, self.rxq.index);
+        }
+
+        serial_println!(
+            "VirtIO-net ready, MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            self.mac[0],
+            self.mac[1],
+            self.mac[2],
+            self.mac[3],
+            self.mac[4],
+            self.mac[5]
+        );
+        Ok(())
+    }
+
+    pub fn mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    /// Walks the vendor capabilities and maps the BARs holding the common,
+    /// notify, ISR and device config structures.
+    fn map_capabilities(
+        &mut self,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<(), &'static str> {
+        let mut current = (self.dev.read_config(0x34) & 0xFC) as u8;
+        // Same bound as `find_capability`: at most 48 entries fit.
+        for _ in 0..48 {
+            if current == 0 {
+                break;
+            }
+            let cap_data = self.dev.read_config(current);
+            let next = ((cap_data >> 8) & 0xFC) as u8;
+
+            if (cap_data & 0xFF) as u8 == super::PCI_CAP_ID_VENDOR {
+                let cfg_type = ((cap_data >> 24) & 0xFF) as u8;
+                let bar_index = (self.dev.read_config(current + 4) & 0xFF) as usize;
+                let offset = self.dev.read_config(current + 8) as usize;
+                if let Some(bar) = self.dev.get_bar(bar_index) {
+                    let base = super::map_mmio(bar.address, bar.size, mapper, frame_allocator)?;
+                    let ptr = unsafe { base.add(offset) };
+                    match cfg_type {
+                        VIRTIO_PCI_CAP_COMMON_CFG => self.common_cfg = ptr,
+                        VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                            self.notify_base = ptr;
+                            self.notify_off_multiplier = self.dev.read_config(current + 16);
+                        }
+                        VIRTIO_PCI_CAP_ISR_CFG => self.isr = ptr,
+                        VIRTIO_PCI_CAP_DEVICE_CFG => self.device_cfg = ptr,
+                        _ => {}
+                    }
+                }
+            }
+            current = next;
+        }
+
+        if self.common_cfg.is_null() || self.notify_base.is_null() || self.isr.is_null() {
+            return Err("VirtIO-net is missing modern PCI capabilities");
+        }
+        Ok(())
+    }
+
+    /// Resets the device and accepts only `VIRTIO_F_VERSION_1` and
+    /// `VIRTIO_NET_F_MAC`, which keeps the packet header fixed at 12 bytes.
+    fn negotiate_features(&mut self) -> Result<u64, &'static str> {
+        unsafe {
+            self.write_common_u8(VIRTIO_PCI_COMMON_STATUS, 0);
+            self.write_common_u8(VIRTIO_PCI_COMMON_STATUS, VIRTIO_STATUS_ACKNOWLEDGE);
+            self.write_common_u8(
+                VIRTIO_PCI_COMMON_STATUS,
+                VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER,
+            );
+
+            self.write_common_u32(VIRTIO_PCI_COMMON_DFSELECT, 0);
+            let low = self.read_common_u32(VIRTIO_PCI_COMMON_DF) as u64;
+            self.write_common_u32(VIRTIO_PCI_COMMON_DFSELECT, 1);
+            let high = self.read_common_u32(VIRTIO_PCI_COMMON_DF) as u64;
+            let offered = low | (high << 32);
+            if offered & VIRTIO_F_VERSION_1 == 0 {
+                return Err("VirtIO-net device is legacy-only");
+            }
+
+            let accepted = offered & (VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC);
+            self.write_common_u32(VIRTIO_PCI_COMMON_GFSELECT, 0);
+            self.write_common_u32(VIRTIO_PCI_COMMON_GF, accepted as u32);
+            self.write_common_u32(VIRTIO_PCI_COMMON_GFSELECT, 1);
+            self.write_common_u32(VIRTIO_PCI_COMMON_GF, (accepted >> 32) as u32);
+
+            self.write_common_u8(
+                VIRTIO_PCI_COMMON_STATUS,
+                VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER | VIRTIO_STATUS_FEATURES_OK,
+            );
+            if self.read_common_u8(VIRTIO_PCI_COMMON_STATUS) & VIRTIO_STATUS_FEATURES_OK == 0 {
+                return Err("Features not OK");
+            }
+            Ok(accepted)
+        }
+    }
+
+    fn setup_queue(
+        &mut self,
+        index: u16,
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl ContiguousFrameAllocator,
+    ) -> Result<Virtq, &'static str> {
+        unsafe {
+            self.write_common_u16(VIRTIO_PCI_COMMON_Q_SELECT, index);
+            if self.read_common_u16(VIRTIO_PCI_COMMON_Q_SIZE) < QUEUE_SIZE {
+                return Err("VirtIO-net queue too small");
+            }
+            self.write_common_u16(VIRTIO_PCI_COMMON_Q_SIZE, QUEUE_SIZE);
+
+            // The rings live for as long as the device, so their buffers
+            // are never freed.
+            let desc_buf = alloc_dma(4096, mapper, frame_allocator)?;
+            let avail_buf = alloc_dma(4096, mapper, frame_allocator)?;
+            let used_buf = alloc_dma(4096, mapper, frame_allocator)?;
+
+            let notify_off = self.read_common_u16(VIRTIO_PCI_COMMON_Q_NOFF) as usize;
+            let queue = Virtq {
+                desc: desc_buf.virt as *mut VirtqDesc,
+                avail: avail_buf.virt as *mut VirtqAvail,
+                used: used_buf.virt as *mut VirtqUsed,
+                desc_phys: desc_buf.phys,
+                avail_phys: avail_buf.phys,
+                used_phys: used_buf.phys,
+                free_head: 0,
+                used_idx: 0,
+                notify: self
+                    .notify_base
+                    .add(notify_off * self.notify_off_multiplier as usize)
+                    as *mut u16,
+                index,
+            };
+
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_DESCLO, queue.desc_phys as u32);
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_DESCHI, (queue.desc_phys >> 32) as u32);
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_AVAILLO, queue.avail_phys as u32);
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_AVAILHI, (queue.avail_phys >> 32) as u32);
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_USEDLO, queue.used_phys as u32);
+            self.write_common_u32(VIRTIO_PCI_COMMON_Q_USEDHI, (queue.used_phys >> 32) as u32);
+            self.write_common_u16(VIRTIO_PCI_COMMON_Q_ENABLE, 1);
+
+            Ok(queue)
+        }
+    }
+
+    /// Marks TX slots the device has finished with as free again.
+    fn reclaim_tx(&mut self) {
+        unsafe {
+            let used_idx = read_volatile(&(*self.txq.used).idx);
+            while self.txq.used_idx != used_idx {
+                let slot = self.txq.used_idx % QUEUE_SIZE;
+                let id = read_volatile(&(*self.txq.used).ring[slot as usize].id) as usize;
+                if let Some(busy) = self.tx_busy.get_mut(id) {
+                    *busy = false;
+                }
+                self.txq.used_idx = self.txq.used_idx.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Queues one Ethernet frame (without FCS) for transmission. Returns
+    /// once the device has been notified, not when the frame is on the wire.
+    pub fn send(&mut self, frame: &[u8]) -> Result<(), &'static str> {
+        if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&frame.len()) {
+            return Err("Frame size out of range");
+        }
+        let tx_bufs = self.tx_bufs.as_ref().ok_or("VirtIO-net not initialized")?;
+        let (tx_virt, tx_phys) = (tx_bufs.virt, tx_bufs.phys);
+
+        self.reclaim_tx();
+        let slot = self
+            .tx_busy
+            .iter()
+            .position(|busy| !busy)
+            .ok_or("Transmit queue full")?;
+        self.tx_busy[slot] = true;
+
+        unsafe {
+            let buf = tx_virt.add(slot * PACKET_BUF_SIZE);
+            write_volatile(buf as *mut VirtioNetHdr, VirtioNetHdr::default());
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buf.add(HDR_LEN), frame.len());
+
+            let desc = &mut *self.txq.desc.add(slot);
+            desc.addr = tx_phys + (slot * PACKET_BUF_SIZE) as u64;
+            desc.len = (HDR_LEN + frame.len()) as u32;
+            desc.flags = 0;
+            desc.next = 0;
+
+            let avail_idx = read_volatile(&(*self.txq.avail).idx);
+            (*self.txq.avail).ring[(avail_idx % QUEUE_SIZE) as usize] = slot as u16;
+            fence(Ordering::SeqCst);
+            write_volatile(&mut (*self.txq.avail).idx, avail_idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            write_volatile(self.txq.notify, self.txq.index);
+        }
+        Ok(())
+    }
+
+    /// Takes the next received frame, if one is waiting, and gives its
+    /// buffer back to the device.
+    pub fn try_recv(&mut self) -> Option<Vec<u8>> {
+        let rx_virt = self.rx_bufs.as_ref()?.virt;
+        unsafe {
+            if read_volatile(&(*self.rxq.used).idx) == self.rxq.used_idx {
+                return None;
+            }
+            let slot = self.rxq.used_idx % QUEUE_SIZE;
+            let elem = &(*self.rxq.used).ring[slot as usize];
+            let id = read_volatile(&elem.id) as u16;
+            let len = (read_volatile(&elem.len) as usize).min(PACKET_BUF_SIZE);
+            self.rxq.used_idx = self.rxq.used_idx.wrapping_add(1);
+
+            let buf = rx_virt.add(id as usize * PACKET_BUF_SIZE);
+            let frame =
+                core::slice::from_raw_parts(buf.add(HDR_LEN), len.saturating_sub(HDR_LEN)).to_vec();
+
+            let avail_idx = read_volatile(&(*self.rxq.avail).idx);
+            (*self.rxq.avail).ring[(avail_idx % QUEUE_SIZE) as usize] = id;
+            fence(Ordering::SeqCst);
+            write_volatile(&mut (*self.rxq.avail).idx, avail_idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            write_volatile(self.rxq.notify, self.rxq.index);
+
+            Some(frame)
+        }
+    }
+
+    unsafe fn write_common_u8(&self, offset: usize, value: u8) {
+        write_volatile(self.common_cfg.add(offset), value);
+    }
+
+    unsafe fn write_common_u16(&self, offset: usize, value: u16) {
+        write_volatile(self.common_cfg.add(offset) as *mut u16, value);
+    }
+
+    unsafe fn write_common_u32(&self, offset: usize, value: u32) {
+        write_volatile(self.common_cfg.add(offset) as *mut u32, value);
+    }
+
+    unsafe fn read_common_u8(&self, offset: usize) -> u8 {
+        read_volatile(self.common_cfg.add(offset))
+    }
+
+    unsafe fn read_common_u16(&self, offset: usize) -> u16 {
+        read_volatile(self.common_cfg.add(offset) as *const u16)
+    }
+
+    unsafe fn read_common_u32(&self, offset: usize) -> u32 {
+        read_volatile(self.common_cfg.add(offset) as *const u32)
+    }
+}
+
+/// Makes `net` the device behind `send` and `recv` and hooks its legacy
+/// INTx line, so `recv` wakes when frames arrive.
+pub fn install(net: VirtioNet) -> Result<(), &'static str> {
+    let irq = (net.dev.read_config(0x3C) & 0xFF) as u8;
+    ISR.store(net.isr, Ordering::SeqCst);
+    *NET.lock() = Some(net);
+    crate::interrupts::register_irq(irq, handle_irq)?;
+    serial_println!("VirtIO-net using IRQ {}", irq);
+    Ok(())
+}
+
+/// Reading the ISR status acknowledges the interrupt. The line may be
+/// shared, so only wake when this device raised it.
+fn handle_irq() {
+    let isr = ISR.load(Ordering::SeqCst);
+    if !isr.is_null() && unsafe { read_volatile(isr) } & 1 != 0 {
+        RX_WAKER.wake();
+    }
+}
+
+/// The installed device's MAC address.
+pub fn mac_address() -> Option<[u8; 6]> {
+    NET.lock().as_ref().map(VirtioNet::mac)
+}
+
+/// Sends one Ethernet frame through the installed device.
+pub fn send(frame: &[u8]) -> Result<(), &'static str> {
+    NET.lock()
+        .as_mut()
+        .ok_or("No VirtIO-net device installed")?
+        .send(frame)
+}
+
+/// Waits for the next received Ethernet frame. Only one task should wait
+/// at a time; a second waiter replaces the first's wake-up.
+pub async fn recv() -> Vec<u8> {
+    core::future::poll_fn(|cx| {
+        let try_recv = || NET.lock().as_mut().and_then(VirtioNet::try_recv);
+        if let Some(frame) = try_recv() {
+            return Poll::Ready(frame);
+        }
+        RX_WAKER.register(cx.waker());
+        match try_recv() {
+            Some(frame) => Poll::Ready(frame),
+            None => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Asks QEMU's user-mode network for the gateway's MAC with an ARP request
+/// from the guest's usual address and waits for the reply. Run with
+/// `-netdev user,id=net0 -device virtio-net-pci,netdev=net0`.
+pub async fn test_arp_echo() -> Result<(), &'static str> {
+    use crate::task::timeout::with_timeout;
+    use core::time::Duration;
+
+    const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+    const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+    serial_println!("=== VirtIO-net ARP Test ===");
+    let mac = mac_address().ok_or("No VirtIO-net device installed")?;
+
+    let mut request = Vec::with_capacity(42);
+    request.extend_from_slice(&[0xFF; 6]);
+    request.extend_from_slice(&mac);
+    request.extend_from_slice(&[0x08, 0x06]); // ARP
+    request.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01]);
+    request.extend_from_slice(&mac);
+    request.extend_from_slice(&GUEST_IP);
+    request.extend_from_slice(&[0; 6]);
+    request.extend_from_slice(&GATEWAY_IP);
+    send(&request)?;
+
+    let reply = with_timeout(
+        async {
+            loop {
+                let frame = recv().await;
+                // An ARP reply (opcode 2) from the gateway.
+                if frame.len() >= 42
+                    && frame[12..14] == [0x08, 0x06]
+                    && frame[20..22] == [0x00, 0x02]
+                    && frame[28..32] == GATEWAY_IP
+                {
+                    return frame;
+                }
+            }
+        },
+        Duration::from_secs(2),
+    )
+    .await
+    .map_err(|_| "no ARP reply from the gateway")?;
+
+    serial_println!(
+        "✓ Gateway {}.{}.{}.{} is at {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        GATEWAY_IP[0],
+        GATEWAY_IP[1],
+        GATEWAY_IP[2],
+        GATEWAY_IP[3],
+        reply[22],
+        reply[23],
+        reply[24],
+        reply[25],
+        reply[26],
+        reply[27]
+    );
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use embedded_sdmmc::Timestamp;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOURS_PM: u8 = 1 << 7;
+
+/// Raw register values, still in whatever format the RTC is set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+fn read_register(reg: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        // Keep bit 7 set so NMIs stay disabled, as the BIOS leaves them.
+        address.write(0x80 | reg);
+        data.read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Reads the current wall-clock time from the CMOS RTC. The RTC has no
+/// reliable century register, so years are taken to be 20xx.
+pub fn now() -> Timestamp {
+    // An update can still start between the flag check and the reads, so
+    // read until two passes agree.
+    let mut raw = read_raw();
+    loop {
+        let again = read_raw();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status_b = read_register(REG_STATUS_B);
+    let pm = raw.hour & HOURS_PM != 0;
+    let mut hour = raw.hour & !HOURS_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        raw.second = bcd_to_binary(raw.second);
+        raw.minute = bcd_to_binary(raw.minute);
+        hour = bcd_to_binary(hour);
+        raw.day = bcd_to_binary(raw.day);
+        raw.month = bcd_to_binary(raw.month);
+        raw.year = bcd_to_binary(raw.year);
+    }
+
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12-hour mode: 12 AM is midnight, 12 PM is noon.
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    Timestamp {
+        year_since_1970: raw.year + 30,
+        zero_indexed_month: raw.month.saturating_sub(1),
+        zero_indexed_day: raw.day.saturating_sub(1),
+        hours: hour,
+        minutes: raw.minute,
+        seconds: raw.second,
+    }
+}
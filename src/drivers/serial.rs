@@ -1,28 +1,216 @@
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
 use lazy_static::lazy_static;
 use spin::Mutex;
-use uart_16550::SerialPort;
+use x86_64::instructions::{interrupts, port::Port};
+
+pub const COM1: u16 = 0x3F8;
+pub const COM2: u16 = 0x2F8;
+
+/// Baud rate the ports come up at; divisor 3 off the 115200 base clock.
+pub const DEFAULT_BAUD: u32 = 38_400;
+const UART_BASE_BAUD: u32 = 115_200;
+
+const REG_DATA: u16 = 0;
+const REG_IER: u16 = 1;
+const REG_IIR_FCR: u16 = 2;
+const REG_LCR: u16 = 3;
+const REG_MCR: u16 = 4;
+const REG_LSR: u16 = 5;
+
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 1 << 7;
+/// Enable and clear both FIFOs, interrupt at 14 bytes.
+const FCR_ENABLE_FIFOS: u8 = 0xC7;
+/// DTR, RTS and OUT2, which gates the IRQ line on PCs.
+const MCR_DTR_RTS_OUT2: u8 = 0x0B;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+static INPUT_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// A 16550-compatible UART.
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Programs the UART at `base` for `baud` 8N1 with FIFOs on. The divisor
+    /// is `115200 / baud`, so rates that don't divide evenly round down to
+    /// the next faster supported one.
+    ///
+    /// # Safety
+    /// `base` must be the I/O base of a 16550-compatible UART.
+    pub unsafe fn new(base: u16, baud: u32) -> Self {
+        let port = SerialPort { base };
+        let divisor = (UART_BASE_BAUD / baud.max(1)).clamp(1, u16::MAX as u32) as u16;
+        unsafe {
+            port.write_reg(REG_IER, 0);
+            port.write_reg(REG_LCR, LCR_DLAB);
+            port.write_reg(REG_DATA, divisor as u8);
+            port.write_reg(REG_IER, (divisor >> 8) as u8);
+            port.write_reg(REG_LCR, LCR_8N1);
+            port.write_reg(REG_IIR_FCR, FCR_ENABLE_FIFOS);
+            port.write_reg(REG_MCR, MCR_DTR_RTS_OUT2);
+        }
+        port
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    unsafe fn write_reg(&self, reg: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + reg).write(value) };
+    }
+
+    unsafe fn read_reg(&self, reg: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + reg).read() }
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.read_reg(REG_LSR) & LSR_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.write_reg(REG_DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    pub static ref SERIAL1: Mutex<SerialPort> =
+        Mutex::new(unsafe { SerialPort::new(COM1, DEFAULT_BAUD) });
+    pub static ref SERIAL2: Mutex<SerialPort> =
+        Mutex::new(unsafe { SerialPort::new(COM2, DEFAULT_BAUD) });
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
+    _print_on(&SERIAL1, args);
+}
+
+/// Prints one line to COM1 prefixed with the uptime, as `[secs.millis]`.
+#[doc(hidden)]
+pub fn _println(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
+    let ms = crate::timer::uptime_ms();
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
+        let mut port = SERIAL1.lock();
+        write!(port, "[{:5}.{:03}] {}\n", ms / 1000, ms % 1000, args)
+            .expect("Printing to serial failed");
+    });
+}
+
+/// Frees COM1 if something died holding it, so the panic handler can
+/// still print. Output from another CPU may interleave with the report.
+///
+/// # Safety
+///
+/// Only for the panic path; whoever held the lock must never run again.
+pub unsafe fn force_unlock() {
+    unsafe { SERIAL1.force_unlock() };
+}
+
+#[doc(hidden)]
+pub fn _print_on(port: &Mutex<SerialPort>, args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    interrupts::without_interrupts(|| {
+        port.lock()
             .write_fmt(args)
             .expect("Printing to serial failed");
     });
 }
 
+const COM1_IRQ: u8 = 4;
+
+/// Turns on the COM1 received-data interrupt and registers for IRQ 4, so
+/// input shows up on `SerialStream`.
+pub fn enable_input() {
+    lazy_static::initialize(&SERIAL1);
+    interrupts::without_interrupts(|| unsafe {
+        SERIAL1.lock().write_reg(REG_IER, IER_RECEIVED_DATA);
+    });
+    crate::interrupts::register_irq(COM1_IRQ, handle_irq).expect("IRQ 4 is a valid line");
+}
+
+/// IRQ 4 handler. Drains every byte the UART is holding,
+/// which is also what clears the interrupt.
+fn handle_irq() {
+    let mut lsr: Port<u8> = Port::new(COM1 + REG_LSR);
+    let mut data: Port<u8> = Port::new(COM1 + REG_DATA);
+    unsafe {
+        // Reading IIR acknowledges whichever condition raised the IRQ.
+        Port::<u8>::new(COM1 + REG_IIR_FCR).read();
+        while lsr.read() & LSR_DATA_READY != 0 {
+            let byte = data.read();
+            if let Ok(queue) = INPUT_QUEUE.try_get() {
+                if queue.push(byte).is_ok() {
+                    WAKER.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Bytes received on COM1. Input that arrives before the stream is created
+/// is dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        INPUT_QUEUE
+            .try_init_once(|| ArrayQueue::new(1024))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = INPUT_QUEUE
+            .try_get()
+            .expect("serial input queue not initialized");
+
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => {
@@ -30,10 +218,25 @@ macro_rules! serial_print {
     };
 }
 
+/// Like `serial_print!`, but writes to the given `Mutex<SerialPort>`, e.g.
+/// `serial_print_on!(SERIAL2, ...)`.
+#[macro_export]
+macro_rules! serial_print_on {
+    ($port:expr, $($arg:tt)*) => {
+        $crate::serial::_print_on(&$port, format_args!($($arg)*));
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println_on {
+    ($port:expr) => ($crate::serial_print_on!($port, "\n"));
+    ($port:expr, $fmt:expr) => ($crate::serial_print_on!($port, concat!($fmt, "\n")));
+    ($port:expr, $fmt:expr, $($arg:tt)*) => ($crate::serial_print_on!(
+        $port, concat!($fmt, "\n"), $($arg)*));
+}
+
 #[macro_export]
 macro_rules! serial_println {
     () => ($crate::serial_print!("\n"));
-    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
-        concat!($fmt, "\n"), $($arg)*));
+    ($($arg:tt)*) => ($crate::serial::_println(format_args!($($arg)*)));
 }
@@ -1,31 +1,331 @@
-use crate::task::keyboard::read_line;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::task::keyboard::{read_key, KeyEvent};
 use crate::{print, println};
 
-pub async fn shell() {
-    let mut buf = [0u8; 1024];
-
-    let mut i = 0;
-    loop {
-        let c = read_line().await.unwrap();
-        match c {
-            '\n' | '\r' => {
-                println!();
-                break;
-            }
-            '\x08' => {
-                if i > 0 {
-                    i -= 1;
-                    print!("\x08");
+const MAX_LINE: usize = 1024;
+/// Commands kept for Up/Down recall.
+const HISTORY_LEN: usize = 32;
+/// How much of a screenshot goes to the filesystem per write.
+const SCREENSHOT_CHUNK: usize = 32 * 1024;
+
+/// Handler for a shell command. `args` excludes the command name.
+pub type CommandFn = fn(shell: &Shell, args: &[&str]);
+
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: CommandFn,
+}
+
+/// Line-oriented shell with a table of commands that subsystems register
+/// into.
+pub struct Shell {
+    prompt: &'static str,
+    commands: Vec<Command>,
+    history: VecDeque<String>,
+}
+
+impl Shell {
+    /// A shell with only the built-in commands (help, echo, clear/cls,
+    /// theme, screenshot, reboot, halt, shutdown, history, uptime).
+    pub fn new(prompt: &'static str) -> Self {
+        let mut shell = Shell {
+            prompt,
+            commands: Vec::new(),
+            history: VecDeque::new(),
+        };
+        shell.register("help", "list commands", cmd_help);
+        shell.register("echo", "print the arguments", cmd_echo);
+        shell.register("clear", "clear the screen", cmd_clear);
+        shell.register("cls", "clear the screen", cmd_clear);
+        shell.register(
+            "theme",
+            "list color themes or switch: theme [name]",
+            cmd_theme,
+        );
+        shell.register(
+            "screenshot",
+            "save the screen to a file: screenshot <path>",
+            cmd_screenshot,
+        );
+        shell.register("reboot", "restart the machine", cmd_reboot);
+        shell.register("halt", "stop the machine", cmd_halt);
+        shell.register("shutdown", "power off the machine", cmd_shutdown);
+        shell.register("history", "list previous commands", cmd_history);
+        shell.register("uptime", "time since boot", cmd_uptime);
+        shell
+    }
+
+    /// Adds a command, replacing any earlier one with the same name.
+    pub fn register(&mut self, name: &'static str, help: &'static str, run: CommandFn) {
+        self.commands.retain(|c| c.name != name);
+        self.commands.push(Command { name, help, run });
+    }
+
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Reads one line of input with echo. Left/Right/Home/End move within
+    /// the line (as do Ctrl+A/Ctrl+E), Backspace/Delete edit at the cursor,
+    /// Up/Down walk the history and Ctrl+C abandons the line.
+    pub async fn read_command(&mut self) -> String {
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Position in `history` while browsing; `history.len()` is the line
+        // being typed, which is kept in `draft`.
+        let mut browsing = self.history.len();
+        let mut draft: Vec<char> = Vec::new();
+
+        loop {
+            let Some(key) = read_key().await else {
+                return String::new();
+            };
+            let old_len = line.len();
+            match key {
+                KeyEvent::Char('\n') | KeyEvent::Char('\r') => {
+                    println!();
+                    let command: String = line.into_iter().collect();
+                    self.remember(&command);
+                    return command;
                 }
-            }
-            _ => {
-                if i < buf.len() {
-                    buf[i] = c as u8;
-                    i += 1;
-                    print!("{}", c);
+                KeyEvent::Char('\x08') => {
+                    if cursor == 0 {
+                        continue;
+                    }
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                KeyEvent::Char('\x7f') | KeyEvent::Delete => {
+                    if cursor == line.len() {
+                        continue;
+                    }
+                    line.remove(cursor);
+                }
+                KeyEvent::Char(c) => {
+                    if line.len() >= MAX_LINE || c.is_control() {
+                        continue;
+                    }
+                    line.insert(cursor, c);
+                    cursor += 1;
                 }
+                KeyEvent::Ctrl('c') => {
+                    println!("^C");
+                    line.clear();
+                    cursor = 0;
+                    browsing = self.history.len();
+                    self.print_prompt();
+                    continue;
+                }
+                KeyEvent::Ctrl('a') => cursor = 0,
+                KeyEvent::Ctrl('e') => cursor = line.len(),
+                KeyEvent::Left => cursor = cursor.saturating_sub(1),
+                KeyEvent::Right => cursor = (cursor + 1).min(line.len()),
+                KeyEvent::Home => cursor = 0,
+                KeyEvent::End => cursor = line.len(),
+                KeyEvent::Up | KeyEvent::Down => {
+                    let target = match key {
+                        KeyEvent::Up if browsing > 0 => browsing - 1,
+                        KeyEvent::Down if browsing < self.history.len() => browsing + 1,
+                        _ => continue,
+                    };
+                    if browsing == self.history.len() {
+                        draft = line.clone();
+                    }
+                    browsing = target;
+                    line = match self.history.get(browsing) {
+                        Some(entry) => entry.chars().collect(),
+                        None => draft.clone(),
+                    };
+                    cursor = line.len();
+                }
+                _ => continue,
             }
+            self.redraw(&line, cursor, old_len);
+        }
+    }
+
+    /// Rewrites the prompt line after an edit and leaves the cursor at
+    /// `cursor`. Backspace erases on both consoles, so the cursor is placed
+    /// by returning to column 0 and reprinting up to it.
+    fn redraw(&self, line: &[char], cursor: usize, old_len: usize) {
+        print!("\r");
+        self.print_prompt();
+        print_chars(line);
+        for _ in line.len()..old_len {
+            print!(" ");
+        }
+        print!("\r");
+        self.print_prompt();
+        print_chars(&line[..cursor]);
+    }
+
+    /// Prints the prompt in the theme's prompt color.
+    fn print_prompt(&self) {
+        crate::vga_buffer::_print_prompt(format_args!("{}", self.prompt));
+    }
+
+    /// Adds `command` to the history, skipping blanks and repeats of the
+    /// previous entry.
+    fn remember(&mut self, command: &str) {
+        if command.trim().is_empty() || self.history.back().map(String::as_str) == Some(command) {
+            return;
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(command));
+    }
+
+    /// Runs the command named by the first word of `line`.
+    pub fn execute(&self, line: &str) {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = args.split_first() else {
+            return;
+        };
+
+        match self.commands.iter().find(|c| c.name == name) {
+            Some(command) => (command.run)(self, args),
+            None => crate::println_error!("{}: command not found", name),
+        }
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    pub async fn run(&mut self) {
+        println!("sOS shell. Type 'help' for a list of commands.");
+        loop {
+            self.print_prompt();
+            let line = self.read_command().await;
+            self.execute(&line);
+        }
+    }
+}
+
+/// Asks a yes/no question and blocks until a line is entered. Only `y` or
+/// `yes` counts as yes. Anything typed before the question is discarded.
+/// For commands, which run synchronously inside the shell task.
+pub fn confirm(question: &str) -> bool {
+    while crate::task::keyboard::try_pop().is_some() {}
+    print!("{} [y/N] ", question);
+    let mut line = String::new();
+    let answer = loop {
+        if let Some(answer) = crate::task::keyboard::poll_line(&mut line) {
+            break answer;
+        }
+        x86_64::instructions::hlt();
+    };
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+fn print_chars(chars: &[char]) {
+    for c in chars {
+        print!("{}", c);
+    }
+}
+
+fn cmd_help(shell: &Shell, _args: &[&str]) {
+    for command in shell.commands() {
+        println!("  {:<10} {}", command.name, command.help);
+    }
+}
+
+fn cmd_history(shell: &Shell, _args: &[&str]) {
+    for (i, command) in shell.history().enumerate() {
+        println!("{:>4}  {}", i + 1, command);
+    }
+}
+
+fn cmd_uptime(_shell: &Shell, _args: &[&str]) {
+    let secs = crate::timer::uptime_ms() / 1000;
+    println!(
+        "up {}:{:02}:{:02} ({} ticks at {} Hz)",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        crate::timer::uptime_ticks(),
+        crate::timer::frequency_hz()
+    );
+}
+
+fn cmd_echo(_shell: &Shell, args: &[&str]) {
+    println!("{}", args.join(" "));
+}
+
+fn cmd_clear(_shell: &Shell, _args: &[&str]) {
+    crate::vga_buffer::clear_screen();
+}
+
+fn cmd_theme(_shell: &Shell, args: &[&str]) {
+    use crate::vga_buffer::{find_theme, set_theme, theme, THEMES};
+
+    let Some(&name) = args.first() else {
+        let current = theme();
+        for theme in THEMES {
+            let marker = if theme == current { '*' } else { ' ' };
+            println!("{} {}", marker, theme.name);
+        }
+        return;
+    };
+    match find_theme(name) {
+        Some(theme) => set_theme(theme),
+        None => crate::println_error!("theme: no theme called '{}'", name),
+    }
+}
+
+fn cmd_screenshot(_shell: &Shell, args: &[&str]) {
+    use crate::fs::mount;
+
+    let Some(&path) = args.first() else {
+        println!("usage: screenshot <path>");
+        return;
+    };
+    let image = crate::vga_buffer::capture();
+    // A framebuffer image runs to megabytes, so it is written a chunk at a
+    // time rather than handed to the filesystem in one go.
+    let written = mount::write_file(path, &[]).and_then(|()| {
+        for (i, chunk) in image.chunks(SCREENSHOT_CHUNK).enumerate() {
+            mount::write_at(path, (i * SCREENSHOT_CHUNK) as u64, chunk)?;
         }
+        Ok(())
+    });
+    match written {
+        Ok(()) => println!("wrote {} bytes to {}", image.len(), path),
+        Err(e) => println!("screenshot: {}", e),
     }
-    // i
+}
+
+fn cmd_reboot(_shell: &Shell, _args: &[&str]) {
+    println!("Rebooting...");
+    crate::reboot();
+}
+
+fn cmd_halt(_shell: &Shell, _args: &[&str]) {
+    println!("System halted.");
+    crate::halt();
+}
+
+fn cmd_shutdown(_shell: &Shell, _args: &[&str]) {
+    println!("Powering off...");
+    crate::shutdown();
+}
+
+/// The kernel shell: built-ins plus the ATA, filesystem, keyboard, task,
+/// logging and rand commands.
+pub async fn shell() {
+    let mut shell = Shell::new("sos> ");
+    crate::drivers::ata::register_commands(&mut shell);
+    crate::fs::mount::register_commands(&mut shell);
+    crate::task::keyboard::register_commands(&mut shell);
+    crate::task::executor::register_commands(&mut shell);
+    crate::memory::allocator::register_commands(&mut shell);
+    crate::elf::register_commands(&mut shell);
+    crate::drivers::logger::register_commands(&mut shell);
+    crate::rand::register_commands(&mut shell);
+    shell.run().await;
 }
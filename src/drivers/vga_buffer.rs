@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 
+use alloc::vec::Vec;
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -12,6 +13,11 @@ lazy_static! {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        shadow: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        // Whatever the bootloader left on screen goes on the first flush.
+        dirty: Some((0, BUFFER_HEIGHT - 1)),
+        scrollback: Scrollback::new(),
+        ansi: AnsiParser::new(),
     });
 }
 
@@ -36,6 +42,31 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    /// The standard VGA palette entry as a 0xAARRGGBB pixel, for the
+    /// framebuffer console.
+    pub const fn rgb(self) -> u32 {
+        match self {
+            Color::Black => 0xFF00_0000,
+            Color::Blue => 0xFF00_00AA,
+            Color::Green => 0xFF00_AA00,
+            Color::Cyan => 0xFF00_AAAA,
+            Color::Red => 0xFFAA_0000,
+            Color::Magenta => 0xFFAA_00AA,
+            Color::Brown => 0xFFAA_5500,
+            Color::LightGray => 0xFFAA_AAAA,
+            Color::DarkGray => 0xFF55_5555,
+            Color::LightBlue => 0xFF55_55FF,
+            Color::LightGreen => 0xFF55_FF55,
+            Color::LightCyan => 0xFF55_FFFF,
+            Color::LightRed => 0xFFFF_5555,
+            Color::Pink => 0xFFFF_55FF,
+            Color::Yellow => 0xFFFF_FF55,
+            Color::White => 0xFFFF_FFFF,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct ColorCode(u8);
@@ -58,27 +89,129 @@ struct ScreenChar {
 
 pub const BUFFER_HEIGHT: usize = 25;
 pub const BUFFER_WIDTH: usize = 80;
+pub const SCROLLBACK_LINES: usize = 200;
+
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode::new(Color::Yellow, Color::Black),
+};
+
+/// The glyphs code page 437 puts at 0x01-0x1F, where ASCII has controls.
+const CP437_LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕', '‼',
+    '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Code page 437 from 0x80 up: accented letters, box drawing, shading and
+/// maths symbols.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Maps `c` to the CP437 byte whose glyph shows it, if there is one.
+fn to_cp437(c: char) -> Option<u8> {
+    match c {
+        ' '..='~' => Some(c as u8),
+        '⌂' => Some(0x7f),
+        // Greek small beta and the German sharp s share a glyph.
+        'β' => Some(0xe1),
+        _ => {
+            if let Some(i) = CP437_LOW.iter().position(|&g| g == c) {
+                Some(i as u8 + 1)
+            } else {
+                CP437_HIGH
+                    .iter()
+                    .position(|&g| g == c)
+                    .map(|i| i as u8 + 0x80)
+            }
+        }
+    }
+}
 
-#[repr(transparent)]
-struct Buffer {
-    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+/// What a console should do with a byte after ANSI escape handling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiAction {
+    /// Not part of an escape sequence: draw or interpret it as usual.
+    Byte(u8),
+    /// Swallowed by an escape sequence that is still going or unsupported.
+    None,
+    /// `ESC [ 2 J`: blank the screen and home the cursor.
+    ClearScreen,
+    /// `ESC [ H`: move the cursor to the top left.
+    Home,
 }
 
-fn update_cursor(row: usize, col: usize) {
-    use x86_64::instructions::port::Port;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    Ground,
+    Escape,
+    /// Inside `ESC [`, with the first numeric parameter so far.
+    Csi(Option<u16>),
+}
 
-    let pos: u16 = (row as u16) * (BUFFER_WIDTH as u16) + (col as u16);
-    unsafe {
-        let mut crtc_index: Port<u8> = Port::new(0x3D4);
-        let mut crtc_data: Port<u8> = Port::new(0x3D5);
+/// Picks the few ANSI escape sequences the consoles understand out of a
+/// byte stream. A sequence may be split across writes.
+#[derive(Debug, Clone, Copy)]
+pub struct AnsiParser {
+    state: AnsiState,
+}
+
+impl AnsiParser {
+    pub const fn new() -> Self {
+        AnsiParser {
+            state: AnsiState::Ground,
+        }
+    }
+
+    /// Whether a sequence has started but not finished.
+    pub fn in_escape(&self) -> bool {
+        self.state != AnsiState::Ground
+    }
 
-        crtc_index.write(0x0F);
-        crtc_data.write((pos & 0xFF) as u8);
-        crtc_index.write(0x0E);
-        crtc_data.write(((pos >> 8) & 0xFF) as u8);
+    pub fn feed(&mut self, byte: u8) -> AnsiAction {
+        match (self.state, byte) {
+            (AnsiState::Ground, 0x1b) => self.state = AnsiState::Escape,
+            (AnsiState::Ground, byte) => return AnsiAction::Byte(byte),
+            (AnsiState::Escape, b'[') => self.state = AnsiState::Csi(None),
+            (AnsiState::Csi(param), b'0'..=b'9') => {
+                let digit = u16::from(byte - b'0');
+                let param = param.unwrap_or(0).saturating_mul(10).saturating_add(digit);
+                self.state = AnsiState::Csi(Some(param));
+            }
+            // Only the first parameter matters to the sequences handled.
+            (AnsiState::Csi(_), b';') => {}
+            (AnsiState::Csi(param), final_byte) => {
+                self.state = AnsiState::Ground;
+                return match (param, final_byte) {
+                    (Some(2), b'J') => AnsiAction::ClearScreen,
+                    (None, b'H') => AnsiAction::Home,
+                    _ => AnsiAction::None,
+                };
+            }
+            (AnsiState::Escape, _) => self.state = AnsiState::Ground,
+        }
+        AnsiAction::None
     }
 }
 
+impl Default for AnsiParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(transparent)]
+struct Buffer {
+    chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
+}
+
 pub fn enable_cursor(start: u8, end: u8) {
     unsafe {
         let mut index_port = Port::<u8>::new(0x3D4);
@@ -123,50 +256,200 @@ fn set_cursor_pos_rc(row: usize, col: usize) {
     set_cursor_pos_cell(row * BUFFER_WIDTH + col);
 }
 
+/// The cell the hardware cursor is on.
+fn cursor_pos_cell() -> usize {
+    unsafe {
+        let mut index_port = Port::<u8>::new(0x3D4);
+        let mut data_port = Port::<u8>::new(0x3D5);
+
+        index_port.write(0x0F);
+        let low = data_port.read();
+        index_port.write(0x0E);
+        let high = data_port.read();
+        usize::from(u16::from_le_bytes([low, high]))
+    }
+}
+
+/// Lines that scrolled off the top of the screen.
+struct Scrollback {
+    lines: [[ScreenChar; BUFFER_WIDTH]; SCROLLBACK_LINES],
+    /// Slot the next line goes into.
+    head: usize,
+    len: usize,
+    /// How many lines the view is scrolled up from the bottom.
+    offset: usize,
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        Scrollback {
+            lines: [[BLANK; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            head: 0,
+            len: 0,
+            offset: 0,
+        }
+    }
+
+    fn push(&mut self, line: [ScreenChar; BUFFER_WIDTH]) {
+        self.lines[self.head] = line;
+        self.head = (self.head + 1) % SCROLLBACK_LINES;
+        self.len = (self.len + 1).min(SCROLLBACK_LINES);
+    }
+
+    /// History line `i`, oldest first.
+    fn line(&self, i: usize) -> &[ScreenChar; BUFFER_WIDTH] {
+        let oldest = (self.head + SCROLLBACK_LINES - self.len) % SCROLLBACK_LINES;
+        &self.lines[(oldest + i) % SCROLLBACK_LINES]
+    }
+}
+
+/// Text output to the VGA console. Writes land in `shadow` and reach the
+/// hardware buffer, which is slow to touch, only on `flush`.
 pub struct Writer {
     pub row_position: usize,
     pub column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    /// The live screen as it will look after the next flush.
+    shadow: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    /// First and last row changed since the last flush.
+    dirty: Option<(usize, usize)>,
+    scrollback: Scrollback,
+    ansi: AnsiParser,
 }
 
 impl Writer {
     #[inline]
     fn put_at(&mut self, row: usize, col: usize, byte: u8) {
-        self.buffer.chars[row][col].write(ScreenChar {
+        self.shadow[row][col] = ScreenChar {
             ascii_character: byte,
             color_code: self.color_code,
+        };
+        self.mark_dirty(row, row);
+    }
+
+    fn mark_dirty(&mut self, first: usize, last: usize) {
+        self.dirty = Some(match self.dirty {
+            Some((a, b)) => (a.min(first), b.max(last)),
+            None => (first, last),
         });
     }
 
+    /// Copies the rows changed since the last flush to VGA memory in one pass
+    /// and moves the hardware cursor. While the view is scrolled back the
+    /// screen shows history, so the copy waits until it snaps to the bottom.
+    pub fn flush(&mut self) {
+        if self.scrollback.offset == 0 {
+            if let Some((first, last)) = self.dirty.take() {
+                for row in first..=last {
+                    for (col, chr) in self.shadow[row].iter().enumerate() {
+                        self.buffer.chars[row][col].write(*chr);
+                    }
+                }
+            }
+        }
+        self.sync_hw_cursor();
+    }
+
     #[inline]
     fn sync_hw_cursor(&self) {
-        set_cursor_pos_rc(self.row_position, self.column_position);
+        let row = self.row_position + self.scrollback.offset;
+        if row < BUFFER_HEIGHT {
+            set_cursor_pos_rc(row, self.column_position);
+        } else {
+            // The live cursor is scrolled out of view; park it off-screen.
+            set_cursor_pos_cell(BUFFER_HEIGHT * BUFFER_WIDTH);
+        }
+    }
+
+    /// Scrolls the view `lines` further back into the scrollback history.
+    pub fn scroll_up(&mut self, lines: usize) {
+        if self.scrollback.offset == 0 {
+            // Anything still pending belongs on the live screen.
+            self.flush();
+        }
+        self.scrollback.offset = (self.scrollback.offset + lines).min(self.scrollback.len);
+        self.render_view();
     }
 
+    /// Scrolls the view `lines` back towards the live screen.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.scrollback.offset == 0 {
+            return;
+        }
+        self.scrollback.offset = self.scrollback.offset.saturating_sub(lines);
+        self.render_view();
+    }
+
+    fn snap_to_bottom(&mut self) {
+        if self.scrollback.offset != 0 {
+            self.scrollback.offset = 0;
+            self.render_view();
+        }
+    }
+
+    /// Copies the window `offset` lines above the live screen into VGA
+    /// memory. Lines past the history come from the shadow buffer.
+    fn render_view(&mut self) {
+        let top = self.scrollback.len - self.scrollback.offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = top + row;
+            let chars = if line < self.scrollback.len {
+                *self.scrollback.line(line)
+            } else {
+                self.shadow[line - self.scrollback.len]
+            };
+            for (col, chr) in chars.iter().enumerate() {
+                self.buffer.chars[row][col].write(*chr);
+            }
+        }
+        self.dirty = None;
+        self.sync_hw_cursor();
+    }
+
+    /// Writes one byte to the shadow buffer. Call `flush` to show it.
     pub fn write_byte(&mut self, byte: u8) {
+        self.snap_to_bottom();
+        let byte = match self.ansi.feed(byte) {
+            AnsiAction::Byte(byte) => byte,
+            AnsiAction::None => return,
+            AnsiAction::ClearScreen => return self.clear_screen(),
+            AnsiAction::Home => {
+                self.row_position = 0;
+                self.column_position = 0;
+                return;
+            }
+        };
         match byte {
             b'\n' => self.new_line(),
             b'\r' => self.column_position = 0,
             0x08 => self.backspace(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
-                let row = self.row_position;
-                let col = self.column_position;
-                self.put_at(row, col, byte);
-                self.column_position += 1;
-            }
+            byte => self.write_glyph(byte),
         }
-        update_cursor(self.row_position, self.column_position);
     }
 
+    /// Puts CP437 glyph `byte` at the cursor and advances, without treating
+    /// control codes specially, so e.g. 0x08 draws `◘`.
+    fn write_glyph(&mut self, byte: u8) {
+        self.snap_to_bottom();
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        let row = self.row_position;
+        let col = self.column_position;
+        self.put_at(row, col, byte);
+        self.column_position += 1;
+    }
+
+    /// Writes `s`, drawing each character as its CP437 glyph. Characters
+    /// the code page lacks come out as `■`. `ESC [ 2 J` clears the screen
+    /// and `ESC [ H` homes the cursor; other escape sequences are dropped.
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' | b'\r' | 0x08 => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+        for c in s.chars() {
+            match c {
+                '\n' | '\r' | '\x08' | '\x1b' => self.write_byte(c as u8),
+                c if self.ansi.in_escape() && c.is_ascii() => self.write_byte(c as u8),
+                c => self.write_glyph(to_cp437(c).unwrap_or(0xfe)),
             }
         }
     }
@@ -183,7 +466,6 @@ impl Writer {
         let row = self.row_position;
         let col = self.column_position;
         self.put_at(row, col, b' ');
-        update_cursor(row, col);
     }
 
     fn new_line(&mut self) {
@@ -192,15 +474,11 @@ impl Writer {
             self.column_position = 0;
             return;
         }
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let chr = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(chr);
-            }
-        }
+        self.scrollback.push(self.shadow[0]);
+        self.shadow.copy_within(1.., 0);
         self.clear_row(BUFFER_HEIGHT - 1);
+        self.mark_dirty(0, BUFFER_HEIGHT - 1);
         self.column_position = 0;
-        update_cursor(self.row_position, self.column_position);
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -208,9 +486,8 @@ impl Writer {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
-        }
+        self.shadow[row] = [blank; BUFFER_WIDTH];
+        self.mark_dirty(row, row);
     }
 
     pub fn set_color(&mut self, fg: Color, bg: Color) {
@@ -229,16 +506,19 @@ impl Writer {
         self.set_color(fg, bg);
         self.write_string(s);
         self.color_code = old;
-        self.sync_hw_cursor();
+        self.flush();
     }
 
+    /// Blanks the live screen, homes the cursor and returns the view from
+    /// any scrollback to the bottom. The history itself is kept.
     pub fn clear_screen(&mut self) {
+        self.snap_to_bottom();
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row);
         }
         self.row_position = 0;
         self.column_position = 0;
-        self.sync_hw_cursor();
+        self.flush();
     }
 }
 
@@ -290,25 +570,325 @@ pub fn get_colors() -> (Color, Color) {
     interrupts::without_interrupts(|| WRITER.lock().get_color())
 }
 
+/// A named set of console colors. Text normally uses `foreground` on
+/// `background`; shell prompts and error messages get their own
+/// foreground.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub name: &'static str,
+    pub foreground: Color,
+    pub background: Color,
+    pub error: Color,
+    pub prompt: Color,
+}
+
+pub const CLASSIC_AMBER: Theme = Theme {
+    name: "amber",
+    foreground: Color::Yellow,
+    background: Color::Black,
+    error: Color::LightRed,
+    prompt: Color::Brown,
+};
+
+pub const GREEN_ON_BLACK: Theme = Theme {
+    name: "green",
+    foreground: Color::Green,
+    background: Color::Black,
+    error: Color::LightRed,
+    prompt: Color::LightGreen,
+};
+
+pub const GRAY_ON_BLACK: Theme = Theme {
+    name: "gray",
+    foreground: Color::LightGray,
+    background: Color::Black,
+    error: Color::Red,
+    prompt: Color::White,
+};
+
+pub const WHITE_ON_BLUE: Theme = Theme {
+    name: "blue",
+    foreground: Color::White,
+    background: Color::Blue,
+    error: Color::LightRed,
+    prompt: Color::Yellow,
+};
+
+pub const THEMES: [Theme; 4] = [CLASSIC_AMBER, GREEN_ON_BLACK, GRAY_ON_BLACK, WHITE_ON_BLUE];
+
+/// The writer starts out yellow on black, which is the amber theme.
+static THEME: Mutex<Theme> = Mutex::new(CLASSIC_AMBER);
+
+/// The built-in theme called `name`.
+pub fn find_theme(name: &str) -> Option<Theme> {
+    THEMES
+        .iter()
+        .find(|theme| theme.name.eq_ignore_ascii_case(name))
+        .copied()
+}
+
+pub fn theme() -> Theme {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| *THEME.lock())
+}
+
+/// Switches both consoles to `theme`'s colors for text written from now
+/// on. `set_colors` can still override the text colors afterwards; prompts
+/// and errors keep following the theme.
+pub fn set_theme(theme: Theme) {
+    use crate::drivers::fb_console::FB_CONSOLE;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        *THEME.lock() = theme;
+        WRITER.lock().set_color(theme.foreground, theme.background);
+        if let Some(console) = FB_CONSOLE.lock().as_mut() {
+            console.set_colors(theme.foreground.rgb(), theme.background.rgb());
+        }
+    });
+}
+
+/// Clears whichever console `print!` goes to.
 pub fn clear_screen() {
+    use crate::drivers::fb_console::{console_target, ConsoleTarget, FB_CONSOLE};
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
+        if console_target() == ConsoleTarget::Framebuffer {
+            if let Some(console) = FB_CONSOLE.lock().as_mut() {
+                console.clear();
+                return;
+            }
+        }
         WRITER.lock().clear_screen();
     });
-    update_cursor(0, 0);
+}
+
+pub fn scroll_up(lines: usize) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        WRITER.lock().scroll_up(lines);
+    });
+}
+
+pub fn scroll_down(lines: usize) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        WRITER.lock().scroll_down(lines);
+    });
 }
 
 pub fn _print(args: fmt::Arguments) {
+    use crate::drivers::fb_console::{console_target, ConsoleTarget, FB_CONSOLE};
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
+        if console_target() == ConsoleTarget::Framebuffer {
+            if let Some(console) = FB_CONSOLE.lock().as_mut() {
+                console.write_fmt(args).unwrap();
+                return;
+            }
+        }
         let mut w = WRITER.lock();
         w.write_fmt(args).unwrap();
-        w.sync_hw_cursor();
+        w.flush();
+    });
+}
+
+/// Prints in `foreground` over the current background, on whichever
+/// console is active, then goes back to the previous colors.
+pub fn _print_in(foreground: Color, args: fmt::Arguments) {
+    use crate::drivers::fb_console::{console_target, ConsoleTarget, FB_CONSOLE};
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if console_target() == ConsoleTarget::Framebuffer {
+            if let Some(console) = FB_CONSOLE.lock().as_mut() {
+                let (old_fg, bg) = console.colors();
+                console.set_colors(foreground.rgb(), bg);
+                console.write_fmt(args).unwrap();
+                console.set_colors(old_fg, bg);
+                return;
+            }
+        }
+        let mut w = WRITER.lock();
+        let old = w.color_code;
+        let (_, background) = w.get_color();
+        w.set_color(foreground, background);
+        w.write_fmt(args).unwrap();
+        w.color_code = old;
+        w.flush();
+    });
+}
+
+/// Prints in the theme's error color.
+pub fn _print_error(args: fmt::Arguments) {
+    _print_in(theme().error, args);
+}
+
+/// Prints in the theme's prompt color.
+pub fn _print_prompt(args: fmt::Arguments) {
+    _print_in(theme().prompt, args);
+}
+
+/// `println!` in the theme's error color.
+#[macro_export]
+macro_rules! println_error {
+    ($($arg:tt)*) => ($crate::vga_buffer::_print_error(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// First bytes of every `capture` image.
+pub const SCREENSHOT_MAGIC: [u8; 4] = *b"SOSS";
+/// `capture` header: magic, then width, height and format as little-endian
+/// u32s. Pixel data follows.
+pub const SCREENSHOT_HEADER_LEN: usize = 16;
+
+/// How the data after a screenshot header is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum CaptureFormat {
+    /// `width * height` cells of (CP437 byte, attribute byte).
+    VgaText = 0,
+    /// `width * height` little-endian 0xAARRGGBB pixels.
+    Xrgb8888 = 1,
+}
+
+fn screenshot_header(width: usize, height: usize, format: CaptureFormat) -> Vec<u8> {
+    let mut image = Vec::with_capacity(SCREENSHOT_HEADER_LEN);
+    image.extend_from_slice(&SCREENSHOT_MAGIC);
+    image.extend_from_slice(&(width as u32).to_le_bytes());
+    image.extend_from_slice(&(height as u32).to_le_bytes());
+    image.extend_from_slice(&(format as u32).to_le_bytes());
+    image
+}
+
+/// Returns what is on the console right now, with a screenshot header: the
+/// GPU framebuffer when output goes there, otherwise the visible VGA text.
+pub fn capture() -> Vec<u8> {
+    use crate::drivers::fb_console::{console_target, ConsoleTarget, FB_CONSOLE};
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if console_target() == ConsoleTarget::Framebuffer {
+            if let Some(console) = FB_CONSOLE.lock().as_ref() {
+                let (width, height, pixels) = console.pixels();
+                let mut image = screenshot_header(width, height, CaptureFormat::Xrgb8888);
+                image.reserve(pixels.len() * 4);
+                for pixel in pixels {
+                    image.extend_from_slice(&pixel.to_le_bytes());
+                }
+                return image;
+            }
+        }
+
+        let mut w = WRITER.lock();
+        w.flush();
+        let mut image = screenshot_header(BUFFER_WIDTH, BUFFER_HEIGHT, CaptureFormat::VgaText);
+        image.reserve(BUFFER_WIDTH * BUFFER_HEIGHT * 2);
+        for row in w.buffer.chars.iter() {
+            for cell in row.iter() {
+                let chr = cell.read();
+                image.push(chr.ascii_character);
+                image.push(chr.color_code.raw());
+            }
+        }
+        image
+    })
+}
+
+/// Times a full-screen redraw written straight to VGA memory a character at
+/// a time, as the writer did before it had a shadow buffer, against the same
+/// redraw into the shadow buffer and one flush. Reports both on serial and
+/// puts the screen back afterwards.
+pub fn benchmark_redraw() {
+    use core::arch::x86_64::_rdtsc;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut w = WRITER.lock();
+        w.snap_to_bottom();
+        w.flush();
+        let saved = (w.shadow, w.row_position, w.column_position);
+        let glyph = |row: usize, col: usize| b'!' + ((row * BUFFER_WIDTH + col) % 94) as u8;
+
+        let start = unsafe { _rdtsc() };
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let chr = ScreenChar {
+                    ascii_character: glyph(row, col),
+                    color_code: w.color_code,
+                };
+                w.buffer.chars[row][col].write(chr);
+                set_cursor_pos_rc(row, col);
+            }
+        }
+        let direct = unsafe { _rdtsc() } - start;
+
+        let start = unsafe { _rdtsc() };
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                w.put_at(row, col, glyph(row, col));
+            }
+        }
+        w.flush();
+        let buffered = unsafe { _rdtsc() } - start;
+
+        (w.shadow, w.row_position, w.column_position) = saved;
+        w.mark_dirty(0, BUFFER_HEIGHT - 1);
+        w.flush();
+
+        crate::serial_println!(
+            "VGA redraw: {} cycles direct, {} cycles buffered ({}x)",
+            direct,
+            buffered,
+            direct / buffered.max(1)
+        );
     });
 }
 
+/// Clears the screen with an escape sequence while scrolled back, checks
+/// the view returned to the bottom and the screen and hardware cursor are
+/// at (0, 0), then puts the screen back.
+pub fn test_clear_screen() -> Result<(), &'static str> {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut w = WRITER.lock();
+        w.snap_to_bottom();
+        w.flush();
+        let saved = (w.shadow, w.row_position, w.column_position);
+
+        for _ in 0..BUFFER_HEIGHT {
+            w.write_string("filler\n");
+        }
+        w.scroll_up(2);
+        let _ = write!(w, "before\x1b[2J");
+        w.flush();
+
+        let result = if w.scrollback.offset != 0 {
+            Err("clearing left the view scrolled back")
+        } else if (w.row_position, w.column_position) != (0, 0) {
+            Err("clearing did not home the cursor")
+        } else if cursor_pos_cell() != 0 {
+            Err("hardware cursor not at (0, 0) after clearing")
+        } else if w.shadow.iter().flatten().any(|c| c.ascii_character != b' ') {
+            Err("text left on screen after clearing")
+        } else {
+            Ok(())
+        };
+
+        (w.shadow, w.row_position, w.column_position) = saved;
+        w.mark_dirty(0, BUFFER_HEIGHT - 1);
+        w.flush();
+        result
+    })?;
+
+    crate::serial_println!("✓ Screen clear homes the cursor");
+    Ok(())
+}
+
 pub fn init_vga_with_cursor() {
     enable_cursor(0, 15);
     x86_64::instructions::interrupts::without_interrupts(|| {
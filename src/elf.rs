@@ -0,0 +1,477 @@
+use crate::memory::paging::{phys_to_virt, with_active_mapper, GlobalFrameAllocator};
+use crate::serial_println;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::{
+    structures::paging::{
+        mapper::TranslateResult, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+pub const PT_NULL: u32 = 0;
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_INTERP: u32 = 3;
+pub const PT_NOTE: u32 = 4;
+pub const PT_PHDR: u32 = 6;
+pub const PT_TLS: u32 = 7;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+/// Where user programs live. Executables must be linked to load inside
+/// it, below the stack at the top. Kept to its own level 4 entry, so it
+/// shares no page tables with the kernel.
+pub const USER_REGION_START: u64 = 0x_1000_0000_0000;
+pub const USER_REGION_SIZE: u64 = 0x_4000_0000; // 1 GiB
+pub const USER_STACK_TOP: u64 = USER_REGION_START + USER_REGION_SIZE;
+const USER_STACK_PAGES: u64 = 16;
+const USER_STACK_BOTTOM: u64 = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// A parsed static ELF64 executable borrowing the file's bytes.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    pub program_headers: Vec<ProgramHeader>,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the ELF header and reads the program headers. Only static
+    /// x86_64 executables are accepted.
+    pub fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < EHDR_SIZE || data[..4] != ELF_MAGIC {
+            return Err("not an ELF file");
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err("not a little-endian ELF64 file");
+        }
+        match u16_at(data, 16) {
+            ET_EXEC => {}
+            ET_DYN => return Err("position-independent executables are not supported"),
+            _ => return Err("not an executable"),
+        }
+        if u16_at(data, 18) != EM_X86_64 {
+            return Err("not an x86_64 executable");
+        }
+
+        let entry = u64_at(data, 24);
+        let phoff = u64_at(data, 32) as usize;
+        let phentsize = usize::from(u16_at(data, 54));
+        let phnum = usize::from(u16_at(data, 56));
+        if phnum > 0 && phentsize < PHDR_SIZE {
+            return Err("bad program header size");
+        }
+        let table_end = phentsize
+            .checked_mul(phnum)
+            .and_then(|size| size.checked_add(phoff))
+            .ok_or("bad program header table")?;
+        if table_end > data.len() {
+            return Err("program header table past end of file");
+        }
+
+        let program_headers = (0..phnum)
+            .map(|i| {
+                let ph = phoff + i * phentsize;
+                ProgramHeader {
+                    p_type: u32_at(data, ph),
+                    flags: u32_at(data, ph + 4),
+                    offset: u64_at(data, ph + 8),
+                    vaddr: u64_at(data, ph + 16),
+                    filesz: u64_at(data, ph + 32),
+                    memsz: u64_at(data, ph + 40),
+                }
+            })
+            .collect();
+
+        Ok(ElfFile {
+            data,
+            entry,
+            program_headers,
+        })
+    }
+
+    /// The loadable segments, after rejecting segment types that need
+    /// support the loader doesn't have. Purely informational ones are
+    /// skipped.
+    fn load_segments(&self) -> Result<Vec<ProgramHeader>, &'static str> {
+        let mut segments = Vec::new();
+        for ph in &self.program_headers {
+            match ph.p_type {
+                PT_LOAD => segments.push(*ph),
+                PT_DYNAMIC | PT_INTERP => {
+                    return Err("dynamically linked executables are not supported")
+                }
+                PT_TLS => return Err("thread-local storage is not supported"),
+                PT_NULL | PT_NOTE | PT_PHDR => {}
+                other => serial_println!("elf: skipping segment type {:#x}", other),
+            }
+        }
+        Ok(segments)
+    }
+}
+
+/// Checks that `ph` fits in the file and in the image part of the user
+/// region.
+fn check_segment(ph: &ProgramHeader, file_len: usize) -> Result<(), &'static str> {
+    if ph.filesz > ph.memsz {
+        return Err("segment file size exceeds memory size");
+    }
+    let file_end = ph
+        .offset
+        .checked_add(ph.filesz)
+        .ok_or("bad segment offset")?;
+    if file_end > file_len as u64 {
+        return Err("segment past end of file");
+    }
+    let mem_end = ph
+        .vaddr
+        .checked_add(ph.memsz)
+        .ok_or("bad segment address")?;
+    if ph.vaddr < USER_REGION_START || mem_end > USER_STACK_BOTTOM {
+        return Err("segment outside the user region");
+    }
+    Ok(())
+}
+
+fn page_flags(segment_flags: u32) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if segment_flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment_flags & PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// A program mapped into the user region, ready for `run_user`.
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+    pages: Vec<Page>,
+}
+
+impl LoadedProgram {
+    /// Unmaps the program and its stack and frees their frames.
+    pub fn unload(self) {
+        let _ = unsafe { with_active_mapper(|mapper| unmap_pages(mapper, &self.pages)) };
+    }
+}
+
+fn unmap_pages(mapper: &mut OffsetPageTable, pages: &[Page]) {
+    for &page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Maps `page` to a zeroed frame, or widens its flags if the program
+/// already mapped it for an earlier segment sharing the page.
+fn map_user_page(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    flags: PageTableFlags,
+    pages: &mut Vec<Page>,
+) -> Result<(), &'static str> {
+    if pages.contains(&page) {
+        let TranslateResult::Mapped { flags: old, .. } = mapper.translate(page.start_address())
+        else {
+            return Err("lost a user page");
+        };
+        // Writable if either segment is, executable if either is.
+        let mut merged = old | flags;
+        if !(old & flags).contains(PageTableFlags::NO_EXECUTE) {
+            merged.remove(PageTableFlags::NO_EXECUTE);
+        }
+        unsafe {
+            mapper
+                .update_flags(page, merged)
+                .map_err(|_| "update_flags failed")?
+                .flush();
+        }
+        return Ok(());
+    }
+    if mapper.translate_page(page).is_ok() {
+        return Err("user region is already in use");
+    }
+
+    let mut frames = GlobalFrameAllocator;
+    let frame: PhysFrame = frames.allocate_frame().ok_or("out of frames")?;
+    let virt = phys_to_virt(frame.start_address()).ok_or("Paging is not initialized")?;
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+    match unsafe { mapper.map_to(page, frame, flags, &mut frames) } {
+        Ok(flush) => flush.flush(),
+        Err(_) => {
+            unsafe { frames.deallocate_frame(frame) };
+            return Err("map_to failed");
+        }
+    }
+    pages.push(page);
+    Ok(())
+}
+
+/// Copies `bytes` to user address `addr` through the physical memory
+/// mapping, so read-only pages can be filled too.
+fn copy_to_user(mapper: &OffsetPageTable, addr: u64, bytes: &[u8]) -> Result<(), &'static str> {
+    let mut done = 0;
+    while done < bytes.len() {
+        let virt = VirtAddr::new(addr + done as u64);
+        let phys = mapper.translate_addr(virt).ok_or("user page not mapped")?;
+        let dst = phys_to_virt(phys).ok_or("Paging is not initialized")?;
+        let chunk = (PAGE_SIZE - virt.as_u64() % PAGE_SIZE).min((bytes.len() - done) as u64);
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes[done..].as_ptr(),
+                dst.as_mut_ptr::<u8>(),
+                chunk as usize,
+            );
+        }
+        done += chunk as usize;
+    }
+    Ok(())
+}
+
+/// Maps the PT_LOAD segments of `elf` and a stack into the user region.
+/// Memory past each segment's file contents is zeroed. On failure nothing
+/// stays mapped.
+pub fn load(elf: &ElfFile) -> Result<LoadedProgram, &'static str> {
+    let segments = elf.load_segments()?;
+    if segments.is_empty() {
+        return Err("no loadable segments");
+    }
+    for ph in &segments {
+        check_segment(ph, elf.data.len())?;
+    }
+    let entry_ok = segments
+        .iter()
+        .any(|ph| ph.flags & PF_X != 0 && (ph.vaddr..ph.vaddr + ph.memsz).contains(&elf.entry));
+    if !entry_ok {
+        return Err("entry point is not in an executable segment");
+    }
+
+    // The page table lock is held while mapping, so the list must not
+    // need to grow then.
+    let segment_pages: u64 = segments
+        .iter()
+        .map(|ph| (ph.vaddr % PAGE_SIZE + ph.memsz).div_ceil(PAGE_SIZE))
+        .sum();
+    let mut pages = Vec::with_capacity((segment_pages + USER_STACK_PAGES) as usize);
+    let result = unsafe {
+        with_active_mapper(|mapper| {
+            for ph in segments.iter().filter(|ph| ph.memsz > 0) {
+                let first = Page::<Size4KiB>::containing_address(VirtAddr::new(ph.vaddr));
+                let last =
+                    Page::<Size4KiB>::containing_address(VirtAddr::new(ph.vaddr + ph.memsz - 1));
+                for page in Page::range_inclusive(first, last) {
+                    map_user_page(mapper, page, page_flags(ph.flags), &mut pages)?;
+                }
+                let start = ph.offset as usize;
+                copy_to_user(
+                    mapper,
+                    ph.vaddr,
+                    &elf.data[start..start + ph.filesz as usize],
+                )?;
+            }
+
+            let stack_flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE
+                | PageTableFlags::NO_EXECUTE;
+            let stack = Page::<Size4KiB>::containing_address(VirtAddr::new(USER_STACK_BOTTOM));
+            for i in 0..USER_STACK_PAGES {
+                map_user_page(mapper, stack + i, stack_flags, &mut pages)?;
+            }
+            Ok(())
+        })
+    };
+
+    match result {
+        Ok(Ok(())) => Ok(LoadedProgram {
+            entry: VirtAddr::new(elf.entry),
+            stack_top: VirtAddr::new(USER_STACK_TOP),
+            pages,
+        }),
+        Ok(Err(e)) | Err(e) => {
+            LoadedProgram {
+                entry: VirtAddr::zero(),
+                stack_top: VirtAddr::zero(),
+                pages,
+            }
+            .unload();
+            Err(e)
+        }
+    }
+}
+
+/// Reads a whole file from the FAT volume.
+fn read_file(path: &str) -> Result<Vec<u8>, &'static str> {
+    let stat = crate::fs::fat::stat(path)?;
+    if stat.is_directory {
+        return Err("is a directory");
+    }
+    let mut data = vec![0; stat.size as usize];
+    let n = crate::fs::fat::read_file(path, &mut data)?;
+    data.truncate(n);
+    Ok(data)
+}
+
+/// Loads the executable at `path` on the FAT volume, runs it in ring 3
+/// until it exits, and returns its exit code.
+pub fn exec(path: &str) -> Result<u64, &'static str> {
+    let data = read_file(path)?;
+    let program = load(&ElfFile::parse(&data)?)?;
+    let code = unsafe { crate::usermode::run_user(program.entry, program.stack_top) };
+    program.unload();
+    code
+}
+
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register("exec", "run a user program: exec <path>", cmd_exec);
+}
+
+fn cmd_exec(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let [path] = args else {
+        crate::println!("usage: exec <path>");
+        return;
+    };
+    match exec(path) {
+        Ok(code) => crate::println!("{} exited with code {}", path, code),
+        Err(e) => crate::println!("exec: {}: {}", path, e),
+    }
+}
+
+/// Builds a minimal executable with one read/execute segment, loaded at
+/// the start of the user region, whose code is `code`.
+fn build_test_executable(code: &[u8], p_type: u32) -> Vec<u8> {
+    let code_offset = (EHDR_SIZE + PHDR_SIZE) as u64;
+    let file_size = code_offset + code.len() as u64;
+
+    let mut image = Vec::with_capacity(file_size as usize);
+    image.extend_from_slice(&ELF_MAGIC);
+    image.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    image.resize(16, 0);
+    image.extend_from_slice(&ET_EXEC.to_le_bytes());
+    image.extend_from_slice(&EM_X86_64.to_le_bytes());
+    image.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    image.extend_from_slice(&(USER_REGION_START + code_offset).to_le_bytes()); // e_entry
+    image.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+    image.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    image.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    image.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    image.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+    image.extend_from_slice(&[0; 6]); // no section headers
+
+    image.extend_from_slice(&p_type.to_le_bytes());
+    image.extend_from_slice(&(PF_R | PF_X).to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes()); // p_offset
+    image.extend_from_slice(&USER_REGION_START.to_le_bytes()); // p_vaddr
+    image.extend_from_slice(&USER_REGION_START.to_le_bytes()); // p_paddr
+    image.extend_from_slice(&file_size.to_le_bytes()); // p_filesz
+    image.extend_from_slice(&file_size.to_le_bytes()); // p_memsz
+    image.extend_from_slice(&PAGE_SIZE.to_le_bytes()); // p_align
+
+    image.extend_from_slice(code);
+    image
+}
+
+/// Writes a program that only calls `sys_exit(42)` to the FAT volume, runs
+/// it, runs one that uses the `syscall` instruction instead, and checks
+/// that a dynamically linked one is turned away.
+pub fn test_elf_loader() -> Result<(), &'static str> {
+    const PATH: &str = "/EXIT.ELF";
+    let sys_exit = crate::syscall::SYS_EXIT as u8;
+    #[rustfmt::skip]
+    let code = [
+        0xB8, sys_exit, 0, 0, 0, // mov eax, SYS_EXIT
+        0xBF, 42, 0, 0, 0,       // mov edi, 42
+        0xCD, 0x80,              // int 0x80
+        0xEB, 0xFE,              // jmp $
+    ];
+
+    serial_println!("=== ELF Loader Test ===");
+    crate::fs::fat::write_file(PATH, &build_test_executable(&code, PT_LOAD))?;
+    let result = exec(PATH);
+    crate::fs::fat::remove_file(PATH)?;
+    match result? {
+        42 => serial_println!("✓ User program exited with code 42"),
+        _ => return Err("user program returned the wrong exit code"),
+    }
+
+    // rdi has to survive `sys_getpid`, which fails with ESRCH outside a
+    // thread, so the program exits with 7 + 0xFFFF_FFFD = 4.
+    let sys_getpid = crate::syscall::SYS_GETPID as u8;
+    #[rustfmt::skip]
+    let fast_code = [
+        0xBF, 7, 0, 0, 0,          // mov edi, 7
+        0xB8, sys_getpid, 0, 0, 0, // mov eax, SYS_GETPID
+        0x0F, 0x05,                // syscall
+        0x01, 0xC7,                // add edi, eax
+        0xB8, sys_exit, 0, 0, 0,   // mov eax, SYS_EXIT
+        0x0F, 0x05,                // syscall
+        0xEB, 0xFE,                // jmp $
+    ];
+    let program = load(&ElfFile::parse(&build_test_executable(
+        &fast_code, PT_LOAD,
+    ))?)?;
+    let result = unsafe { crate::usermode::run_user(program.entry, program.stack_top) };
+    program.unload();
+    match result? {
+        4 => serial_println!("✓ syscall/sysret round trip preserved registers"),
+        _ => return Err("syscall fast path returned the wrong result"),
+    }
+
+    let dynamic = build_test_executable(&code, PT_INTERP);
+    match ElfFile::parse(&dynamic).and_then(|elf| load(&elf)) {
+        Err(e) => serial_println!("✓ Rejected PT_INTERP: {}", e),
+        Ok(program) => {
+            program.unload();
+            return Err("loaded an executable with PT_INTERP");
+        }
+    }
+    Ok(())
+}
@@ -1,175 +1,519 @@
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
-use embedded_sdmmc::{Directory, Mode, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use embedded_sdmmc::{
+    Block, BlockCount, BlockDevice, BlockIdx, Directory, Mode, TimeSource, Timestamp, VolumeIdx,
+    VolumeManager,
+};
 use spin::Mutex;
 
+use crate::drivers::ata::AtaError;
 use crate::fs::ata_block::SosAtaBlockDevice;
+use crate::fs::ram_block::RamBlockDevice;
+use crate::fs::vfs::{DirEntry, FileSystem, FsError, VolumeStats};
 
-pub struct DummyTime;
-impl TimeSource for DummyTime {
+/// Stamps files with the current time from the CMOS RTC.
+pub struct RtcTime;
+impl TimeSource for RtcTime {
     fn get_timestamp(&self) -> Timestamp {
-        Timestamp {
-            year_since_1970: 54,
-            zero_indexed_month: 0,
-            zero_indexed_day: 0,
-            hours: 0,
-            minutes: 0,
-            seconds: 0,
+        crate::drivers::rtc::now()
+    }
+}
+
+/// The disk under the mounted volume: the ATA drive, or a RAM disk in tests.
+pub enum FatBlockDevice {
+    Ata(SosAtaBlockDevice),
+    Ram(RamBlockDevice),
+}
+
+impl BlockDevice for FatBlockDevice {
+    type Error = AtaError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        reason: &str,
+    ) -> Result<(), Self::Error> {
+        match self {
+            FatBlockDevice::Ata(dev) => dev.read(blocks, start_block_idx, reason),
+            FatBlockDevice::Ram(dev) => dev.read(blocks, start_block_idx, reason),
+        }
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        match self {
+            FatBlockDevice::Ata(dev) => dev.write(blocks, start_block_idx),
+            FatBlockDevice::Ram(dev) => dev.write(blocks, start_block_idx),
+        }
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        match self {
+            FatBlockDevice::Ata(dev) => dev.num_blocks(),
+            FatBlockDevice::Ram(dev) => dev.num_blocks(),
         }
     }
 }
 
-pub static VOLUME_MANAGER: Mutex<Option<VolumeManager<SosAtaBlockDevice, DummyTime>>> =
-    Mutex::new(None);
+type FatVolumeManager = VolumeManager<FatBlockDevice, RtcTime>;
+
+pub static VOLUME_MANAGER: Mutex<Option<FatVolumeManager>> = Mutex::new(None);
+
+/// MBR partition index of the mounted root volume.
+static ROOT_VOLUME: AtomicUsize = AtomicUsize::new(0);
+
+pub fn mount_root_fs(
+    device: crate::drivers::ata::AtaDevice,
+    partition: usize,
+    block_count: u32,
+) -> Result<(), &'static str> {
+    let entry = crate::drivers::ata::find_partition(true, device, partition)
+        .map_err(|_| "partition not found")?;
+    crate::serial_println!(
+        "FAT: mounting partition {} (type 0x{:02X}) at LBA {}",
+        partition,
+        entry.partition_type,
+        entry.start_lba
+    );
 
-pub fn mount_root_fs(device: crate::drivers::ata::AtaDevice, block_count: u32) {
     let dev = SosAtaBlockDevice {
         primary: true,
         device,
         block_count,
     };
-    let manager = VolumeManager::new(dev, DummyTime);
+    let manager = VolumeManager::new(FatBlockDevice::Ata(dev), RtcTime);
+    ROOT_VOLUME.store(partition, Ordering::Relaxed);
     *VOLUME_MANAGER.lock() = Some(manager);
+    Ok(())
 }
 
-fn split_path(path: &str) -> Vec<&str> {
-    path.split('/').filter(|p| !p.is_empty()).collect()
+/// Mounts MBR partition `partition` of a RAM disk as the root volume, in
+/// place of whatever was mounted, which is returned.
+pub fn mount_ram_fs(device: RamBlockDevice, partition: usize) -> Option<FatVolumeManager> {
+    let manager = VolumeManager::new(FatBlockDevice::Ram(device), RtcTime);
+    ROOT_VOLUME.store(partition, Ordering::Relaxed);
+    VOLUME_MANAGER.lock().replace(manager)
 }
 
-pub fn write_file(path: &str, data: &[u8]) -> Result<(), &'static str> {
-    let components = split_path(path);
+fn root_volume() -> VolumeIdx {
+    VolumeIdx(ROOT_VOLUME.load(Ordering::Relaxed))
+}
 
-    if components.len() != 1 {
-        return Err("Only root directory files supported currently");
-    }
+type FatDirectory<'a> = Directory<'a, FatBlockDevice, RtcTime, 4, 4, 1>;
 
-    let file_name = components[0];
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|p| !p.is_empty()).collect()
+}
+
+/// Splits `path` into its parent directory components and the final name.
+fn split_parent(path: &str) -> Result<(Vec<&str>, &str), &'static str> {
+    let mut components = split_path(path);
+    let name = components.pop().ok_or("Empty path")?;
+    Ok((components, name))
+}
 
+/// Opens the root directory, walks down `dirs` and runs `f` on the directory
+/// found there. Missing directories are created on the way when
+/// `create_missing` is set.
+fn with_directory_at_path<R>(
+    dirs: &[&str],
+    create_missing: bool,
+    f: impl FnOnce(&mut FatDirectory) -> Result<R, &'static str>,
+) -> Result<R, &'static str> {
     let mut guard = VOLUME_MANAGER.lock();
     let manager = guard.as_mut().ok_or("No volume manager")?;
     let mut volume = manager
-        .open_volume(VolumeIdx(0))
+        .open_volume(root_volume())
         .map_err(|_| "open_volume failed")?;
 
-    let mut root_dir = volume.open_root_dir().map_err(|_| "open_root_dir failed")?;
-    let mut file = root_dir
-        .open_file_in_dir(file_name, Mode::ReadWriteCreateOrTruncate)
-        .map_err(|_| "open_file failed")?;
-    file.write(data).map_err(|_| "file.write failed")?;
-    Ok(())
+    let mut dir = volume.open_root_dir().map_err(|_| "open_root_dir failed")?;
+    for &name in dirs {
+        if dir.change_dir(name).is_ok() {
+            continue;
+        }
+        if !create_missing {
+            return Err("Directory not found");
+        }
+        dir.make_dir_in_dir(name)
+            .map_err(|_| "make_dir_in_dir failed")?;
+        dir.change_dir(name).map_err(|_| "change_dir failed")?;
+    }
+
+    f(&mut dir)
 }
 
-pub fn read_file(path: &str, buf: &mut [u8]) -> Result<usize, &'static str> {
-    let components = split_path(path);
+/// Creates every directory along `path` that does not exist yet.
+pub fn ensure_path_exists(path: &str) -> Result<(), &'static str> {
+    with_directory_at_path(&split_path(path), true, |_| Ok(()))
+}
 
-    if components.len() != 1 {
-        return Err("Only root directory files supported currently");
-    }
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), &'static str> {
+    let (dirs, file_name) = split_parent(path)?;
+
+    with_directory_at_path(&dirs, true, |dir| {
+        let mut file = dir
+            .open_file_in_dir(file_name, Mode::ReadWriteCreateOrTruncate)
+            .map_err(|_| "open_file failed")?;
+        file.write(data).map_err(|_| "file.write failed")?;
+        Ok(())
+    })
+}
 
-    let file_name = components[0];
+/// Adds `data` to the end of `path`, creating it and its directories if
+/// needed, without rewriting what is already there.
+pub fn append_file(path: &str, data: &[u8]) -> Result<(), &'static str> {
+    let (dirs, file_name) = split_parent(path)?;
+
+    with_directory_at_path(&dirs, true, |dir| {
+        let mut file = dir
+            .open_file_in_dir(file_name, Mode::ReadWriteCreateOrAppend)
+            .map_err(|_| "open_file failed")?;
+        file.write(data).map_err(|_| "file.write failed")?;
+        Ok(())
+    })
+}
 
-    let mut guard = VOLUME_MANAGER.lock();
-    let manager = guard.as_mut().ok_or("No volume manager")?;
-    let mut volume = manager
-        .open_volume(VolumeIdx(0))
-        .map_err(|_| "open_volume failed")?;
+pub fn read_file(path: &str, buf: &mut [u8]) -> Result<usize, &'static str> {
+    read_file_at(path, 0, buf)
+}
 
-    let mut root_dir = volume.open_root_dir().map_err(|_| "open_root_dir failed")?;
-    let mut file = root_dir
-        .open_file_in_dir(file_name, Mode::ReadOnly)
-        .map_err(|_| "open_file failed")?;
-    let n = file.read(buf).map_err(|_| "file.read failed")?;
-    Ok(n)
+/// Returned by `read_file_at` for an offset beyond the end of the file.
+const OFFSET_PAST_EOF: &str = "Offset past end of file";
+
+/// Reads from `offset` into `buf` until it is full or the file ends.
+/// Returns the number of bytes read, 0 at the end of the file. An offset
+/// beyond the end is an error rather than an empty read.
+pub fn read_file_at(path: &str, offset: u32, buf: &mut [u8]) -> Result<usize, &'static str> {
+    let (dirs, file_name) = split_parent(path)?;
+
+    with_directory_at_path(&dirs, false, |dir| {
+        let mut file = dir
+            .open_file_in_dir(file_name, Mode::ReadOnly)
+            .map_err(|e| match e {
+                embedded_sdmmc::Error::NotFound => "File not found",
+                _ => "open_file failed",
+            })?;
+        if offset > file.length() {
+            return Err(OFFSET_PAST_EOF);
+        }
+        if offset == file.length() {
+            return Ok(0);
+        }
+        file.seek_from_start(offset)
+            .map_err(|_| "file.seek failed")?;
+
+        let mut total = 0;
+        while total < buf.len() && !file.is_eof() {
+            let n = file
+                .read(&mut buf[total..])
+                .map_err(|_| "file.read failed")?;
+            if n == 0 {
+                break;
+            }
+            total += n;
+        }
+        Ok(total)
+    })
+}
+
+/// Writes `data` at `offset`, creating the file if needed. Offsets past the
+/// end are clamped to it, so files never get holes.
+pub fn write_file_at(path: &str, offset: u32, data: &[u8]) -> Result<(), &'static str> {
+    let (dirs, file_name) = split_parent(path)?;
+
+    with_directory_at_path(&dirs, true, |dir| {
+        let mut file = dir
+            .open_file_in_dir(file_name, Mode::ReadWriteCreateOrAppend)
+            .map_err(|_| "open_file failed")?;
+        let offset = offset.min(file.length());
+        file.seek_from_start(offset)
+            .map_err(|_| "file.seek failed")?;
+        file.write(data).map_err(|_| "file.write failed")?;
+        Ok(())
+    })
 }
 
 pub fn remove_file(path: &str) -> Result<(), &'static str> {
-    let components = split_path(path);
+    let (dirs, file_name) = split_parent(path)?;
 
-    if components.len() != 1 {
-        return Err("Only root directory files supported currently");
-    }
+    with_directory_at_path(&dirs, false, |dir| {
+        dir.delete_file_in_dir(file_name).map_err(|e| match e {
+            embedded_sdmmc::Error::NotFound => "File not found",
+            _ => "delete_file failed",
+        })
+    })
+}
 
-    let file_name = components[0];
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u32,
+    pub is_directory: bool,
+}
 
-    let mut guard = VOLUME_MANAGER.lock();
-    let manager = guard.as_mut().ok_or("No volume manager")?;
-    let mut volume = manager
-        .open_volume(VolumeIdx(0))
-        .map_err(|_| "open_volume failed")?;
+pub fn stat(path: &str) -> Result<FileStat, &'static str> {
+    let Ok((dirs, name)) = split_parent(path) else {
+        // The root directory has no entry of its own.
+        return Ok(FileStat {
+            size: 0,
+            is_directory: true,
+        });
+    };
 
-    let mut root_dir = volume.open_root_dir().map_err(|_| "open_root_dir failed")?;
-    root_dir
-        .delete_file_in_dir(file_name)
-        .map_err(|_| "delete_file failed")?;
-    Ok(())
+    with_directory_at_path(&dirs, false, |dir| {
+        let entry = dir
+            .find_directory_entry(name)
+            .map_err(|_| "File not found")?;
+        Ok(FileStat {
+            size: entry.size,
+            is_directory: entry.attributes.is_directory(),
+        })
+    })
 }
 
-pub fn create_dir(path: &str) -> Result<(), &'static str> {
-    let components = split_path(path);
-
-    if components.len() != 1 {
-        return Err("Only root directory creation supported currently");
+/// Moves a file by copying it to `new_path` and deleting `old_path`, since
+/// embedded-sdmmc has no rename. Fails without touching anything if
+/// `new_path` already exists.
+pub fn rename_file(old_path: &str, new_path: &str) -> Result<(), &'static str> {
+    let info = stat(old_path)?;
+    if info.is_directory {
+        return Err("Cannot rename directories");
+    }
+    if stat(new_path).is_ok() {
+        return Err("Destination exists");
     }
 
-    let dir_name = components[0];
+    let mut data = alloc::vec![0u8; info.size as usize];
+    let n = read_file(old_path, &mut data)?;
+    if n != data.len() {
+        return Err("Short read while renaming");
+    }
+    write_file(new_path, &data)?;
+    remove_file(old_path)
+}
 
-    let mut guard = VOLUME_MANAGER.lock();
-    let manager = guard.as_mut().ok_or("No volume manager")?;
-    let mut volume = manager
-        .open_volume(VolumeIdx(0))
-        .map_err(|_| "open_volume failed")?;
+pub fn create_dir(path: &str) -> Result<(), &'static str> {
+    let (dirs, dir_name) = split_parent(path)?;
 
-    let mut root_dir = volume.open_root_dir().map_err(|_| "open_root_dir failed")?;
-    root_dir
-        .make_dir_in_dir(dir_name)
-        .map_err(|_| "make_dir_in_dir failed")?;
-    Ok(())
+    with_directory_at_path(&dirs, true, |dir| {
+        dir.make_dir_in_dir(dir_name)
+            .map_err(|_| "make_dir_in_dir failed")
+    })
 }
 
 pub fn remove_dir(path: &str) -> Result<(), &'static str> {
-    let components = split_path(path);
+    let (dirs, dir_name) = split_parent(path)?;
 
-    if components.len() != 1 {
-        return Err("Only root directory removal supported currently");
-    }
+    with_directory_at_path(&dirs, false, |dir| {
+        dir.delete_file_in_dir(dir_name).map_err(|e| match e {
+            embedded_sdmmc::Error::NotFound => "Directory not found",
+            _ => "Directory removal failed - method may not exist or directory not empty",
+        })
+    })
+}
 
-    let dir_name = components[0];
+pub fn list_dir(path: &str) -> Result<Vec<String>, &'static str> {
+    with_directory_at_path(&split_path(path), false, |dir| {
+        let mut names = Vec::new();
+        dir.iterate_dir(|entry| {
+            names.push(entry.name.to_string());
+        })
+        .map_err(|_| "iterate_dir failed")?;
+        Ok(names)
+    })
+}
 
+/// Sectors of the FAT read per ATA request while counting free clusters.
+const FAT_SCAN_SECTORS: u64 = 64;
+
+/// Size and free space of the mounted volume. embedded-sdmmc does not track
+/// free space, so this reads the boot sector and counts the zero entries in
+/// the first FAT. Only FAT16 and FAT32 volumes are handled.
+pub fn volume_stats() -> Result<VolumeStats, &'static str> {
     let mut guard = VOLUME_MANAGER.lock();
-    let manager = guard.as_mut().ok_or("No volume manager")?;
-    let mut volume = manager
-        .open_volume(VolumeIdx(0))
-        .map_err(|_| "open_volume failed")?;
+    let dev = guard.as_mut().ok_or("No volume manager")?.device();
+    let read = |lba: u64, blocks: &mut [Block]| {
+        let lba = u32::try_from(lba).map_err(|_| "FAT read failed")?;
+        dev.read(blocks, BlockIdx(lba), "volume_stats")
+            .map_err(|_| "FAT read failed")
+    };
 
-    let mut root_dir = volume.open_root_dir().map_err(|_| "open_root_dir failed")?;
+    let mut block = [Block::new()];
+    read(0, &mut block).map_err(|_| "MBR read failed")?;
+    let mbr = &block[0].contents;
+    let partition = ROOT_VOLUME.load(Ordering::Relaxed);
+    if partition >= 4 || mbr[510..512] != [0x55, 0xAA] {
+        return Err("partition not found");
+    }
+    let entry = 446 + partition * 16;
+    let start = u32::from_le_bytes([
+        mbr[entry + 8],
+        mbr[entry + 9],
+        mbr[entry + 10],
+        mbr[entry + 11],
+    ]) as u64;
+
+    read(start, &mut block).map_err(|_| "boot sector read failed")?;
+    let boot = &block[0].contents;
+    let u16_at = |i: usize| u16::from_le_bytes([boot[i], boot[i + 1]]) as u64;
+    let u32_at =
+        |i: usize| u32::from_le_bytes([boot[i], boot[i + 1], boot[i + 2], boot[i + 3]]) as u64;
+
+    let bytes_per_sector = u16_at(0x0B);
+    let sectors_per_cluster = boot[0x0D] as u64;
+    let reserved = u16_at(0x0E);
+    let fat_count = boot[0x10] as u64;
+    let root_entries = u16_at(0x11);
+    let total_sectors = match u16_at(0x13) {
+        0 => u32_at(0x20),
+        n => n,
+    };
+    let fat_sectors = match u16_at(0x16) {
+        0 => u32_at(0x24),
+        n => n,
+    };
+    if bytes_per_sector != 512 || sectors_per_cluster == 0 {
+        return Err("Unsupported FAT geometry");
+    }
 
-    root_dir
-        .delete_file_in_dir(dir_name)
-        .map_err(|_| "Directory removal failed - method may not exist or directory not empty")?;
-    Ok(())
+    let root_dir_sectors = (root_entries * 32).div_ceil(bytes_per_sector);
+    let data_start = reserved + fat_count * fat_sectors + root_dir_sectors;
+    let clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+    let entry_size = match clusters {
+        0..4085 => return Err("FAT12 is not supported"),
+        4085..65525 => 2,
+        _ => 4,
+    };
+
+    // Entries 0 and 1 are reserved; clusters are numbered from 2.
+    let mut free = 0;
+    let mut entry = 0u64;
+    let mut buf = alloc::vec![Block::new(); FAT_SCAN_SECTORS as usize];
+    let fat_start = start + reserved;
+    let mut sector = 0;
+    while sector < fat_sectors && entry < clusters + 2 {
+        let count = FAT_SCAN_SECTORS.min(fat_sectors - sector);
+        let chunk = &mut buf[..count as usize];
+        read(fat_start + sector, chunk)?;
+        let entries = chunk
+            .iter()
+            .flat_map(|block| block.contents.chunks_exact(entry_size));
+        for raw in entries {
+            if (2..clusters + 2).contains(&entry) {
+                let value = match entry_size {
+                    2 => u16::from_le_bytes([raw[0], raw[1]]) as u32,
+                    _ => u32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) & 0x0FFF_FFFF,
+                };
+                if value == 0 {
+                    free += 1;
+                }
+            }
+            entry += 1;
+        }
+        sector += count;
+    }
+
+    let cluster_size = sectors_per_cluster * bytes_per_sector;
+    Ok(VolumeStats {
+        total_bytes: clusters * cluster_size,
+        free_bytes: free * cluster_size,
+        cluster_size: cluster_size as u32,
+    })
 }
 
-pub fn list_dir(path: &str) -> Result<Vec<String>, &'static str> {
-    let components = split_path(path);
+/// The mounted FAT volume behind `VOLUME_MANAGER`, as a `FileSystem`.
+pub struct FatFileSystem;
 
-    if !components.is_empty() {
-        return Err("Only root directory listing supported currently");
+impl FileSystem for FatFileSystem {
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        if stat(path).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(write_file(path, &[])?)
     }
 
-    let mut guard = VOLUME_MANAGER.lock();
-    let manager = guard.as_mut().ok_or("No volume manager")?;
-    let mut volume = manager
-        .open_volume(VolumeIdx(0))
-        .map_err(|_| "open_volume failed")?;
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let info = stat(path)?;
+        if info.is_directory {
+            return Err(FsError::IsADirectory);
+        }
+        let mut data = alloc::vec![0u8; info.size as usize];
+        let n = read_file(path, &mut data)?;
+        data.truncate(n);
+        Ok(data)
+    }
 
-    let mut root_dir = volume.open_root_dir().map_err(|_| "open_root_dir failed")?;
-    let mut names = Vec::new();
-    root_dir
-        .iterate_dir(|entry| {
-            names.push(entry.name.to_string());
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        if stat(path).is_ok_and(|info| info.is_directory) {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(write_file(path, data)?)
+    }
+
+    fn delete_file(&mut self, path: &str) -> Result<(), FsError> {
+        if stat(path)?.is_directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(remove_file(path)?)
+    }
+
+    fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        if !stat(path)?.is_directory {
+            return Err(FsError::NotADirectory);
+        }
+        with_directory_at_path(&split_path(path), false, |dir| {
+            let mut entries = Vec::new();
+            dir.iterate_dir(|entry| {
+                entries.push(DirEntry {
+                    name: entry.name.to_string(),
+                    size: entry.size as u64,
+                    is_directory: entry.attributes.is_directory(),
+                });
+            })
+            .map_err(|_| "iterate_dir failed")?;
+            Ok(entries)
         })
-        .map_err(|_| "iterate_dir failed")?;
-    Ok(names)
+        .map_err(FsError::from)
+    }
+
+    fn stat(&mut self, path: &str) -> Result<DirEntry, FsError> {
+        let info = stat(path)?;
+        Ok(DirEntry {
+            name: split_path(path).last().unwrap_or(&"").to_string(),
+            size: info.size as u64,
+            is_directory: info.is_directory,
+        })
+    }
+
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        // FAT files end below 4 GiB, so a larger offset is past the end.
+        // The VFS reads nothing there rather than failing.
+        match u32::try_from(offset).map(|offset| read_file_at(path, offset, buf)) {
+            Ok(Err(OFFSET_PAST_EOF)) | Err(_) => Ok(0),
+            Ok(result) => Ok(result?),
+        }
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let offset = u32::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        Ok(write_file_at(path, offset, data)?)
+    }
+
+    fn rename(&mut self, old_path: &str, new_path: &str) -> Result<(), FsError> {
+        Ok(rename_file(old_path, new_path)?)
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        Ok(create_dir(path)?)
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        Ok(remove_dir(path)?)
+    }
+
+    fn volume_stats(&mut self) -> Result<VolumeStats, FsError> {
+        Ok(volume_stats()?)
+    }
 }
 
 pub fn test_fat32() {
@@ -217,6 +561,32 @@ pub fn test_fat32() {
         return;
     }
 
+    match append_file(test_path, b"!!") {
+        Ok(()) => match read_file_at(test_path, test_data.len() as u32, &mut buf) {
+            Ok(2) if &buf[..2] == b"!!" => {
+                println!("FAT32 test: Append and seek passed");
+            }
+            Ok(_) => {
+                println!("FAT32 test: Appended data not found at the old end");
+            }
+            Err(e) => {
+                println!("FAT32 test: Read at offset failed: {}", e);
+            }
+        },
+        Err(e) => {
+            println!("FAT32 test: Append failed: {}", e);
+        }
+    }
+
+    match read_file_at(test_path, test_data.len() as u32 + 3, &mut buf) {
+        Err(OFFSET_PAST_EOF) => {
+            println!("FAT32 test: Read past end of file rejected");
+        }
+        _ => {
+            println!("FAT32 test: Read past end of file was not rejected");
+        }
+    }
+
     let test_dir = "TESTDIR";
     match create_dir(test_dir) {
         Ok(()) => {
@@ -227,6 +597,24 @@ pub fn test_fat32() {
         }
     }
 
+    let nested_path = "TESTDIR/SUBDIR/NESTED.TXT";
+    match write_file(nested_path, test_data) {
+        Ok(()) => match read_file(nested_path, &mut buf) {
+            Ok(n) if &buf[..n] == test_data => {
+                println!("FAT32 test: Nested file round-trip passed");
+            }
+            Ok(_) => {
+                println!("FAT32 test: Nested file content mismatch");
+            }
+            Err(e) => {
+                println!("FAT32 test: Nested read failed: {}", e);
+            }
+        },
+        Err(e) => {
+            println!("FAT32 test: Nested write failed: {}", e);
+        }
+    }
+
     match list_dir("") {
         Ok(entries) => {
             println!("FAT32 test: Directory listing successful");
@@ -266,15 +654,127 @@ pub fn test_fat32() {
     println!("FAT32 test: All tests completed!");
 }
 
-pub fn test_fat32_with_device(device: crate::drivers::ata::AtaDevice, block_count: u32) {
+pub fn test_fat32_with_device(
+    device: crate::drivers::ata::AtaDevice,
+    partition: usize,
+    block_count: u32,
+) {
     use crate::serial_println as println;
 
     println!(
-        "Mounting FAT32 filesystem on device {:?} with {} blocks...",
-        device, block_count
+        "Mounting FAT32 filesystem on device {:?} partition {} with {} blocks...",
+        device, partition, block_count
     );
 
-    mount_root_fs(device, block_count);
+    if let Err(e) = mount_root_fs(device, partition, block_count) {
+        println!("FAT32 test: Mount failed: {}", e);
+        return;
+    }
+
+    test_fat32();
+}
+
+/// Runs the FAT tests against a freshly formatted RAM disk, so they pass or
+/// fail without a second ATA drive. The previous root volume is put back.
+pub fn test_fat_on_ram_disk() {
+    use crate::serial_println as println;
+
+    const BLOCKS: u32 = 8192;
+
+    println!("FAT RAM disk test: formatting {} blocks...", BLOCKS);
+    let disk = match RamBlockDevice::format_fat16(BLOCKS) {
+        Ok(disk) => disk,
+        Err(e) => {
+            println!("FAT RAM disk test:  Format failed: {}", e);
+            return;
+        }
+    };
+    let previous_volume = ROOT_VOLUME.load(Ordering::Relaxed);
+    let previous = mount_ram_fs(disk, 0);
+
+    match volume_stats() {
+        Ok(stats) if stats.free_bytes == stats.total_bytes && stats.total_bytes > 0 => {
+            println!(
+                "FAT RAM disk test:  Empty volume, {} bytes free",
+                stats.free_bytes
+            );
+        }
+        Ok(stats) => {
+            println!(
+                "FAT RAM disk test:  Fresh volume not empty: {} of {} bytes free",
+                stats.free_bytes, stats.total_bytes
+            );
+        }
+        Err(e) => println!("FAT RAM disk test:  volume_stats failed: {}", e),
+    }
 
     test_fat32();
+    if let Err(e) = crate::fs::vfs::test_filesystem("FAT on RAM disk", &mut FatFileSystem) {
+        println!("FAT RAM disk test:  VFS suite failed: {}", e);
+    }
+
+    let data = [0x5Au8; 4096];
+    let used = write_file("BIG.BIN", &data)
+        .and_then(|()| volume_stats())
+        .map(|stats| stats.total_bytes - stats.free_bytes);
+    match used {
+        Ok(bytes) if bytes >= data.len() as u64 => {
+            println!("FAT RAM disk test:  Free space drops by {} bytes", bytes);
+        }
+        Ok(bytes) => println!("FAT RAM disk test:  Only {} bytes in use", bytes),
+        Err(e) => println!("FAT RAM disk test:  Write failed: {}", e),
+    }
+    let _ = remove_file("BIG.BIN");
+
+    *VOLUME_MANAGER.lock() = previous;
+    ROOT_VOLUME.store(previous_volume, Ordering::Relaxed);
+    println!("FAT RAM disk test: All tests completed!");
+}
+
+/// Writes a file to a fresh RAM disk, reads it back and removes it. The
+/// previous root volume is put back whether or not it passes.
+pub fn test_fat_round_trip() -> Result<(), &'static str> {
+    const PATH: &str = "ROUND.BIN";
+
+    let disk = RamBlockDevice::format_fat16(8192)?;
+    let previous_volume = ROOT_VOLUME.load(Ordering::Relaxed);
+    let previous = mount_ram_fs(disk, 0);
+
+    let result = (|| {
+        let mut data = alloc::vec![0u8; 3000];
+        crate::rand::SplitMix64::new(0x5050).fill(&mut data);
+        write_file(PATH, &data)?;
+        if stat(PATH)?.size != data.len() as u32 {
+            return Err("stat size does not match what was written");
+        }
+        let mut buf = alloc::vec![0u8; data.len() + 16];
+        let read = read_file(PATH, &mut buf)?;
+        if buf[..read] != data[..] {
+            return Err("read back different data");
+        }
+
+        append_file(PATH, b"tail")?;
+        let mut tail = [0u8; 8];
+        if read_file_at(PATH, data.len() as u32, &mut tail)? != 4 || &tail[..4] != b"tail" {
+            return Err("appended data not found at the old end");
+        }
+        if read_file_at(PATH, 1000, &mut tail)? != tail.len() || tail[..] != data[1000..1008] {
+            return Err("read from an offset returned the wrong bytes");
+        }
+        if read_file_at(PATH, data.len() as u32 + 4, &mut tail)? != 0 {
+            return Err("read at the end of the file returned data");
+        }
+        if read_file_at(PATH, data.len() as u32 + 5, &mut tail) != Err(OFFSET_PAST_EOF) {
+            return Err("read past the end of the file did not fail");
+        }
+        remove_file(PATH)?;
+        if stat(PATH).is_ok() {
+            return Err("file still there after remove");
+        }
+        Ok(())
+    })();
+
+    *VOLUME_MANAGER.lock() = previous;
+    ROOT_VOLUME.store(previous_volume, Ordering::Relaxed);
+    result
 }
@@ -0,0 +1,40 @@
+use alloc::boxed::Box;
+
+use crate::fs::ramdisk::RamDiskFs;
+use crate::fs::tar;
+use crate::fs::vfs::FsError;
+
+/// Where `init` mounts the initrd.
+pub const MOUNT_POINT: &str = "/init";
+
+/// Bootloader 0.9 can't load modules next to the kernel, so with the
+/// `initrd` feature the archive is linked into the kernel image instead:
+/// `initrd.tar` next to `Cargo.toml`, a POSIX ustar archive as made by
+/// `tar --format=ustar -cf initrd.tar -C initrd .`.
+#[cfg(feature = "initrd")]
+static IMAGE: Option<&[u8]> = Some(include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/initrd.tar"
+)));
+#[cfg(not(feature = "initrd"))]
+static IMAGE: Option<&[u8]> = None;
+
+/// Unpacks a ustar archive into a fresh `RamDiskFs`. Regular files and
+/// directories are kept; links and devices are skipped.
+pub fn load(image: &[u8]) -> Result<RamDiskFs, FsError> {
+    let mut fs = RamDiskFs::new();
+    tar::extract_to(image, &mut fs, "/")?;
+    Ok(fs)
+}
+
+/// Unpacks the initrd, if the kernel was built with one, and mounts it at
+/// `MOUNT_POINT`. Without one there is nothing to mount.
+pub fn init() -> Result<(), FsError> {
+    let Some(image) = IMAGE else {
+        crate::serial_println!("No initrd, {} not mounted", MOUNT_POINT);
+        return Ok(());
+    };
+    let fs = load(image)?;
+    crate::serial_println!("initrd: {} bytes unpacked", image.len());
+    crate::fs::mount::mount(MOUNT_POINT, Box::new(fs))
+}
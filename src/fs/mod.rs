@@ -1,3 +1,103 @@
+use alloc::borrow::Cow;
+use alloc::string::String;
+use core::fmt;
+
+use crate::util::HexDump;
+
 pub mod ata_block;
 pub mod fat;
+pub mod initrd;
+pub mod mount;
+pub mod ram_block;
+pub mod ramdisk;
 pub mod syscalls;
+pub mod tar;
+pub mod vfs;
+
+/// Share of bytes that must be printable for `display_file` to show a file
+/// as text, in percent.
+const TEXT_THRESHOLD_PERCENT: usize = 95;
+
+/// A file's contents, ready to print as text or as a hex dump. Both end in
+/// a newline unless empty.
+pub enum FileDisplay<'a> {
+    Text(Cow<'a, str>),
+    Hex(HexDump<'a>),
+}
+
+impl fmt::Display for FileDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileDisplay::Text(text) => {
+                f.write_str(text)?;
+                if !text.is_empty() && !text.ends_with('\n') {
+                    f.write_str("\n")?;
+                }
+                Ok(())
+            }
+            FileDisplay::Hex(dump) => write!(f, "{}", dump),
+        }
+    }
+}
+
+/// Whether `data` reads as text: no NULs and at least
+/// `TEXT_THRESHOLD_PERCENT` printable bytes. Tabs and line breaks count as
+/// printable, and so do bytes of multi-byte UTF-8 characters.
+pub fn is_text(data: &[u8]) -> bool {
+    if data.contains(&0) {
+        return false;
+    }
+    let printable = data
+        .iter()
+        .filter(|&&b| b.is_ascii_graphic() || b" \t\n\r".contains(&b) || b >= 0x80)
+        .count();
+    printable * 100 >= data.len() * TEXT_THRESHOLD_PERCENT
+}
+
+/// Picks how to show a file: as text if `is_text` says so, with invalid
+/// UTF-8 replaced, otherwise as a hex dump.
+pub fn display_file(data: &[u8]) -> FileDisplay<'_> {
+    if is_text(data) {
+        FileDisplay::Text(String::from_utf8_lossy(data))
+    } else {
+        display_file_hex(data)
+    }
+}
+
+/// Shows a file as a hex dump whatever it holds.
+pub fn display_file_hex(data: &[u8]) -> FileDisplay<'_> {
+    FileDisplay::Hex(HexDump::new(data, 0))
+}
+
+/// Checks text, binary and nearly-text files are told apart and that text
+/// gets a trailing newline while hex dumps match `HexDump`.
+pub fn test_display_file() -> Result<(), &'static str> {
+    use alloc::format;
+
+    let text = "héllo\tworld\r\nsecond line";
+    if format!("{}", display_file(text.as_bytes())) != format!("{}\n", text) {
+        return Err("text file was not shown as text");
+    }
+    if !format!("{}", display_file(b"")).is_empty() {
+        return Err("empty file produced output");
+    }
+
+    let binary = [0x7F, b'E', b'L', b'F', 2, 1, 1, 0];
+    if format!("{}", display_file(&binary)) != format!("{}", HexDump::new(&binary, 0)) {
+        return Err("binary file was not shown as hex");
+    }
+
+    // One escape byte in 40 is still text; one in 10 is not.
+    let mut mostly_text = [b'a'; 40];
+    mostly_text[3] = 0x1B;
+    if !is_text(&mostly_text) || is_text(&mostly_text[..10]) {
+        return Err("printable ratio misjudged");
+    }
+
+    if !matches!(display_file_hex(text.as_bytes()), FileDisplay::Hex(_)) {
+        return Err("forced hex shown as text");
+    }
+
+    crate::serial_println!("✓ Files shown as text or hex as appropriate");
+    Ok(())
+}
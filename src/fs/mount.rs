@@ -0,0 +1,441 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::fs::vfs::{DirEntry, FileSystem, FsError, VolumeStats};
+
+struct Mount {
+    /// Normalised: starts with `/` and has no trailing `/` except for the
+    /// root itself.
+    prefix: String,
+    fs: Box<dyn FileSystem>,
+}
+
+/// Every mounted filesystem. Lookups pick the longest prefix that covers the
+/// path, so `/ram/x` goes to a `/ram` mount even with `/` mounted too.
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Makes `path` absolute and strips trailing slashes, keeping `/` itself.
+fn normalize(path: &str) -> String {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.starts_with('/') {
+        trimmed.to_string()
+    } else if trimmed.is_empty() {
+        String::from("/")
+    } else {
+        alloc::format!("/{}", trimmed)
+    }
+}
+
+/// Returns the part of `path` below `prefix`, or `None` if `prefix` does not
+/// cover `path`. Matches whole components only, so `/ram` covers `/ram` and
+/// `/ram/a` but not `/ramdisk`.
+fn strip_mount_prefix<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    if prefix == "/" {
+        return Some(path);
+    }
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+}
+
+/// Finds the longest mount covering the normalised `path`, returning its
+/// index and the path relative to it.
+fn resolve<'a>(mounts: &[Mount], path: &'a str) -> Result<(usize, &'a str), FsError> {
+    mounts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, m)| Some((i, strip_mount_prefix(&m.prefix, path)?)))
+        .max_by_key(|&(i, _)| mounts[i].prefix.len())
+        .ok_or(FsError::NotMounted)
+}
+
+/// Mounts `fs` at `path_prefix`. Fails with `AlreadyExists` if something is
+/// mounted there already.
+pub fn mount(path_prefix: &str, fs: Box<dyn FileSystem>) -> Result<(), FsError> {
+    if !path_prefix.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let prefix = normalize(path_prefix);
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|m| m.prefix == prefix) {
+        return Err(FsError::AlreadyExists);
+    }
+    crate::serial_println!("VFS: mounted filesystem at {}", prefix);
+    mounts.push(Mount { prefix, fs });
+    Ok(())
+}
+
+/// Removes the filesystem mounted at `path_prefix` and hands it back.
+pub fn unmount(path_prefix: &str) -> Result<Box<dyn FileSystem>, FsError> {
+    let prefix = normalize(path_prefix);
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|m| m.prefix == prefix)
+        .ok_or(FsError::NotMounted)?;
+    crate::serial_println!("VFS: unmounted {}", prefix);
+    Ok(mounts.remove(index).fs)
+}
+
+/// The prefixes currently mounted, in mount order.
+pub fn mount_points() -> Vec<String> {
+    MOUNTS.lock().iter().map(|m| m.prefix.clone()).collect()
+}
+
+/// Resolves `path` to its mount and runs `f` on that filesystem with the
+/// path relative to the mount point.
+fn with_fs<R>(
+    path: &str,
+    f: impl FnOnce(&mut dyn FileSystem, &str) -> Result<R, FsError>,
+) -> Result<R, FsError> {
+    let path = normalize(path);
+    let mut mounts = MOUNTS.lock();
+    let (index, rel) = resolve(&mounts, &path)?;
+    f(mounts[index].fs.as_mut(), rel)
+}
+
+pub fn create_file(path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rel| fs.create_file(rel))
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    with_fs(path, |fs, rel| fs.read_file(rel))
+}
+
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    with_fs(path, |fs, rel| fs.write_file(rel, data))
+}
+
+pub fn read_at(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    with_fs(path, |fs, rel| fs.read_at(rel, offset, buf))
+}
+
+pub fn write_at(path: &str, offset: u64, data: &[u8]) -> Result<(), FsError> {
+    with_fs(path, |fs, rel| fs.write_at(rel, offset, data))
+}
+
+pub fn delete_file(path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rel| fs.delete_file(rel))
+}
+
+pub fn stat(path: &str) -> Result<DirEntry, FsError> {
+    with_fs(path, |fs, rel| fs.stat(rel))
+}
+
+/// Capacity of the filesystem mounted exactly at `path_prefix`.
+pub fn volume_stats(path_prefix: &str) -> Result<VolumeStats, FsError> {
+    let prefix = normalize(path_prefix);
+    let mut mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter_mut()
+        .find(|m| m.prefix == prefix)
+        .ok_or(FsError::NotMounted)?;
+    mount.fs.volume_stats()
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rel| fs.create_dir(rel))
+}
+
+pub fn remove_dir(path: &str) -> Result<(), FsError> {
+    with_fs(path, |fs, rel| fs.remove_dir(rel))
+}
+
+/// Lists `path`, adding the mount points directly below it so they show up
+/// even if the parent filesystem has no directory of that name.
+pub fn list_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let mut entries = with_fs(path, |fs, rel| fs.list_dir(rel))?;
+    let dir = normalize(path);
+    for prefix in mount_points() {
+        let Some(name) = strip_mount_prefix(&dir, &prefix)
+            .map(|rest| rest.trim_start_matches('/'))
+            .filter(|rest| !rest.is_empty() && !rest.contains('/'))
+        else {
+            continue;
+        };
+        if !entries.iter().any(|e| e.name.eq_ignore_ascii_case(name)) {
+            entries.push(DirEntry {
+                name: name.to_string(),
+                size: 0,
+                is_directory: true,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Renames within one filesystem. Moving between mounts is `Unsupported`.
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let old_path = normalize(old_path);
+    let new_path = normalize(new_path);
+    let mut mounts = MOUNTS.lock();
+    let (old_index, old_rel) = resolve(&mounts, &old_path)?;
+    let (new_index, new_rel) = resolve(&mounts, &new_path)?;
+    if old_index != new_index {
+        return Err(FsError::Unsupported);
+    }
+    mounts[old_index].fs.rename(old_rel, new_rel)
+}
+
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register("ls", "list a directory: ls [path]", cmd_ls);
+    shell.register(
+        "cat",
+        "print a file, as hex if it isn't text: cat [--hex] <path>",
+        cmd_cat,
+    );
+    shell.register("write", "write a file: write <path> <text>", cmd_write);
+    shell.register("rm", "remove a file: rm <path>", cmd_rm);
+    shell.register("cp", "copy a file: cp <src> <dst>", cmd_cp);
+    shell.register("mv", "move a file: mv <src> <dst>", cmd_mv);
+    shell.register("mkdir", "create a directory: mkdir <path>", cmd_mkdir);
+    shell.register("mounts", "list mounted filesystems", cmd_mounts);
+    shell.register("df", "show free space: df [mount point]", cmd_df);
+    shell.register("umount", "unmount a filesystem: umount <path>", cmd_umount);
+}
+
+fn cmd_ls(_shell: &crate::sshell::Shell, args: &[&str]) {
+    match list_dir(args.first().copied().unwrap_or("/")) {
+        Ok(entries) => {
+            for entry in entries {
+                crate::println!("{}", entry.name);
+            }
+        }
+        Err(e) => crate::println!("ls: {}", e),
+    }
+}
+
+fn cmd_cat(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let (hex, args) = match args.split_first() {
+        Some((&"--hex", rest)) => (true, rest),
+        _ => (false, args),
+    };
+    let Some(&path) = args.first() else {
+        crate::println!("usage: cat [--hex] <path>");
+        return;
+    };
+    match read_file(path) {
+        Ok(data) if hex => crate::print!("{}", crate::fs::display_file_hex(&data)),
+        Ok(data) => crate::print!("{}", crate::fs::display_file(&data)),
+        Err(e) => crate::println!("cat: {}", e),
+    }
+}
+
+fn cmd_write(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let Some((&path, text)) = args.split_first() else {
+        crate::println!("usage: write <path> <text>");
+        return;
+    };
+    if let Err(e) = write_file(path, text.join(" ").as_bytes()) {
+        crate::println!("write: {}", e);
+    }
+}
+
+fn cmd_rm(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        crate::println!("usage: rm <path>");
+        return;
+    };
+    if let Err(e) = delete_file(path) {
+        crate::println!("rm: {}", e);
+    }
+}
+
+fn cmd_cp(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let [src, dst] = args else {
+        crate::println!("usage: cp <src> <dst>");
+        return;
+    };
+    if let Err(e) = copy_file(src, dst) {
+        crate::println!("cp: {}", e);
+    }
+}
+
+fn cmd_mv(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let [src, dst] = args else {
+        crate::println!("usage: mv <src> <dst>");
+        return;
+    };
+    if let Err(e) = move_file(src, dst) {
+        crate::println!("mv: {}", e);
+    }
+}
+
+fn cmd_mkdir(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        crate::println!("usage: mkdir <path>");
+        return;
+    };
+    if let Err(e) = create_dir(path) {
+        crate::println!("mkdir: {}", e);
+    }
+}
+
+fn cmd_mounts(_shell: &crate::sshell::Shell, _args: &[&str]) {
+    for prefix in mount_points() {
+        crate::println!("{}", prefix);
+    }
+}
+
+fn cmd_df(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let prefixes = match args.first() {
+        Some(&prefix) => alloc::vec![normalize(prefix)],
+        None => mount_points(),
+    };
+    if prefixes.is_empty() {
+        crate::println!("df: nothing mounted");
+        return;
+    }
+    crate::println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>5}",
+        "MOUNT",
+        "SIZE(K)",
+        "USED(K)",
+        "FREE(K)",
+        "USE%"
+    );
+    for prefix in prefixes {
+        match volume_stats(&prefix) {
+            Ok(stats) => {
+                let used = stats.total_bytes - stats.free_bytes;
+                let percent = (used * 100).checked_div(stats.total_bytes).unwrap_or(0);
+                crate::println!(
+                    "{:<12} {:>10} {:>10} {:>10} {:>4}%",
+                    prefix,
+                    stats.total_bytes / 1024,
+                    used / 1024,
+                    stats.free_bytes / 1024,
+                    percent
+                );
+            }
+            Err(e) => crate::println!("df: {}: {}", prefix, e),
+        }
+    }
+}
+
+fn cmd_umount(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let Some(&path) = args.first() else {
+        crate::println!("usage: umount <path>");
+        return;
+    };
+    if let Err(e) = unmount(path) {
+        crate::println!("umount: {}", e);
+    }
+}
+
+/// Where a copy of `src` lands: `dst` itself, or inside it when `dst` is an
+/// existing directory.
+fn copy_target(src: &str, dst: &str) -> String {
+    let dst = normalize(dst);
+    if stat(&dst).is_ok_and(|e| e.is_directory) {
+        let name = src.rsplit('/').next().unwrap_or(src);
+        return alloc::format!("{}/{}", dst.trim_end_matches('/'), name);
+    }
+    dst
+}
+
+/// Copies the bytes of `src` to `dst`, replacing anything already there.
+/// Works across mounts. Returns the path written.
+pub fn copy_file(src: &str, dst: &str) -> Result<String, FsError> {
+    let src = normalize(src);
+    let target = copy_target(&src, dst);
+    let data = read_file(&src)?;
+    write_file(&target, &data)?;
+    Ok(target)
+}
+
+/// Copies `src` to `dst` and then deletes `src`. Moving a file onto itself
+/// leaves it untouched.
+pub fn move_file(src: &str, dst: &str) -> Result<String, FsError> {
+    let src = normalize(src);
+    let target = copy_target(&src, dst);
+    if src.eq_ignore_ascii_case(&target) {
+        stat(&src)?;
+        return Ok(target);
+    }
+    copy_file(&src, &target)?;
+    delete_file(&src)?;
+    Ok(target)
+}
+
+/// Copies a file holding every byte value over an existing one, moves it,
+/// and checks the bytes survive both steps and a missing source fails with
+/// `NotFound`. Expects `/` to be mounted.
+pub fn test_copy_move() -> Result<(), FsError> {
+    crate::serial_println!("=== Copy/Move Test ===");
+
+    let data: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+    write_file("/CPSRC.BIN", &data)?;
+    write_file("/CPDST.BIN", b"to be replaced")?;
+
+    copy_file("/CPSRC.BIN", "/CPDST.BIN")?;
+    if read_file("/CPDST.BIN")? != data || read_file("/CPSRC.BIN")? != data {
+        return Err(FsError::Io("cp changed the contents"));
+    }
+    move_file("/CPDST.BIN", "/CPMOVED.BIN")?;
+    if read_file("/CPMOVED.BIN")? != data {
+        return Err(FsError::Io("mv changed the contents"));
+    }
+    if stat("/CPDST.BIN").err() != Some(FsError::NotFound) {
+        return Err(FsError::Io("mv left the source behind"));
+    }
+    move_file("/CPMOVED.BIN", "/CPMOVED.BIN")?;
+    if copy_file("/NOSUCH.BIN", "/CPDST.BIN").err() != Some(FsError::NotFound) {
+        return Err(FsError::Io("copied a missing file"));
+    }
+
+    delete_file("/CPSRC.BIN")?;
+    delete_file("/CPMOVED.BIN")?;
+    crate::serial_println!("✓ cp and mv preserve binary contents");
+    Ok(())
+}
+
+/// Mounts a second view of the FAT volume at `/mnt` next to the one at `/`
+/// and checks lookups pick the longest prefix, mounting twice and
+/// unmounting twice fail, and paths with no mount report `NotMounted`.
+/// Expects `/` to be mounted already.
+pub fn test_mount_table() -> Result<(), FsError> {
+    use crate::fs::fat::FatFileSystem;
+
+    crate::serial_println!("=== Mount Table Test ===");
+
+    mount("/mnt", Box::new(FatFileSystem))?;
+    if mount("/mnt/", Box::new(FatFileSystem)) != Err(FsError::AlreadyExists) {
+        return Err(FsError::Io("mounted twice at the same prefix"));
+    }
+
+    // Both mounts see the same volume, so a file written through `/mnt`
+    // must land in the volume's root rather than under a `MNT` directory.
+    write_file("/mnt/MOUNTED.TXT", b"via /mnt")?;
+    if read_file("/MOUNTED.TXT")? != b"via /mnt" {
+        return Err(FsError::Io("/mnt did not resolve to its own mount"));
+    }
+    if !list_dir("/")?
+        .iter()
+        .any(|e| e.name == "mnt" && e.is_directory)
+    {
+        return Err(FsError::Io("mount point missing from the parent listing"));
+    }
+    rename("/MOUNTED.TXT", "/MOVED.TXT")?;
+    if rename("/MOVED.TXT", "/mnt/MOVED2.TXT") != Err(FsError::Unsupported) {
+        return Err(FsError::Io("rename crossed mounts"));
+    }
+    delete_file("/mnt/MOVED.TXT")?;
+
+    unmount("/mnt")?;
+    if unmount("/mnt").err() != Some(FsError::NotMounted) {
+        return Err(FsError::Io("unmounted a prefix twice"));
+    }
+
+    // With `/` gone too nothing covers any path.
+    let root = unmount("/")?;
+    let orphaned = stat("/MOVED.TXT");
+    mount("/", root)?;
+    if orphaned.err() != Some(FsError::NotMounted) {
+        return Err(FsError::Io("lookup succeeded with nothing mounted"));
+    }
+
+    crate::serial_println!("✓ Mount table resolves, rejects and unmounts correctly");
+    Ok(())
+}
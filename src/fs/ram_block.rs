@@ -0,0 +1,156 @@
+use crate::drivers::ata::AtaError;
+use alloc::vec;
+use alloc::vec::Vec;
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use spin::Mutex;
+
+/// Where `format_fat16` puts its one partition.
+const PARTITION_START: u32 = 1;
+const PARTITION_TYPE_FAT16: u8 = 0x06;
+const ROOT_ENTRIES: u32 = 512;
+const RESERVED_SECTORS: u32 = 1;
+const FAT_COUNT: u32 = 2;
+
+/// A disk in a heap buffer, for testing FAT without an ATA drive. Errors use
+/// `AtaError` like `SosAtaBlockDevice`, so both fit the same volume manager.
+pub struct RamBlockDevice {
+    /// `BlockDevice::write` only gets `&self`.
+    blocks: Mutex<Vec<Block>>,
+}
+
+impl RamBlockDevice {
+    /// A zeroed disk of `block_count` blocks.
+    pub fn new(block_count: u32) -> Self {
+        RamBlockDevice {
+            blocks: Mutex::new(vec![Block::new(); block_count as usize]),
+        }
+    }
+
+    /// A disk holding a copy of `image`, e.g. one made with `mkfs.fat` and
+    /// pulled in with `include_bytes!`. A partial last block is zero padded.
+    pub fn from_image(image: &[u8]) -> Self {
+        let blocks = image
+            .chunks(Block::LEN)
+            .map(|chunk| {
+                let mut block = Block::new();
+                block.contents[..chunk.len()].copy_from_slice(chunk);
+                block
+            })
+            .collect();
+        RamBlockDevice {
+            blocks: Mutex::new(blocks),
+        }
+    }
+
+    /// A disk of `block_count` blocks with an MBR and one empty FAT16
+    /// partition filling it, as partition 0. FAT16 needs at least 4085
+    /// clusters, so with 512-byte clusters that is a bit over 2 MiB.
+    pub fn format_fat16(block_count: u32) -> Result<Self, &'static str> {
+        let total = block_count
+            .checked_sub(PARTITION_START)
+            .ok_or("RAM disk too small")?;
+        let root_dir_sectors = ROOT_ENTRIES * 32 / Block::LEN_U32;
+
+        // Use the smallest cluster that keeps the count below FAT32's.
+        let mut sectors_per_cluster = 1u32;
+        let (fat_sectors, clusters) = loop {
+            let fat_sectors = ((total / sectors_per_cluster + 2) * 2).div_ceil(Block::LEN_U32);
+            let data_sectors = total
+                .checked_sub(RESERVED_SECTORS + FAT_COUNT * fat_sectors + root_dir_sectors)
+                .ok_or("RAM disk too small")?;
+            let clusters = data_sectors / sectors_per_cluster;
+            if clusters < 65525 {
+                break (fat_sectors, clusters);
+            }
+            sectors_per_cluster *= 2;
+            if sectors_per_cluster > 128 {
+                return Err("RAM disk too large for FAT16");
+            }
+        };
+        if clusters < 4085 {
+            return Err("RAM disk too small for FAT16");
+        }
+
+        let disk = Self::new(block_count);
+        {
+            let mut blocks = disk.blocks.lock();
+
+            let mbr = &mut blocks[0].contents;
+            let entry = &mut mbr[446..462];
+            entry[4] = PARTITION_TYPE_FAT16;
+            entry[8..12].copy_from_slice(&PARTITION_START.to_le_bytes());
+            entry[12..16].copy_from_slice(&total.to_le_bytes());
+            mbr[510] = 0x55;
+            mbr[511] = 0xAA;
+
+            let boot = &mut blocks[PARTITION_START as usize].contents;
+            boot[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+            boot[3..11].copy_from_slice(b"SOS     ");
+            boot[11..13].copy_from_slice(&(Block::LEN as u16).to_le_bytes());
+            boot[13] = sectors_per_cluster as u8;
+            boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+            boot[16] = FAT_COUNT as u8;
+            boot[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+            match u16::try_from(total) {
+                Ok(small) => boot[19..21].copy_from_slice(&small.to_le_bytes()),
+                Err(_) => boot[32..36].copy_from_slice(&total.to_le_bytes()),
+            }
+            boot[21] = 0xF8; // fixed disk
+            boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+            boot[24..26].copy_from_slice(&32u16.to_le_bytes()); // sectors per track
+            boot[26..28].copy_from_slice(&64u16.to_le_bytes()); // heads
+            boot[28..32].copy_from_slice(&PARTITION_START.to_le_bytes());
+            boot[36] = 0x80; // drive number
+            boot[38] = 0x29; // extended boot signature
+            boot[39..43].copy_from_slice(&0x5305_0000u32.to_le_bytes());
+            boot[43..54].copy_from_slice(b"SOS RAMDISK");
+            boot[54..62].copy_from_slice(b"FAT16   ");
+            boot[510] = 0x55;
+            boot[511] = 0xAA;
+
+            // Entries 0 and 1 hold the media byte and the clean flags.
+            for fat in 0..FAT_COUNT {
+                let first = PARTITION_START + RESERVED_SECTORS + fat * fat_sectors;
+                blocks[first as usize].contents[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+            }
+        }
+        Ok(disk)
+    }
+
+    pub fn block_count(&self) -> u32 {
+        self.blocks.lock().len() as u32
+    }
+}
+
+impl BlockDevice for RamBlockDevice {
+    type Error = AtaError;
+
+    fn read(
+        &self,
+        blocks: &mut [Block],
+        start_block_idx: BlockIdx,
+        _reason: &str,
+    ) -> Result<(), Self::Error> {
+        let disk = self.blocks.lock();
+        let start = start_block_idx.0 as usize;
+        let source = disk
+            .get(start..start + blocks.len())
+            .ok_or(AtaError::InvalidLba)?;
+        blocks.clone_from_slice(source);
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+        let mut disk = self.blocks.lock();
+        let start = start_block_idx.0 as usize;
+        let target = disk
+            .get_mut(start..start + blocks.len())
+            .ok_or(AtaError::InvalidLba)?;
+        target.clone_from_slice(blocks);
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+        Ok(BlockCount(self.block_count()))
+    }
+}
@@ -0,0 +1,241 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::fs::vfs::{test_filesystem, DirEntry, FileSystem, FsError};
+
+enum Node {
+    File(Vec<u8>),
+    Directory,
+}
+
+/// A filesystem held entirely on the heap, for the initrd and anything
+/// else that needs files before a disk is up. Nodes are keyed by their
+/// full path without leading or trailing slashes, e.g. `bin/hello`, so a
+/// directory's children are one range of the map. Names are case-sensitive.
+#[derive(Default)]
+pub struct RamDiskFs {
+    nodes: BTreeMap<String, Node>,
+}
+
+/// Turns `path` into a node key: slashes at either end and empty or `.`
+/// components dropped. The root is `""`.
+fn key(path: &str) -> String {
+    path.split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The key of `key`'s parent, `""` for entries in the root.
+fn parent(key: &str) -> &str {
+    key.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+impl RamDiskFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails unless `key`'s parent is an existing directory.
+    fn check_parent(&self, key: &str) -> Result<(), FsError> {
+        let parent = parent(key);
+        if parent.is_empty() {
+            return Ok(());
+        }
+        match self.nodes.get(parent) {
+            Some(Node::Directory) => Ok(()),
+            Some(Node::File(_)) => Err(FsError::NotADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    /// Creates `path` and any missing directories above it. Existing
+    /// directories along the way are fine; a file in the way is not.
+    pub fn create_dir_all(&mut self, path: &str) -> Result<(), FsError> {
+        let key = key(path);
+        let mut end = 0;
+        for component in key.split('/').filter(|c| !c.is_empty()) {
+            end += component.len();
+            match self.nodes.get(&key[..end]) {
+                Some(Node::Directory) => {}
+                Some(Node::File(_)) => return Err(FsError::NotADirectory),
+                None => {
+                    self.nodes.insert(key[..end].to_string(), Node::Directory);
+                }
+            }
+            end += 1;
+        }
+        Ok(())
+    }
+
+    /// The direct children of directory `key`, as `(name, node)`.
+    fn children<'a>(&'a self, key: &str) -> impl Iterator<Item = (&'a str, &'a Node)> + 'a {
+        let prefix = if key.is_empty() {
+            String::new()
+        } else {
+            alloc::format!("{}/", key)
+        };
+        let skip = prefix.len();
+        self.nodes
+            .range(prefix.clone()..)
+            .take_while(move |(k, _)| k.starts_with(&prefix))
+            .filter(move |(k, _)| !k[skip..].contains('/'))
+            .map(move |(k, node)| (&k[skip..], node))
+    }
+}
+
+impl FileSystem for RamDiskFs {
+    fn create_file(&mut self, path: &str) -> Result<(), FsError> {
+        let key = key(path);
+        if key.is_empty() || self.nodes.contains_key(&key) {
+            return Err(FsError::AlreadyExists);
+        }
+        self.check_parent(&key)?;
+        self.nodes.insert(key, Node::File(Vec::new()));
+        Ok(())
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        match self.nodes.get(&key(path)) {
+            Some(Node::File(data)) => Ok(data.clone()),
+            Some(Node::Directory) => Err(FsError::IsADirectory),
+            None if key(path).is_empty() => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let key = key(path);
+        match self.nodes.get_mut(&key) {
+            Some(Node::File(contents)) => {
+                contents.clear();
+                contents.extend_from_slice(data);
+                Ok(())
+            }
+            Some(Node::Directory) => Err(FsError::IsADirectory),
+            None if key.is_empty() => Err(FsError::IsADirectory),
+            None => {
+                self.check_parent(&key)?;
+                self.nodes.insert(key, Node::File(data.to_vec()));
+                Ok(())
+            }
+        }
+    }
+
+    fn delete_file(&mut self, path: &str) -> Result<(), FsError> {
+        let key = key(path);
+        match self.nodes.get(&key) {
+            Some(Node::File(_)) => {
+                self.nodes.remove(&key);
+                Ok(())
+            }
+            Some(Node::Directory) => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let key = key(path);
+        match self.nodes.get(&key) {
+            Some(Node::Directory) => {}
+            Some(Node::File(_)) => return Err(FsError::NotADirectory),
+            None if key.is_empty() => {}
+            None => return Err(FsError::NotFound),
+        }
+        Ok(self
+            .children(&key)
+            .map(|(name, node)| DirEntry {
+                name: name.to_string(),
+                size: match node {
+                    Node::File(data) => data.len() as u64,
+                    Node::Directory => 0,
+                },
+                is_directory: matches!(node, Node::Directory),
+            })
+            .collect())
+    }
+
+    fn stat(&mut self, path: &str) -> Result<DirEntry, FsError> {
+        let key = key(path);
+        let name = key.rsplit('/').next().unwrap_or("").to_string();
+        match self.nodes.get(&key) {
+            Some(Node::File(data)) => Ok(DirEntry {
+                name,
+                size: data.len() as u64,
+                is_directory: false,
+            }),
+            Some(Node::Directory) => Ok(DirEntry {
+                name,
+                size: 0,
+                is_directory: true,
+            }),
+            None if key.is_empty() => Ok(DirEntry {
+                name,
+                size: 0,
+                is_directory: true,
+            }),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let key = key(path);
+        if key.is_empty() || self.nodes.contains_key(&key) {
+            return Err(FsError::AlreadyExists);
+        }
+        self.check_parent(&key)?;
+        self.nodes.insert(key, Node::Directory);
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: &str) -> Result<(), FsError> {
+        let key = key(path);
+        match self.nodes.get(&key) {
+            Some(Node::Directory) => {}
+            Some(Node::File(_)) => return Err(FsError::NotADirectory),
+            None if key.is_empty() => return Err(FsError::InvalidPath),
+            None => return Err(FsError::NotFound),
+        }
+        if self.children(&key).next().is_some() {
+            return Err(FsError::NotEmpty);
+        }
+        self.nodes.remove(&key);
+        Ok(())
+    }
+}
+
+/// Runs the VFS suite on an empty RAM disk, then checks nested
+/// directories: creation needs the parent, listings only show direct
+/// children and non-empty directories can't be removed.
+pub fn test_ramdisk() -> Result<(), FsError> {
+    let mut fs = RamDiskFs::new();
+    test_filesystem("RAM disk", &mut fs)?;
+
+    if fs.write_file("/a/b/file", b"x") != Err(FsError::NotFound) {
+        return Err(FsError::Io("file created under a missing directory"));
+    }
+    fs.create_dir_all("/a/b")?;
+    fs.write_file("/a/b/file", b"nested")?;
+    fs.write_file("/a/top", b"top")?;
+    if fs.read_file("a/b/file")? != b"nested" {
+        return Err(FsError::Io("nested file read back different contents"));
+    }
+
+    let mut names: Vec<String> = fs.list_dir("/a")?.into_iter().map(|e| e.name).collect();
+    names.sort();
+    if names != ["b", "top"] {
+        return Err(FsError::Io("listing shows more than the direct children"));
+    }
+    if !fs.stat("/a/b")?.is_directory || fs.stat("/a/top")?.size != 3 {
+        return Err(FsError::Io("stat reports the wrong size or type"));
+    }
+    if fs.remove_dir("/a/b") != Err(FsError::NotEmpty) {
+        return Err(FsError::Io("removed a non-empty directory"));
+    }
+    fs.delete_file("/a/b/file")?;
+    fs.remove_dir("/a/b")?;
+
+    crate::serial_println!("✓ RAM disk handles nested directories");
+    Ok(())
+}
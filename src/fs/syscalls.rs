@@ -1,11 +1,59 @@
-use crate::fs::fat;
+use crate::fs::mount;
+use crate::fs::vfs::FsError;
+use crate::syscall::errno::Errno;
 use alloc::string::String;
 use core::ptr;
 use spin::Mutex;
 
-lazy_static::lazy_static! {
-    static ref LAST_FILENAME: Mutex<Option<String>> = Mutex::new(None);
-    static ref READ_BUFFER: Mutex<[u8; 1024]> = Mutex::new([0u8; 1024]);
+/// Returned for an fd that isn't open (or not open for the operation).
+pub const EBADF: u64 = Errno::BadF.as_u64();
+/// Returned by `sys_open` when every descriptor is in use.
+pub const EMFILE: u64 = Errno::MFile.as_u64();
+/// Returned by `sys_lseek` for a bad `whence` or a negative result.
+pub const EINVAL: u64 = Errno::Inval.as_u64();
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// What `sys_stat` writes to its buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stat {
+    pub size: u64,
+    pub is_directory: u64,
+}
+
+/// 0-2 are left for stdin/stdout/stderr.
+const FIRST_FD: usize = 3;
+const MAX_FDS: usize = 32;
+
+struct OpenFile {
+    path: String,
+    writable: bool,
+    offset: u32,
+}
+
+/// Open files, indexed by `fd - FIRST_FD`. Global until there are processes
+/// to hang it off.
+static FD_TABLE: Mutex<[Option<OpenFile>; MAX_FDS]> = Mutex::new([const { None }; MAX_FDS]);
+
+/// The syscall return value for a filesystem error.
+fn errno(e: FsError) -> u64 {
+    Errno::from(e).as_u64()
+}
+
+/// Runs `f` on the open file behind `fd`, or returns `EBADF`.
+fn with_fd(fd: u64, f: impl FnOnce(&mut OpenFile) -> u64) -> u64 {
+    let mut table = FD_TABLE.lock();
+    let slot = (fd as usize)
+        .checked_sub(FIRST_FD)
+        .and_then(|i| table.get_mut(i))
+        .and_then(Option::as_mut);
+    match slot {
+        Some(file) => f(file),
+        None => EBADF,
+    }
 }
 
 pub unsafe fn copy_in_cstr(ptr: u64) -> String {
@@ -22,68 +70,114 @@ pub unsafe fn copy_in_cstr(ptr: u64) -> String {
     String::from_utf8(buf).unwrap_or_default()
 }
 
+/// Opens `filename_ptr` and returns a new fd. A non-zero `write_flag`
+/// creates or truncates the file for writing; otherwise it must exist.
+/// Fails with `ENOENT`, `EISDIR` and the like; see `Errno`.
 pub fn sys_open(filename_ptr: u64, write_flag: u64, _unused: u64) -> u64 {
-    let filename = unsafe { copy_in_cstr(filename_ptr) };
-    *LAST_FILENAME.lock() = Some(filename);
-    if write_flag != 0 {
-        0
+    let path = unsafe { copy_in_cstr(filename_ptr) };
+    let writable = write_flag != 0;
+    let opened = if writable {
+        mount::write_file(&path, &[])
     } else {
-        1
+        mount::stat(&path).and_then(|info| {
+            if info.is_directory {
+                Err(FsError::IsADirectory)
+            } else {
+                Ok(())
+            }
+        })
+    };
+    if let Err(e) = opened {
+        return errno(e);
     }
+
+    let mut table = FD_TABLE.lock();
+    let Some(index) = table.iter().position(Option::is_none) else {
+        return EMFILE;
+    };
+    table[index] = Some(OpenFile {
+        path,
+        writable,
+        offset: 0,
+    });
+    (index + FIRST_FD) as u64
 }
 
-pub fn sys_read(_fd: u64, buf_ptr: u64, count: u64) -> u64 {
-    let filename = LAST_FILENAME.lock().clone().unwrap_or_default();
-    let mut temp_buf = alloc::vec::Vec::with_capacity(count as usize);
-    temp_buf.resize(count as usize, 0);
-    match fat::read_file(&filename, &mut temp_buf[..]) {
-        Ok(n) => {
-            unsafe {
-                ptr::copy_nonoverlapping(temp_buf.as_ptr(), buf_ptr as *mut u8, n);
+pub fn sys_read(fd: u64, buf_ptr: u64, count: u64) -> u64 {
+    with_fd(fd, |file| {
+        let buf = unsafe { core::slice::from_raw_parts_mut(buf_ptr as *mut u8, count as usize) };
+        match mount::read_at(&file.path, file.offset as u64, buf) {
+            Ok(n) => {
+                file.offset += n as u32;
+                n as u64
             }
-            n as u64
+            Err(e) => errno(e),
         }
-        Err(_) => u64::MAX,
-    }
+    })
+}
+
+pub fn sys_write(fd: u64, buf_ptr: u64, count: u64) -> u64 {
+    with_fd(fd, |file| {
+        if !file.writable {
+            return EBADF;
+        }
+        let buf = unsafe { core::slice::from_raw_parts(buf_ptr as *const u8, count as usize) };
+        match mount::write_at(&file.path, file.offset as u64, buf) {
+            Ok(()) => {
+                file.offset += count as u32;
+                count
+            }
+            Err(e) => errno(e),
+        }
+    })
 }
 
-pub fn sys_write(_fd: u64, buf_ptr: u64, count: u64) -> u64 {
-    let filename = LAST_FILENAME.lock().clone().unwrap_or_default();
-    let buf = unsafe { core::slice::from_raw_parts(buf_ptr as *const u8, count as usize) };
-    match fat::write_file(&filename, buf) {
-        Ok(()) => count,
-        Err(_) => u64::MAX,
+pub fn sys_close(fd: u64, _a1: u64, _a2: u64) -> u64 {
+    let mut table = FD_TABLE.lock();
+    match (fd as usize)
+        .checked_sub(FIRST_FD)
+        .and_then(|i| table.get_mut(i))
+    {
+        Some(slot @ Some(_)) => {
+            *slot = None;
+            0
+        }
+        _ => EBADF,
     }
 }
 
-pub fn sys_close(_fd: u64, _a1: u64, _a2: u64) -> u64 {
-    0
+/// What `sys_unlink`, `sys_mkdir` and `sys_rmdir` return: 0 on success.
+fn zero_or_errno(result: Result<(), FsError>) -> u64 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => errno(e),
+    }
 }
 
 pub fn sys_unlink(filename_ptr: u64, _a1: u64, _a2: u64) -> u64 {
     let filename = unsafe { copy_in_cstr(filename_ptr) };
-    fat::remove_file(&filename).is_ok() as u64
+    zero_or_errno(mount::delete_file(&filename))
 }
 
 pub fn sys_mkdir(path_ptr: u64, _a1: u64, _a2: u64) -> u64 {
     let path = unsafe { copy_in_cstr(path_ptr) };
-    fat::create_dir(&path).is_ok() as u64
+    zero_or_errno(mount::create_dir(&path))
 }
 
 pub fn sys_rmdir(path_ptr: u64, _a1: u64, _a2: u64) -> u64 {
     let path = unsafe { copy_in_cstr(path_ptr) };
-    fat::remove_dir(&path).is_ok() as u64
+    zero_or_errno(mount::remove_dir(&path))
 }
 
 pub fn sys_listdir(path_ptr: u64, buf_ptr: u64, max: u64) -> u64 {
     let path = unsafe { copy_in_cstr(path_ptr) };
-    match fat::list_dir(&path) {
+    match mount::list_dir(&path) {
         Ok(entries) => {
             let count = entries.len().min(max as usize);
-            for (i, name) in entries.into_iter().take(count).enumerate() {
+            for (i, entry) in entries.into_iter().take(count).enumerate() {
                 unsafe {
                     let p = (buf_ptr as *mut u8).add(i * 256);
-                    let bytes = name.as_bytes();
+                    let bytes = entry.name.as_bytes();
                     let len = bytes.len().min(255);
                     ptr::copy_nonoverlapping(bytes.as_ptr(), p, len);
                     *p.add(len) = 0;
@@ -91,6 +185,53 @@ pub fn sys_listdir(path_ptr: u64, buf_ptr: u64, max: u64) -> u64 {
             }
             count as u64
         }
-        Err(_) => u64::MAX,
+        Err(e) => errno(e),
+    }
+}
+
+/// Moves `fd`'s offset and returns the new one. `offset` is signed for
+/// `SEEK_CUR` and `SEEK_END`.
+pub fn sys_lseek(fd: u64, offset: u64, whence: u64) -> u64 {
+    with_fd(fd, |file| {
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => file.offset as i64,
+            SEEK_END => match mount::stat(&file.path) {
+                Ok(info) => info.size as i64,
+                Err(e) => return errno(e),
+            },
+            _ => return EINVAL,
+        };
+        match base.checked_add(offset as i64) {
+            Some(new) if (0..=u32::MAX as i64).contains(&new) => {
+                file.offset = new as u32;
+                new as u64
+            }
+            _ => EINVAL,
+        }
+    })
+}
+
+pub fn sys_stat(path_ptr: u64, statbuf_ptr: u64, _a2: u64) -> u64 {
+    let path = unsafe { copy_in_cstr(path_ptr) };
+    match mount::stat(&path) {
+        Ok(info) => {
+            let stat = Stat {
+                size: info.size,
+                is_directory: info.is_directory as u64,
+            };
+            unsafe { ptr::write_unaligned(statbuf_ptr as *mut Stat, stat) };
+            0
+        }
+        Err(e) => errno(e),
+    }
+}
+
+pub fn sys_rename(old_ptr: u64, new_ptr: u64, _a2: u64) -> u64 {
+    let old_path = unsafe { copy_in_cstr(old_ptr) };
+    let new_path = unsafe { copy_in_cstr(new_ptr) };
+    match mount::rename(&old_path, &new_path) {
+        Ok(()) => 0,
+        Err(e) => errno(e),
     }
 }
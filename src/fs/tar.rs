@@ -0,0 +1,267 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::ramdisk::RamDiskFs;
+use crate::fs::vfs::{FileSystem, FsError};
+
+/// Headers and file data both come in blocks of this size.
+pub const BLOCK_SIZE: usize = 512;
+
+const NAME: core::ops::Range<usize> = 0..100;
+const MODE: core::ops::Range<usize> = 100..108;
+const SIZE: core::ops::Range<usize> = 124..136;
+const CHECKSUM: core::ops::Range<usize> = 148..156;
+const TYPEFLAG: usize = 156;
+const MAGIC: core::ops::Range<usize> = 257..263;
+const PREFIX: core::ops::Range<usize> = 345..500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// Links, devices and the like, with their type flag.
+    Other(u8),
+}
+
+/// One member of an archive. `data` borrows from the archive.
+#[derive(Debug)]
+pub struct Entry<'a> {
+    /// The full path, with the ustar prefix joined on.
+    pub name: String,
+    pub mode: u32,
+    pub size: usize,
+    pub kind: EntryKind,
+    pub data: &'a [u8],
+}
+
+/// Parses an octal header field, which tar pads with spaces or NULs.
+fn octal(field: &[u8]) -> Result<usize, &'static str> {
+    let mut value = 0usize;
+    for &digit in field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| (b'0'..=b'7').contains(&b))
+    {
+        value = value
+            .checked_mul(8)
+            .and_then(|v| v.checked_add(usize::from(digit - b'0')))
+            .ok_or("tar number field overflows")?;
+    }
+    Ok(value)
+}
+
+/// A NUL-terminated header field as text.
+fn text(field: &[u8]) -> Result<&str, &'static str> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).map_err(|_| "tar name is not UTF-8")
+}
+
+/// The header checksum: every byte summed, with the checksum field itself
+/// counted as spaces.
+fn checksum(header: &[u8]) -> usize {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if CHECKSUM.contains(&i) { b' ' } else { b })
+        .map(usize::from)
+        .sum()
+}
+
+/// Iterator over the members of a POSIX ustar archive, from `entries`.
+/// Stops at the end marker, a zeroed block, or after the first error.
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+/// Reads the members of the ustar archive in `archive`. Old v7 headers,
+/// without the `ustar` magic, work too as long as names fit in 100 bytes.
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries {
+        archive,
+        offset: 0,
+        done: false,
+    }
+}
+
+impl<'a> Entries<'a> {
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, &'static str> {
+        let Some(header) = self.archive.get(self.offset..self.offset + BLOCK_SIZE) else {
+            // Some writers leave the end marker off.
+            return if self.offset >= self.archive.len() {
+                Ok(None)
+            } else {
+                Err("tar archive ends inside a header")
+            };
+        };
+        // The archive ends with two zeroed blocks; the first is enough.
+        if header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        if octal(&header[CHECKSUM])? != checksum(header) {
+            return Err("bad tar header checksum");
+        }
+
+        let name = text(&header[NAME])?;
+        let prefix = if &header[MAGIC] == b"ustar\0" || &header[MAGIC] == b"ustar " {
+            text(&header[PREFIX])?
+        } else {
+            ""
+        };
+        let name = if prefix.is_empty() {
+            String::from(name)
+        } else {
+            alloc::format!("{}/{}", prefix, name)
+        };
+        let mode = octal(&header[MODE])? as u32;
+        let size = octal(&header[SIZE])?;
+        let kind = match header[TYPEFLAG] {
+            b'0' | 0 => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            other => EntryKind::Other(other),
+        };
+
+        let data_start = self.offset + BLOCK_SIZE;
+        let data = data_start
+            .checked_add(size)
+            .and_then(|end| self.archive.get(data_start..end))
+            .ok_or("tar archive ends inside a file")?;
+        // Data is padded out to a whole number of blocks.
+        self.offset = data_start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+        Ok(Some(Entry {
+            name,
+            mode,
+            size,
+            kind,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, &'static str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.parse().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Creates `path` and every directory above it that is missing.
+fn create_dirs(fs: &mut dyn FileSystem, path: &str) -> Result<(), FsError> {
+    let mut end = 0;
+    for component in path.split('/') {
+        end += component.len() + 1;
+        if component.is_empty() || component == "." {
+            continue;
+        }
+        match fs.create_dir(&path[..end - 1]) {
+            Ok(()) | Err(FsError::AlreadyExists) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Writes every file and directory in `archive` into `fs` under `prefix`,
+/// creating missing parent directories, and returns the number of files
+/// written. Other member types are skipped.
+pub fn extract_to(archive: &[u8], fs: &mut dyn FileSystem, prefix: &str) -> Result<usize, FsError> {
+    let prefix = prefix.trim_end_matches('/');
+    create_dirs(fs, prefix)?;
+    let mut files = 0;
+    for entry in entries(archive) {
+        let entry = entry?;
+        let path = alloc::format!("{}/{}", prefix, entry.name.trim_end_matches('/'));
+        match entry.kind {
+            EntryKind::Directory => create_dirs(fs, &path)?,
+            EntryKind::File => {
+                if let Some((parent, _)) = path.rsplit_once('/') {
+                    create_dirs(fs, parent)?;
+                }
+                fs.write_file(&path, entry.data)?;
+                files += 1;
+            }
+            EntryKind::Other(_) => {}
+        }
+    }
+    Ok(files)
+}
+
+/// Appends a ustar header for `name` and then `data`, padded to a block.
+fn push_member(archive: &mut Vec<u8>, name: &str, typeflag: u8, mode: u32, data: &[u8]) {
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[MODE][..7].copy_from_slice(alloc::format!("{:07o}", mode).as_bytes());
+    header[SIZE][..11].copy_from_slice(alloc::format!("{:011o}", data.len()).as_bytes());
+    header[TYPEFLAG] = typeflag;
+    header[MAGIC].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let sum = checksum(&header);
+    header[CHECKSUM][..7].copy_from_slice(alloc::format!("{:06o}\0", sum).as_bytes());
+    header[CHECKSUM][7] = b' ';
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+}
+
+/// Builds a small archive by hand, with a file spanning two blocks, a
+/// nested file whose directory has no entry of its own and a symlink,
+/// then checks the entries and extracts them into a `RamDiskFs`.
+pub fn test_tar() -> Result<(), FsError> {
+    let long: Vec<u8> = (0..600u32).map(|i| b'a' + (i % 26) as u8).collect();
+    let mut archive = Vec::new();
+    push_member(&mut archive, "docs/", b'5', 0o755, &[]);
+    push_member(&mut archive, "docs/long.txt", b'0', 0o644, &long);
+    push_member(&mut archive, "bin/hello", b'0', 0o755, b"hello\n");
+    push_member(&mut archive, "link", b'2', 0o777, &[]);
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+
+    let parsed = entries(&archive).collect::<Result<Vec<_>, _>>()?;
+    let summary: Vec<_> = parsed
+        .iter()
+        .map(|e| (e.name.as_str(), e.mode, e.size, e.kind))
+        .collect();
+    if summary
+        != [
+            ("docs/", 0o755, 0, EntryKind::Directory),
+            ("docs/long.txt", 0o644, 600, EntryKind::File),
+            ("bin/hello", 0o755, 6, EntryKind::File),
+            ("link", 0o777, 0, EntryKind::Other(b'2')),
+        ]
+    {
+        return Err(FsError::Io("entries parsed wrong"));
+    }
+
+    let mut fs = RamDiskFs::new();
+    if extract_to(&archive, &mut fs, "/pkg")? != 2 {
+        return Err(FsError::Io("wrong number of files extracted"));
+    }
+    if fs.read_file("/pkg/docs/long.txt")? != long || fs.read_file("/pkg/bin/hello")? != b"hello\n"
+    {
+        return Err(FsError::Io("extracted file has the wrong contents"));
+    }
+    if fs.stat("/pkg/link") != Err(FsError::NotFound) {
+        return Err(FsError::Io("symlink was extracted"));
+    }
+
+    if entries(&archive[..BLOCK_SIZE + 100]).all(|e| e.is_ok()) {
+        return Err(FsError::Io("truncated archive was accepted"));
+    }
+    archive[CHECKSUM.start] ^= 1;
+    if entries(&archive).next().is_some_and(|e| e.is_ok()) {
+        return Err(FsError::Io("corrupt header was accepted"));
+    }
+
+    crate::serial_println!("✓ tar archive extracted into a RAM disk");
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::drivers::ata::AtaError;
+
+/// Error type shared by every `FileSystem` backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    AlreadyExists,
+    IsADirectory,
+    NotADirectory,
+    NoSpace,
+    InvalidPath,
+    /// No filesystem is mounted over the path.
+    NotMounted,
+    /// The device behind the filesystem isn't there.
+    NoDevice,
+    /// The directory still has entries.
+    NotEmpty,
+    /// The device did not answer in time.
+    TimedOut,
+    /// The backend does not implement the operation.
+    Unsupported,
+    /// The backend failed for a reason of its own.
+    Io(&'static str),
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "No such file or directory"),
+            FsError::AlreadyExists => write!(f, "File exists"),
+            FsError::IsADirectory => write!(f, "Is a directory"),
+            FsError::NotADirectory => write!(f, "Not a directory"),
+            FsError::NoSpace => write!(f, "No space left on device"),
+            FsError::InvalidPath => write!(f, "Invalid path"),
+            FsError::NotMounted => write!(f, "No filesystem mounted there"),
+            FsError::NoDevice => write!(f, "No such device"),
+            FsError::NotEmpty => write!(f, "Directory not empty"),
+            FsError::TimedOut => write!(f, "Timed out"),
+            FsError::Unsupported => write!(f, "Operation not supported"),
+            FsError::Io(msg) => write!(f, "I/O error: {}", msg),
+        }
+    }
+}
+
+impl From<AtaError> for FsError {
+    fn from(e: AtaError) -> Self {
+        match e {
+            AtaError::DeviceNotFound => FsError::NotFound,
+            AtaError::InvalidLba => FsError::NoSpace,
+            AtaError::Timeout => FsError::TimedOut,
+            AtaError::DeviceFault => FsError::Io("ATA device fault"),
+            _ => FsError::Io("ATA command failed"),
+        }
+    }
+}
+
+/// The FAT wrappers report errors as strings; the ones with a meaning of
+/// their own get a variant.
+impl From<&'static str> for FsError {
+    fn from(msg: &'static str) -> Self {
+        match msg {
+            "File not found" | "Directory not found" => FsError::NotFound,
+            "Empty path" => FsError::InvalidPath,
+            "Destination exists" => FsError::AlreadyExists,
+            "Cannot rename directories" => FsError::IsADirectory,
+            "No volume manager" => FsError::NoDevice,
+            _ => FsError::Io(msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub size: u64,
+    pub is_directory: bool,
+}
+
+/// Capacity of a mounted filesystem, for `df`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolumeStats {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub cluster_size: u32,
+}
+
+/// Operations every mountable filesystem supports. Paths are relative to
+/// the filesystem's root; a leading `/` is allowed and `""` is the root.
+///
+/// Only the whole-file operations are required. The rest have defaults
+/// built on them, which backends with something cheaper override.
+pub trait FileSystem: Send {
+    /// Creates an empty file. Fails with `AlreadyExists` if `path` exists.
+    fn create_file(&mut self, path: &str) -> Result<(), FsError>;
+    fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError>;
+    /// Creates `path` or replaces its contents with `data`.
+    fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError>;
+    fn delete_file(&mut self, path: &str) -> Result<(), FsError>;
+    fn list_dir(&mut self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Looks `path` up in its parent's listing. The root is a directory.
+    fn stat(&mut self, path: &str) -> Result<DirEntry, FsError> {
+        let path = path.trim_matches('/');
+        let Some(name) = path.rsplit('/').next().filter(|n| !n.is_empty()) else {
+            return Ok(DirEntry {
+                name: String::new(),
+                size: 0,
+                is_directory: true,
+            });
+        };
+        let parent = &path[..path.len() - name.len()];
+        self.list_dir(parent)?
+            .into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Reads from `offset` into `buf` and returns the number of bytes read,
+    /// 0 at or past the end of the file.
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.read_file(path)?;
+        let start = data.len().min(offset as usize);
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    /// Writes `data` at `offset`, creating the file if needed. Offsets past
+    /// the end are clamped to it, so files never get holes.
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut contents = match self.read_file(path) {
+            Ok(contents) => contents,
+            Err(FsError::NotFound) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let start = contents.len().min(offset as usize);
+        let end = start + data.len();
+        if end > contents.len() {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(data);
+        self.write_file(path, &contents)
+    }
+
+    /// Moves a file by copying and deleting it. Fails with `AlreadyExists`
+    /// if `new_path` exists.
+    fn rename(&mut self, old_path: &str, new_path: &str) -> Result<(), FsError> {
+        if self.stat(old_path)?.is_directory {
+            return Err(FsError::IsADirectory);
+        }
+        if self.stat(new_path).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        let data = self.read_file(old_path)?;
+        self.write_file(new_path, &data)?;
+        self.delete_file(old_path)
+    }
+
+    fn create_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn remove_dir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    fn volume_stats(&mut self) -> Result<VolumeStats, FsError> {
+        Err(FsError::Unsupported)
+    }
+}
+
+/// The same checks for any backend: create, write, read back, list, replace
+/// and delete a file in the root directory.
+pub fn test_filesystem(name: &str, fs: &mut dyn FileSystem) -> Result<(), FsError> {
+    crate::serial_println!("=== VFS Test: {} ===", name);
+
+    const PATH: &str = "/VFSTEST.TXT";
+    const FIRST: &[u8] = b"first contents";
+    const SECOND: &[u8] = b"second, longer contents";
+
+    // Leftovers from an earlier run would make create_file fail.
+    let _ = fs.delete_file(PATH);
+
+    fs.create_file(PATH)?;
+    if fs.create_file(PATH) != Err(FsError::AlreadyExists) {
+        return Err(FsError::Io("create_file accepted an existing name"));
+    }
+    if !fs.read_file(PATH)?.is_empty() {
+        return Err(FsError::Io("new file is not empty"));
+    }
+
+    fs.write_file(PATH, FIRST)?;
+    if fs.read_file(PATH)? != FIRST {
+        return Err(FsError::Io("read back different contents"));
+    }
+    let listed = fs
+        .list_dir("/")?
+        .into_iter()
+        .find(|e| e.name.eq_ignore_ascii_case(&PATH[1..]))
+        .ok_or(FsError::Io("file missing from the listing"))?;
+    if listed.size != FIRST.len() as u64 || listed.is_directory {
+        return Err(FsError::Io("listing reports the wrong size or type"));
+    }
+
+    fs.write_file(PATH, SECOND)?;
+    if fs.read_file(PATH)? != SECOND {
+        return Err(FsError::Io("overwrite did not replace the contents"));
+    }
+
+    fs.delete_file(PATH)?;
+    if fs.read_file(PATH) != Err(FsError::NotFound) {
+        return Err(FsError::Io("file still readable after delete"));
+    }
+
+    crate::serial_println!("✓ {} passed the VFS suite", name);
+    Ok(())
+}
@@ -1,26 +1,33 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
 pub mod arch;
 pub mod drivers;
+pub mod elf;
 pub mod fs;
 pub mod memory;
+pub mod net;
+pub mod rand;
 pub mod sched;
 pub mod sync;
 pub mod syscall;
 pub mod task;
+pub mod testing;
+pub mod util;
 
-pub use arch::x86_64::{gdt, interrupts, smp, timer};
+pub use arch::{reboot, shutdown};
+pub use arch::x86_64::{acpi, crash, gdt, interrupts, ioapic, smp, timer, usermode};
 pub use drivers::{ata, serial, sshell, vga_buffer};
 pub use memory::{allocator, paging};
-pub use sched::{context, processor, rr, std_thread, thread_pool};
+pub use sched::{context, priority, processor, rr, std_thread, thread_pool};
 pub use sync::interrupt;
 use x86_64::structures::paging::OffsetPageTable;
 
-use crate::memory::BootInfoFrameAllocator;
+use crate::memory::{BitmapFrameAllocator, GlobalFrameAllocator};
 
 pub fn hlt_loop() -> ! {
     loop {
@@ -28,19 +35,45 @@ pub fn hlt_loop() -> ! {
     }
 }
 
+/// Stops this CPU for good: interrupts off, then halt.
+pub fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
+    hlt_loop();
+}
+
 use bootloader::BootInfo;
-pub fn init(boot_info: &'static BootInfo) -> (BootInfoFrameAllocator, OffsetPageTable<'static>) {
+pub fn init(boot_info: &'static BootInfo) -> (GlobalFrameAllocator, OffsetPageTable<'static>) {
     use x86_64::VirtAddr;
 
+    drivers::logger::init().expect("Failed to install the serial logger");
+    arch::cpuid::print_summary();
+    // Context switches save the FPU state with fxsave.
+    assert!(
+        arch::cpuid::has_feature(arch::cpuid::Feature::Fxsr),
+        "CPU lacks FXSAVE/FXRSTOR"
+    );
+    sched::context::init_fpu();
     arch::x86_64::gdt::init();
+    arch::x86_64::fast_syscall::init_fast_syscalls()
+        .expect("Failed to enable the syscall instruction");
     arch::x86_64::interrupts::init_idt();
     unsafe { arch::x86_64::interrupts::PICS.lock().initialize() };
+    let tick_hz = arch::x86_64::timer::init_pit(arch::x86_64::timer::DEFAULT_FREQUENCY_HZ);
+    let tsc_hz = arch::x86_64::timer::calibrate_tsc();
+    crate::serial_println!("PIT: {} Hz, TSC: {} MHz", tick_hz, tsc_hz / 1_000_000);
+    task::keyboard::init().expect("Failed to register the keyboard IRQ");
+    drivers::ata::init_irqs().expect("Failed to register the ATA IRQs");
+    arch::x86_64::smp::init_tlb_shootdown().expect("Failed to register the TLB shootdown IPI");
     x86_64::instructions::interrupts::enable();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
     let mut mapper = unsafe { paging::init(phys_mem_offset, &mut frame_allocator) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    if let Err(e) = fs::initrd::init() {
+        crate::serial_println!("Failed to mount the initrd: {}", e);
+    }
 
-    (frame_allocator, mapper)
+    (memory::install_frame_allocator(frame_allocator), mapper)
 }
@@ -3,26 +3,46 @@
 
 extern crate alloc;
 
+use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
 use core::ptr::addr_of_mut;
-use sos::arch::x86_64::smp::{start_one_ap, CPUS, MAX_CPUS};
-use sos::drivers::vga_buffer::{set_colors, Color};
+use sos::arch::x86_64::smp::{
+    current_apic_id, init_bsp, install_trampoline, start_one_ap, CPUS, MAX_CPUS,
+};
+use sos::drivers::vga_buffer::{set_theme, GREEN_ON_BLACK};
+use sos::memory::GlobalFrameAllocator;
+use sos::sched::priority::PriorityScheduler;
 use sos::sched::processor::Processor;
-use sos::sched::rr::RRScheduler;
 use sos::sched::thread_pool::ThreadPool;
 use sos::task::{executor::Executor, Task};
 use sos::{println, serial_println};
+use x86_64::structures::paging::OffsetPageTable;
 
 entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
-    set_colors(Color::Green, Color::Black);
+    set_theme(GREEN_ON_BLACK);
     println!("Welcome to sOS!");
     serial_println!("Welcome to sOS!");
     let (mut frame_allocator, mut mapper) = sos::init(boot_info);
 
+    // Take the legacy IRQs off the 8259s. The APIC timer goes first since
+    // the PIT is masked along with everything else.
+    match sos::timer::init_apic_timer(sos::timer::DEFAULT_FREQUENCY_HZ)
+        .and_then(|()| sos::ioapic::init(&mut mapper, &mut frame_allocator))
+        .and_then(|()| sos::interrupts::switch_to_apic())
+    {
+        Ok(()) => serial_println!("Legacy IRQs now go through the IO APIC"),
+        Err(e) => serial_println!("Staying on the 8259 PIC: {}", e),
+    }
+    start_processors(&mut mapper, &mut frame_allocator);
+    if cfg!(feature = "qemu-test") {
+        run_harnessed_tests();
+    }
+
     if let Some(gpu_dev) = sos::drivers::pci::find_virtio_gpu() {
         serial_println!("Initializing VirtIO-GPU");
 
@@ -31,6 +51,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         match gpu.init(&mut mapper, &mut frame_allocator) {
             Ok(()) => {
                 serial_println!("VirtIO-GPU initialized.");
+                if let Err(e) = gpu.enable_interrupts() {
+                    serial_println!("VirtIO-GPU staying on polling: {}", e);
+                }
+                if let Err(e) = gpu.test_descriptor_recycling() {
+                    serial_println!("✗ VirtIO-GPU descriptor recycling test failed: {}", e);
+                }
 
                 let (fb_ptr, width, height) = gpu.get_framebuffer();
                 serial_println!("Framebuffer ready: {}x{} at {:p}", width, height, fb_ptr);
@@ -42,6 +68,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
                     Err(e) => serial_println!("Failed to refresh display: {}", e),
                 }
                 gpu.debug_and_refresh();
+
+                if let Err(e) = sos::drivers::fb_console::install_framebuffer_console(gpu) {
+                    serial_println!("Failed to set up framebuffer console: {}", e);
+                }
             }
             Err(e) => {
                 serial_println!("Failed to initialize VirtIO-GPU: {}", e);
@@ -50,20 +80,185 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     } else {
         serial_println!("No VirtIO-GPU device found");
     }
+
+    let has_net = match sos::drivers::pci::find_virtio_net() {
+        Some(net_dev) => {
+            let mut net = sos::drivers::pci::virtio_net::VirtioNet::new(net_dev);
+            match net
+                .init(&mut mapper, &mut frame_allocator)
+                .and_then(|()| sos::drivers::pci::virtio_net::install(net))
+            {
+                Ok(()) => true,
+                Err(e) => {
+                    serial_println!("Failed to initialize VirtIO-net: {}", e);
+                    false
+                }
+            }
+        }
+        None => {
+            serial_println!("No VirtIO-net device found");
+            false
+        }
+    };
     serial_println!("==================================");
 
+    sos::serial::enable_input();
+    let has_mouse = match sos::drivers::mouse::init() {
+        Ok(()) => true,
+        Err(e) => {
+            serial_println!("Failed to initialize PS/2 mouse: {}", e);
+            false
+        }
+    };
+
     sos::ata::test_ata_driver_comprehensive();
-    sos::fs::fat::test_fat32_with_device(sos::ata::AtaDevice::Slave, 131072);
+    if let Err(e) = sos::ata::test_command_plan() {
+        serial_println!("✗ ATA command plan test failed: {}", e);
+    }
+    if let Err(e) = sos::ata::test_large_read() {
+        serial_println!("✗ ATA large read test failed: {}", e);
+    }
+    if let Err(e) = sos::ata::test_read_sectors_async() {
+        serial_println!("✗ ATA IRQ read test failed: {}", e);
+    }
+    sos::fs::fat::test_fat_on_ram_disk();
+    sos::fs::fat::test_fat32_with_device(sos::ata::AtaDevice::Slave, 0, 131072);
+    if let Err(e) = sos::fs::vfs::test_filesystem("FAT", &mut sos::fs::fat::FatFileSystem) {
+        serial_println!("✗ VFS suite failed on FAT: {}", e);
+    }
+    if let Err(e) = sos::fs::mount::mount("/", Box::new(sos::fs::fat::FatFileSystem)) {
+        serial_println!("Failed to mount the FAT volume at /: {}", e);
+    }
+    if let Err(e) = sos::fs::ramdisk::test_ramdisk() {
+        serial_println!("✗ RAM disk test failed: {}", e);
+    }
+    if let Err(e) = sos::fs::tar::test_tar() {
+        serial_println!("✗ tar test failed: {}", e);
+    }
+    if let Err(e) = sos::fs::test_display_file() {
+        serial_println!("✗ File display test failed: {}", e);
+    }
+    if let Err(e) = sos::fs::mount::test_mount_table() {
+        serial_println!("✗ Mount table test failed: {}", e);
+    }
+    if let Err(e) = sos::paging::test_large_page_mapping() {
+        serial_println!("✗ Large page mapping test failed: {}", e);
+    }
+    if let Err(e) = sos::allocator::test_heap_growth() {
+        serial_println!("✗ Heap growth test failed: {}", e);
+    }
+    if let Err(e) = sos::fs::mount::test_copy_move() {
+        serial_println!("✗ Copy/move test failed: {}", e);
+    }
     sos::syscall::test_syscalls();
+    if let Err(e) = sos::elf::test_elf_loader() {
+        serial_println!("✗ ELF loader test failed: {}", e);
+    }
+    sos::vga_buffer::benchmark_redraw();
+    if let Err(e) = sos::vga_buffer::test_clear_screen() {
+        serial_println!("✗ Screen clear test failed: {}", e);
+    }
+    if let Err(e) = sos::acpi::test_madt() {
+        serial_println!("✗ MADT test failed: {}", e);
+    }
+    if let Err(e) = sos::acpi::cpu_count().and_then(sos::smp::test_aps_online) {
+        serial_println!("✗ AP startup test failed: {}", e);
+    }
+    if let Err(e) = sos::smp::test_ap_preemption() {
+        serial_println!("✗ AP preemption test failed: {}", e);
+    }
+    if let Err(e) = sos::std_thread::test_spawn_join() {
+        serial_println!("✗ Thread spawn/join test failed: {}", e);
+    }
+    if let Err(e) = sos::context::test_entry_arg() {
+        serial_println!("✗ Thread entry argument test failed: {}", e);
+    }
+    if let Err(e) = sos::sync::test_thread_sync() {
+        serial_println!("✗ Thread sync test failed: {}", e);
+    }
+    if let Err(e) = sos::context::test_fpu_context_switch() {
+        serial_println!("✗ FPU context switch test failed: {}", e);
+    }
+    if let Err(e) = sos::priority::test_priority_scheduler() {
+        serial_println!("✗ Priority scheduler test failed: {}", e);
+    }
+    if let Err(e) = sos::priority::test_priority_threads() {
+        serial_println!("✗ Priority thread test failed: {}", e);
+    }
+    if let Err(e) = sos::interrupt::test_nested_no_interrupt() {
+        serial_println!("✗ Nested no_interrupt test failed: {}", e);
+    }
+    if let Err(e) = sos::rand::test_rand() {
+        serial_println!("✗ rand test failed: {}", e);
+    }
+    if let Err(e) = sos::timer::test_pit_divisor() {
+        serial_println!("✗ PIT divisor test failed: {}", e);
+    }
+    if let Err(e) = sos::util::test_hexdump() {
+        serial_println!("✗ hexdump test failed: {}", e);
+    }
+    if let Err(e) = sos::task::channel::test_channel() {
+        serial_println!("✗ Channel test failed: {}", e);
+    }
+    if let Err(e) = sos::task::executor::test_executor_wakeups() {
+        serial_println!("✗ Executor wakeup test failed: {}", e);
+    }
 
-    serial_println!("Entering an infinite loop.");
-    sos::hlt_loop();
+    // Create the scancode queue before anything awaits input, so early
+    // keypresses aren't dropped.
+    lazy_static::initialize(&sos::task::keyboard::INPUT);
+    if let Err(e) = sos::task::keyboard::test_keyboard_irq() {
+        serial_println!("✗ Keyboard IRQ test failed: {}", e);
+    }
+    if let Err(e) = sos::task::keyboard::test_inject_str() {
+        serial_println!("✗ Keyboard injection test failed: {}", e);
+    }
+
+    let mut executor = Executor::new();
+    // Run the timeout demo to completion first: the keyboard stream wakes
+    // only one waiting task, so the shell must not be reading alongside it.
+    if has_net {
+        // The raw ARP test reads frames itself, so the stack only starts
+        // receiving once it is done.
+        executor.spawn(Task::named("net", async {
+            if let Err(e) = sos::drivers::pci::virtio_net::test_arp_echo().await {
+                serial_println!("✗ VirtIO-net ARP test failed: {}", e);
+            }
+            sos::net::run().await;
+        }));
+        executor.spawn(Task::named("ping-test", async {
+            if let Err(e) = sos::net::test_ping().await {
+                serial_println!("✗ Ping test failed: {}", e);
+            }
+        }));
+    }
+    if has_mouse {
+        executor.spawn(Task::named("mouse", sos::drivers::mouse::track_cursor()));
+    }
+    executor.spawn(Task::named("shell", async {
+        sos::task::timeout::wait_for_keypress_demo().await;
+        sos::sshell::shell().await;
+    }));
+    executor.run();
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Capture first, before printing clobbers the registers. Nothing below
+    // allocates, so a broken heap still gets a full report.
+    let regs = sos::crash::RegisterDump::capture();
+    x86_64::instructions::interrupts::disable();
+    unsafe { sos::serial::force_unlock() };
+
     serial_println!("=== KERNEL PANIC ===");
     serial_println!("PANIC: {}", info);
+    if CPUS.online_count() > 1 {
+        serial_println!(
+            "on CPU {} (APIC ID {})",
+            sos::smp::current_cpu_id(),
+            current_apic_id()
+        );
+    }
 
     if let Some(location) = info.location() {
         serial_println!(
@@ -76,84 +271,181 @@ fn panic(info: &PanicInfo) -> ! {
     let message = info.message();
     serial_println!("Panic message: {}", message);
 
+    serial_println!("{}", regs);
+    unsafe {
+        sos::crash::dump_stack(regs.rsp);
+        sos::crash::print_backtrace(regs.rbp);
+    }
+
     serial_println!("System halted due to panic - entering infinite loop");
 
     sos::hlt_loop();
 }
 
-fn processors() -> ! {
-    use sos::smp::nop;
-    println!("Initializing CPU storage...");
+/// The tests `test.sh` gates on. Each must pass in a headless QEMU with
+/// only the boot disk and `disk.img` attached.
+fn run_harnessed_tests() -> ! {
+    use alloc::format;
+    use sos::testing::{run_tests, TestCase, TestError};
+
+    run_tests(&[
+        TestCase {
+            name: "ata::identify_boot_disk",
+            run: || sos::ata::test_identify_boot_disk().map_err(|e| format!("{}", e).into()),
+        },
+        TestCase {
+            name: "ata::command_plan",
+            run: || sos::ata::test_command_plan().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "ata::large_read",
+            run: || match sos::ata::test_large_read() {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(TestError::Skipped(
+                    "primary slave too small or without LBA48",
+                )),
+                Err(e) => Err(format!("{}", e).into()),
+            },
+        },
+        TestCase {
+            name: "ata::read_sectors_async",
+            run: || sos::ata::test_read_sectors_async().map_err(|e| format!("{}", e).into()),
+        },
+        TestCase {
+            name: "fat::round_trip",
+            run: || sos::fs::fat::test_fat_round_trip().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "fs::ramdisk",
+            run: || sos::fs::ramdisk::test_ramdisk().map_err(|e| format!("{}", e).into()),
+        },
+        TestCase {
+            name: "fs::tar",
+            run: || sos::fs::tar::test_tar().map_err(|e| format!("{}", e).into()),
+        },
+        TestCase {
+            name: "keyboard::inject_str",
+            run: || sos::task::keyboard::test_inject_str().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "fs::display_file",
+            run: || sos::fs::test_display_file().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "allocator::heap_growth",
+            run: || sos::allocator::test_heap_growth().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "paging::large_page_mapping",
+            run: || sos::paging::test_large_page_mapping().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "interrupt::nested_no_interrupt",
+            run: || sos::interrupt::test_nested_no_interrupt().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "rand::rand",
+            run: || sos::rand::test_rand().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "timer::pit_divisor",
+            run: || sos::timer::test_pit_divisor().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "util::hexdump",
+            run: || sos::util::test_hexdump().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "acpi::madt",
+            run: || sos::acpi::test_madt().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "smp::aps_online",
+            run: || {
+                sos::acpi::cpu_count()
+                    .and_then(sos::smp::test_aps_online)
+                    .map_err(|e| e.into())
+            },
+        },
+        TestCase {
+            name: "smp::ap_preemption",
+            run: || sos::smp::test_ap_preemption().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "std_thread::spawn_join",
+            run: || sos::std_thread::test_spawn_join().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "context::entry_arg",
+            run: || sos::context::test_entry_arg().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "context::fpu_context_switch",
+            run: || sos::context::test_fpu_context_switch().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "sync::thread_sync",
+            run: || sos::sync::test_thread_sync().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "priority::scheduler",
+            run: || sos::priority::test_priority_scheduler().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "priority::threads",
+            run: || sos::priority::test_priority_threads().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "task::channel",
+            run: || sos::task::channel::test_channel().map_err(|e| e.into()),
+        },
+        TestCase {
+            name: "task::executor_wakeups",
+            run: || sos::task::executor::test_executor_wakeups().map_err(|e| e.into()),
+        },
+    ])
+}
+
+/// Sets up the thread pool on the BSP and starts the APs, so threads can be
+/// spawned from here on.
+fn start_processors(mapper: &mut OffsetPageTable, frame_allocator: &mut GlobalFrameAllocator) {
+    serial_println!("Initializing CPU storage...");
     CPUS.init();
-    println!("CPUs initialized");
+
+    if let Err(e) = install_trampoline(mapper, frame_allocator) {
+        serial_println!("Failed to install AP trampoline: {}", e);
+    }
 
     static mut PROCESSORS: [Processor; MAX_CPUS] = [const { Processor::new() }; MAX_CPUS];
 
     let processors_ptr: *mut Processor = unsafe { addr_of_mut!(PROCESSORS[0]) as *mut Processor };
 
-    let scheduler = RRScheduler::new(20);
+    // Let a starved low-priority thread through after 50 picks.
+    let scheduler = PriorityScheduler::new(20, Some(50));
     let pool = Arc::new(ThreadPool::new(scheduler, MAX_CPUS));
+    init_bsp(pool.clone(), processors_ptr);
 
-    println!("Starting Application Processors...");
-
-    println!("Starting AP #1...");
-    start_one_ap(1, 1, pool.clone(), processors_ptr);
-    println!("Started AP #1");
-    nop(1_000_000);
-
-    println!("Starting AP #2...");
-    start_one_ap(2, 2, pool.clone(), processors_ptr);
-    println!("Started AP #2");
-    nop(1_000_000);
-
-    println!("Starting AP #3...");
-    start_one_ap(3, 3, pool.clone(), processors_ptr);
-    println!("Started AP #3");
-    nop(1_000_000);
-
-    println!("Starting AP #4...");
-    start_one_ap(4, 4, pool.clone(), processors_ptr);
-    println!("Started AP #4");
-
-    println!("All APs started! Running on {} total CPUs", 5);
-    nop(5_000_000);
-
-    for i in 0..5 {
-        let cpu = CPUS.get(i);
-        if cpu.online.load(core::sync::atomic::Ordering::SeqCst) == 1 {
-            println!("CPU {} is online (APIC ID: {})", i, cpu.apic_id);
-        } else {
-            println!("CPU {} failed to start", i);
+    let apic_ids = match sos::acpi::local_apic_ids(mapper.phys_offset()) {
+        Ok(ids) => ids,
+        Err(e) => {
+            serial_println!("ACPI: {}, running on the BSP only", e);
+            Vec::new()
         }
-    }
-
-    println!("Creating task executor...");
-    let mut executor = Executor::new();
-
-    println!("Spawning test tasks...");
-    for task_id in 0..10 {
-        executor.spawn(Task::new(async move {
-            println!("Task {} starting", task_id);
-            for i in 0..3 {
-                println!("Task {} iteration {}", task_id, i);
-                sos::task::keyboard::read_line().await;
-            }
-            println!("Task {} completed", task_id);
-        }));
-    }
-
-    executor.spawn(Task::new(async {
-        println!("BSP main task running!");
-        for i in 0..5 {
-            println!("BSP main iteration {}", i);
-
-            for _ in 0..1_000_000 {
-                x86_64::instructions::nop();
-            }
+    };
+    let bsp_apic_id = current_apic_id();
+    let ap_ids: Vec<u32> = apic_ids
+        .into_iter()
+        .filter(|&id| id != bsp_apic_id)
+        .take(MAX_CPUS - 1)
+        .collect();
+
+    for (i, &apic_id) in ap_ids.iter().enumerate() {
+        let ap_index = i + 1;
+        serial_println!("Starting AP #{} (APIC ID {})...", ap_index, apic_id);
+        if !start_one_ap(ap_index, apic_id, pool.clone(), processors_ptr) {
+            serial_println!("AP #{} did not come online", ap_index);
         }
-        println!("BSP main task completed");
-    }));
+    }
 
-    println!("Starting executor...");
-    executor.run();
+    serial_println!("Running on {} CPU(s)", CPUS.online_count());
 }
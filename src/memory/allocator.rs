@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use linked_list_allocator::LockedHeap;
 use x86_64::{
     structures::paging::{
@@ -10,9 +11,177 @@ use x86_64::{
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4MB
+/// Virtual space set aside for the heap; growth stops here.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// Smallest step the heap grows by when an allocation doesn't fit.
+const MIN_GROWTH_PAGES: usize = 16;
+
+/// Held while `grow_heap` maps pages past the heap's top, so two growths
+/// can't map the same range.
+static GROWTH_LOCK: spin::Mutex<()> = spin::Mutex::new(());
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: CountingHeap = CountingHeap {
+    heap: LockedHeap::empty(),
+    allocations: AtomicUsize::new(0),
+};
+
+/// The kernel heap, counting live allocations on top of the byte usage
+/// the linked-list heap already tracks.
+struct CountingHeap {
+    heap: LockedHeap,
+    allocations: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for CountingHeap {
+    /// Grows the heap once and retries before reporting failure.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = unsafe { self.heap.alloc(layout) };
+        if ptr.is_null() {
+            // Room for the block at any alignment plus the heap's own
+            // bookkeeping.
+            let pages = (layout.size() + layout.align())
+                .div_ceil(4096)
+                .saturating_add(1)
+                .max(MIN_GROWTH_PAGES);
+            if grow_heap(pages).is_ok() {
+                ptr = unsafe { self.heap.alloc(layout) };
+            }
+        }
+        if !ptr.is_null() {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.heap.dealloc(ptr, layout) };
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the kernel heap, in bytes except for `allocations`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+    /// Blocks currently allocated and not yet freed.
+    pub allocations: usize,
+}
+
+/// Current heap usage. Takes the heap lock, so don't call it while an
+/// allocation on this core is in progress.
+pub fn stats() -> HeapStats {
+    let heap = ALLOCATOR.heap.lock();
+    HeapStats {
+        total: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed),
+    }
+}
+
+/// Maps `additional_pages` more pages at the end of the heap, backed by
+/// frames from the global frame allocator, and hands them to the heap.
+/// Fails without growing if the heap's virtual range would run out, and
+/// part way if physical memory does.
+pub fn grow_heap(additional_pages: usize) -> Result<(), &'static str> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _growing = GROWTH_LOCK.lock();
+        let top = ALLOCATOR.heap.lock().top();
+        let bytes = additional_pages
+            .checked_mul(4096)
+            .ok_or("Heap address space exhausted")?;
+        if top + bytes > HEAP_START + HEAP_MAX_SIZE {
+            return Err("Heap address space exhausted");
+        }
+
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(top as u64));
+        let pages = (0..additional_pages as u64).map(|i| first + i);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let mut frames = crate::memory::GlobalFrameAllocator;
+        let mapped = unsafe { crate::memory::paging::map_fresh_pages(pages, flags, &mut frames)? };
+        if mapped > 0 {
+            unsafe { ALLOCATOR.heap.lock().extend(mapped * 4096) };
+        }
+        if mapped < additional_pages {
+            return Err("Heap growth stopped partway");
+        }
+        Ok(())
+    })
+}
+
+/// Allocates 8 MiB more than the initial heap holds, in 1 MiB blocks, and
+/// checks the heap grew to fit them and every block is usable.
+pub fn test_heap_growth() -> Result<(), &'static str> {
+    use alloc::vec::Vec;
+
+    crate::serial_println!("=== Heap Growth Test ===");
+    const BLOCK: usize = 1024 * 1024;
+    const GROWTH: usize = 8 * 1024 * 1024;
+
+    let before = stats();
+    let mut blocks = Vec::new();
+    for i in 0..(HEAP_SIZE + GROWTH) / BLOCK {
+        let mut block = Vec::new();
+        block
+            .try_reserve_exact(BLOCK)
+            .map_err(|_| "heap could not grow to fit a block")?;
+        block.resize(BLOCK, i as u8);
+        blocks.push(block);
+    }
+    let after = stats();
+    if after.total < HEAP_SIZE + GROWTH {
+        return Err("heap did not grow");
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        if block[0] != i as u8 || block[BLOCK - 1] != i as u8 {
+            return Err("grown heap lost data");
+        }
+    }
+
+    crate::serial_println!(
+        "✓ Heap grew from {} KiB to {} KiB",
+        before.total / 1024,
+        after.total / 1024
+    );
+    Ok(())
+}
+
+/// Reports the failed request and the heap state, then halts. The heap
+/// lock is only tried so a failure inside the allocator can't deadlock here.
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    crate::serial_println!(
+        "Out of memory: failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+    match ALLOCATOR.heap.try_lock() {
+        Some(heap) => crate::serial_println!(
+            "Heap: {} of {} bytes used, {} free, {} live allocations",
+            heap.used(),
+            heap.size(),
+            heap.free(),
+            ALLOCATOR.allocations.load(Ordering::Relaxed)
+        ),
+        None => crate::serial_println!("Heap: locked, stats unavailable"),
+    }
+    crate::hlt_loop();
+}
+
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register("mem", "show kernel heap usage", cmd_mem);
+}
+
+fn cmd_mem(_shell: &crate::sshell::Shell, _args: &[&str]) {
+    let stats = stats();
+    crate::println!("heap:        {} KiB", stats.total / 1024);
+    crate::println!("used:        {} KiB", stats.used / 1024);
+    crate::println!("free:        {} KiB", stats.free / 1024);
+    crate::println!("allocations: {}", stats.allocations);
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -35,7 +204,7 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.heap.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
@@ -1,5 +1,6 @@
 pub mod allocator;
 pub mod paging;
+pub mod syscalls;
 
 pub use allocator::*;
 pub use paging::*;
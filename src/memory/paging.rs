@@ -1,16 +1,27 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
-        Size4KiB,
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
 
+/// Where the bootloader mapped physical memory, saved by `init` for code
+/// that has no mapper passed to it.
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Serializes page table edits made through the saved offset.
+static PAGE_TABLE_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+/// The frame allocator set up at boot, shared through `GlobalFrameAllocator`.
+static FRAME_ALLOCATOR: spin::Mutex<Option<BitmapFrameAllocator>> = spin::Mutex::new(None);
+
 pub unsafe fn init(
     physical_memory_offset: VirtAddr,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> OffsetPageTable<'static> {
+    PHYS_MEM_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         let mut mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
@@ -39,6 +50,271 @@ unsafe fn map_apic(
     }
 }
 
+/// Sets or clears PRESENT on an already mapped 4 KiB page, keeping its
+/// frame, so the page can be turned into a guard page and back. Clearing
+/// it flushes the page from every CPU's TLB.
+///
+/// # Safety
+///
+/// Nothing may rely on `page` being accessible while it is not present.
+pub unsafe fn set_page_present(page: Page, present: bool) -> Result<(), &'static str> {
+    let mut flags = PageTableFlags::WRITABLE;
+    if present {
+        flags |= PageTableFlags::PRESENT;
+    }
+    unsafe {
+        with_active_mapper(|mapper| {
+            mapper
+                .update_flags(page, flags)
+                .map(|flush| flush.flush())
+                .map_err(|_| "Page is not mapped")
+        })??
+    }
+    if !present {
+        crate::smp::tlb_shootdown(page);
+    }
+    Ok(())
+}
+
+/// Runs `f` on the active page tables, found through the offset saved by
+/// `init`, with other page table edits through here locked out.
+///
+/// # Safety
+///
+/// `f` must not break mappings that other code relies on, and must not
+/// allocate from the heap, which may need this lock to grow.
+pub unsafe fn with_active_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>) -> R,
+) -> Result<R, &'static str> {
+    let offset = PHYS_MEM_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return Err("Paging is not initialized");
+    }
+
+    let _guard = PAGE_TABLE_LOCK.lock();
+    let mut mapper = unsafe {
+        OffsetPageTable::new(
+            active_level_4_table(VirtAddr::new(offset)),
+            VirtAddr::new(offset),
+        )
+    };
+    Ok(f(&mut mapper))
+}
+
+/// Where physical address `phys` can be reached through the bootloader's
+/// mapping of all physical memory.
+pub fn phys_to_virt(phys: PhysAddr) -> Option<VirtAddr> {
+    match PHYS_MEM_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset + phys.as_u64())),
+    }
+}
+
+/// Maps each of `pages` to a fresh frame from `frame_allocator`, which also
+/// supplies any page tables needed. Stops at the first page that can't be
+/// mapped and returns how many were.
+///
+/// # Safety
+///
+/// The pages must not already be in use by anything else.
+pub unsafe fn map_fresh_pages(
+    pages: impl Iterator<Item = Page>,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, &'static str> {
+    unsafe {
+        with_active_mapper(|mapper| {
+            let mut mapped = 0;
+            for page in pages {
+                let Some(frame) = frame_allocator.allocate_frame() else {
+                    break;
+                };
+                match mapper.map_to(page, frame, flags, frame_allocator) {
+                    Ok(flush) => flush.flush(),
+                    Err(_) => break,
+                }
+                mapped += 1;
+            }
+            mapped
+        })
+    }
+}
+
+/// Whether `virt` is already mapped to `phys`, through a page of any size.
+fn maps_to(mapper: &OffsetPageTable, virt: VirtAddr, phys: PhysAddr) -> bool {
+    mapper.translate_addr(virt) == Some(phys)
+}
+
+/// Maps `size` bytes at `phys` to `virt` with `flags`, using 2 MiB pages
+/// wherever both addresses are 2 MiB aligned with at least 2 MiB left and
+/// 4 KiB pages for the rest. Parts already mapped to the same physical
+/// addresses are left alone. Returns how many 2 MiB pages were used.
+///
+/// # Safety
+///
+/// `virt` and `phys` must be 4 KiB aligned, and nothing else may be using
+/// the virtual range for something different.
+pub unsafe fn map_region(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, &'static str> {
+    let mut huge_pages = 0;
+    let mut offset = 0;
+    while offset < size {
+        let (v, p) = (virt + offset, phys + offset);
+        if v.is_aligned(Size2MiB::SIZE)
+            && p.is_aligned(Size2MiB::SIZE)
+            && size - offset >= Size2MiB::SIZE
+        {
+            let page = Page::<Size2MiB>::containing_address(v);
+            let frame = PhysFrame::<Size2MiB>::containing_address(p);
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    huge_pages += 1;
+                    offset += Size2MiB::SIZE;
+                    continue;
+                }
+                Err(MapToError::FrameAllocationFailed) => {
+                    return Err("Out of frames for page tables")
+                }
+                // Partly mapped already, e.g. a BAR sharing pages with an
+                // MSI-X table: go page by page below.
+                Err(_) => {}
+            }
+        }
+
+        let page = Page::<Size4KiB>::containing_address(v);
+        let frame = PhysFrame::<Size4KiB>::containing_address(p);
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_) | MapToError::ParentEntryHugePage)
+                if maps_to(mapper, v, p) => {}
+            Err(_) => return Err("Region mapping failed"),
+        }
+        offset += Size4KiB::SIZE;
+    }
+    Ok(huge_pages)
+}
+
+/// Unmaps `size` bytes from `virt` as mapped by `map_region`, passing each
+/// 4 KiB frame that was behind it to `frame_deallocator`. Unmapped parts
+/// are skipped.
+///
+/// # Safety
+///
+/// Nothing may use the range afterwards, and the frames must be the
+/// deallocator's to take back.
+pub unsafe fn unmap_region(
+    mapper: &mut OffsetPageTable,
+    virt: VirtAddr,
+    size: u64,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let mut offset = 0;
+    while offset < size {
+        let v = virt + offset;
+        let frames = match mapper.translate(v) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } if v.is_aligned(Size2MiB::SIZE) => {
+                match mapper.unmap(Page::<Size2MiB>::containing_address(v)) {
+                    Ok((frame, flush)) => {
+                        flush.flush();
+                        Some((frame.start_address(), Size2MiB::SIZE))
+                    }
+                    Err(_) => None,
+                }
+            }
+            _ => match mapper.unmap(Page::<Size4KiB>::containing_address(v)) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    Some((frame.start_address(), Size4KiB::SIZE))
+                }
+                Err(_) => None,
+            },
+        };
+        match frames {
+            Some((start, len)) => {
+                for i in 0..len / Size4KiB::SIZE {
+                    let frame = PhysFrame::containing_address(start + i * Size4KiB::SIZE);
+                    unsafe { frame_deallocator.deallocate_frame(frame) };
+                }
+                offset += len;
+            }
+            None => offset += Size4KiB::SIZE,
+        }
+    }
+}
+
+/// Maps a 2 MiB aligned run of frames plus a 4 KiB tail uncached with
+/// `map_region` and checks it used a huge page for the aligned part,
+/// kept `NO_CACHE`, reaches the right memory and unmaps cleanly.
+pub fn test_large_page_mapping() -> Result<(), &'static str> {
+    crate::serial_println!("=== Large Page Mapping Test ===");
+
+    const TEST_VIRT: u64 = 0x_6666_0000_0000;
+    const FRAMES: usize = 512 + 1;
+    let size = FRAMES as u64 * Size4KiB::SIZE;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+
+    let mut frames = GlobalFrameAllocator;
+    let first = frames
+        .allocate_contiguous_aligned(FRAMES, 512)
+        .ok_or("no 2 MiB aligned frames free")?;
+    let phys = first.start_address();
+    let virt = VirtAddr::new(TEST_VIRT);
+
+    let checked = unsafe {
+        with_active_mapper(|mapper| {
+            let huge_pages = map_region(mapper, virt, phys, size, flags, &mut frames)?;
+            if huge_pages != 1 {
+                return Err("aligned part was not mapped with a 2 MiB page");
+            }
+            for (addr, huge) in [(virt, true), (virt + Size2MiB::SIZE, false)] {
+                match mapper.translate(addr) {
+                    TranslateResult::Mapped { frame, flags, .. } => {
+                        if matches!(frame, MappedFrame::Size2MiB(_)) != huge {
+                            return Err("region mapped with the wrong page size");
+                        }
+                        if !flags.contains(PageTableFlags::NO_CACHE) {
+                            return Err("NO_CACHE was dropped");
+                        }
+                    }
+                    _ => return Err("region is not mapped"),
+                }
+            }
+
+            // The last byte of the huge page and the tail page must both
+            // land on the frames behind them.
+            let direct = phys_to_virt(phys).ok_or("Paging is not initialized")?;
+            for offset in [Size2MiB::SIZE - 1, size - 1] {
+                (virt + offset).as_mut_ptr::<u8>().write_volatile(0x5A);
+                if (direct + offset).as_ptr::<u8>().read_volatile() != 0x5A {
+                    return Err("mapping reaches the wrong memory");
+                }
+            }
+
+            unmap_region(mapper, virt, size, &mut frames);
+            if mapper.translate_addr(virt).is_some()
+                || mapper.translate_addr(virt + (size - 1)).is_some()
+            {
+                return Err("region still mapped after unmap_region");
+            }
+            Ok(())
+        })
+    };
+    checked??;
+
+    crate::serial_println!("✓ 2 MiB mapping kept NO_CACHE and unmapped cleanly");
+    Ok(())
+}
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
 
@@ -59,32 +335,215 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
+/// Physical frame allocator backed by a bitmap with one bit per 4 KiB frame
+/// (set = in use). The bitmap itself lives in the first usable region large
+/// enough to hold it and is accessed through the physical memory mapping, so
+/// it is available before the heap is.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// Word index where the next search starts.
     next: usize,
+    total_frames: usize,
+    used_frames: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
+impl BitmapFrameAllocator {
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (max_addr / 4096) as usize;
+        let words = frame_count.div_ceil(64);
+        let bitmap_frames = (words * 8).div_ceil(4096) as u64;
+
+        let bitmap_region = usable_regions()
+            .find(|r| {
+                r.range.start_addr() != 0
+                    && r.range.end_addr() - r.range.start_addr() >= bitmap_frames * 4096
+            })
+            .expect("no usable region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+
+        let bitmap = unsafe {
+            let ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+            core::slice::from_raw_parts_mut(ptr, words)
+        };
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
             next: 0,
+            total_frames: 0,
+            used_frames: 0,
+        };
+
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / 4096) as usize;
+            let end = (region.range.end_addr() / 4096) as usize;
+            for frame in start..end {
+                allocator.set_free(frame);
+            }
+            allocator.total_frames += end - start;
+        }
+
+        let first_bitmap_frame = (bitmap_start / 4096) as usize;
+        for frame in first_bitmap_frame..first_bitmap_frame + bitmap_frames as usize {
+            allocator.set_used(frame);
+            allocator.used_frames += 1;
         }
+
+        allocator
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames,
+            used: self.used_frames,
+            free: self.total_frames - self.used_frames,
+        }
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        self.bitmap[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        self.bitmap[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let words = self.bitmap.len();
+        for offset in 0..words {
+            let idx = (self.next + offset) % words;
+            let word = self.bitmap[idx];
+            if word == u64::MAX {
+                continue;
+            }
+
+            let bit = (!word).trailing_zeros() as usize;
+            let frame = idx * 64 + bit;
+            self.set_used(frame);
+            self.used_frames += 1;
+            self.next = idx;
+            return Some(PhysFrame::containing_address(PhysAddr::new(
+                frame as u64 * 4096,
+            )));
+        }
+        None
+    }
+}
+
+/// A frame allocator that can hand out physically contiguous runs, for
+/// device buffers that are described to hardware by a single address.
+pub trait ContiguousFrameAllocator: FrameAllocator<Size4KiB> {
+    /// Allocates `count` adjacent frames and returns the first one.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        self.allocate_contiguous_aligned(count, 1)
+    }
+
+    /// Like `allocate_contiguous`, with the first frame's number a multiple
+    /// of `align` (512 for a run that can back 2 MiB pages).
+    fn allocate_contiguous_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrame>;
+}
+
+impl ContiguousFrameAllocator for BitmapFrameAllocator {
+    fn allocate_contiguous_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 0 || align == 0 {
+            return None;
+        }
+        if count == 1 && align == 1 {
+            return self.allocate_frame();
+        }
+
+        let frames = self.bitmap.len() * 64;
+        let mut start = 0;
+        while start + count <= frames {
+            match (start..start + count).find(|&f| self.is_used(f)) {
+                Some(used) => start = (used + 1).next_multiple_of(align),
+                None => {
+                    for frame in start..start + count {
+                        self.set_used(frame);
+                    }
+                    self.used_frames += count;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(
+                        start as u64 * 4096,
+                    )));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let frame = (frame.start_address().as_u64() / 4096) as usize;
+        if self.is_used(frame) {
+            self.set_free(frame);
+            self.used_frames -= 1;
+            self.next = self.next.min(frame / 64);
+        }
+    }
+}
+
+/// Handle to the frame allocator `init` set up, usable from anywhere.
+/// Each call takes a lock with interrupts disabled, so it is safe from
+/// interrupt handlers; allocating before `install_frame_allocator` fails.
+#[derive(Debug, Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+/// Makes `allocator` the one behind `GlobalFrameAllocator`.
+pub fn install_frame_allocator(allocator: BitmapFrameAllocator) -> GlobalFrameAllocator {
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+    GlobalFrameAllocator
+}
+
+impl GlobalFrameAllocator {
+    fn with<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> Option<R> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR.lock().as_mut().map(f)
+        })
+    }
+
+    pub fn stats(&self) -> Option<FrameStats> {
+        Self::with(|allocator| allocator.stats())
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        Self::with(|allocator| allocator.allocate_frame()).flatten()
+    }
+}
+
+impl ContiguousFrameAllocator for GlobalFrameAllocator {
+    fn allocate_contiguous_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        Self::with(|allocator| allocator.allocate_contiguous_aligned(count, align)).flatten()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        Self::with(|allocator| unsafe { allocator.deallocate_frame(frame) });
     }
 }
@@ -0,0 +1,189 @@
+use crate::fs::syscalls::EINVAL;
+use crate::memory::paging::{phys_to_virt, with_active_mapper, GlobalFrameAllocator};
+use crate::syscall::errno::Errno;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+pub const PROT_READ: u64 = 0x1;
+pub const PROT_WRITE: u64 = 0x2;
+pub const PROT_EXEC: u64 = 0x4;
+
+/// Place the mapping at exactly `addr` instead of picking an address.
+pub const MAP_FIXED: u64 = 0x10;
+/// Zero-filled memory not backed by a file; the only kind supported.
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Virtual range handed out by `sys_mmap`, well away from the heap.
+pub const MMAP_START: u64 = 0x_5555_0000_0000;
+pub const MMAP_SIZE: u64 = 0x_0100_0000_0000; // 1 TiB
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Next address `sys_mmap` hands out when no fixed address is asked for.
+/// Addresses aren't reused after `sys_munmap`; the range is large enough
+/// not to matter yet. Also serializes the syscalls.
+static NEXT_MMAP: Mutex<u64> = Mutex::new(MMAP_START);
+
+/// Packs the third argument of `sys_mmap`, which only has three registers
+/// to work with: `prot` in the low 32 bits, `flags` in the high 32.
+pub const fn mmap_prot_flags(prot: u64, flags: u64) -> u64 {
+    (prot & 0xFFFF_FFFF) | (flags << 32)
+}
+
+/// Whether `start..start + len` lies inside the mmap range.
+fn in_mmap_range(start: u64, len: u64) -> bool {
+    start >= MMAP_START
+        && start
+            .checked_add(len)
+            .is_some_and(|end| end <= MMAP_START + MMAP_SIZE)
+}
+
+fn page_flags(prot: u64) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+/// Unmaps whatever is mapped in `pages` and returns the frames behind it,
+/// which other CPUs may still reach through their TLBs.
+fn unmap_pages(
+    mapper: &mut impl Mapper<Size4KiB>,
+    pages: impl Iterator<Item = Page>,
+) -> Vec<PhysFrame> {
+    let mut frames = Vec::new();
+    for page in pages {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            frames.push(frame);
+        }
+    }
+    frames
+}
+
+fn free_frames(frames: Vec<PhysFrame>) {
+    for frame in frames {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+/// Maps `len` bytes (rounded up to whole pages) of zeroed anonymous memory
+/// and returns its address. `prot_flags` is built with
+/// `mmap_prot_flags`. `addr` is only used with `MAP_FIXED`, where it must
+/// be page aligned, inside the mmap range and not already mapped.
+/// `PROT_NONE` is not supported. Fails with `EINVAL` for a bad request,
+/// `EEXIST` if a fixed range is taken and `ENOMEM` when out of frames.
+pub fn sys_mmap(addr: u64, len: u64, prot_flags: u64) -> u64 {
+    let prot = prot_flags & 0xFFFF_FFFF;
+    let flags = prot_flags >> 32;
+    if len == 0 || flags & MAP_ANONYMOUS == 0 || prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0 {
+        return EINVAL;
+    }
+    let Some(len) = len.checked_next_multiple_of(PAGE_SIZE) else {
+        return EINVAL;
+    };
+
+    let fixed = flags & MAP_FIXED != 0;
+    let mut next = NEXT_MMAP.lock();
+    let start = if fixed {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return EINVAL;
+        }
+        addr
+    } else {
+        *next
+    };
+    if !in_mmap_range(start, len) {
+        return EINVAL;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let count = len / PAGE_SIZE;
+    let page_flags = page_flags(prot);
+    let mapped = unsafe {
+        with_active_mapper(|mapper| {
+            if (0..count).any(|i| mapper.translate_page(first + i).is_ok()) {
+                return Err(Errno::Exist);
+            }
+            for i in 0..count {
+                if map_zeroed(mapper, first + i, page_flags).is_err() {
+                    // Never handed out, so no other CPU has used them.
+                    free_frames(unmap_pages(mapper, (0..i).map(|j| first + j)));
+                    return Err(Errno::NoMem);
+                }
+            }
+            Ok(())
+        })
+    };
+    match mapped {
+        Ok(Ok(())) => {}
+        Ok(Err(errno)) => return errno.as_u64(),
+        Err(_) => return EINVAL,
+    }
+
+    // Keep later picks clear of fixed mappings as well.
+    *next = (*next).max(start + len);
+    start
+}
+
+/// Maps `page` to a new frame, zeroed through the physical memory mapping
+/// so read-only pages start out clean too.
+fn map_zeroed(
+    mapper: &mut impl Mapper<Size4KiB>,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), &'static str> {
+    let mut frames = GlobalFrameAllocator;
+    let frame: PhysFrame = frames.allocate_frame().ok_or("out of frames")?;
+    let virt = phys_to_virt(frame.start_address()).ok_or("Paging is not initialized")?;
+    unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+    match unsafe { mapper.map_to(page, frame, flags, &mut frames) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(_) => {
+            unsafe { frames.deallocate_frame(frame) };
+            Err("map_to failed")
+        }
+    }
+}
+
+/// Unmaps `len` bytes (rounded up to whole pages) from page-aligned `addr`
+/// and frees their frames once every CPU's TLB is flushed. Pages in the
+/// range that aren't mapped are skipped. Returns 0, or `EINVAL` for a bad
+/// range.
+pub fn sys_munmap(addr: u64, len: u64, _a2: u64) -> u64 {
+    if !addr.is_multiple_of(PAGE_SIZE) || len == 0 {
+        return EINVAL;
+    }
+    let Some(len) = len.checked_next_multiple_of(PAGE_SIZE) else {
+        return EINVAL;
+    };
+    if !in_mmap_range(addr, len) {
+        return EINVAL;
+    }
+
+    let _serialize = NEXT_MMAP.lock();
+    let pages = Page::range(
+        Page::<Size4KiB>::containing_address(VirtAddr::new(addr)),
+        Page::containing_address(VirtAddr::new(addr + len)),
+    );
+    let Ok(frames) = (unsafe { with_active_mapper(|mapper| unmap_pages(mapper, pages)) }) else {
+        return EINVAL;
+    };
+    // Flushed everywhere before the frames can be handed out again.
+    crate::smp::tlb_shootdown_range(pages);
+    free_frames(frames);
+    0
+}
@@ -0,0 +1,107 @@
+use super::{local_mac, send_frame, Ipv4Addr, MacAddr, BROADCAST_MAC, ETHERTYPE_ARP, ETHERTYPE_IPV4, LOCAL_IP};
+use crate::task::timeout::with_timeout;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::task::Poll;
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+const HTYPE_ETHERNET: u16 = 1;
+const OP_REQUEST: u16 = 1;
+const OP_REPLY: u16 = 2;
+/// An Ethernet/IPv4 ARP packet.
+const PACKET_LEN: usize = 28;
+
+const RESOLVE_ATTEMPTS: usize = 3;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Known neighbours. Entries never expire; there is one interface on a
+/// small, static network.
+static CACHE: Mutex<BTreeMap<Ipv4Addr, MacAddr>> = Mutex::new(BTreeMap::new());
+/// Woken whenever the cache changes, for tasks waiting in `resolve`.
+static CACHE_WAKER: AtomicWaker = AtomicWaker::new();
+
+pub fn lookup(ip: Ipv4Addr) -> Option<MacAddr> {
+    CACHE.lock().get(&ip).copied()
+}
+
+/// A snapshot of the cache, by address.
+pub fn entries() -> Vec<(Ipv4Addr, MacAddr)> {
+    CACHE.lock().iter().map(|(&ip, &mac)| (ip, mac)).collect()
+}
+
+/// Returns the MAC address for `ip`, asking with broadcast requests if it
+/// isn't cached yet. `ip` must be on the local subnet; see `next_hop`.
+pub async fn resolve(ip: Ipv4Addr) -> Result<MacAddr, &'static str> {
+    for _ in 0..RESOLVE_ATTEMPTS {
+        if let Some(mac) = lookup(ip) {
+            return Ok(mac);
+        }
+        send_packet(OP_REQUEST, [0; 6], ip)?;
+        let reply = core::future::poll_fn(|cx| {
+            if let Some(mac) = lookup(ip) {
+                return Poll::Ready(mac);
+            }
+            CACHE_WAKER.register(cx.waker());
+            lookup(ip).map_or(Poll::Pending, Poll::Ready)
+        });
+        if let Ok(mac) = with_timeout(reply, RESOLVE_TIMEOUT).await {
+            return Ok(mac);
+        }
+    }
+    Err("ARP resolution timed out")
+}
+
+fn send_packet(op: u16, target_mac: MacAddr, target_ip: Ipv4Addr) -> Result<(), &'static str> {
+    let mac = local_mac()?;
+    let mut packet = Vec::with_capacity(PACKET_LEN);
+    packet.extend_from_slice(&HTYPE_ETHERNET.to_be_bytes());
+    packet.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet.extend_from_slice(&[6, 4]);
+    packet.extend_from_slice(&op.to_be_bytes());
+    packet.extend_from_slice(&mac);
+    packet.extend_from_slice(&LOCAL_IP.0);
+    packet.extend_from_slice(&target_mac);
+    packet.extend_from_slice(&target_ip.0);
+
+    let dst = if op == OP_REQUEST {
+        BROADCAST_MAC
+    } else {
+        target_mac
+    };
+    send_frame(dst, ETHERTYPE_ARP, &packet)
+}
+
+/// Learns from an incoming ARP packet and answers requests for our
+/// address. As in RFC 826, a sender already in the cache is refreshed by
+/// any packet, but only packets aimed at us add new entries.
+pub(super) fn handle(packet: &[u8]) -> Result<(), &'static str> {
+    if packet.len() < PACKET_LEN {
+        return Err("ARP packet too short");
+    }
+    let htype = u16::from_be_bytes([packet[0], packet[1]]);
+    let ptype = u16::from_be_bytes([packet[2], packet[3]]);
+    if htype != HTYPE_ETHERNET || ptype != ETHERTYPE_IPV4 || packet[4] != 6 || packet[5] != 4 {
+        return Ok(());
+    }
+    let op = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut sender_mac = [0; 6];
+    sender_mac.copy_from_slice(&packet[8..14]);
+    let sender_ip = Ipv4Addr([packet[14], packet[15], packet[16], packet[17]]);
+    let target_ip = Ipv4Addr([packet[24], packet[25], packet[26], packet[27]]);
+
+    let for_us = target_ip == LOCAL_IP;
+    {
+        let mut cache = CACHE.lock();
+        if for_us || cache.contains_key(&sender_ip) {
+            cache.insert(sender_ip, sender_mac);
+        }
+    }
+    CACHE_WAKER.wake();
+
+    if for_us && op == OP_REQUEST {
+        send_packet(OP_REPLY, sender_mac, sender_ip)?;
+    }
+    Ok(())
+}
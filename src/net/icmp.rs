@@ -0,0 +1,95 @@
+use super::ipv4::{self, Ipv4Header, PROTOCOL_ICMP};
+use super::{arp, checksum, next_hop, Ipv4Addr, MacAddr};
+use crate::task::timeout::with_timeout;
+use crate::timer::uptime_ms;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::Poll;
+use core::time::Duration;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_LEN: usize = 8;
+
+/// Identifier in every echo request `ping` sends ("SO").
+const ECHO_ID: u16 = 0x534F;
+const ECHO_DATA: &[u8] = b"sOS ping";
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+static NEXT_SEQ: AtomicU16 = AtomicU16::new(0);
+/// Echo replies that arrived, by sender and sequence number, until the
+/// `ping` waiting for them picks them up.
+static REPLIES: Mutex<BTreeSet<(Ipv4Addr, u16)>> = Mutex::new(BTreeSet::new());
+static REPLY_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Answers echo requests and records echo replies meant for `ping`.
+pub(super) fn handle(
+    src_mac: MacAddr,
+    header: &Ipv4Header,
+    message: &[u8],
+) -> Result<(), &'static str> {
+    if message.len() < HEADER_LEN {
+        return Err("ICMP message too short");
+    }
+    if checksum(message) != 0 {
+        return Err("bad ICMP checksum");
+    }
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            // Identifier, sequence number and data are echoed unchanged.
+            let mut reply = message.to_vec();
+            reply[0] = TYPE_ECHO_REPLY;
+            reply[2..4].fill(0);
+            let sum = checksum(&reply);
+            reply[2..4].copy_from_slice(&sum.to_be_bytes());
+            ipv4::send_to(src_mac, header.src, PROTOCOL_ICMP, &reply)
+        }
+        TYPE_ECHO_REPLY => {
+            let id = u16::from_be_bytes([message[4], message[5]]);
+            let seq = u16::from_be_bytes([message[6], message[7]]);
+            if id == ECHO_ID {
+                REPLIES.lock().insert((header.src, seq));
+                REPLY_WAKER.wake();
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Sends one echo request to `dst` and waits for the reply. Returns the
+/// round trip in milliseconds, not counting ARP resolution. Only one ping
+/// should be in flight at a time.
+pub async fn ping(dst: Ipv4Addr) -> Result<u64, &'static str> {
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    let mut request = Vec::with_capacity(HEADER_LEN + ECHO_DATA.len());
+    request.extend_from_slice(&[TYPE_ECHO_REQUEST, 0, 0, 0]);
+    request.extend_from_slice(&ECHO_ID.to_be_bytes());
+    request.extend_from_slice(&seq.to_be_bytes());
+    request.extend_from_slice(ECHO_DATA);
+    let sum = checksum(&request);
+    request[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let mac = arp::resolve(next_hop(dst)).await?;
+    let start = uptime_ms();
+    ipv4::send_to(mac, dst, PROTOCOL_ICMP, &request)?;
+
+    let reply = core::future::poll_fn(|cx| {
+        if REPLIES.lock().remove(&(dst, seq)) {
+            return Poll::Ready(());
+        }
+        REPLY_WAKER.register(cx.waker());
+        if REPLIES.lock().remove(&(dst, seq)) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    });
+    with_timeout(reply, PING_TIMEOUT)
+        .await
+        .map_err(|_| "request timed out")?;
+    Ok(uptime_ms() - start)
+}
@@ -0,0 +1,153 @@
+use super::{arp, checksum, icmp, next_hop, send_frame, MacAddr, ETHERTYPE_IPV4, LOCAL_IP};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+
+/// Header without options; the only kind sent.
+const HEADER_LEN: usize = 20;
+const DEFAULT_TTL: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 0x4000;
+const FLAG_MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET_MASK: u16 = 0x1FFF;
+/// Largest payload that fits one Ethernet frame with a bare header.
+pub const MAX_PAYLOAD: usize = 1500 - HEADER_LEN;
+
+/// Identification field of the next packet sent.
+static NEXT_ID: AtomicU16 = AtomicU16::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    /// Whether `self` and `other` share the network part under `mask`.
+    pub fn in_subnet(self, other: Ipv4Addr, mask: Ipv4Addr) -> bool {
+        (0..4).all(|i| self.0[i] & mask.0[i] == other.0[i] & mask.0[i])
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = &'static str;
+
+    /// Parses dotted-decimal notation, e.g. `10.0.2.2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts
+                .next()
+                .and_then(|part| part.parse().ok())
+                .ok_or("invalid IPv4 address")?;
+        }
+        if parts.next().is_some() {
+            return Err("invalid IPv4 address");
+        }
+        Ok(Ipv4Addr(octets))
+    }
+}
+
+/// The fields of a received header the stack cares about.
+#[derive(Debug, Clone, Copy)]
+pub struct Ipv4Header {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    /// Header length in bytes, options included.
+    pub header_len: usize,
+    /// Header plus payload, from the total length field.
+    pub total_len: usize,
+}
+
+impl Ipv4Header {
+    /// Validates the header at the start of `packet`. Fragments are
+    /// rejected since nothing reassembles them.
+    pub fn parse(packet: &[u8]) -> Result<Self, &'static str> {
+        if packet.len() < HEADER_LEN {
+            return Err("IPv4 packet too short");
+        }
+        if packet[0] >> 4 != 4 {
+            return Err("not an IPv4 packet");
+        }
+        let header_len = usize::from(packet[0] & 0x0F) * 4;
+        let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        if header_len < HEADER_LEN || total_len < header_len || total_len > packet.len() {
+            return Err("bad IPv4 length");
+        }
+        if checksum(&packet[..header_len]) != 0 {
+            return Err("bad IPv4 header checksum");
+        }
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if fragment & (FLAG_MORE_FRAGMENTS | FRAGMENT_OFFSET_MASK) != 0 {
+            return Err("IPv4 fragments are not supported");
+        }
+
+        Ok(Ipv4Header {
+            src: Ipv4Addr([packet[12], packet[13], packet[14], packet[15]]),
+            dst: Ipv4Addr([packet[16], packet[17], packet[18], packet[19]]),
+            protocol: packet[9],
+            ttl: packet[8],
+            header_len,
+            total_len,
+        })
+    }
+}
+
+/// Handles a packet that arrived in a frame from `src_mac`. Packets for
+/// other hosts and unknown protocols are ignored.
+pub(super) fn handle(src_mac: MacAddr, packet: &[u8]) -> Result<(), &'static str> {
+    let header = Ipv4Header::parse(packet)?;
+    if header.dst != LOCAL_IP && header.dst != Ipv4Addr::BROADCAST {
+        return Ok(());
+    }
+    let payload = &packet[header.header_len..header.total_len];
+    match header.protocol {
+        PROTOCOL_ICMP => icmp::handle(src_mac, &header, payload),
+        _ => Ok(()),
+    }
+}
+
+/// Sends `payload` to `dst`, resolving the next hop's MAC address first.
+pub async fn send(dst: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), &'static str> {
+    let mac = arp::resolve(next_hop(dst)).await?;
+    send_to(mac, dst, protocol, payload)
+}
+
+/// Sends `payload` to `dst` through the neighbour at `mac`.
+pub(super) fn send_to(
+    mac: MacAddr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Result<(), &'static str> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err("IPv4 payload too large");
+    }
+    let total_len = (HEADER_LEN + payload.len()) as u16;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut packet = Vec::with_capacity(usize::from(total_len));
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&LOCAL_IP.0);
+    packet.extend_from_slice(&dst.0);
+    let sum = checksum(&packet);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    send_frame(mac, ETHERTYPE_IPV4, &packet)
+}
@@ -0,0 +1,109 @@
+pub mod arp;
+pub mod icmp;
+pub mod ipv4;
+
+pub use icmp::ping;
+pub use ipv4::Ipv4Addr;
+
+use crate::drivers::pci::virtio_net;
+use crate::serial_println;
+use alloc::vec::Vec;
+
+pub type MacAddr = [u8; 6];
+
+pub const BROADCAST_MAC: MacAddr = [0xFF; 6];
+
+/// The stack has one interface, set up the way QEMU's user-mode network
+/// expects its first guest.
+pub const LOCAL_IP: Ipv4Addr = Ipv4Addr([10, 0, 2, 15]);
+pub const NETMASK: Ipv4Addr = Ipv4Addr([255, 255, 255, 0]);
+pub const GATEWAY: Ipv4Addr = Ipv4Addr([10, 0, 2, 2]);
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+const ETH_HEADER_LEN: usize = 14;
+/// Shortest frame on the wire without FCS; shorter ones are zero padded.
+const ETH_MIN_FRAME_LEN: usize = 60;
+
+/// The interface's MAC address.
+pub fn local_mac() -> Result<MacAddr, &'static str> {
+    virtio_net::mac_address().ok_or("No network device")
+}
+
+/// Where a packet for `dst` goes first: straight to it on the local
+/// subnet, otherwise to the gateway.
+pub fn next_hop(dst: Ipv4Addr) -> Ipv4Addr {
+    if dst.in_subnet(LOCAL_IP, NETMASK) {
+        dst
+    } else {
+        GATEWAY
+    }
+}
+
+/// Wraps `payload` in an Ethernet header and sends it.
+fn send_frame(dst: MacAddr, ethertype: u16, payload: &[u8]) -> Result<(), &'static str> {
+    let mut frame = Vec::with_capacity(ETH_MIN_FRAME_LEN.max(ETH_HEADER_LEN + payload.len()));
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&local_mac()?);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < ETH_MIN_FRAME_LEN {
+        frame.resize(ETH_MIN_FRAME_LEN, 0);
+    }
+    virtio_net::send(&frame)
+}
+
+/// The Internet checksum (RFC 1071): the ones' complement of the ones'
+/// complement sum of `data` as big-endian 16-bit words, with an odd
+/// trailing byte padded with zero. Running it over data that already
+/// holds a correct checksum gives 0.
+pub fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| match *pair {
+            [hi, lo] => u32::from(u16::from_be_bytes([hi, lo])),
+            [hi] => u32::from(u16::from_be_bytes([hi, 0])),
+            _ => 0,
+        })
+        .fold(0, u32::wrapping_add);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Receives frames for as long as the device is up and hands them to ARP
+/// and IPv4. This must be the only task calling `virtio_net::recv`.
+pub async fn run() {
+    loop {
+        let frame = virtio_net::recv().await;
+        if let Err(e) = handle_frame(&frame) {
+            serial_println!("net: dropped frame: {}", e);
+        }
+    }
+}
+
+fn handle_frame(frame: &[u8]) -> Result<(), &'static str> {
+    if frame.len() < ETH_HEADER_LEN {
+        return Err("frame too short");
+    }
+    let mut src_mac = [0; 6];
+    src_mac.copy_from_slice(&frame[6..12]);
+    let payload = &frame[ETH_HEADER_LEN..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => arp::handle(payload),
+        ETHERTYPE_IPV4 => ipv4::handle(src_mac, payload),
+        _ => Ok(()),
+    }
+}
+
+/// Pings the QEMU gateway a few times. Needs `run` going in another task.
+pub async fn test_ping() -> Result<(), &'static str> {
+    serial_println!("=== Ping Test ===");
+    for _ in 0..3 {
+        let ms = ping(GATEWAY).await?;
+        serial_println!("✓ Reply from {}: time={} ms", GATEWAY, ms);
+    }
+    Ok(())
+}
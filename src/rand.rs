@@ -0,0 +1,174 @@
+use crate::arch::cpuid::{self, Feature};
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// SplitMix64's increment, 2^64 divided by the golden ratio.
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+/// Intel's advice: retry RDRAND ten times before giving up on it.
+const RDRAND_RETRIES: usize = 10;
+
+static STATE: AtomicU64 = AtomicU64::new(0);
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// SplitMix64: fast, small, and passes BigCrush, but not cryptographic.
+/// Use one directly for reproducible test data.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        mix(self.state)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// A value from the CPU's hardware generator, if it has one and it
+/// delivers within a few tries.
+pub fn rdrand() -> Option<u64> {
+    if !cpuid::has_feature(Feature::Rdrand) {
+        return None;
+    }
+    for _ in 0..RDRAND_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {value}",
+                "setc {ok}",
+                value = out(reg) value,
+                ok = out(reg_byte) ok,
+                options(nomem, nostack),
+            );
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Seeds the global generator, e.g. with a fixed value to replay a test.
+pub fn seed(seed: u64) {
+    STATE.store(seed, Ordering::SeqCst);
+    SEEDED.store(true, Ordering::SeqCst);
+}
+
+/// Seeds from the TSC, mixed with RDRAND where the CPU has it. Runs on
+/// first use if nothing seeded the generator before.
+fn seed_from_entropy() {
+    let tsc = unsafe { _rdtsc() };
+    let entropy = match rdrand() {
+        Some(hardware) => mix(tsc) ^ hardware,
+        None => mix(tsc),
+    };
+    // Whoever seeds first wins; a racing CPU's seed is dropped.
+    if !SEEDED.swap(true, Ordering::SeqCst) {
+        STATE.store(entropy, Ordering::SeqCst);
+    }
+}
+
+/// A random u64 from the global generator. Safe to call from any CPU.
+pub fn u64() -> u64 {
+    if !SEEDED.load(Ordering::Relaxed) {
+        seed_from_entropy();
+    }
+    let state = STATE
+        .fetch_add(GOLDEN_GAMMA, Ordering::Relaxed)
+        .wrapping_add(GOLDEN_GAMMA);
+    mix(state)
+}
+
+/// Fills `buf` with random bytes.
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+/// A uniformly random value in `lo..hi`. Draws that would bias the result
+/// towards small values are thrown away.
+pub fn range(lo: u64, hi: u64) -> u64 {
+    assert!(lo < hi, "rand::range: empty range {}..{}", lo, hi);
+    let span = hi - lo;
+    let zone = u64::MAX - (u64::MAX - span + 1) % span;
+    loop {
+        let value = u64();
+        if value <= zone {
+            return lo + value % span;
+        }
+    }
+}
+
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register("rand", "print a random number: rand [lo hi]", cmd_rand);
+}
+
+fn cmd_rand(_shell: &crate::sshell::Shell, args: &[&str]) {
+    match args {
+        [] => crate::println!("{}", u64()),
+        [lo, hi] => match (lo.parse::<u64>(), hi.parse::<u64>()) {
+            (Ok(lo), Ok(hi)) if lo < hi => crate::println!("{}", range(lo, hi)),
+            _ => crate::println!("rand: need two numbers with lo < hi"),
+        },
+        _ => crate::println!("usage: rand [lo hi]"),
+    }
+}
+
+/// Checks that `range` stays in bounds and reaches both ends of a small
+/// range, and that a seeded `SplitMix64` repeats itself.
+pub fn test_rand() -> Result<(), &'static str> {
+    let mut seen = [false; 6];
+    for _ in 0..1000 {
+        let value = range(10, 16);
+        if !(10..16).contains(&value) {
+            return Err("range returned a value out of bounds");
+        }
+        seen[(value - 10) as usize] = true;
+    }
+    if !seen.iter().all(|&hit| hit) {
+        return Err("range never hit some values in 1000 draws");
+    }
+
+    let mut a = SplitMix64::new(42);
+    let mut b = SplitMix64::new(42);
+    if (0..16).any(|_| a.next_u64() != b.next_u64()) {
+        return Err("SplitMix64 is not deterministic");
+    }
+
+    let mut buf = [0u8; 64];
+    fill(&mut buf);
+    if buf.iter().all(|&byte| byte == 0) {
+        return Err("fill left the buffer zeroed");
+    }
+
+    crate::serial_println!(
+        "✓ rand: RDRAND {}",
+        if cpuid::has_feature(Feature::Rdrand) {
+            "present"
+        } else {
+            "absent"
+        }
+    );
+    Ok(())
+}
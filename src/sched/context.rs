@@ -1,7 +1,15 @@
+use alloc::alloc::Layout;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::arch::global_asm;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::paging::Page;
+use x86_64::VirtAddr;
+
+use crate::thread_pool::Tid;
+
+const PAGE_SIZE: usize = 4096;
 
 #[repr(C)]
 pub struct RawContext {
@@ -12,9 +20,37 @@ pub struct RawContext {
     pub rbx: usize,
     pub rbp: usize,
     pub rsp: usize,
+    /// Where `ctx_switch` saves and restores the x87/SSE state.
+    pub fpu: *mut FpuState,
+}
+
+const _: () = assert!(size_of::<RawContext>() == 8 * size_of::<usize>());
+
+/// An `fxsave` area.
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+const _: () = assert!(size_of::<FpuState>() == 512);
+
+impl FpuState {
+    /// The state `fninit` leaves behind, with all exceptions masked and
+    /// round-to-nearest in both the x87 control word and MXCSR.
+    pub fn new() -> Self {
+        const FCW_DEFAULT: u16 = 0x037F;
+        const MXCSR_DEFAULT: u32 = 0x1F80;
+
+        let mut area = [0u8; 512];
+        area[0..2].copy_from_slice(&FCW_DEFAULT.to_le_bytes());
+        area[24..28].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+        FpuState(area)
+    }
 }
 
-const _: () = assert!(size_of::<RawContext>() == 7 * size_of::<usize>());
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 unsafe extern "C" {
     fn ctx_switch(old: *mut RawContext, new: *const RawContext);
@@ -26,6 +62,8 @@ global_asm!(
     .global ctx_switch
     .type ctx_switch, @function
 ctx_switch:
+    mov rax, [rdi + 56]
+    fxsave64 [rax]
     mov [rdi + 0], r15
     mov [rdi + 8], r14
     mov [rdi + 16], r13
@@ -41,6 +79,8 @@ ctx_switch:
     mov rbx, [rsi + 32]
     mov rbp, [rsi + 40]
     mov rsp, [rsi + 48]
+    mov rax, [rsi + 56]
+    fxrstor64 [rax]
 
     ret
 "#
@@ -52,19 +92,118 @@ pub trait LocalContext {
     fn raw_ptr(&self) -> *const RawContext;
 }
 
+/// Pages left non-present below every new thread stack.
+static STACK_GUARD_PAGES: AtomicUsize = AtomicUsize::new(1);
+
+/// Guard ranges of live stacks, for the fault handlers to recognise an
+/// overflow.
+static GUARD_RANGES: spin::Mutex<Vec<GuardRange>> = spin::Mutex::new(Vec::new());
+
+struct GuardRange {
+    start: u64,
+    end: u64,
+    tid: Option<Tid>,
+}
+
+/// Sets how many guard pages stacks created from now on get. Zero turns
+/// guards off.
+pub fn set_stack_guard_pages(pages: usize) {
+    STACK_GUARD_PAGES.store(pages, Ordering::Relaxed);
+}
+
+/// If `addr` is in a stack guard page, returns the owning thread's id, or
+/// `Some(None)` for a stack no thread owns (a CPU's loop context). Used by
+/// the fault handlers, so it gives up rather than spin on the lock.
+pub fn stack_guard_owner(addr: u64) -> Option<Option<Tid>> {
+    let ranges = GUARD_RANGES.try_lock()?;
+    ranges
+        .iter()
+        .find(|r| (r.start..r.end).contains(&addr))
+        .map(|r| r.tid)
+}
+
+/// A page-aligned kernel stack with non-present guard pages at the bottom,
+/// so running off the end faults instead of corrupting the heap.
+struct Stack {
+    base: *mut u8,
+    layout: Layout,
+    guard_size: usize,
+}
+
+impl Stack {
+    fn new(size: usize) -> Self {
+        let guard_size = STACK_GUARD_PAGES.load(Ordering::Relaxed) * PAGE_SIZE;
+        let size = size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let layout =
+            Layout::from_size_align(guard_size + size, PAGE_SIZE).expect("stack layout overflow");
+        let base = unsafe { alloc::alloc::alloc(layout) };
+        if base.is_null() {
+            alloc::alloc::handle_alloc_error(layout);
+        }
+
+        let mut stack = Stack {
+            base,
+            layout,
+            guard_size: 0,
+        };
+        for offset in (0..guard_size).step_by(PAGE_SIZE) {
+            let page = Page::containing_address(VirtAddr::from_ptr(unsafe { base.add(offset) }));
+            if let Err(e) = unsafe { crate::memory::set_page_present(page, false) } {
+                crate::serial_println!("stack: no guard page: {}", e);
+                break;
+            }
+            stack.guard_size = offset + PAGE_SIZE;
+        }
+        if stack.guard_size != 0 {
+            GUARD_RANGES.lock().push(GuardRange {
+                start: base as u64,
+                end: base as u64 + stack.guard_size as u64,
+                tid: None,
+            });
+        }
+        stack
+    }
+
+    fn top(&self) -> usize {
+        self.base as usize + self.layout.size()
+    }
+
+    fn set_owner(&self, tid: Tid) {
+        let mut ranges = GUARD_RANGES.lock();
+        if let Some(range) = ranges.iter_mut().find(|r| r.start == self.base as u64) {
+            range.tid = Some(tid);
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        if self.guard_size != 0 {
+            GUARD_RANGES.lock().retain(|r| r.start != self.base as u64);
+        }
+        // The allocator writes into freed memory, so the guard pages have to
+        // be present again first.
+        for offset in (0..self.guard_size).step_by(PAGE_SIZE) {
+            let page =
+                Page::containing_address(VirtAddr::from_ptr(unsafe { self.base.add(offset) }));
+            unsafe { crate::memory::set_page_present(page, true) }
+                .expect("stack: failed to restore guard page");
+        }
+        unsafe { alloc::alloc::dealloc(self.base, self.layout) };
+    }
+}
+
 pub struct ContextImpl {
     raw: RawContext,
-    _stack: Box<[u8]>,
+    stack: Stack,
+    /// Boxed so `raw.fpu` stays valid when the context is moved.
+    _fpu: Box<FpuState>,
 }
 
 impl ContextImpl {
     pub fn new_with_entry(stack_size: usize, entry_fn: extern "C" fn() -> !) -> Self {
-        let mut v = Vec::with_capacity(stack_size);
-        unsafe {
-            v.set_len(stack_size);
-        }
-        let boxed = v.into_boxed_slice();
-        let top = boxed.as_ptr() as usize + boxed.len();
+        let stack = Stack::new(stack_size);
+        let top = stack.top();
 
         let new_rsp = top - core::mem::size_of::<usize>();
         unsafe {
@@ -72,6 +211,7 @@ impl ContextImpl {
             ptr.write(entry_fn as usize);
         }
 
+        let mut fpu = Box::new(FpuState::new());
         let raw = RawContext {
             r15: 0,
             r14: 0,
@@ -80,9 +220,14 @@ impl ContextImpl {
             rbx: 0,
             rbp: 0,
             rsp: new_rsp,
+            fpu: &mut *fpu,
         };
 
-        ContextImpl { raw, _stack: boxed }
+        ContextImpl {
+            raw,
+            stack,
+            _fpu: fpu,
+        }
     }
 }
 
@@ -100,6 +245,42 @@ impl LocalContext for ContextImpl {
     }
 }
 
+global_asm!(
+    r#"
+    .text
+    .global kernel_thread_start
+    .type kernel_thread_start, @function
+kernel_thread_start:
+    sti
+    and rsp, -16
+    push 0
+    mov rdi, r12
+    jmp r13
+"#
+);
+
+unsafe extern "C" {
+    safe fn kernel_thread_start() -> !;
+}
+
+impl ContextImpl {
+    /// Like `new_with_entry`, but `entry` receives `arg` in `rdi`. The first
+    /// switch lands on `kernel_thread_start`, which moves `arg` and `entry`
+    /// out of the callee-saved registers restored by `ctx_switch`, aligns the
+    /// stack as the SysV ABI expects at a call and enables interrupts, since
+    /// switches always happen with them off.
+    pub fn new_with_entry_arg(
+        stack_size: usize,
+        entry: extern "C" fn(usize) -> !,
+        arg: usize,
+    ) -> Self {
+        let mut ctx = Self::new_with_entry(stack_size, kernel_thread_start);
+        ctx.raw.r12 = arg;
+        ctx.raw.r13 = entry as usize;
+        ctx
+    }
+}
+
 extern "C" fn context_loop() -> ! {
     loop {
         unsafe {
@@ -117,6 +298,10 @@ impl tp_mod::Context for ContextImpl {
             ctx_switch(self.raw_mut_ptr(), (&mut (*data_ptr)).raw_ptr());
         }
     }
+
+    fn set_tid(&mut self, tid: Tid) {
+        self.stack.set_owner(tid);
+    }
 }
 
 #[unsafe(no_mangle)]
@@ -126,3 +311,176 @@ pub extern "Rust" fn create_loop_context_for_thread_pool() -> *mut dyn tp_mod::C
     let boxed: Box<dyn tp_mod::Context> = Box::new(ctx_impl);
     Box::into_raw(boxed)
 }
+
+/// Spawns a few threads whose entry argument is the boxed closure, and checks
+/// each one ran its own closure. A lost or mixed-up `rdi` would crash or
+/// return the wrong value.
+pub fn test_entry_arg() -> Result<(), &'static str> {
+    use crate::std_thread;
+    use alloc::vec::Vec;
+
+    crate::serial_println!("=== Thread Entry Argument Test ===");
+
+    let handles: Vec<_> = (0..4usize)
+        .map(|i| std_thread::spawn(move || i * 0x1111 + 7))
+        .collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        match handle.join() {
+            Ok(value) if value == i * 0x1111 + 7 => {}
+            _ => return Err("thread did not run the closure it was given"),
+        }
+    }
+
+    crate::serial_println!("✓ Every thread received its own argument");
+    Ok(())
+}
+
+/// Turns on OSFXSR and OSXMMEXCPT in CR4 and makes sure SSE instructions
+/// aren't trapped through CR0. Without them `fxsave` leaves the XMM
+/// registers out and `ldmxcsr`/`stmxcsr` raise #UD. Every CPU needs this
+/// before its first context switch.
+pub fn init_fpu() {
+    use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+}
+
+/// The 16 XMM registers, as bytes.
+type XmmState = [[u8; 16]; 16];
+
+/// Loads all 16 XMM registers from `state`. The kernel is built soft-float,
+/// so compiled code never touches them and they keep these values until a
+/// context switch swaps them out.
+fn load_xmm(state: &XmmState) {
+    unsafe {
+        core::arch::asm!(
+            "movups xmm0, [{0}]",
+            "movups xmm1, [{0} + 16]",
+            "movups xmm2, [{0} + 32]",
+            "movups xmm3, [{0} + 48]",
+            "movups xmm4, [{0} + 64]",
+            "movups xmm5, [{0} + 80]",
+            "movups xmm6, [{0} + 96]",
+            "movups xmm7, [{0} + 112]",
+            "movups xmm8, [{0} + 128]",
+            "movups xmm9, [{0} + 144]",
+            "movups xmm10, [{0} + 160]",
+            "movups xmm11, [{0} + 176]",
+            "movups xmm12, [{0} + 192]",
+            "movups xmm13, [{0} + 208]",
+            "movups xmm14, [{0} + 224]",
+            "movups xmm15, [{0} + 240]",
+            in(reg) state.as_ptr(),
+            options(nostack, readonly),
+        );
+    }
+}
+
+fn store_xmm(state: &mut XmmState) {
+    unsafe {
+        core::arch::asm!(
+            "movups [{0}], xmm0",
+            "movups [{0} + 16], xmm1",
+            "movups [{0} + 32], xmm2",
+            "movups [{0} + 48], xmm3",
+            "movups [{0} + 64], xmm4",
+            "movups [{0} + 80], xmm5",
+            "movups [{0} + 96], xmm6",
+            "movups [{0} + 112], xmm7",
+            "movups [{0} + 128], xmm8",
+            "movups [{0} + 144], xmm9",
+            "movups [{0} + 160], xmm10",
+            "movups [{0} + 176], xmm11",
+            "movups [{0} + 192], xmm12",
+            "movups [{0} + 208], xmm13",
+            "movups [{0} + 224], xmm14",
+            "movups [{0} + 240], xmm15",
+            in(reg) state.as_mut_ptr(),
+            options(nostack),
+        );
+    }
+}
+
+fn read_mxcsr() -> u32 {
+    let mut mxcsr = 0u32;
+    unsafe { core::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack)) };
+    mxcsr
+}
+
+fn write_mxcsr(mxcsr: u32) {
+    unsafe { core::arch::asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack)) };
+}
+
+/// Two threads each load their own pattern into every XMM register and
+/// their own MXCSR rounding mode, then yield over and over, checking both
+/// survive each switch. The caller does the same around the joins, which
+/// covers switches in and out of the loop context.
+pub fn test_fpu_context_switch() -> Result<(), &'static str> {
+    use crate::std_thread;
+
+    const ROUNDS: u32 = 1000;
+    const MXCSR_NEAREST: u32 = 0x1F80;
+    const MXCSR_DOWN: u32 = 0x3F80;
+    const MXCSR_ZERO: u32 = 0x7F80;
+
+    fn pattern(seed: u8) -> XmmState {
+        let mut state = [[0u8; 16]; 16];
+        for (reg, bytes) in state.iter_mut().enumerate() {
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte = seed ^ (reg * 16 + i) as u8;
+            }
+        }
+        state
+    }
+
+    /// Loads `seed`'s pattern and `mxcsr`, then checks they are still there
+    /// after each of `ROUNDS` yields.
+    fn hold_state(seed: u8, mxcsr: u32) -> Result<(), &'static str> {
+        let expected = pattern(seed);
+        let mut actual = [[0u8; 16]; 16];
+        load_xmm(&expected);
+        write_mxcsr(mxcsr);
+        for _ in 0..ROUNDS {
+            std_thread::yield_now();
+            store_xmm(&mut actual);
+            if actual != expected {
+                return Err("XMM registers changed across a context switch");
+            }
+            if read_mxcsr() != mxcsr {
+                return Err("MXCSR changed across a context switch");
+            }
+        }
+        write_mxcsr(MXCSR_NEAREST);
+        Ok(())
+    }
+
+    crate::serial_println!("=== FPU Context Switch Test ===");
+
+    let own = pattern(0x5A);
+    let mut actual = [[0u8; 16]; 16];
+    load_xmm(&own);
+    write_mxcsr(MXCSR_ZERO);
+
+    let first = std_thread::spawn(|| hold_state(0x11, MXCSR_NEAREST));
+    let second = std_thread::spawn(|| hold_state(0xEE, MXCSR_DOWN));
+    let first = first.join().map_err(|_| "FPU thread exited early")?;
+    let second = second.join().map_err(|_| "FPU thread exited early")?;
+
+    store_xmm(&mut actual);
+    let own_mxcsr = read_mxcsr();
+    write_mxcsr(MXCSR_NEAREST);
+    first?;
+    second?;
+    if actual != own || own_mxcsr != MXCSR_ZERO {
+        return Err("caller's SSE state changed while it ran other threads");
+    }
+
+    crate::serial_println!("✓ Every thread kept its own XMM registers and MXCSR");
+    Ok(())
+}
@@ -1,10 +1,13 @@
 pub mod context;
+pub mod priority;
 pub mod processor;
 pub mod rr;
 pub mod std_thread;
+pub mod syscalls;
 pub mod thread_pool;
 
 pub use context::*;
+pub use priority::*;
 pub use processor::*;
 pub use rr::*;
 pub use std_thread::*;
@@ -0,0 +1,264 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use log::trace;
+use spin::Mutex;
+
+use crate::rr::Scheduler;
+
+type Tid = usize;
+
+/// Number of priority levels. Level `PRIORITY_LEVELS - 1` is the most
+/// important.
+pub const PRIORITY_LEVELS: usize = 4;
+/// Level threads get unless spawned with another one.
+pub const DEFAULT_PRIORITY: u8 = 1;
+
+/// Multi-level round-robin: always runs the highest non-empty level and
+/// time-slices within it. With aging on, a thread that has sat at the head
+/// of a lower level for `aging` picks runs next regardless of level, so it
+/// can't starve forever.
+pub struct PriorityScheduler {
+    inner: Mutex<PrioritySchedulerInner>,
+}
+
+struct PrioritySchedulerInner {
+    max_time_slice: usize,
+    aging: Option<usize>,
+    /// Incremented on every pop; used to measure how long a thread waited.
+    pops: usize,
+    queues: [VecDeque<Tid>; PRIORITY_LEVELS],
+    infos: Vec<PriorityInfo>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PriorityInfo {
+    priority: u8,
+    present: bool,
+    rest_slice: usize,
+    queued_at: usize,
+    /// The only CPU the thread may run on, if it is pinned.
+    cpu: Option<usize>,
+}
+
+impl Default for PriorityInfo {
+    fn default() -> Self {
+        PriorityInfo {
+            priority: DEFAULT_PRIORITY,
+            present: false,
+            rest_slice: 0,
+            queued_at: 0,
+            cpu: None,
+        }
+    }
+}
+
+impl PriorityScheduler {
+    pub fn new(max_time_slice: usize, aging: Option<usize>) -> Self {
+        PriorityScheduler {
+            inner: Mutex::new(PrioritySchedulerInner {
+                max_time_slice,
+                aging,
+                pops: 0,
+                queues: Default::default(),
+                infos: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl Scheduler for PriorityScheduler {
+    fn push(&self, tid: Tid) {
+        self.inner.lock().push(tid);
+    }
+    fn pop(&self, cpu_id: usize) -> Option<Tid> {
+        self.inner.lock().pop(cpu_id)
+    }
+    fn tick(&self, current_tid: Tid) -> bool {
+        self.inner.lock().tick(current_tid)
+    }
+    fn set_priority(&self, tid: Tid, priority: u8) {
+        self.inner.lock().set_priority(tid, priority);
+    }
+    fn set_affinity(&self, tid: Tid, cpu_id: Option<usize>) {
+        self.inner.lock().info(tid).cpu = cpu_id;
+    }
+    fn remove(&self, tid: Tid) {
+        self.inner.lock().remove(tid);
+    }
+}
+
+impl PrioritySchedulerInner {
+    fn info(&mut self, tid: Tid) -> &mut PriorityInfo {
+        if self.infos.len() <= tid {
+            self.infos.resize(tid + 1, PriorityInfo::default());
+        }
+        &mut self.infos[tid]
+    }
+
+    fn push(&mut self, tid: Tid) {
+        let (max_time_slice, pops) = (self.max_time_slice, self.pops);
+        let info = self.info(tid);
+        assert!(!info.present);
+        info.present = true;
+        info.queued_at = pops;
+        if info.rest_slice == 0 {
+            info.rest_slice = max_time_slice;
+        }
+        let level = info.priority as usize;
+        self.queues[level].push_back(tid);
+        trace!("priority push {} at level {}", tid, level);
+    }
+
+    /// Whether `tid` may run on `cpu_id`.
+    fn runs_on(&self, tid: Tid, cpu_id: usize) -> bool {
+        self.infos[tid].cpu.is_none_or(|cpu| cpu == cpu_id)
+    }
+
+    /// Position of the first thread in `level` that may run on `cpu_id`.
+    fn first_for(&self, level: usize, cpu_id: usize) -> Option<usize> {
+        self.queues[level]
+            .iter()
+            .position(|&tid| self.runs_on(tid, cpu_id))
+    }
+
+    /// Takes the next thread for `cpu_id`, skipping threads pinned to other
+    /// CPUs.
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        self.pops += 1;
+        let (level, pos) = self.aged_level(cpu_id).or_else(|| {
+            (0..PRIORITY_LEVELS)
+                .rev()
+                .find_map(|l| Some((l, self.first_for(l, cpu_id)?)))
+        })?;
+        let tid = self.queues[level].remove(pos)?;
+        self.infos[tid].present = false;
+        trace!("priority pop {} from level {}", tid, level);
+        Some(tid)
+    }
+
+    /// The lowest level whose first thread for `cpu_id` has waited at least
+    /// `aging` pops, with that thread's position, if any.
+    fn aged_level(&self, cpu_id: usize) -> Option<(usize, usize)> {
+        let aging = self.aging?;
+        (0..PRIORITY_LEVELS).find_map(|l| {
+            let pos = self.first_for(l, cpu_id)?;
+            let tid = self.queues[l][pos];
+            (self.pops - self.infos[tid].queued_at >= aging).then_some((l, pos))
+        })
+    }
+
+    /// Uses up a tick of `current`'s slice. Also asks for a reschedule as
+    /// soon as a more important thread is waiting.
+    fn tick(&mut self, current: Tid) -> bool {
+        let info = self.info(current);
+        assert!(!info.present);
+        info.rest_slice = info.rest_slice.saturating_sub(1);
+        let (expired, priority) = (info.rest_slice == 0, info.priority as usize);
+        expired
+            || self.queues[priority + 1..]
+                .iter()
+                .any(|queue| !queue.is_empty())
+    }
+
+    fn set_priority(&mut self, tid: Tid, priority: u8) {
+        let priority = priority.min(PRIORITY_LEVELS as u8 - 1);
+        let info = self.info(tid);
+        let (old, present) = (info.priority as usize, info.present);
+        info.priority = priority;
+        if present && old != priority as usize {
+            self.queues[old].retain(|&t| t != tid);
+            self.queues[priority as usize].push_back(tid);
+        }
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if tid < self.infos.len() && self.infos[tid].present {
+            let level = self.infos[tid].priority as usize;
+            self.queues[level].retain(|&t| t != tid);
+            self.infos[tid].present = false;
+        }
+    }
+}
+
+/// Queues a low-priority thread, then a high-priority one, and checks the
+/// high one is picked first; then checks aging lets a starved low-priority
+/// thread through, and that a pinned thread is only picked on its CPU.
+/// Runs on the scheduler alone, no threads are started.
+pub fn test_priority_scheduler() -> Result<(), &'static str> {
+    crate::serial_println!("=== Priority Scheduler Test ===");
+
+    let scheduler = PriorityScheduler::new(10, None);
+    let (low, high) = (1, 2);
+    scheduler.set_priority(low, 0);
+    scheduler.set_priority(high, PRIORITY_LEVELS as u8 - 1);
+    scheduler.push(low);
+    scheduler.push(high);
+    if scheduler.pop(0) != Some(high) || scheduler.pop(0) != Some(low) {
+        return Err("high-priority thread did not run first");
+    }
+    // `low` is running now; a waiting `high` should preempt it.
+    scheduler.push(high);
+    if !scheduler.tick(low) {
+        return Err("running low-priority thread was not preempted");
+    }
+    scheduler.remove(high);
+    crate::serial_println!("✓ Higher level picked before a queued lower one");
+
+    let scheduler = PriorityScheduler::new(10, Some(3));
+    scheduler.set_priority(low, 0);
+    scheduler.set_priority(high, PRIORITY_LEVELS as u8 - 1);
+    scheduler.push(low);
+    let mut low_ran = false;
+    for _ in 0..5 {
+        scheduler.push(high);
+        match scheduler.pop(0) {
+            Some(tid) if tid == low => {
+                low_ran = true;
+                scheduler.remove(high);
+                break;
+            }
+            Some(_) => {}
+            None => return Err("scheduler ran dry"),
+        }
+    }
+    if !low_ran {
+        return Err("aging did not let the low-priority thread run");
+    }
+    crate::serial_println!("✓ Aging lets a starved thread through");
+
+    let scheduler = PriorityScheduler::new(10, None);
+    let (pinned, free) = (1, 2);
+    scheduler.set_affinity(pinned, Some(1));
+    scheduler.push(pinned);
+    scheduler.push(free);
+    if scheduler.pop(0) != Some(free) || scheduler.pop(0).is_some() {
+        return Err("thread pinned to CPU 1 was picked on CPU 0");
+    }
+    if scheduler.pop(1) != Some(pinned) {
+        return Err("pinned thread not picked on its own CPU");
+    }
+    crate::serial_println!("✓ Pinned thread only picked on its CPU");
+    Ok(())
+}
+
+/// Spawns a thread at every level on the pool built at boot, moves the
+/// lowest one to the top level and joins them all, so spawning with a
+/// priority and `set_priority` work end to end on the real scheduler.
+pub fn test_priority_threads() -> Result<(), &'static str> {
+    use crate::std_thread;
+
+    crate::serial_println!("=== Priority Thread Test ===");
+
+    let handles: Vec<_> = (0..PRIORITY_LEVELS as u8)
+        .map(|level| std_thread::spawn_with_priority(level, move || level))
+        .collect();
+    std_thread::set_priority(handles[0].thread().id(), PRIORITY_LEVELS as u8 - 1);
+    for (level, handle) in handles.into_iter().enumerate() {
+        if handle.join() != Ok(level as u8) {
+            return Err("thread at a priority level did not run to completion");
+        }
+    }
+
+    crate::serial_println!("✓ Threads at every level ran on the boot pool");
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use crate::interrupt::no_interrupt;
 use crate::thread_pool::*;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
@@ -48,26 +49,64 @@ impl Processor {
             .expect("tid(): no thread is running on this CPU")
     }
 
+    /// Index of the CPU this processor runs on.
+    pub fn id(&self) -> usize {
+        self.inner().id
+    }
+
+    /// The running thread's id, or `None` outside any thread.
+    pub fn try_tid(&self) -> Option<Tid> {
+        let inner = unsafe { &*self.inner.get() }.as_ref()?;
+        inner.thread.as_ref().map(|(tid, _)| *tid)
+    }
+
     pub fn manager(&self) -> &Arc<ThreadPool> {
         &self.inner().manager
     }
 
+    /// Switches from the running thread back to this CPU's loop context.
+    /// The thread stays in `inner.thread` so `run_next` can hand it back to
+    /// the pool once the switch returns there.
     pub fn yield_now(&self) {
         let inner = self.inner();
-        if let Some((tid, mut ctx)) = inner.thread.take() {
-            let loop_ctx = &mut inner.loop_context;
-            unsafe { ctx.switch_to(&mut **loop_ctx) };
-            inner.thread = Some((tid, ctx));
-        } else {
-            panic!("yield_now() called with no running thread");
+        match inner.thread.as_mut() {
+            Some((_, ctx)) => unsafe { ctx.switch_to(&mut *inner.loop_context) },
+            None => panic!("yield_now() called with no running thread"),
         }
     }
-    pub fn run_next(&self, cpu_id: usize) {
-        let inner = self.inner();
-        if let Some((tid, next_ctx)) = inner.manager.run(cpu_id) {
+
+    /// Runs the next ready thread until it yields, then returns it to the
+    /// pool. Returns `false` if there was nothing to run.
+    ///
+    /// Interrupts stay off throughout, so the timer tick can't find the
+    /// pool's locks held on this CPU.
+    pub fn run_next(&self, cpu_id: usize) -> bool {
+        no_interrupt(|| {
+            let inner = self.inner();
+            let Some((tid, next_ctx)) = inner.manager.run(cpu_id) else {
+                return false;
+            };
+
             inner.thread = Some((tid, next_ctx));
             let (_, ctx_ref) = inner.thread.as_mut().unwrap();
             unsafe { inner.loop_context.switch_to(&mut **ctx_ref) };
+
+            let (tid, ctx) = inner.thread.take().expect("thread vanished while running");
+            inner.manager.stop(tid, ctx);
+            true
+        })
+    }
+
+    /// Called from the timer interrupt. Advances the pool's timer and, once
+    /// the running thread has used up its quantum, switches back to the loop
+    /// context so the scheduler can pick another one.
+    pub fn tick(&self) {
+        let Some(inner) = (unsafe { &mut *self.inner.get() }).as_mut() else {
+            return;
+        };
+        let tid = inner.thread.as_ref().map(|(tid, _)| *tid);
+        if inner.manager.tick(tid) && tid.is_some() {
+            self.yield_now();
         }
     }
 
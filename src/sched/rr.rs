@@ -1,6 +1,7 @@
 #[allow(dead_code)]
 use log::{trace, warn};
 
+use alloc::vec;
 use alloc::vec::Vec;
 
 use spin::Mutex;
@@ -17,6 +18,8 @@ pub trait Scheduler: 'static {
     fn tick(&self, current_tid: Tid) -> bool;
     /// Set priority of a thread.
     fn set_priority(&self, tid: Tid, priority: u8);
+    /// Pin a thread to one CPU, or let it run on any with `None`.
+    fn set_affinity(&self, tid: Tid, cpu_id: Option<usize>);
     /// remove a thread in ready queue.
     fn remove(&self, tid: Tid);
 }
@@ -38,6 +41,8 @@ struct RRSchedulerInner {
 struct RRProcInfo {
     present: bool,
     rest_slice: usize,
+    /// The only CPU the thread may run on, if it is pinned.
+    cpu: Option<usize>,
     prev: Tid,
     next: Tid,
 }
@@ -46,13 +51,16 @@ impl Scheduler for RRScheduler {
     fn push(&self, tid: usize) {
         self.inner.lock().push(tid);
     }
-    fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        self.inner.lock().pop(cpu_id)
     }
     fn tick(&self, current_tid: usize) -> bool {
         self.inner.lock().tick(current_tid)
     }
     fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn set_affinity(&self, tid: usize, cpu_id: Option<usize>) {
+        self.inner.lock().set_affinity(tid, cpu_id)
+    }
     fn remove(&self, tid: usize) {
         self.inner.lock().remove(tid)
     }
@@ -60,9 +68,11 @@ impl Scheduler for RRScheduler {
 
 impl RRScheduler {
     pub fn new(max_time_slice: usize) -> Self {
+        // Slot 0 is the sentinel head of the ready list; thread `tid` lives
+        // at `tid + 1`.
         let inner = RRSchedulerInner {
             max_time_slice,
-            infos: Vec::default(),
+            infos: vec![RRProcInfo::default()],
         };
         RRScheduler {
             inner: Mutex::new(inner),
@@ -86,8 +96,13 @@ impl RRSchedulerInner {
         trace!("rr push {}", tid - 1);
     }
 
-    fn pop(&mut self) -> Option<Tid> {
-        let ret = match self.infos[0].next {
+    /// Takes the first thread in the ready list that may run on `cpu_id`.
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        let mut tid = self.infos[0].next;
+        while tid != 0 && self.infos[tid].cpu.is_some_and(|cpu| cpu != cpu_id) {
+            tid = self.infos[tid].next;
+        }
+        let ret = match tid {
             0 => None,
             tid => {
                 self.infos[tid].present = false;
@@ -99,6 +114,12 @@ impl RRSchedulerInner {
         ret
     }
 
+    fn set_affinity(&mut self, tid: Tid, cpu_id: Option<usize>) {
+        let tid = tid + 1;
+        expand(&mut self.infos, tid);
+        self.infos[tid].cpu = cpu_id;
+    }
+
     fn tick(&mut self, current: Tid) -> bool {
         let current = current + 1;
         expand(&mut self.infos, current);
@@ -114,8 +135,11 @@ impl RRSchedulerInner {
     }
 
     fn remove(&mut self, tid: Tid) {
-        self._list_remove(tid + 1);
-        self.infos[tid + 1].present = false;
+        let tid = tid + 1;
+        if tid < self.infos.len() && self.infos[tid].present {
+            self._list_remove(tid);
+            self.infos[tid].present = false;
+        }
     }
 }
 
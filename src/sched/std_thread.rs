@@ -6,16 +6,37 @@ use core::marker::PhantomData;
 use core::time::Duration;
 use log::*;
 
-#[unsafe(no_mangle)]
+const KERNEL_STACK_SIZE: usize = 16 * 1024;
+/// Set in the pool's exit code when a thread called `exit` instead of
+/// returning. Return values are heap pointers, which on x86_64 live in the
+/// lower half and never have this bit set.
+const EXPLICIT_EXIT: usize = 1 << 63;
+
+/// This CPU's `Processor`, or `None` before `smp::init_bsp` has run.
+pub(crate) fn try_processor() -> Option<&'static Processor> {
+    let procs = unsafe { crate::smp::PROCESSORS_PTR };
+    if procs.is_null() {
+        return None;
+    }
+    Some(unsafe { &*procs.add(crate::smp::current_cpu_id()) })
+}
+
 fn processor() -> &'static Processor {
-    #[cfg(not(target_os = "uefi"))]
-    unimplemented!("thread: Please implement and export `processor`")
+    try_processor().expect("thread: processors are not initialized")
 }
 
-#[unsafe(no_mangle)]
-fn new_kernel_context(_entry: extern "C" fn(usize) -> !, _arg: usize) -> Box<dyn Context> {
-    #[cfg(not(target_os = "uefi"))]
-    unimplemented!("thread: Please implement and export `new_kernel_context`")
+/// Runs `f` on the thread pool with interrupts off, so the timer tick can't
+/// preempt us while one of the pool's locks is held.
+fn with_manager<R>(f: impl FnOnce(&ThreadPool) -> R) -> R {
+    no_interrupt(|| f(processor().manager()))
+}
+
+fn new_kernel_context(entry: extern "C" fn(usize) -> !, arg: usize) -> Box<dyn Context> {
+    Box::new(crate::context::ContextImpl::new_with_entry_arg(
+        KERNEL_STACK_SIZE,
+        entry,
+        arg,
+    ))
 }
 
 pub fn current() -> Thread {
@@ -24,15 +45,36 @@ pub fn current() -> Thread {
     }
 }
 
+/// The running thread, or `None` when called outside one (for instance
+/// from the boot code before the scheduler takes over).
+pub fn try_current() -> Option<Thread> {
+    try_processor()?.try_tid().map(|tid| Thread { tid })
+}
+
+/// Ends the calling thread. Its joiner sees `Err(code)`; `code` must fit
+/// in 63 bits.
+pub fn exit(code: usize) -> ! {
+    trace!("exit: {}", code);
+    with_manager(|m| m.exit(current().id(), code | EXPLICIT_EXIT));
+    yield_now();
+    unreachable!("exited thread was scheduled again")
+}
+
+/// Parks the calling thread for at least `dur`, rounded up to whole timer
+/// ticks.
 pub fn sleep(dur: Duration) {
-    let time = dur_to_ticks(dur);
+    let time = crate::timer::duration_to_ticks(dur);
     trace!("sleep: {:?} ticks", time);
-    processor().manager().sleep(current().id(), time);
-    park();
-
-    fn dur_to_ticks(dur: Duration) -> usize {
-        return dur.as_secs() as usize * 100 + dur.subsec_nanos() as usize / 10_000_000;
+    if time == 0 {
+        yield_now();
+        return;
     }
+    // With interrupts off the wake-up can't land between marking ourselves
+    // asleep and switching away.
+    no_interrupt(|| {
+        with_manager(|m| m.sleep(current().id(), time));
+        yield_now();
+    });
 }
 
 pub fn spawn<F, T>(f: F) -> JoinHandle<T>
@@ -40,7 +82,33 @@ where
     F: Send + 'static + FnOnce() -> T,
     T: Send + 'static,
 {
-    trace!("spawn:");
+    spawn_with_priority(crate::priority::DEFAULT_PRIORITY, f)
+}
+
+/// Like `spawn`, but the thread is queued at `priority` from the start.
+pub fn spawn_with_priority<F, T>(priority: u8, f: F) -> JoinHandle<T>
+where
+    F: Send + 'static + FnOnce() -> T,
+    T: Send + 'static,
+{
+    spawn_inner(priority, None, f)
+}
+
+/// Like `spawn`, but the thread only ever runs on CPU `cpu_id`.
+pub fn spawn_on<F, T>(cpu_id: usize, f: F) -> JoinHandle<T>
+where
+    F: Send + 'static + FnOnce() -> T,
+    T: Send + 'static,
+{
+    spawn_inner(crate::priority::DEFAULT_PRIORITY, Some(cpu_id), f)
+}
+
+fn spawn_inner<F, T>(priority: u8, cpu_id: Option<usize>, f: F) -> JoinHandle<T>
+where
+    F: Send + 'static + FnOnce() -> T,
+    T: Send + 'static,
+{
+    trace!("spawn: priority {}, cpu {:?}", priority, cpu_id);
 
     let f = Box::into_raw(Box::new(f));
 
@@ -52,13 +120,13 @@ where
         let f = unsafe { Box::from_raw(f as *mut F) };
         let ret = Box::new(f());
         let exit_code = Box::into_raw(ret) as usize;
-        processor().manager().exit(current().id(), exit_code);
+        with_manager(|m| m.exit(current().id(), exit_code));
         yield_now();
         unreachable!()
     }
 
     let context = new_kernel_context(kernel_thread_entry::<F, T>, f as usize);
-    let tid = processor().manager().add(context);
+    let tid = with_manager(|m| m.add_on(context, priority, cpu_id));
 
     return JoinHandle {
         thread: Thread { tid },
@@ -66,6 +134,12 @@ where
     };
 }
 
+/// Moves thread `tid` to priority `level`. Levels above
+/// `PRIORITY_LEVELS - 1` are clamped.
+pub fn set_priority(tid: usize, level: u8) {
+    with_manager(|m| m.set_priority(tid, level));
+}
+
 pub fn yield_now() {
     trace!("yield:");
     no_interrupt(|| {
@@ -75,13 +149,13 @@ pub fn yield_now() {
 
 pub fn park() {
     trace!("park:");
-    processor().manager().sleep(current().id(), 0);
+    with_manager(|m| m.sleep(current().id(), 0));
     yield_now();
 }
 
 pub fn park_action(f: impl FnOnce()) {
     trace!("park:");
-    processor().manager().sleep(current().id(), 0);
+    with_manager(|m| m.sleep(current().id(), 0));
     f();
     yield_now();
 }
@@ -91,8 +165,13 @@ pub struct Thread {
 }
 
 impl Thread {
+    /// Wakes the thread. If it is still on its way into `park`, the park
+    /// is cancelled instead, so an early unpark isn't lost.
     pub fn unpark(&self) {
-        processor().manager().wakeup(self.tid);
+        with_manager(|m| {
+            m.cancel_sleeping(self.tid);
+            m.wakeup(self.tid);
+        });
     }
     pub fn id(&self) -> usize {
         self.tid
@@ -108,21 +187,66 @@ impl<T> JoinHandle<T> {
     pub fn thread(&self) -> &Thread {
         &self.thread
     }
-    pub fn join(self) -> Result<T, ()> {
+    /// Waits for the thread to finish. Returns what its closure returned,
+    /// or `Err(code)` if it ended itself with `exit(code)`. Works from boot
+    /// code outside any thread too, once `smp::init_bsp` has run.
+    pub fn join(self) -> Result<T, usize> {
         loop {
             trace!("try to join thread {}", self.thread.tid);
-            if let Some(exit_code) = processor().manager().try_remove(self.thread.tid) {
+            if let Some(exit_code) = with_manager(|m| m.try_remove(self.thread.tid)) {
                 core::mem::forget(self);
+                if exit_code & EXPLICIT_EXIT != 0 {
+                    return Err(exit_code & !EXPLICIT_EXIT);
+                }
                 return Ok(unsafe { *Box::from_raw(exit_code as *mut T) });
             }
-            processor().manager().wait(current().id(), self.thread.tid);
-            yield_now();
+            match try_current() {
+                Some(me) => {
+                    with_manager(|m| m.wait(me.id(), self.thread.tid));
+                    yield_now();
+                }
+                // Boot code isn't a thread and can't wait, so it runs
+                // threads on this CPU itself until the one it wants is done.
+                None => {
+                    if !processor().run_next(crate::smp::current_cpu_id()) {
+                        x86_64::instructions::hlt();
+                    }
+                }
+            }
         }
     }
 }
 
 impl<T> Drop for JoinHandle<T> {
     fn drop(&mut self) {
-        processor().manager().detach(self.thread.tid);
+        with_manager(|m| m.detach(self.thread.tid));
     }
 }
+
+/// Spawns threads from the caller, which may be boot code rather than a
+/// thread, and checks `join` hands back each one's result, `exit` codes
+/// included, and that a thread can join another.
+pub fn test_spawn_join() -> Result<(), &'static str> {
+    use alloc::vec::Vec;
+
+    crate::serial_println!("=== Thread Spawn/Join Test ===");
+
+    let handles: Vec<_> = (0..3u64).map(|i| spawn(move || i * i)).collect();
+    for (i, handle) in handles.into_iter().enumerate() {
+        if handle.join() != Ok((i * i) as u64) {
+            return Err("joined thread returned the wrong value");
+        }
+    }
+
+    if spawn::<_, ()>(|| exit(5)).join() != Err(5) {
+        return Err("exit code was not passed to join");
+    }
+
+    let outer = spawn(|| spawn(|| 42u32).join());
+    if outer.join() != Ok(Ok(42)) {
+        return Err("thread could not join another thread");
+    }
+
+    crate::serial_println!("✓ Threads spawned and joined");
+    Ok(())
+}
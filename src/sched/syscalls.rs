@@ -0,0 +1,31 @@
+use crate::syscall::errno::Errno;
+use crate::{std_thread, usermode};
+
+/// Returns the calling thread's id, or `ESRCH` outside a thread.
+pub fn sys_getpid(_a0: u64, _a1: u64, _a2: u64) -> u64 {
+    match std_thread::try_current() {
+        Some(thread) => thread.id() as u64,
+        None => Errno::Srch.as_u64(),
+    }
+}
+
+/// Ends the running user program with `code`, which `run_user` returns,
+/// or else the calling thread, whose joiner receives `Err(code)`. Only
+/// returns, with `ESRCH`, when called outside both.
+pub fn sys_exit(code: u64, _a1: u64, _a2: u64) -> u64 {
+    usermode::exit_user_mode(code);
+    if std_thread::try_current().is_none() {
+        return Errno::Srch.as_u64();
+    }
+    std_thread::exit(code as usize)
+}
+
+/// Gives up the rest of the calling thread's time slice. Returns 0, or
+/// `ESRCH` outside a thread.
+pub fn sys_yield(_a0: u64, _a1: u64, _a2: u64) -> u64 {
+    if std_thread::try_current().is_none() {
+        return Errno::Srch.as_u64();
+    }
+    std_thread::yield_now();
+    0
+}
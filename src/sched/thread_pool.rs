@@ -1,6 +1,7 @@
 #[allow(dead_code)]
+use crate::priority::DEFAULT_PRIORITY;
 use crate::rr::Scheduler;
-use crate::timer::Timer;
+use crate::timer::{self, Sleeper};
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use log::*;
@@ -31,11 +32,6 @@ pub enum Status {
     Exited(ExitCode),
 }
 
-#[derive(Eq, PartialEq)]
-enum Event {
-    Wakeup(Tid),
-}
-
 pub trait Context {
     unsafe fn switch_to(&mut self, target: &mut dyn Context);
 
@@ -45,7 +41,6 @@ pub trait Context {
 pub struct ThreadPool {
     threads: Vec<Mutex<Option<Thread>>>,
     scheduler: Box<dyn Scheduler>,
-    timer: Mutex<Timer<Event>>,
 }
 
 impl ThreadPool {
@@ -53,7 +48,6 @@ impl ThreadPool {
         ThreadPool {
             threads: new_vec_default(max_proc_num),
             scheduler: Box::new(scheduler),
-            timer: Mutex::new(Timer::new()),
         }
     }
 
@@ -67,8 +61,28 @@ impl ThreadPool {
         panic!("Thread number exceeded");
     }
 
-    pub fn add(&self, mut context: Box<dyn Context>) -> Tid {
+    pub fn add(&self, context: Box<dyn Context>) -> Tid {
+        self.add_with_priority(context, DEFAULT_PRIORITY)
+    }
+
+    /// Adds a thread that is queued at `priority` from the start.
+    pub fn add_with_priority(&self, context: Box<dyn Context>, priority: u8) -> Tid {
+        self.add_on(context, priority, None)
+    }
+
+    /// Like `add_with_priority`, but with `cpu_id` set the thread only ever
+    /// runs on that CPU.
+    pub fn add_on(
+        &self,
+        mut context: Box<dyn Context>,
+        priority: u8,
+        cpu_id: Option<usize>,
+    ) -> Tid {
         let (tid, mut thread) = self.alloc_tid();
+        // The slot may have belonged to an earlier thread with another
+        // priority or affinity.
+        self.scheduler.set_priority(tid, priority);
+        self.scheduler.set_affinity(tid, cpu_id);
         context.set_tid(tid);
         *thread = Some(Thread {
             status: Status::Ready,
@@ -81,16 +95,9 @@ impl ThreadPool {
         tid
     }
 
-    pub(crate) fn tick(&self, cpu_id: usize, tid: Option<Tid>) -> bool {
-        if cpu_id == 0 {
-            let mut timer = self.timer.lock();
-            timer.tick();
-            while let Some(event) = timer.pop() {
-                match event {
-                    Event::Wakeup(tid) => self.set_status(tid, Status::Ready),
-                }
-            }
-        }
+    /// Charges a tick to the running thread. Returns `true` once it should
+    /// be preempted.
+    pub(crate) fn tick(&self, tid: Option<Tid>) -> bool {
         match tid {
             Some(tid) => self.scheduler.tick(tid),
             None => false,
@@ -138,7 +145,7 @@ impl ThreadPool {
                 (Status::Ready, Status::Ready) => return,
                 (Status::Ready, _) => self.scheduler.remove(tid),
                 (Status::Exited(_), _) => panic!("can not set status for a exited thread"),
-                (Status::Sleeping, Status::Exited(_)) => self.timer.lock().stop(Event::Wakeup(tid)),
+                (Status::Sleeping, Status::Exited(_)) => timer::cancel(Sleeper::Thread(tid)),
                 (Status::Running(_), Status::Ready) => {}
                 (_, Status::Ready) => self.scheduler.push(tid),
                 _ => {}
@@ -176,7 +183,7 @@ impl ThreadPool {
     pub fn sleep(&self, tid: Tid, time: usize) {
         self.set_status(tid, Status::Sleeping);
         if time != 0 {
-            self.timer.lock().start(time, Event::Wakeup(tid));
+            timer::wake_after(time, Sleeper::Thread(tid));
         }
     }
 
@@ -0,0 +1,112 @@
+use crate::sync::mutex::{ThreadMutex, ThreadMutexGuard, WaitQueue};
+use crate::{serial_println, std_thread};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+
+/// Condition variable for `ThreadMutex`. Waiters are parked until notified;
+/// as usual, check the condition again after `wait` returns.
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Releases `guard`'s mutex, parks until notified, then takes the mutex
+    /// again. Must be called from a scheduler thread.
+    pub fn wait<'a, T: ?Sized>(&self, guard: ThreadMutexGuard<'a, T>) -> ThreadMutexGuard<'a, T> {
+        let mutex = guard.mutex();
+        // The guard is released only after we're queued, so a notify made
+        // under the mutex always finds us.
+        self.waiters.park(move || mem::drop(guard));
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Two threads bump a shared counter under a `ThreadMutex`, then a producer
+/// hands values to a consumer one at a time through a `Condvar`. All the
+/// waiting happens on spawned threads, so boot code can run it too.
+pub fn test_thread_sync() -> Result<(), &'static str> {
+    const INCREMENTS: usize = 1000;
+    const ITEMS: u32 = 10;
+
+    serial_println!("=== ThreadMutex/Condvar Test ===");
+
+    let counter = Arc::new(ThreadMutex::new(0usize));
+    let workers: Vec<_> = (0..2)
+        .map(|_| {
+            let counter = counter.clone();
+            std_thread::spawn(move || {
+                for _ in 0..INCREMENTS {
+                    *counter.lock() += 1;
+                    std_thread::yield_now();
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().map_err(|_| "counter thread exited early")?;
+    }
+    if *counter.lock() != 2 * INCREMENTS {
+        return Err("counter lost increments");
+    }
+    serial_println!("✓ Counter reached {}", 2 * INCREMENTS);
+
+    // `None` means the slot is free for the producer to fill.
+    let slot = Arc::new((ThreadMutex::new(None::<u32>), Condvar::new()));
+    let consumer = {
+        let slot = slot.clone();
+        std_thread::spawn(move || {
+            let (value, changed) = &*slot;
+            let mut received = Vec::new();
+            while received.len() < ITEMS as usize {
+                let mut guard = value.lock();
+                while guard.is_none() {
+                    guard = changed.wait(guard);
+                }
+                received.push(guard.take().unwrap());
+                changed.notify_all();
+            }
+            received
+        })
+    };
+
+    let producer = std_thread::spawn(move || {
+        let (value, changed) = &*slot;
+        for item in 0..ITEMS {
+            let mut guard = value.lock();
+            while guard.is_some() {
+                guard = changed.wait(guard);
+            }
+            *guard = Some(item);
+            changed.notify_all();
+        }
+    });
+
+    producer.join().map_err(|_| "producer exited early")?;
+    let received = consumer.join().map_err(|_| "consumer exited early")?;
+    if !received.iter().copied().eq(0..ITEMS) {
+        return Err("consumer received values out of order");
+    }
+    serial_println!("✓ Consumer received all {} values in order", ITEMS);
+    Ok(())
+}
@@ -1,8 +1,13 @@
+use core::marker::PhantomData;
 use x86_64::{disable_and_store, restore};
 mod x86_64 {
 
     use core::arch::asm;
 
+    /// RFLAGS.IF.
+    const INTERRUPT_FLAG: usize = 1 << 9;
+
+    /// Turns interrupts off and returns RFLAGS as they were before.
     pub fn disable_and_store() -> usize {
         let rflags: usize;
         unsafe {
@@ -14,21 +19,83 @@ mod x86_64 {
                 options(nomem, preserves_flags),
             );
         }
-        rflags & (1 << 9)
+        rflags
     }
 
+    /// Turns interrupts back on only if `flags`, from `disable_and_store`,
+    /// says they were on. A nested caller that found them off leaves them
+    /// off for whoever turned them off.
     pub fn restore(flags: usize) {
-        unsafe {
-            if flags != 0 {
-                asm!("sti", options(nomem, preserves_flags));
-            }
+        if flags & INTERRUPT_FLAG != 0 {
+            unsafe { asm!("sti", options(nomem, preserves_flags)) };
+        }
+    }
+}
+
+/// Keeps interrupts off on this CPU while it is alive. Dropping it puts
+/// them back the way `new` found them, so an inner guard leaves them off
+/// for the outer one. Not `Send`: it has to be dropped on the CPU that
+/// made it.
+#[must_use = "interrupts come back on as soon as the guard is dropped"]
+pub struct IrqGuard {
+    flags: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl IrqGuard {
+    pub fn new() -> Self {
+        IrqGuard {
+            flags: disable_and_store(),
+            _not_send: PhantomData,
         }
     }
 }
 
+impl Default for IrqGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for IrqGuard {
+    fn drop(&mut self) {
+        restore(self.flags);
+    }
+}
+
 pub fn no_interrupt<T>(f: impl FnOnce() -> T) -> T {
-    let flags = disable_and_store();
-    let ret = f();
-    restore(flags);
-    ret
+    let _guard = IrqGuard::new();
+    f()
+}
+
+/// Nests `no_interrupt` and `IrqGuard` and checks that interrupts stay off
+/// until the outermost one ends, then come back as they were.
+pub fn test_nested_no_interrupt() -> Result<(), &'static str> {
+    use ::x86_64::instructions::interrupts::are_enabled;
+
+    let before = are_enabled();
+    let (inner, after_inner) = no_interrupt(|| {
+        let inner = no_interrupt(are_enabled);
+        (inner, are_enabled())
+    });
+    if inner {
+        return Err("interrupts on inside the inner no_interrupt");
+    }
+    if after_inner {
+        return Err("inner no_interrupt turned interrupts back on");
+    }
+
+    {
+        let _outer = IrqGuard::new();
+        drop(IrqGuard::new());
+        if are_enabled() {
+            return Err("inner IrqGuard turned interrupts back on");
+        }
+    }
+    if are_enabled() != before {
+        return Err("interrupt state not restored after nesting");
+    }
+
+    crate::serial_println!("✓ Nested no_interrupt keeps interrupts off");
+    Ok(())
 }
@@ -1,3 +1,7 @@
+pub mod condvar;
 pub mod interrupt;
+pub mod mutex;
 
+pub use condvar::*;
 pub use interrupt::*;
+pub use mutex::*;
@@ -0,0 +1,136 @@
+use crate::interrupt::no_interrupt;
+use crate::std_thread::{self, Thread};
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Threads blocked on a `ThreadMutex` or `Condvar`.
+pub(crate) struct WaitQueue {
+    threads: spin::Mutex<VecDeque<Thread>>,
+}
+
+impl WaitQueue {
+    pub(crate) const fn new() -> Self {
+        WaitQueue {
+            threads: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Parks the calling thread on the queue. `after_enqueue` runs once the
+    /// thread is queued and marked asleep, so a wake-up it triggers can't be
+    /// lost. Interrupts stay off until the switch, so the timer can't
+    /// preempt us halfway.
+    pub(crate) fn park(&self, after_enqueue: impl FnOnce()) {
+        no_interrupt(|| {
+            std_thread::park_action(|| {
+                self.threads.lock().push_back(std_thread::current());
+                after_enqueue();
+            })
+        });
+    }
+
+    pub(crate) fn wake_one(&self) -> bool {
+        let thread = self.threads.lock().pop_front();
+        match thread {
+            Some(thread) => {
+                thread.unpark();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+}
+
+/// A mutex that parks contending threads instead of spinning. The
+/// uncontended lock and unlock are a single atomic each. Outside a
+/// scheduler thread there is nothing to park, so it falls back to spinning.
+pub struct ThreadMutex<T: ?Sized> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for ThreadMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for ThreadMutex<T> {}
+
+pub struct ThreadMutexGuard<'a, T: ?Sized> {
+    mutex: &'a ThreadMutex<T>,
+}
+
+impl<T> ThreadMutex<T> {
+    pub const fn new(data: T) -> Self {
+        ThreadMutex {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> ThreadMutex<T> {
+    pub fn try_lock(&self) -> Option<ThreadMutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| ThreadMutexGuard { mutex: self })
+    }
+
+    pub fn lock(&self) -> ThreadMutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            if std_thread::try_current().is_none() {
+                core::hint::spin_loop();
+                continue;
+            }
+            self.waiters.park(|| {
+                // The holder may have unlocked before we were queued, in
+                // which case nobody is left to wake us.
+                if !self.locked.load(Ordering::SeqCst) {
+                    self.waiters.wake_one();
+                }
+            });
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+}
+
+impl<T: ?Sized> Deref for ThreadMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for ThreadMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for ThreadMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+impl<'a, T: ?Sized> ThreadMutexGuard<'a, T> {
+    pub(crate) fn mutex(&self) -> &'a ThreadMutex<T> {
+        self.mutex
+    }
+}
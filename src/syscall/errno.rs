@@ -0,0 +1,174 @@
+use crate::ata::AtaError;
+use crate::fs::vfs::FsError;
+use core::fmt;
+
+/// Largest error number; results above `-MAX_ERRNO` are errors.
+const MAX_ERRNO: u64 = 4095;
+
+/// Why a syscall failed. A failing syscall returns the negated number as a
+/// `u64`, so results from -4095 to -1 are errors and anything else is a
+/// value, as on Linux. The numbers are Linux's, so they stay stable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum Errno {
+    Perm = 1,
+    NoEnt = 2,
+    Srch = 3,
+    Io = 5,
+    NxIo = 6,
+    BadF = 9,
+    NoMem = 12,
+    Busy = 16,
+    Exist = 17,
+    NoDev = 19,
+    NotDir = 20,
+    IsDir = 21,
+    Inval = 22,
+    MFile = 24,
+    NoSpc = 28,
+    Range = 34,
+    NoSys = 38,
+    NotEmpty = 39,
+    OpNotSupp = 95,
+    TimedOut = 110,
+}
+
+const ALL: [Errno; 20] = [
+    Errno::Perm,
+    Errno::NoEnt,
+    Errno::Srch,
+    Errno::Io,
+    Errno::NxIo,
+    Errno::BadF,
+    Errno::NoMem,
+    Errno::Busy,
+    Errno::Exist,
+    Errno::NoDev,
+    Errno::NotDir,
+    Errno::IsDir,
+    Errno::Inval,
+    Errno::MFile,
+    Errno::NoSpc,
+    Errno::Range,
+    Errno::NoSys,
+    Errno::NotEmpty,
+    Errno::OpNotSupp,
+    Errno::TimedOut,
+];
+
+impl Errno {
+    /// What a syscall returns for this error.
+    pub const fn as_u64(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+
+    /// The error a syscall result stands for, or `None` for success.
+    pub fn from_return(ret: u64) -> Option<Errno> {
+        if !is_error(ret) {
+            return None;
+        }
+        let number = ret.wrapping_neg();
+        ALL.iter().copied().find(|&errno| errno as u64 == number)
+    }
+
+    /// The C name, e.g. `ENOENT`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Errno::Perm => "EPERM",
+            Errno::NoEnt => "ENOENT",
+            Errno::Srch => "ESRCH",
+            Errno::Io => "EIO",
+            Errno::NxIo => "ENXIO",
+            Errno::BadF => "EBADF",
+            Errno::NoMem => "ENOMEM",
+            Errno::Busy => "EBUSY",
+            Errno::Exist => "EEXIST",
+            Errno::NoDev => "ENODEV",
+            Errno::NotDir => "ENOTDIR",
+            Errno::IsDir => "EISDIR",
+            Errno::Inval => "EINVAL",
+            Errno::MFile => "EMFILE",
+            Errno::NoSpc => "ENOSPC",
+            Errno::Range => "ERANGE",
+            Errno::NoSys => "ENOSYS",
+            Errno::NotEmpty => "ENOTEMPTY",
+            Errno::OpNotSupp => "EOPNOTSUPP",
+            Errno::TimedOut => "ETIMEDOUT",
+        }
+    }
+}
+
+/// Whether a syscall result is an error number rather than a value.
+pub fn is_error(ret: u64) -> bool {
+    ret > MAX_ERRNO.wrapping_neg()
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            Errno::Perm => "Operation not permitted",
+            Errno::NoEnt => "No such file or directory",
+            Errno::Srch => "No such process",
+            Errno::Io => "Input/output error",
+            Errno::NxIo => "No such device or address",
+            Errno::BadF => "Bad file descriptor",
+            Errno::NoMem => "Cannot allocate memory",
+            Errno::Busy => "Device or resource busy",
+            Errno::Exist => "File exists",
+            Errno::NoDev => "No such device",
+            Errno::NotDir => "Not a directory",
+            Errno::IsDir => "Is a directory",
+            Errno::Inval => "Invalid argument",
+            Errno::MFile => "Too many open files",
+            Errno::NoSpc => "No space left on device",
+            Errno::Range => "Result too large",
+            Errno::NoSys => "Function not implemented",
+            Errno::NotEmpty => "Directory not empty",
+            Errno::OpNotSupp => "Operation not supported",
+            Errno::TimedOut => "Connection timed out",
+        };
+        write!(f, "{} ({})", message, self.name())
+    }
+}
+
+/// Timeouts, busy or missing devices and bad requests keep their meaning;
+/// everything the drive itself reports as failed is `EIO`.
+impl From<AtaError> for Errno {
+    fn from(e: AtaError) -> Self {
+        match e {
+            AtaError::Timeout => Errno::TimedOut,
+            AtaError::NotReady => Errno::Busy,
+            AtaError::DeviceNotFound => Errno::NoDev,
+            AtaError::BufferTooSmall => Errno::Range,
+            AtaError::InvalidSectorSize => Errno::Inval,
+            AtaError::UnsupportedOperation => Errno::OpNotSupp,
+            AtaError::InvalidLba => Errno::NxIo,
+            AtaError::Error(_) | AtaError::CommandFailed | AtaError::DeviceFault => Errno::Io,
+        }
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::NotFound | FsError::NotMounted => Errno::NoEnt,
+            FsError::AlreadyExists => Errno::Exist,
+            FsError::IsADirectory => Errno::IsDir,
+            FsError::NotADirectory => Errno::NotDir,
+            FsError::NoSpace => Errno::NoSpc,
+            FsError::InvalidPath => Errno::Inval,
+            FsError::Unsupported => Errno::OpNotSupp,
+            FsError::NoDevice => Errno::NoDev,
+            FsError::NotEmpty => Errno::NotEmpty,
+            FsError::TimedOut => Errno::TimedOut,
+            FsError::Io(_) => Errno::Io,
+        }
+    }
+}
+
+/// For the FAT wrappers' string errors, which go through `FsError`.
+impl From<&'static str> for Errno {
+    fn from(msg: &'static str) -> Self {
+        FsError::from(msg).into()
+    }
+}
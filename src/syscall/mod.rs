@@ -1,7 +1,13 @@
+pub mod errno;
+
 use crate::fs::syscalls::{
-    sys_close, sys_listdir, sys_mkdir, sys_open, sys_read, sys_rmdir, sys_unlink, sys_write,
+    sys_close, sys_listdir, sys_lseek, sys_mkdir, sys_open, sys_read, sys_rename, sys_rmdir,
+    sys_stat, sys_unlink, sys_write,
 };
+use crate::memory::syscalls::{sys_mmap, sys_munmap};
+use crate::sched::syscalls::{sys_exit, sys_getpid, sys_yield};
 use crate::serial_println;
+use errno::{is_error, Errno};
 use spin::Mutex;
 
 pub const SYS_OPEN: u64 = 0;
@@ -12,6 +18,14 @@ pub const SYS_UNLINK: u64 = 4;
 pub const SYS_MKDIR: u64 = 5;
 pub const SYS_RMDIR: u64 = 6;
 pub const SYS_LISTDIR: u64 = 7;
+pub const SYS_LSEEK: u64 = 8;
+pub const SYS_STAT: u64 = 9;
+pub const SYS_RENAME: u64 = 10;
+pub const SYS_GETPID: u64 = 11;
+pub const SYS_EXIT: u64 = 12;
+pub const SYS_YIELD: u64 = 13;
+pub const SYS_MMAP: u64 = 14;
+pub const SYS_MUNMAP: u64 = 15;
 
 pub const SYSCALLS: &[fn(u64, u64, u64) -> u64] = &[
     sys_open,
@@ -22,8 +36,37 @@ pub const SYSCALLS: &[fn(u64, u64, u64) -> u64] = &[
     sys_mkdir,
     sys_rmdir,
     sys_listdir,
+    sys_lseek,
+    sys_stat,
+    sys_rename,
+    sys_getpid,
+    sys_exit,
+    sys_yield,
+    sys_mmap,
+    sys_munmap,
 ];
 
+/// Makes a system call through `int 0x80`, the same way user code would.
+///
+/// Calling convention: the syscall number goes in `rax` and up to three
+/// arguments in `rdi`, `rsi` and `rdx`. The result comes back in `rax`.
+/// Errors come back as a negated `Errno`, `ENOSYS` for unknown numbers;
+/// `errno::is_error` tells them from values. All other registers are
+/// preserved.
+pub fn syscall3(num: u64, a0: u64, a1: u64, a2: u64) -> u64 {
+    let ret: u64;
+    unsafe {
+        core::arch::asm!(
+            "int 0x80",
+            inlateout("rax") num => ret,
+            in("rdi") a0,
+            in("rsi") a1,
+            in("rdx") a2,
+        );
+    }
+    ret
+}
+
 pub fn syscall_identifier(num: u64, a0: u64, a1: u64, a2: u64) -> u64 {
     let idx = num as usize;
     if idx < SYSCALLS.len() {
@@ -31,7 +74,7 @@ pub fn syscall_identifier(num: u64, a0: u64, a1: u64, a2: u64) -> u64 {
         SYSCALLS[idx](a0, a1, a2)
     } else {
         serial_println!("syscall: unknown syscall number {}", num);
-        u64::MAX
+        Errno::NoSys.as_u64()
     }
 }
 
@@ -45,7 +88,7 @@ pub fn test_syscalls_filesystem_fixed() -> Result<(), &'static str> {
 
     let fd = syscall_identifier(SYS_OPEN, FILENAME.as_ptr() as u64, 1, 0);
 
-    if fd == u64::MAX {
+    if is_error(fd) {
         return Err("failed to open file");
     }
     serial_println!("Opened file with fd: {}", fd);
@@ -63,7 +106,7 @@ pub fn test_syscalls_filesystem_fixed() -> Result<(), &'static str> {
 
     let read_fd = syscall_identifier(SYS_OPEN, FILENAME.as_ptr() as u64, 0, 0);
 
-    if read_fd == u64::MAX {
+    if is_error(read_fd) {
         return Err("failed to open file for reading");
     }
     serial_println!("Opened file for reading with fd: {}", read_fd);
@@ -76,7 +119,7 @@ pub fn test_syscalls_filesystem_fixed() -> Result<(), &'static str> {
     );
     serial_println!("Read returned: {} bytes", read_ret);
 
-    if read_ret != u64::MAX && read_ret > 0 {
+    if !is_error(read_ret) && read_ret > 0 {
         let bytes_read = read_ret as usize;
         let read_data = &READ_BUFFER.lock()[..bytes_read];
 
@@ -107,6 +150,339 @@ pub fn test_syscalls_filesystem_fixed() -> Result<(), &'static str> {
     Ok(())
 }
 
+/// Writes two files through interleaved fds, then reads one back in two
+/// chunks to check that each fd keeps its own offset.
+pub fn test_syscalls_fd_table() -> Result<(), &'static str> {
+    use crate::fs::syscalls::EBADF;
+
+    serial_println!("=== FD Table Syscall Test ===");
+
+    static FIRST: &[u8] = b"FDA.TXT\0";
+    static SECOND: &[u8] = b"FDB.TXT\0";
+
+    let a = syscall_identifier(SYS_OPEN, FIRST.as_ptr() as u64, 1, 0);
+    let b = syscall_identifier(SYS_OPEN, SECOND.as_ptr() as u64, 1, 0);
+    if is_error(a) || is_error(b) || a == b {
+        return Err("open did not hand out two distinct fds");
+    }
+
+    for (fd, data) in [(a, b"aaa"), (b, b"bbb"), (a, b"AAA"), (b, b"BBB")] {
+        syscall_identifier(SYS_WRITE, fd, data.as_ptr() as u64, data.len() as u64);
+    }
+    syscall_identifier(SYS_CLOSE, a, 0, 0);
+    syscall_identifier(SYS_CLOSE, b, 0, 0);
+
+    if syscall_identifier(SYS_CLOSE, a, 0, 0) != EBADF {
+        return Err("closing a closed fd did not return EBADF");
+    }
+
+    let mut buf = [0u8; 3];
+    let fd = syscall_identifier(SYS_OPEN, FIRST.as_ptr() as u64, 0, 0);
+    let mut read_back = alloc::vec::Vec::new();
+    for _ in 0..2 {
+        let n = syscall_identifier(SYS_READ, fd, buf.as_mut_ptr() as u64, buf.len() as u64);
+        if is_error(n) {
+            return Err("read failed");
+        }
+        read_back.extend_from_slice(&buf[..n as usize]);
+    }
+    syscall_identifier(SYS_CLOSE, fd, 0, 0);
+
+    syscall_identifier(SYS_UNLINK, FIRST.as_ptr() as u64, 0, 0);
+    syscall_identifier(SYS_UNLINK, SECOND.as_ptr() as u64, 0, 0);
+
+    if read_back != b"aaaAAA" {
+        return Err("interleaved writes or chunked reads lost data");
+    }
+    serial_println!("✓ Interleaved fds kept separate offsets");
+    Ok(())
+}
+
+/// Seeks around a file, stats it, renames it and checks that the contents
+/// survive the move.
+pub fn test_syscalls_seek_stat_rename() -> Result<(), &'static str> {
+    use crate::fs::syscalls::{Stat, SEEK_CUR, SEEK_END, SEEK_SET};
+
+    serial_println!("=== Seek/Stat/Rename Syscall Test ===");
+
+    static OLD_NAME: &[u8] = b"OLDNAME.TXT\0";
+    static NEW_NAME: &[u8] = b"NEWNAME.TXT\0";
+    static CONTENT: &[u8] = b"0123456789";
+
+    let fd = syscall_identifier(SYS_OPEN, OLD_NAME.as_ptr() as u64, 1, 0);
+    syscall_identifier(SYS_WRITE, fd, CONTENT.as_ptr() as u64, CONTENT.len() as u64);
+    syscall_identifier(SYS_CLOSE, fd, 0, 0);
+
+    let fd = syscall_identifier(SYS_OPEN, OLD_NAME.as_ptr() as u64, 0, 0);
+    if syscall_identifier(SYS_LSEEK, fd, -3i64 as u64, SEEK_END) != 7 {
+        return Err("SEEK_END landed in the wrong place");
+    }
+    let mut buf = [0u8; 3];
+    syscall_identifier(SYS_READ, fd, buf.as_mut_ptr() as u64, 3);
+    if &buf != b"789" {
+        return Err("read after SEEK_END returned the wrong bytes");
+    }
+    syscall_identifier(SYS_LSEEK, fd, 2, SEEK_SET);
+    if syscall_identifier(SYS_LSEEK, fd, 1, SEEK_CUR) != 3 {
+        return Err("SEEK_CUR did not add to the offset");
+    }
+    syscall_identifier(SYS_CLOSE, fd, 0, 0);
+
+    let mut stat = Stat::default();
+    if syscall_identifier(
+        SYS_STAT,
+        OLD_NAME.as_ptr() as u64,
+        &mut stat as *mut Stat as u64,
+        0,
+    ) != 0
+        || stat.size != CONTENT.len() as u64
+        || stat.is_directory != 0
+    {
+        return Err("stat reported the wrong size or type");
+    }
+    serial_println!("✓ lseek and stat agree with the file");
+
+    if syscall_identifier(
+        SYS_RENAME,
+        OLD_NAME.as_ptr() as u64,
+        NEW_NAME.as_ptr() as u64,
+        0,
+    ) != 0
+    {
+        return Err("rename failed");
+    }
+    if syscall_identifier(
+        SYS_STAT,
+        OLD_NAME.as_ptr() as u64,
+        &mut stat as *mut Stat as u64,
+        0,
+    ) != Errno::NoEnt.as_u64()
+    {
+        return Err("old name still exists after rename");
+    }
+
+    let mut moved = [0u8; 16];
+    let fd = syscall_identifier(SYS_OPEN, NEW_NAME.as_ptr() as u64, 0, 0);
+    let n = syscall_identifier(SYS_READ, fd, moved.as_mut_ptr() as u64, moved.len() as u64);
+    syscall_identifier(SYS_CLOSE, fd, 0, 0);
+    syscall_identifier(SYS_UNLINK, NEW_NAME.as_ptr() as u64, 0, 0);
+
+    if is_error(n) || &moved[..n as usize] != CONTENT {
+        return Err("rename did not preserve the contents");
+    }
+    serial_println!("✓ Rename preserved the contents");
+    Ok(())
+}
+
+/// Size of one entry in the `SYS_LISTDIR` output buffer: a NUL-terminated
+/// name padded out to a fixed slot.
+pub const LISTDIR_ENTRY_SIZE: usize = 256;
+
+pub fn test_syscalls_directories() -> Result<(), &'static str> {
+    serial_println!("=== Directory Syscall Test ===");
+
+    static DIRNAME: &[u8] = b"SYSDIR\0";
+    static FILENAME: &[u8] = b"SYSDIR/ENTRY.TXT\0";
+    static CONTENT: &[u8] = b"listed";
+    const MAX_ENTRIES: usize = 8;
+
+    static LIST_BUFFER: Mutex<[u8; LISTDIR_ENTRY_SIZE * MAX_ENTRIES]> =
+        Mutex::new([0u8; LISTDIR_ENTRY_SIZE * MAX_ENTRIES]);
+
+    if syscall_identifier(SYS_MKDIR, DIRNAME.as_ptr() as u64, 0, 0) != 0 {
+        return Err("mkdir failed");
+    }
+    serial_println!("✓ Directory created");
+
+    let fd = syscall_identifier(SYS_OPEN, FILENAME.as_ptr() as u64, 1, 0);
+    syscall_identifier(SYS_WRITE, fd, CONTENT.as_ptr() as u64, CONTENT.len() as u64);
+    syscall_identifier(SYS_CLOSE, fd, 0, 0);
+
+    let mut list = LIST_BUFFER.lock();
+    let count = syscall_identifier(
+        SYS_LISTDIR,
+        DIRNAME.as_ptr() as u64,
+        list.as_mut_ptr() as u64,
+        MAX_ENTRIES as u64,
+    );
+    if is_error(count) {
+        return Err("listdir failed");
+    }
+
+    let mut found = false;
+    for slot in list.chunks(LISTDIR_ENTRY_SIZE).take(count as usize) {
+        let len = slot
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("unterminated entry")?;
+        let name = core::str::from_utf8(&slot[..len]).map_err(|_| "invalid entry name")?;
+        serial_println!("  listdir entry: {}", name);
+        found |= name == "ENTRY.TXT";
+    }
+    drop(list);
+    if !found {
+        return Err("listdir did not return the new file");
+    }
+    serial_println!("✓ Directory listing round-tripped {} entries", count);
+
+    if syscall_identifier(SYS_UNLINK, FILENAME.as_ptr() as u64, 0, 0) != 0 {
+        return Err("unlink failed");
+    }
+    if syscall_identifier(SYS_RMDIR, DIRNAME.as_ptr() as u64, 0, 0) == 0 {
+        serial_println!("✓ Directory removed");
+    } else {
+        serial_println!("✗ Directory removal failed");
+    }
+
+    serial_println!("=== Directory Syscall Test Complete ===");
+    Ok(())
+}
+
+/// Goes through the real `int 0x80` path and checks what lands in `rax`.
+pub fn test_syscall_abi() -> Result<(), &'static str> {
+    serial_println!("=== int 0x80 ABI Test ===");
+
+    if syscall3(SYS_CLOSE, 0, 0, 0) != crate::fs::syscalls::EBADF {
+        return Err("SYS_CLOSE on a bad fd did not return EBADF in rax");
+    }
+    // The boot code isn't a scheduler thread, so there is no pid to report.
+    if syscall3(SYS_GETPID, 0, 0, 0) != Errno::Srch.as_u64() {
+        return Err("SYS_GETPID outside a thread did not return ESRCH");
+    }
+    if syscall3(0xFFFF, 1, 2, 3) != Errno::NoSys.as_u64() {
+        return Err("unknown syscall did not return ENOSYS in rax");
+    }
+
+    serial_println!("✓ int 0x80 returned the expected values");
+    Ok(())
+}
+
+/// Maps anonymous memory, checks it is page aligned, zeroed and usable,
+/// that unaligned and empty requests fail, and that unmapping gives every
+/// frame back.
+pub fn test_syscalls_mmap() -> Result<(), &'static str> {
+    use crate::fs::syscalls::EINVAL;
+    use crate::memory::syscalls::{
+        mmap_prot_flags, MAP_ANONYMOUS, MAP_FIXED, PROT_READ, PROT_WRITE,
+    };
+    use crate::memory::GlobalFrameAllocator;
+
+    serial_println!("=== mmap Syscall Test ===");
+
+    let rw = mmap_prot_flags(PROT_READ | PROT_WRITE, MAP_ANONYMOUS);
+    let frames_before = GlobalFrameAllocator
+        .stats()
+        .ok_or("no frame allocator")?
+        .used;
+
+    // 5000 bytes rounds up to two pages.
+    let addr = syscall_identifier(SYS_MMAP, 0, 5000, rw);
+    if is_error(addr) || !addr.is_multiple_of(4096) {
+        return Err("mmap did not return a page-aligned address");
+    }
+    let mapping = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 8192) };
+    if mapping.iter().any(|&b| b != 0) {
+        return Err("anonymous mapping was not zeroed");
+    }
+    mapping[0] = 0x11;
+    mapping[8191] = 0x22;
+    if mapping[0] != 0x11 || mapping[8191] != 0x22 {
+        return Err("mapping did not hold its contents");
+    }
+
+    let fixed = mmap_prot_flags(PROT_READ, MAP_ANONYMOUS | MAP_FIXED);
+    if syscall_identifier(SYS_MMAP, addr + 1, 4096, fixed) != EINVAL {
+        return Err("unaligned MAP_FIXED address was accepted");
+    }
+    if syscall_identifier(SYS_MMAP, addr, 4096, fixed) != Errno::Exist.as_u64() {
+        return Err("MAP_FIXED over an existing mapping was accepted");
+    }
+    if syscall_identifier(SYS_MMAP, 0, 0, rw) != EINVAL {
+        return Err("zero-length mmap was accepted");
+    }
+    if syscall_identifier(SYS_MUNMAP, addr + 1, 4096, 0) != EINVAL {
+        return Err("unaligned munmap was accepted");
+    }
+
+    if syscall_identifier(SYS_MUNMAP, addr, 5000, 0) != 0 {
+        return Err("munmap failed");
+    }
+    let frames_after = GlobalFrameAllocator
+        .stats()
+        .ok_or("no frame allocator")?
+        .used;
+    // Page tables created for the mapping stay behind.
+    if frames_after > frames_before + 4 {
+        return Err("munmap did not free the frames");
+    }
+    // The range is free again, so a fixed mapping there now succeeds.
+    if syscall_identifier(SYS_MMAP, addr, 4096, fixed) != addr {
+        return Err("MAP_FIXED into an unmapped range failed");
+    }
+    syscall_identifier(SYS_MUNMAP, addr, 4096, 0);
+
+    serial_println!("✓ mmap/munmap map, zero and free pages");
+    Ok(())
+}
+
+/// Checks that failing filesystem calls say why.
+pub fn test_syscall_errno() -> Result<(), &'static str> {
+    use crate::drivers::ata::AtaError;
+    use crate::fs::vfs::FsError;
+
+    serial_println!("=== Syscall errno Test ===");
+
+    static MISSING: &[u8] = b"NOSUCH.TXT\0";
+    static DIRNAME: &[u8] = b"ERRDIR\0";
+
+    let fd = syscall_identifier(SYS_OPEN, MISSING.as_ptr() as u64, 0, 0);
+    if Errno::from_return(fd) != Some(Errno::NoEnt) {
+        return Err("opening a missing file did not return ENOENT");
+    }
+    if syscall_identifier(SYS_UNLINK, MISSING.as_ptr() as u64, 0, 0) != Errno::NoEnt.as_u64() {
+        return Err("unlinking a missing file did not return ENOENT");
+    }
+
+    syscall_identifier(SYS_MKDIR, DIRNAME.as_ptr() as u64, 0, 0);
+    let fd = syscall_identifier(SYS_OPEN, DIRNAME.as_ptr() as u64, 0, 0);
+    syscall_identifier(SYS_RMDIR, DIRNAME.as_ptr() as u64, 0, 0);
+    if Errno::from_return(fd) != Some(Errno::IsDir) {
+        return Err("opening a directory did not return EISDIR");
+    }
+
+    if Errno::from(FsError::NotEmpty) != Errno::NotEmpty
+        || Errno::from(FsError::from(AtaError::Timeout)) != Errno::TimedOut
+        || Errno::from("No volume manager") != Errno::NoDev
+    {
+        return Err("FsError mapped to the wrong errno");
+    }
+    if Errno::from_return(5).is_some()
+        || Errno::from_return(Errno::Srch.as_u64()) != Some(Errno::Srch)
+    {
+        return Err("from_return misread a result");
+    }
+    serial_println!("✓ {}", Errno::NoEnt);
+    Ok(())
+}
+
 pub fn test_syscalls() {
+    if let Err(e) = test_syscall_abi() {
+        serial_println!("✗ Syscall ABI test failed: {}", e);
+    }
     let _ = test_syscalls_filesystem_fixed();
+    if let Err(e) = test_syscalls_fd_table() {
+        serial_println!("✗ FD table syscall test failed: {}", e);
+    }
+    if let Err(e) = test_syscalls_seek_stat_rename() {
+        serial_println!("✗ Seek/stat/rename syscall test failed: {}", e);
+    }
+    if let Err(e) = test_syscalls_directories() {
+        serial_println!("✗ Directory syscall test failed: {}", e);
+    }
+    if let Err(e) = test_syscalls_mmap() {
+        serial_println!("✗ mmap syscall test failed: {}", e);
+    }
+    if let Err(e) = test_syscall_errno() {
+        serial_println!("✗ Syscall errno test failed: {}", e);
+    }
 }
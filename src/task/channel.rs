@@ -0,0 +1,287 @@
+use super::{simple_executor::SimpleExecutor, Task};
+use alloc::{sync::Arc, task::Wake, vec::Vec};
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
+use spin::Mutex;
+
+/// Why a value could not be sent. Either way the value comes back.
+#[derive(PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel holds `cap` values already.
+    Full(T),
+    /// The receiver is gone.
+    Closed(T),
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("Full(..)"),
+            TrySendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+
+/// The receiver is gone; the value that could not be sent comes back.
+#[derive(PartialEq, Eq)]
+pub struct Closed<T>(pub T);
+
+impl<T> fmt::Debug for Closed<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Closed(..)")
+    }
+}
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    /// The receiver, waiting for a value or for the last sender to go.
+    receiver_waker: AtomicWaker,
+    /// Senders waiting for room. There can be several, so unlike the
+    /// receiver they can't share one `AtomicWaker`.
+    sender_wakers: Mutex<Vec<Waker>>,
+    senders: AtomicUsize,
+    receiver_closed: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn wake_senders(&self) {
+        for waker in self.sender_wakers.lock().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Creates a channel that holds up to `cap` values, for tasks to pass
+/// values to one another. `Sender::try_send` never blocks or locks, so it
+/// is fine in interrupt handlers, like the keyboard's scancode queue.
+pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(cap),
+        receiver_waker: AtomicWaker::new(),
+        sender_wakers: Mutex::new(Vec::new()),
+        senders: AtomicUsize::new(1),
+        receiver_closed: AtomicBool::new(false),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// The sending half. Clone it for more producers; the channel closes for
+/// the receiver once every clone is dropped.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` if there is room, without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if self.shared.receiver_closed.load(Ordering::Acquire) {
+            return Err(TrySendError::Closed(value));
+        }
+        self.shared.queue.push(value).map_err(TrySendError::Full)?;
+        self.shared.receiver_waker.wake();
+        Ok(())
+    }
+
+    /// Sends `value`, waiting for room while the channel is full.
+    pub fn send(&self, value: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            value: Some(value),
+        }
+    }
+
+    /// Whether the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.receiver_closed.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Let the receiver see the stream has ended.
+            self.shared.receiver_waker.wake();
+        }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct Send<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+}
+
+impl<T> Unpin for Send<'_, T> {}
+
+impl<T> Future for Send<'_, T> {
+    type Output = Result<(), Closed<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let value = self.value.take().expect("Send polled after completion");
+        let value = match self.sender.try_send(value) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => return Poll::Ready(Err(Closed(value))),
+            Err(TrySendError::Full(value)) => value,
+        };
+
+        // Register before trying again, so a value taken in between
+        // still wakes us.
+        let shared = &self.sender.shared;
+        shared.sender_wakers.lock().push(cx.waker().clone());
+        match self.sender.try_send(value) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Closed(value)) => Poll::Ready(Err(Closed(value))),
+            Err(TrySendError::Full(value)) => {
+                self.value = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// The receiving half. As a `Stream` it yields values in the order they
+/// were sent and ends once every `Sender` is gone and the channel is empty.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes a value if one is waiting.
+    pub fn try_recv(&self) -> Option<T> {
+        let value = self.shared.queue.pop()?;
+        self.shared.wake_senders();
+        Some(value)
+    }
+
+    /// Whether every sender is gone. Values sent before that can still be
+    /// waiting in the channel.
+    pub fn is_closed(&self) -> bool {
+        self.shared.senders.load(Ordering::Acquire) == 0
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.try_recv() {
+            return Poll::Ready(Some(value));
+        }
+        if self.is_closed() {
+            // A last value may have landed just before the sender went.
+            return Poll::Ready(self.try_recv());
+        }
+
+        self.shared.receiver_waker.register(cx.waker());
+        if let Some(value) = self.try_recv() {
+            self.shared.receiver_waker.take();
+            return Poll::Ready(Some(value));
+        }
+        if self.is_closed() {
+            self.shared.receiver_waker.take();
+            return Poll::Ready(self.try_recv());
+        }
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_closed.store(true, Ordering::Release);
+        // Senders waiting for room would otherwise wait forever.
+        self.shared.wake_senders();
+    }
+}
+
+/// Counts how often it is woken.
+struct CountingWaker(AtomicUsize);
+
+impl Wake for CountingWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Checks a waiting receiver is woken by a send and by the last sender
+/// going, then runs a producer task that sends more than the channel holds
+/// against a consumer task and checks every value arrives in order.
+pub fn test_channel() -> Result<(), &'static str> {
+    const VALUES: u32 = 50;
+
+    let (sender, mut receiver) = bounded::<u32>(1);
+    let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+    let waker = Waker::from(counter.clone());
+    let mut cx = Context::from_waker(&waker);
+    if Pin::new(&mut receiver).poll_next(&mut cx).is_ready() {
+        return Err("empty channel did not wait");
+    }
+    sender
+        .try_send(7)
+        .map_err(|_| "send into an empty channel failed")?;
+    if !matches!(sender.try_send(8), Err(TrySendError::Full(8))) {
+        return Err("send into a full channel was not refused");
+    }
+    if counter.0.load(Ordering::Relaxed) != 1 {
+        return Err("waiting receiver was not woken by a send");
+    }
+    if Pin::new(&mut receiver).poll_next(&mut cx) != Poll::Ready(Some(7)) {
+        return Err("receiver did not get the value sent");
+    }
+    let _ = Pin::new(&mut receiver).poll_next(&mut cx);
+    drop(sender);
+    if counter.0.load(Ordering::Relaxed) != 2 {
+        return Err("waiting receiver was not woken when the sender went");
+    }
+    if Pin::new(&mut receiver).poll_next(&mut cx) != Poll::Ready(None) {
+        return Err("stream did not end with the sender gone");
+    }
+
+    let (sender, mut receiver) = bounded(4);
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let mut executor = SimpleExecutor::new();
+    executor.spawn(Task::named("channel consumer", async move {
+        while let Some(value) = receiver.next().await {
+            sink.lock().push(value);
+        }
+    }));
+    executor.spawn(Task::named("channel producer", async move {
+        for value in 0..VALUES {
+            if sender.send(value).await.is_err() {
+                return;
+            }
+        }
+    }));
+    executor.run();
+
+    if *received.lock() != (0..VALUES).collect::<Vec<_>>() {
+        return Err("consumer did not get every value in order");
+    }
+
+    crate::serial_println!("✓ Bounded channel passes values between tasks");
+    Ok(())
+}
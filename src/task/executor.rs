@@ -1,12 +1,71 @@
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{collections::BTreeMap, sync::Arc, task::Wake, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+
+/// Where a task is in its poll cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Queued to be polled.
+    Ready,
+    /// Being polled right now.
+    Running,
+    /// Returned `Pending` and waiting for its waker.
+    Waiting,
+}
+
+impl TaskState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            _ => TaskState::Waiting,
+        }
+    }
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            TaskState::Ready => "ready",
+            TaskState::Running => "running",
+            TaskState::Waiting => "waiting",
+        })
+    }
+}
+
+/// One row of `Executor::list_tasks`.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+}
+
+/// Shared with the task's waker, which marks it `Ready` from interrupt
+/// context without touching the registry lock.
+type SharedState = Arc<AtomicU8>;
+
+/// Every live task on any executor, for `list_tasks`. Only touched on spawn,
+/// completion and listing, never from a waker.
+static REGISTRY: Mutex<BTreeMap<TaskId, (&'static str, SharedState)>> = Mutex::new(BTreeMap::new());
+
+/// TSC cycles every executor has spent halted with nothing to poll.
+static IDLE_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// Cycles spent halted in `Executor::run`, to compare against the TSC.
+pub fn idle_cycles() -> u64 {
+    IDLE_CYCLES.load(Ordering::Relaxed)
+}
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     waker_cache: BTreeMap<TaskId, Waker>,
+    states: BTreeMap<TaskId, SharedState>,
 }
 
 impl Executor {
@@ -15,17 +74,34 @@ impl Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
             waker_cache: BTreeMap::new(),
+            states: BTreeMap::new(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
+        let state = Arc::new(AtomicU8::new(TaskState::Ready as u8));
+        REGISTRY.lock().insert(task_id, (task.name, state.clone()));
+        self.states.insert(task_id, state);
         if self.tasks.insert(task.id, task).is_some() {
             panic!("task with same ID already in tasks");
         }
         self.task_queue.push(task_id).expect("queue full");
     }
 
+    /// The live tasks of every executor, by id.
+    pub fn list_tasks() -> Vec<TaskInfo> {
+        REGISTRY
+            .lock()
+            .iter()
+            .map(|(&id, (name, state))| TaskInfo {
+                id,
+                name,
+                state: TaskState::from_u8(state.load(Ordering::Relaxed)),
+            })
+            .collect()
+    }
+
     pub fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
@@ -38,6 +114,7 @@ impl Executor {
             tasks,
             task_queue,
             waker_cache,
+            states,
         } = self;
 
         while let Some(task_id) = task_queue.pop() {
@@ -45,47 +122,89 @@ impl Executor {
                 Some(task) => task,
                 None => continue,
             };
+            let state = &states[&task_id];
             let waker = waker_cache
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone(), state.clone()));
             let mut context = Context::from_waker(waker);
+            state.store(TaskState::Running as u8, Ordering::Relaxed);
             match task.poll(&mut context) {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    states.remove(&task_id);
+                    REGISTRY.lock().remove(&task_id);
+                }
+                Poll::Pending => {
+                    // A wake during the poll already queued it again.
+                    let _ = state.compare_exchange(
+                        TaskState::Running as u8,
+                        TaskState::Waiting as u8,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
                 }
-                Poll::Pending => {}
             }
         }
     }
 
+    /// Halts until the next interrupt if no task is ready. Only wakers
+    /// queue tasks, so with every task waiting on input the CPU stays
+    /// halted between interrupts rather than polling.
     fn sleep_if_idle(&self) {
+        use core::arch::x86_64::_rdtsc;
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
         if self.task_queue.is_empty() {
+            let start = unsafe { _rdtsc() };
             enable_and_hlt();
+            IDLE_CYCLES.fetch_add(unsafe { _rdtsc() } - start, Ordering::Relaxed);
         } else {
             interrupts::enable();
         }
     }
 }
 
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register("tasks", "list async tasks", cmd_tasks);
+}
+
+fn cmd_tasks(_shell: &crate::sshell::Shell, _args: &[&str]) {
+    crate::println!("{:>4}  {:<8} {}", "ID", "STATE", "NAME");
+    for task in Executor::list_tasks() {
+        crate::println!("{:>4}  {:<8} {}", task.id, task.state, task.name);
+    }
+    // The TSC starts at reset, so this is close to the time since boot.
+    let total = unsafe { core::arch::x86_64::_rdtsc() };
+    let percent = (idle_cycles() as u128 * 100)
+        .checked_div(total as u128)
+        .unwrap_or(0);
+    crate::println!("idle {}% of cycles since boot", percent);
+}
+
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    state: SharedState,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, state: SharedState) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
+            state,
         }))
     }
 
+    /// Queues the task unless it is already queued, so repeated wakes
+    /// before it runs cost one poll and can't fill the queue.
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        let previous = self.state.swap(TaskState::Ready as u8, Ordering::Relaxed);
+        if previous != TaskState::Ready as u8 {
+            self.task_queue.push(self.task_id).expect("task_queue full");
+        }
     }
 }
 
@@ -98,3 +217,59 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+/// Spawns a task that waits on a channel and checks it is polled once,
+/// then not again until a send wakes it, that two wakes queue it once, and
+/// that it finishes when the channel closes.
+pub fn test_executor_wakeups() -> Result<(), &'static str> {
+    use crate::task::channel;
+    use core::sync::atomic::AtomicUsize;
+    use futures_util::StreamExt;
+
+    let passes = Arc::new(AtomicUsize::new(0));
+    let (sender, mut receiver) = channel::bounded::<u8>(4);
+    let mut executor = Executor::new();
+    let counter = passes.clone();
+    let task = Task::named("wakeup test", async move {
+        loop {
+            counter.fetch_add(1, Ordering::Relaxed);
+            if receiver.next().await.is_none() {
+                return;
+            }
+        }
+    });
+    let task_id = task.id();
+    executor.spawn(task);
+
+    executor.run_ready_tasks();
+    executor.run_ready_tasks();
+    if passes.load(Ordering::Relaxed) != 1 || !executor.task_queue.is_empty() {
+        return Err("waiting task was queued again without a wake");
+    }
+
+    let waker = executor.waker_cache[&task_id].clone();
+    waker.wake_by_ref();
+    waker.wake_by_ref();
+    if executor.task_queue.len() != 1 {
+        return Err("two wakes queued the task more than once");
+    }
+    executor.run_ready_tasks();
+
+    sender.try_send(1).map_err(|_| "send failed")?;
+    sender.try_send(2).map_err(|_| "send failed")?;
+    executor.run_ready_tasks();
+    // One pass at the start and one per value; the spurious wake finds
+    // nothing and goes back to waiting.
+    if passes.load(Ordering::Relaxed) != 3 {
+        return Err("woken task did not take both values");
+    }
+
+    drop(sender);
+    executor.run_ready_tasks();
+    if !executor.tasks.is_empty() {
+        return Err("task did not finish when the channel closed");
+    }
+
+    crate::serial_println!("✓ Executor only passes woken tasks");
+    Ok(())
+}
@@ -1,7 +1,9 @@
 use crate::{print, println};
+use alloc::string::String;
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     task::{Context, Poll},
 };
 use crossbeam_queue::ArrayQueue;
@@ -10,20 +12,40 @@ use futures_util::{
     task::AtomicWaker,
 };
 use lazy_static::lazy_static;
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1, layouts};
 use spin::Mutex;
+use x86_64::instructions::port::Port;
 
-pub static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+/// One entry of `INPUT_QUEUE`: a scancode read from the keyboard, or a
+/// character `inject_str` has no key for. Sharing one queue keeps both in
+/// the order they came in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Scancode(u8),
+    Char(char),
+}
+
+pub static INPUT_QUEUE: OnceCell<ArrayQueue<Input>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 const KEYBUFFER_SIZE: usize = 1024; // buffer can hold 128 chars
 lazy_static! {
-    pub static ref SCANCODES: ScancodeStream = ScancodeStream::new();
+    pub static ref INPUT: InputStream = InputStream::new();
+}
+
+/// What `RingBuffer::push` does when the buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Keep what is buffered and drop the new character.
+    DropNewest,
+    /// Make room by dropping the oldest buffered character.
+    DropOldest,
 }
 
 pub struct RingBuffer {
     buffer: [Option<char>; KEYBUFFER_SIZE],
     head: usize,
     tail: usize,
+    overflow: Overflow,
 }
 
 impl RingBuffer {
@@ -32,16 +54,33 @@ impl RingBuffer {
             buffer: [None; KEYBUFFER_SIZE],
             head: 0,
             tail: 0,
+            overflow: Overflow::DropNewest,
         }
     }
 
-    pub fn push(&mut self, c: char) {
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Appends `c`. If the buffer is full a character is dropped according
+    /// to the overflow policy and counted in `dropped_count`. Returns
+    /// whether nothing was dropped.
+    pub fn push(&mut self, c: char) -> bool {
         let next = (self.head + 1) % KEYBUFFER_SIZE;
-        if next != self.tail {
-            self.buffer[self.head] = Some(c);
-            self.head = next;
+        let full = next == self.tail;
+        if full {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            DROP_UNREPORTED.store(true, Ordering::Relaxed);
+            match self.overflow {
+                Overflow::DropNewest => return false,
+                Overflow::DropOldest => {
+                    self.tail = (self.tail + 1) % KEYBUFFER_SIZE;
+                }
+            }
         }
-        // TODO: else
+        self.buffer[self.head] = Some(c);
+        self.head = next;
+        !full
     }
 
     pub fn pop(&mut self) -> Option<char> {
@@ -59,72 +98,492 @@ lazy_static! {
     pub static ref KEYBUFFER: Mutex<RingBuffer> = Mutex::new(RingBuffer::new());
 }
 
+/// Scancodes and characters lost to a full (or not yet created) queue or
+/// buffer.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Set when input is dropped. Drops happen in interrupt context, where
+/// printing could deadlock on the console lock, so `report_dropped_input`
+/// warns later from a task.
+static DROP_UNREPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Called from the IRQ handler, so it must not print or take locks.
 pub(crate) fn add_scancode(scancode: u8) {
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
-            WAKER.wake();
-        }
+    add_input(Input::Scancode(scancode));
+}
+
+fn add_input(input: Input) {
+    let queued = INPUT_QUEUE
+        .try_get()
+        .is_ok_and(|queue| queue.push(input).is_ok());
+    if queued {
+        WAKER.wake();
     } else {
-        println!("WARNING: scancode queue uninitialized");
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        DROP_UNREPORTED.store(true, Ordering::Relaxed);
     }
 }
 
-pub async fn read_line() -> Option<char> {
-    let mut scancodes = SCANCODES.clone();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
+/// How many scancodes and characters have been dropped since boot.
+pub fn dropped_count() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Prints the warning `add_scancode` could not, once per burst of drops.
+fn report_dropped_input() {
+    if DROP_UNREPORTED.swap(false, Ordering::Relaxed) {
+        println!(
+            "WARNING: keyboard input dropped ({} so far)",
+            dropped_count()
+        );
+    }
+}
+
+const KEYBOARD_IRQ: u8 = 1;
+
+/// Hooks the keyboard up to IRQ 1.
+pub fn init() -> Result<(), &'static str> {
+    crate::interrupts::register_irq(KEYBOARD_IRQ, handle_irq)
+}
+
+fn handle_irq() {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    add_scancode(scancode);
+}
+
+/// Checks that IRQ 1 still arrives by having the 8042 controller feed a
+/// byte back as if the keyboard sent it. The byte is a left shift release,
+/// which the decoder takes without producing a key.
+pub fn test_keyboard_irq() -> Result<(), &'static str> {
+    const STATUS_INPUT_FULL: u8 = 1 << 1;
+    const CMD_WRITE_KEYBOARD_OUTPUT: u8 = 0xD2;
+    const LEFT_SHIFT_RELEASE: u8 = 0xAA;
+
+    let mut status: Port<u8> = Port::new(0x64);
+    let mut data: Port<u8> = Port::new(0x60);
+    let wait_input_empty = |status: &mut Port<u8>| {
+        let deadline = crate::timer::uptime_ms() + 100;
+        while unsafe { status.read() } & STATUS_INPUT_FULL != 0 {
+            if crate::timer::uptime_ms() > deadline {
+                return Err("8042 controller not accepting commands");
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    };
+
+    let before = crate::interrupts::irq_count(KEYBOARD_IRQ);
+    wait_input_empty(&mut status)?;
+    unsafe { status.write(CMD_WRITE_KEYBOARD_OUTPUT) };
+    wait_input_empty(&mut status)?;
+    unsafe { data.write(LEFT_SHIFT_RELEASE) };
+
+    let deadline = crate::timer::uptime_ms() + 100;
+    while crate::interrupts::irq_count(KEYBOARD_IRQ) == before {
+        if crate::timer::uptime_ms() > deadline {
+            return Err("no IRQ 1 after writing the keyboard output buffer");
+        }
+        x86_64::instructions::hlt();
+    }
+    crate::serial_println!(
+        "✓ Keyboard IRQ delivered through the {:?}",
+        crate::interrupts::interrupt_controller()
     );
+    Ok(())
+}
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => {
-                        KEYBUFFER.lock().push(character);
-                        return Some(character);
-                    }
-                    DecodedKey::RawKey(_) => (),
+/// Feeds a raw set-1 scancode into the keyboard pipeline as if the IRQ handler
+/// had read it from port 0x60.
+pub fn inject_scancode(scancode: u8) {
+    lazy_static::initialize(&INPUT);
+    add_scancode(scancode);
+}
+
+/// Types `s` by injecting the make/break scancodes for each character, so the
+/// text goes through the same decoder as real keypresses. Characters without a
+/// US-layout scancode, such as `é`, come out of the decoder as they are.
+pub fn inject_str(s: &str) {
+    const LSHIFT: u8 = 0x2A;
+
+    for c in s.chars() {
+        let Some((scancode, shift)) = char_to_scancode(c) else {
+            inject_char(c);
+            continue;
+        };
+        if shift {
+            inject_scancode(LSHIFT);
+        }
+        inject_scancode(scancode);
+        inject_scancode(scancode | 0x80);
+        if shift {
+            inject_scancode(LSHIFT | 0x80);
+        }
+    }
+}
+
+/// Queues `c` to be decoded after the scancodes already injected.
+fn inject_char(c: char) {
+    lazy_static::initialize(&INPUT);
+    add_input(Input::Char(c));
+}
+
+/// Types a mix of keyboard and non-ASCII characters with `inject_str` and
+/// checks every one of them comes back from `KEYBUFFER`, in order.
+pub fn test_inject_str() -> Result<(), &'static str> {
+    const TEXT: &str = "Naïve café, 東京 ✓!";
+
+    let previous = layout();
+    set_layout(Layout::Us);
+    while try_pop().is_some() {}
+    inject_str(TEXT);
+    let typed: String = core::iter::from_fn(try_pop).collect();
+    set_layout(previous);
+
+    if typed != TEXT {
+        crate::serial_println!("✗ Typed {:?}, read back {:?}", TEXT, typed);
+        return Err("injected text did not come back unchanged");
+    }
+    crate::serial_println!("✓ Non-ASCII text injected and read back");
+    Ok(())
+}
+
+fn char_to_scancode(c: char) -> Option<(u8, bool)> {
+    const ROW_NUMBERS: &[u8] = b"1234567890-=";
+    const ROW_NUMBERS_SHIFT: &[u8] = b"!@#$%^&*()_+";
+    const ROW_TOP: &[u8] = b"qwertyuiop[]";
+    const ROW_TOP_SHIFT: &[u8] = b"QWERTYUIOP{}";
+    const ROW_HOME: &[u8] = b"asdfghjkl;'`";
+    const ROW_HOME_SHIFT: &[u8] = b"ASDFGHJKL:\"~";
+    const ROW_BOTTOM: &[u8] = b"\\zxcvbnm,./";
+    const ROW_BOTTOM_SHIFT: &[u8] = b"|ZXCVBNM<>?";
+
+    let rows: [(&[u8], u8, bool); 8] = [
+        (ROW_NUMBERS, 0x02, false),
+        (ROW_NUMBERS_SHIFT, 0x02, true),
+        (ROW_TOP, 0x10, false),
+        (ROW_TOP_SHIFT, 0x10, true),
+        (ROW_HOME, 0x1E, false),
+        (ROW_HOME_SHIFT, 0x1E, true),
+        (ROW_BOTTOM, 0x2B, false),
+        (ROW_BOTTOM_SHIFT, 0x2B, true),
+    ];
+
+    match c {
+        '\n' | '\r' => return Some((0x1C, false)),
+        '\x08' => return Some((0x0E, false)),
+        '\t' => return Some((0x0F, false)),
+        ' ' => return Some((0x39, false)),
+        _ => {}
+    }
+
+    if !c.is_ascii() {
+        return None;
+    }
+    let byte = c as u8;
+    for (row, first, shift) in rows {
+        if let Some(pos) = row.iter().position(|&b| b == byte) {
+            return Some((first + pos as u8, shift));
+        }
+    }
+    None
+}
+
+/// Page-Up/Page-Down move the VGA console through its scrollback. Returns
+/// whether `key` was one of them.
+fn handle_scroll_key(key: KeyCode) -> bool {
+    use crate::vga_buffer::{BUFFER_HEIGHT, scroll_down, scroll_up};
+
+    match key {
+        KeyCode::PageUp => scroll_up(BUFFER_HEIGHT - 1),
+        KeyCode::PageDown => scroll_down(BUFFER_HEIGHT - 1),
+        _ => return false,
+    }
+    true
+}
+
+/// Keyboard layouts `set_layout` can switch between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    German,
+}
+
+impl Layout {
+    /// Parses the names the `layout` shell command accepts.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "us" => Some(Layout::Us),
+            "uk" | "gb" => Some(Layout::Uk),
+            "de" => Some(Layout::German),
+            _ => None,
+        }
+    }
+
+    fn keymap(self) -> layouts::AnyLayout {
+        match self {
+            Layout::Us => layouts::AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk => layouts::AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::German => layouts::AnyLayout::De105Key(layouts::De105Key),
+        }
+    }
+}
+
+/// Modifier keys held (or, for Caps Lock, toggled on) when a key was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    /// Left Alt only; Right Alt is AltGr and is left to the layout.
+    pub alt: bool,
+    pub caps_lock: bool,
+}
+
+/// A key press as a line editor sees it: text, a Ctrl/Alt combination, or
+/// one of the keys that have no character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Char(char),
+    /// A printable key typed with Ctrl held, lower-cased: Ctrl+C is
+    /// `Ctrl('c')`.
+    Ctrl(char),
+    /// A printable key typed with Alt held (and not Ctrl).
+    Alt(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Insert,
+    Delete,
+    /// F1-F12.
+    Function(u8),
+}
+
+type Decoder = Keyboard<layouts::AnyLayout, ScancodeSet1>;
+
+fn new_decoder(layout: Layout) -> Decoder {
+    Keyboard::new(ScancodeSet1::new(), layout.keymap(), HandleControl::Ignore)
+}
+
+lazy_static! {
+    /// Decoder shared by `read_key` calls, so modifier state and the 0xE0
+    /// prefix survive between them.
+    static ref KEY_DECODER: Mutex<(Layout, Decoder)> =
+        Mutex::new((Layout::Us, new_decoder(Layout::Us)));
+}
+
+/// Switches the layout used to decode keys. Modifier state starts over.
+pub fn set_layout(layout: Layout) {
+    *KEY_DECODER.lock() = (layout, new_decoder(layout));
+}
+
+pub fn layout() -> Layout {
+    KEY_DECODER.lock().0
+}
+
+/// The modifiers as of the last decoded key.
+pub fn modifiers() -> Modifiers {
+    modifiers_of(&KEY_DECODER.lock().1)
+}
+
+fn modifiers_of(keyboard: &Decoder) -> Modifiers {
+    let m = keyboard.get_modifiers();
+    Modifiers {
+        shift: m.lshift || m.rshift,
+        ctrl: m.lctrl || m.rctrl,
+        alt: m.lalt,
+        caps_lock: m.capslock,
+    }
+}
+
+/// Feeds `input` to `keyboard`. An injected character is taken as typed,
+/// with whatever modifiers are held.
+fn decode_with(keyboard: &mut Decoder, input: Input) -> Option<DecodedKey> {
+    match input {
+        Input::Scancode(scancode) => {
+            let event = keyboard.add_byte(scancode).ok()??;
+            keyboard.process_keyevent(event)
+        }
+        Input::Char(character) => Some(DecodedKey::Unicode(character)),
+    }
+}
+
+fn decode(input: Input) -> Option<(DecodedKey, Modifiers)> {
+    let (_, keyboard) = &mut *KEY_DECODER.lock();
+    let key = decode_with(keyboard, input)?;
+    Some((key, modifiers_of(keyboard)))
+}
+
+fn raw_key_event(key: KeyCode) -> Option<KeyEvent> {
+    const FUNCTION_KEYS: [KeyCode; 12] = [
+        KeyCode::F1,
+        KeyCode::F2,
+        KeyCode::F3,
+        KeyCode::F4,
+        KeyCode::F5,
+        KeyCode::F6,
+        KeyCode::F7,
+        KeyCode::F8,
+        KeyCode::F9,
+        KeyCode::F10,
+        KeyCode::F11,
+        KeyCode::F12,
+    ];
+
+    let event = match key {
+        KeyCode::ArrowUp => KeyEvent::Up,
+        KeyCode::ArrowDown => KeyEvent::Down,
+        KeyCode::ArrowLeft => KeyEvent::Left,
+        KeyCode::ArrowRight => KeyEvent::Right,
+        KeyCode::Home => KeyEvent::Home,
+        KeyCode::End => KeyEvent::End,
+        KeyCode::Insert => KeyEvent::Insert,
+        KeyCode::Delete => KeyEvent::Delete,
+        _ => {
+            let n = FUNCTION_KEYS.iter().position(|&f| f == key)?;
+            KeyEvent::Function(n as u8 + 1)
+        }
+    };
+    Some(event)
+}
+
+/// Decodes one scancode or injected character. Characters are also queued
+/// in `KEYBUFFER`; Page-Up/Page-Down scroll the console and, like lone
+/// modifier keys and partial sequences, produce no event.
+fn process_input(input: Input) -> Option<KeyEvent> {
+    let event = match decode(input)? {
+        (DecodedKey::Unicode(character), mods) if character.is_ascii_graphic() && mods.ctrl => {
+            KeyEvent::Ctrl(character.to_ascii_lowercase())
+        }
+        (DecodedKey::Unicode(character), mods) if character.is_ascii_graphic() && mods.alt => {
+            KeyEvent::Alt(character)
+        }
+        (DecodedKey::Unicode(character), _) => {
+            KEYBUFFER.lock().push(character);
+            KeyEvent::Char(character)
+        }
+        (DecodedKey::RawKey(key), _) => {
+            let event = raw_key_event(key);
+            if event.is_none() {
+                handle_scroll_key(key);
+            }
+            event?
+        }
+    };
+    Some(event)
+}
+
+/// Waits for the next key press. Page-Up/Page-Down are handled here and
+/// never returned, and lone modifier keys are only tracked.
+pub async fn read_key() -> Option<KeyEvent> {
+    let mut inputs = INPUT.clone();
+
+    while let Some(input) = inputs.next().await {
+        report_dropped_input();
+        if let Some(event) = process_input(input) {
+            return Some(event);
+        }
+    }
+    None
+}
+
+/// Waits for the next typed character, skipping every other key.
+pub async fn read_line() -> Option<char> {
+    loop {
+        if let KeyEvent::Char(character) = read_key().await? {
+            return Some(character);
+        }
+    }
+}
+
+/// Returns the next typed character if there is one, without waiting.
+/// Pending input is decoded first, so this works without any task
+/// awaiting `read_key`.
+pub fn try_pop() -> Option<char> {
+    report_dropped_input();
+    if let Ok(queue) = INPUT_QUEUE.try_get() {
+        while let Some(input) = queue.pop() {
+            process_input(input);
+        }
+    }
+    KEYBUFFER.lock().pop()
+}
+
+/// Adds whatever has been typed to `line`, echoing it, and returns the
+/// finished line once Enter is seen. `line` is left empty for the next one.
+/// Returns `None` straight away if Enter has not been pressed yet.
+pub fn poll_line(line: &mut String) -> Option<String> {
+    while let Some(c) = try_pop() {
+        match c {
+            '\n' | '\r' => {
+                println!();
+                return Some(core::mem::take(line));
+            }
+            '\x08' => {
+                if line.pop().is_some() {
+                    print!("\x08");
                 }
             }
+            c if !c.is_control() => {
+                line.push(c);
+                print!("{}", c);
+            }
+            _ => {}
         }
     }
     None
 }
+
+pub fn register_commands(shell: &mut crate::sshell::Shell) {
+    shell.register(
+        "layout",
+        "show or set the keyboard layout: layout [us|uk|de]",
+        cmd_layout,
+    );
+}
+
+fn cmd_layout(_shell: &crate::sshell::Shell, args: &[&str]) {
+    let Some(&name) = args.first() else {
+        println!("{:?}", layout());
+        return;
+    };
+    match Layout::from_name(name) {
+        Some(layout) => set_layout(layout),
+        None => println!("layout: unknown layout '{}' (us, uk, de)", name),
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
-pub struct ScancodeStream {
+pub struct InputStream {
     _private: (),
 }
 
-impl ScancodeStream {
+impl InputStream {
     pub fn new() -> Self {
-        SCANCODE_QUEUE
+        INPUT_QUEUE
             .try_init_once(|| ArrayQueue::new(1024))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+            .expect("InputStream::new should only be called once");
+        InputStream { _private: () }
     }
 }
 
-impl Stream for ScancodeStream {
-    type Item = u8;
+impl Stream for InputStream {
+    type Item = Input;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
-        let queue = SCANCODE_QUEUE
-            .try_get()
-            .expect("scancode queue not initialized");
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Input>> {
+        let queue = INPUT_QUEUE.try_get().expect("input queue not initialized");
 
-        if let Some(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
+        if let Some(input) = queue.pop() {
+            return Poll::Ready(Some(input));
         }
 
         WAKER.register(&cx.waker());
         match queue.pop() {
-            Some(scancode) => {
+            Some(input) => {
                 WAKER.take();
-                Poll::Ready(Some(scancode))
+                Poll::Ready(Some(input))
             }
             None => Poll::Pending,
         }
@@ -132,19 +591,18 @@ impl Stream for ScancodeStream {
 }
 
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    );
+    let mut inputs = InputStream::new();
+    let mut keyboard = new_decoder(layout());
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+    while let Some(input) = inputs.next().await {
+        report_dropped_input();
+        if let Some(key) = decode_with(&mut keyboard, input) {
+            match key {
+                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::RawKey(key) => {
+                    if !handle_scroll_key(key) {
+                        print!("{:?}", key)
+                    }
                 }
             }
         }
@@ -1,35 +1,59 @@
 use alloc::boxed::Box;
 use core::{
+    fmt,
     future::Future,
     pin::Pin,
     sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
 
+pub mod channel;
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod timeout;
 
 pub struct Task {
     id: TaskId,
+    name: &'static str,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task::named("task", future)
+    }
+
+    /// Like `new`, with a name for the `tasks` listing.
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
             id: TaskId::new(),
+            name,
             future: Box::pin(future),
         }
     }
 
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
 
 impl TaskId {
     fn new() -> Self {
@@ -1,5 +1,6 @@
 use super::Task;
 use alloc::collections::VecDeque;
+use core::future::Future;
 use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 
 pub struct SimpleExecutor {
@@ -29,6 +30,25 @@ impl SimpleExecutor {
     }
 }
 
+/// Runs `future` to completion on this CPU, for synchronous code such as
+/// shell commands. Its wakers do nothing; between polls the CPU halts until
+/// the next interrupt, which is what ends any wait the future is in.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = core::pin::pin!(future);
+    let waker = dummy_waker();
+    let mut context = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        if x86_64::instructions::interrupts::are_enabled() {
+            x86_64::instructions::hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
 fn dummy_raw_waker() -> RawWaker {
     fn no_op(_: *const ()) {}
     fn clone(_: *const ()) -> RawWaker {
@@ -0,0 +1,33 @@
+use core::future::Future;
+use core::pin::pin;
+use core::time::Duration;
+use futures_util::future::{Either, select};
+
+use crate::task::keyboard::read_key;
+use crate::timer::sleep_ms;
+
+/// Returned by `with_timeout` when the deadline passes first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+/// Races `future` against a `sleep_ms` timer. Whichever loses is dropped, so
+/// a finished future also cancels its pending timer wake-up.
+pub async fn with_timeout<F: Future>(future: F, duration: Duration) -> Result<F::Output, TimedOut> {
+    let future = pin!(future);
+    let ms = duration.as_millis().try_into().unwrap_or(u64::MAX);
+    match select(future, sleep_ms(ms)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(TimedOut),
+    }
+}
+
+/// Gives the user 5 seconds to press a key. The timer tick wakes the task
+/// when nothing is typed.
+pub async fn wait_for_keypress_demo() {
+    crate::println!("Press a key within 5 seconds...");
+    match with_timeout(read_key(), Duration::from_secs(5)).await {
+        Ok(Some(key)) => crate::println!("Got {:?}", key),
+        Ok(None) => crate::println!("Keyboard stream closed"),
+        Err(TimedOut) => crate::println!("timed out"),
+    }
+}
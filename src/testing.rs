@@ -0,0 +1,62 @@
+use crate::arch::{exit_qemu, QemuExitCode};
+use alloc::string::String;
+
+/// One test for `run_tests`. Tests report failure as a message, so the
+/// existing `test_*` functions fit behind a closure that formats their
+/// error; a test that can't run here returns `TestError::Skipped`.
+pub struct TestCase {
+    pub name: &'static str,
+    pub run: fn() -> Result<(), TestError>,
+}
+
+/// Why a `TestCase` did not pass.
+#[derive(Debug)]
+pub enum TestError {
+    Failed(String),
+    /// The test could not run in this setup, for instance without a
+    /// suitable disk. Counted apart from passes and failures.
+    Skipped(&'static str),
+}
+
+impl From<String> for TestError {
+    fn from(message: String) -> Self {
+        TestError::Failed(message)
+    }
+}
+
+impl From<&str> for TestError {
+    fn from(message: &str) -> Self {
+        TestError::Failed(message.into())
+    }
+}
+
+/// Runs every test in order, prints a summary to serial and exits QEMU
+/// with `Success` only if none of them failed.
+pub fn run_tests(tests: &[TestCase]) -> ! {
+    crate::serial_println!("Running {} tests", tests.len());
+    let (mut failed, mut skipped) = (0, 0);
+    for test in tests {
+        match (test.run)() {
+            Ok(()) => crate::serial_println!("test {} ... ok", test.name),
+            Err(TestError::Skipped(reason)) => {
+                skipped += 1;
+                crate::serial_println!("test {} ... skipped: {}", test.name, reason);
+            }
+            Err(TestError::Failed(e)) => {
+                failed += 1;
+                crate::serial_println!("test {} ... FAILED: {}", test.name, e);
+            }
+        }
+    }
+    crate::serial_println!(
+        "test result: {} passed, {} failed, {} skipped",
+        tests.len() - failed - skipped,
+        failed,
+        skipped
+    );
+    exit_qemu(if failed == 0 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failed
+    });
+}
@@ -0,0 +1,103 @@
+use alloc::format;
+use core::fmt;
+
+/// Bytes per line unless `HexDump::width` says otherwise.
+pub const DEFAULT_HEXDUMP_WIDTH: usize = 16;
+
+/// Formats bytes as lines of offset, hex and printable ASCII, like
+/// `hexdump -C`:
+///
+/// ```text
+/// 000001F0  00 00 00 00 00 00 00 00 00 00 00 00 00 00 55 AA  |..............U.|
+/// ```
+///
+/// Offsets start at `base_addr`, so a dump of part of a sector or of memory
+/// can show where the bytes came from. Every line ends in a newline.
+pub struct HexDump<'a> {
+    bytes: &'a [u8],
+    base_addr: usize,
+    width: usize,
+}
+
+impl<'a> HexDump<'a> {
+    pub fn new(bytes: &'a [u8], base_addr: usize) -> Self {
+        Self {
+            bytes,
+            base_addr,
+            width: DEFAULT_HEXDUMP_WIDTH,
+        }
+    }
+
+    /// Sets the bytes per line. 0 is taken as 1.
+    pub fn width(mut self, width: usize) -> Self {
+        self.width = width.max(1);
+        self
+    }
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, line) in self.bytes.chunks(self.width).enumerate() {
+            write!(f, "{:08X}  ", self.base_addr + i * self.width)?;
+            for byte in line {
+                write!(f, "{:02X} ", byte)?;
+            }
+            // Pad a short last line so its ASCII column lines up.
+            for _ in line.len()..self.width {
+                f.write_str("   ")?;
+            }
+            f.write_str(" |")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            f.write_str("|\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints `bytes` to the screen as a hex dump with offsets from
+/// `base_addr`.
+pub fn hexdump(bytes: &[u8], base_addr: usize) {
+    crate::print!("{}", HexDump::new(bytes, base_addr));
+}
+
+/// Prints `bytes` to the serial port as a hex dump with offsets from
+/// `base_addr`.
+pub fn serial_hexdump(bytes: &[u8], base_addr: usize) {
+    crate::serial_print!("{}", HexDump::new(bytes, base_addr));
+}
+
+/// Checks the dump of a known buffer, including a short last line and a
+/// non-default width, against the expected text.
+pub fn test_hexdump() -> Result<(), &'static str> {
+    let bytes = b"Hello, hexdump!\n\x00\x7F\xFFAB";
+
+    let expected = "\
+00000100  48 65 6C 6C 6F 2C 20 68 65 78 64 75 6D 70 21 0A  |Hello, hexdump!.|
+00000110  00 7F FF 41 42                                   |...AB|
+";
+    if format!("{}", HexDump::new(bytes, 0x100)) != expected {
+        return Err("16-byte dump differs from the expected text");
+    }
+
+    let expected = "\
+00000000  48 65 6C 6C  |Hell|
+00000004  6F           |o|
+";
+    if format!("{}", HexDump::new(&bytes[..5], 0).width(4)) != expected {
+        return Err("4-byte dump differs from the expected text");
+    }
+
+    if !format!("{}", HexDump::new(&[], 0)).is_empty() {
+        return Err("empty input produced output");
+    }
+
+    crate::serial_println!("✓ hexdump output matches");
+    Ok(())
+}
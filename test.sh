@@ -0,0 +1,19 @@
+cargo bootimage --target x86_64-sos.json --features qemu-test || exit 1
+echo "=== FINISHED COMPILING, RUNNING TESTS WITH QEMU ==="
+timeout 300 qemu-system-x86_64 \
+    -drive file=target/x86_64-sos/debug/bootimage-sos.bin,format=raw,if=ide,index=0 \
+    -drive file=disk.img,format=raw,if=ide,index=1 \
+    -smp 4 \
+    -m 2G \
+    -boot order=c \
+    -serial stdio \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -display none
+# isa-debug-exit turns QemuExitCode::Success (0x10) into 33.
+status=$?
+if [ $status -eq 33 ]; then
+    echo "=== ALL TESTS PASSED ==="
+    exit 0
+fi
+echo "=== TESTS FAILED (QEMU exited with $status) ==="
+exit 1